@@ -0,0 +1,97 @@
+//! Measures how many frames per second can be routed over a chain of routers
+//! that are connected over local TCP sockets.
+//!
+//! ```shell
+//! $ cargo bench --bench chain
+//! ```
+use ed25519_consensus::SigningKey;
+use rust_pinecone::{Client, PineconeCodec, PublicKey, SessionListener};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Number of hops between the first and the last router of the chain.
+const HOPS: usize = 3;
+const FRAMES: usize = 100_000;
+const PAYLOAD_SIZE: usize = 64;
+
+async fn connect(a: &Client, b: &Client) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accept = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let accepted = accept.await.unwrap();
+    let (reader, writer) = socket.into_split();
+    let (accepted_reader, accepted_writer) = accepted.into_split();
+    let b = b.clone();
+    let accept = tokio::spawn(async move {
+        b.connect_peer(
+            Box::new(FramedWrite::new(accepted_writer, PineconeCodec)),
+            Box::new(FramedRead::new(accepted_reader, PineconeCodec)),
+        )
+        .await
+        .unwrap();
+    });
+    a.connect_peer(
+        Box::new(FramedWrite::new(writer, PineconeCodec)),
+        Box::new(FramedRead::new(reader, PineconeCodec)),
+    )
+    .await
+    .unwrap();
+    accept.await.unwrap();
+}
+
+#[tokio::main]
+async fn main() {
+    let mut clients: Vec<(Client, SessionListener)> = vec![];
+    for i in 0..=HOPS {
+        clients.push(Client::new(SigningKey::from([i as u8 + 1; 32])).await);
+    }
+    for i in 0..HOPS {
+        connect(&clients[i].0, &clients[i + 1].0).await;
+    }
+    let (_last, mut listener) = clients.pop().unwrap();
+    let first = clients[0].0.clone();
+    let destination = PublicKey::from(&SigningKey::from([HOPS as u8 + 1; 32]));
+
+    // Wait for the tree and the snek to converge so that the first router can reach the last.
+    let mut sender = first.dial_send(destination).await;
+    let mut session = loop {
+        sender.write_all(b"probe").await.unwrap();
+        if let Ok(Some(session)) = timeout(Duration::from_millis(200), listener.recv()).await {
+            break session;
+        }
+    };
+    sleep(Duration::from_secs(1)).await;
+    let mut buf = [0u8; 1024];
+    while let Ok(Ok(_)) = timeout(Duration::from_millis(100), session.read(&mut buf)).await {}
+
+    let start = Instant::now();
+    let receiver = tokio::spawn(async move {
+        let mut received = 0;
+        let mut buf = [0u8; 1024];
+        while received < FRAMES {
+            match timeout(Duration::from_secs(1), session.read(&mut buf)).await {
+                Ok(Ok(_)) => received += 1,
+                _ => break,
+            }
+        }
+        (received, Instant::now())
+    });
+    let payload = [0u8; PAYLOAD_SIZE];
+    for _ in 0..FRAMES {
+        sender.write_all(&payload).await.unwrap();
+    }
+    let (received, end) = receiver.await.unwrap();
+    let elapsed = end.duration_since(start);
+    println!(
+        "{} hops: {} of {} frames in {:?} ({:.0} frames/s)",
+        HOPS,
+        received,
+        FRAMES,
+        elapsed,
+        received as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,266 @@
+use crate::client::Client;
+use crate::daemon::connect_tcp;
+use crate::logger::set_log_filters;
+use crate::public_key::PublicKey;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Socket that `ctl` talks to if no other one is given.
+pub const DEFAULT_ADMIN_SOCKET: &str = "/run/pinecone/admin.sock";
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// A JSON-RPC 2.0 request. Requests and responses are sent as one line of JSON each.
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddPeer {
+    address: String,
+}
+#[derive(Deserialize)]
+struct RemovePeer {
+    public_key: PublicKey,
+}
+#[derive(Deserialize)]
+struct SetLogLevel {
+    level: String,
+}
+
+/// Listens on a Unix socket at `path` that only the owner of the process can use.
+/// A socket that was left behind by an earlier run is replaced.
+pub(crate) fn bind_admin_socket(path: &Path) -> Result<UnixListener, Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Admin socket path exists and isn't a socket",
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answers the admin requests of every connection to `listener`.
+pub(crate) async fn serve_admin(client: Client, listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(client.clone(), stream));
+            }
+            Err(e) => {
+                warn!("Could not accept admin connection: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_connection(client: Client, stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Admin connection failed: {}", e);
+                break;
+            }
+        };
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let result = handle_request(&client, &request.method, request.params).await;
+                Response {
+                    jsonrpc: request.jsonrpc,
+                    id: request.id,
+                    result: result.as_ref().ok().cloned(),
+                    error: result.err(),
+                }
+            }
+            Err(e) => Response {
+                jsonrpc: String::from("2.0"),
+                id: Value::Null,
+                result: None,
+                error: Some(RpcError::new(PARSE_ERROR, e)),
+            },
+        };
+        let mut response = serde_json::to_vec(&response).unwrap();
+        response.push(b'\n');
+        if writer.write_all(&response).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_request(client: &Client, method: &str, params: Value) -> Result<Value, RpcError> {
+    let server_error = |e: &dyn ToString| RpcError::new(SERVER_ERROR, e.to_string());
+    match method {
+        "add_peer" => {
+            let AddPeer { address } = parse_params(params)?;
+            let public_key = connect_tcp(client, address.as_str())
+                .await
+                .map_err(|e| server_error(&e))?;
+            Ok(json!(public_key))
+        }
+        "remove_peer" => {
+            let RemovePeer { public_key } = parse_params(params)?;
+            client.disconnect_peer(public_key).await;
+            Ok(Value::Null)
+        }
+        "list_peers" => {
+            let peers = client.peer_infos().await.map_err(|e| server_error(&e))?;
+            Ok(json!(peers))
+        }
+        "tree" => {
+            let tree = client.tree_info().await.map_err(|e| server_error(&e))?;
+            Ok(json!(tree))
+        }
+        "snek" => {
+            let snek = client.snek_info().await.map_err(|e| server_error(&e))?;
+            Ok(json!(snek))
+        }
+        "set_log_level" => {
+            let SetLogLevel { level } = parse_params(params)?;
+            set_log_filters(&level).map_err(|e| server_error(&e))?;
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        )),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+/// Sends a single request to the admin socket at `path` and returns its result.
+pub async fn admin_request(
+    path: impl AsRef<Path>,
+    method: &str,
+    params: Value,
+) -> Result<Value, Error> {
+    let (reader, mut writer) = UnixStream::connect(path).await?.into_split();
+    let request = Request {
+        jsonrpc: String::from("2.0"),
+        id: json!(1),
+        method: String::from(method),
+        params,
+    };
+    let mut request = serde_json::to_vec(&request)?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "No response"))?;
+    let response: Response = serde_json::from_str(&line)?;
+    match response.error {
+        Some(error) => Err(Error::other(error.message)),
+        None => Ok(response.result.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::daemon::{Daemon, DaemonConfig};
+
+    #[tokio::test]
+    async fn control_daemon() {
+        let relay = Daemon::start(DaemonConfig {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let socket = std::env::temp_dir().join(format!("pinecone-admin-{}", std::process::id()));
+        let node = Daemon::start(DaemonConfig {
+            admin_socket: Some(socket.clone()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let relay_key = relay.client().public_key();
+
+        let added = admin_request(
+            &socket,
+            "add_peer",
+            json!({"address": relay.local_addrs()[0].to_string()}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(added, json!(relay_key));
+        let peers = admin_request(&socket, "list_peers", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(peers, json!([{"public_key": relay_key, "port": 1}]));
+        let tree = admin_request(&socket, "tree", Value::Null).await.unwrap();
+        assert!(tree["coordinates"].is_array());
+        let snek = admin_request(&socket, "snek", Value::Null).await.unwrap();
+        assert!(snek["paths"].is_number());
+
+        admin_request(&socket, "remove_peer", json!({"public_key": relay_key}))
+            .await
+            .unwrap();
+        let peers = admin_request(&socket, "list_peers", Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(peers, json!([]));
+
+        assert!(admin_request(&socket, "remove_peer", json!({}))
+            .await
+            .is_err());
+        assert!(admin_request(&socket, "unknown", Value::Null)
+            .await
+            .is_err());
+
+        node.stop().await.unwrap();
+        relay.stop().await.unwrap();
+        assert!(!socket.exists());
+    }
+}
@@ -1,21 +1,43 @@
+use crate::config::RouterConfig;
+use crate::coordinates::Coordinates;
+use crate::dht::{unix_time, DhtRecord};
 use crate::error::RouterError;
-use crate::frames::Frame;
-use crate::router::{PublicKey, Router};
-use crate::session::{SendSession, Session};
+use crate::frames::{Broadcast, Frame, DEFAULT_HOP_LIMIT};
+use crate::mailbox::{Inbox, MailStatus};
+use crate::metrics::Metrics;
+use crate::names::{check_name, name_key, select_record, NameCache, NameRecord};
+use crate::probe::Hop;
+use crate::public_key::PublicKey;
+use crate::pubsub::{
+    Publication, Subscription, MAX_PUBLICATION_SIZE, MAX_TOPIC_LENGTH, SUBSCRIPTION_BUFFER,
+};
+use crate::router::Router;
+use crate::session::{
+    parse_session_header, session_frame, tree_frame, SendSession, ServiceId, Session, SessionKey,
+    SessionState, TreeSession, EPHEMERAL_SERVICES, SESSION_CLOSE, SESSION_DATA,
+};
+use crate::status::{PeerInfo, SnekInfo, TreeInfo};
 #[cfg(doc)]
 use crate::wire_frame::PineconeCodec;
-use ed25519_consensus::SigningKey;
+use ed25519_consensus::{Signature, SigningKey};
 use futures_sink::Sink;
 use log::{debug, trace, warn};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 #[cfg(doc)]
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
 use tokio::sync::RwLock;
+use tokio::time::{interval, Instant};
 use tokio_stream::Stream;
 #[cfg(doc)]
 use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::PollSender;
 /// This is the object that is used to connect the router with other peers
 /// and get Sessions with other nodes in the overlay network.
 ///
@@ -26,26 +48,63 @@ pub struct Client {
     router_key: PublicKey,
     router: Router,
     upload: Sender<Frame>,
-    session_senders: Arc<RwLock<HashMap<PublicKey, Sender<Frame>>>>,
-    new_incoming: Arc<Sender<Session>>,
+    session_senders: Arc<RwLock<HashMap<SessionKey, SessionEntry>>>,
+    tree_sender: Arc<RwLock<Option<Sender<Frame>>>>,
+    new_incoming: Arc<Sender<SessionEvent>>,
+    /// Listeners of the services other than the default one.
+    service_listeners: Arc<RwLock<HashMap<ServiceId, Sender<SessionEvent>>>>,
+    next_ephemeral_service: Arc<AtomicU16>,
+    closed_sessions: UnboundedSender<(SessionKey, Arc<SessionState>)>,
+    /// Number of received frames that each session can hold.
+    session_queue_size: usize,
+    subscriptions: Arc<RwLock<HashMap<String, Vec<Sender<Publication>>>>>,
+    names: Arc<RwLock<NameCache>>,
+    name_authorities: Arc<Vec<PublicKey>>,
+    name_cache_ttl: Duration,
+}
+/// A [`Session`] as seen by the [`Client`].
+#[derive(Clone)]
+struct SessionEntry {
+    sender: Sender<Frame>,
+    state: Arc<SessionState>,
+    /// Listener that accepted the session. It is told when the other side closes it.
+    listener: Option<Sender<SessionEvent>>,
 }
 #[allow(unused)]
 impl Client {
     /// Creates a pinecone router and spawns various tokio tasks for it.
     pub async fn new(key: SigningKey) -> (Self, SessionListener) {
-        let public_key = key.verification_key().to_bytes();
-        let (upload_sender, upload_receiver) = channel(100);
+        Self::with_config(key, RouterConfig::default()).await
+    }
+    /// Like [`Client::new`] but with custom settings for the router.
+    pub async fn with_config(key: SigningKey, config: RouterConfig) -> (Self, SessionListener) {
+        let public_key = PublicKey::from(key.verification_key());
+        let (upload_sender, upload_receiver) = channel(config.session_upload_queue_size);
         let (download_sender, mut download_receiver) = channel(100);
         let (new_incoming_sender, new_incoming_receiver) = channel(100);
+        let (closed_sender, closed_receiver) = unbounded_channel();
+        let idle_timeout = config.session_idle_timeout;
+        let session_queue_size = config.session_download_queue_size;
+        let name_authorities = Arc::new(config.name_authorities.clone());
+        let name_cache_ttl = config.name_cache_ttl;
         let client = Self {
             router_key: public_key,
-            router: Router::new(key, download_sender, upload_receiver),
+            router: Router::new(key, config, download_sender, upload_receiver),
             upload: upload_sender,
             session_senders: Arc::new(Default::default()),
+            tree_sender: Arc::new(Default::default()),
             new_incoming: Arc::new(new_incoming_sender),
+            service_listeners: Arc::new(Default::default()),
+            next_ephemeral_service: Arc::new(AtomicU16::new(*EPHEMERAL_SERVICES.start())),
+            closed_sessions: closed_sender,
+            session_queue_size,
+            subscriptions: Arc::new(Default::default()),
+            names: Arc::new(Default::default()),
+            name_authorities,
+            name_cache_ttl,
         };
         let client1 = client.clone();
-        tokio::spawn(async move {
+        client.router.spawn(async move {
             loop {
                 let recv = download_receiver.recv().await;
                 match recv {
@@ -55,41 +114,37 @@ impl Client {
                     }
                     Some(frame) => match &frame {
                         Frame::SnekRouted(packet) => {
-                            let senders = client1.session_senders.read().await;
-                            if let Some(sender) = senders.get(&packet.source_key).cloned() {
-                                let public_key = packet.source_key;
-                                if let Err(e) = sender.send(frame).await {
-                                    debug!(
-                                        "Session for {:?} was closed. Removing sender",
-                                        public_key
-                                    );
-                                    drop(senders);
-                                    client1.session_senders.write().await.remove(&public_key);
+                            let Some((local_service, remote_service, kind)) =
+                                parse_session_header(&packet.payload)
+                            else {
+                                trace!("Dropping session frame without header");
+                                continue;
+                            };
+                            let key = SessionKey {
+                                public_key: packet.source_key,
+                                local_service,
+                                remote_service,
+                            };
+                            match kind {
+                                SESSION_DATA => client1.receive_data(key, frame).await,
+                                SESSION_CLOSE => client1.remote_close(key).await,
+                                kind => trace!("Dropping session frame of kind {}", kind),
+                            }
+                        }
+                        Frame::TreeRouted(_) => {
+                            let sender = client1.tree_sender.read().await.clone();
+                            if let Some(sender) = sender {
+                                if sender.send(frame).await.is_err() {
+                                    debug!("Tree session was closed. Removing sender");
+                                    client1.tree_sender.write().await.take();
                                 }
                             } else {
-                                drop(senders);
-                                trace!("No session for {:?}. Creating new one", packet.source_key);
-                                let (download_sender, download_receiver) = channel(100);
-                                download_sender.send(frame.clone()).await;
-                                client1
-                                    .session_senders
-                                    .write()
-                                    .await
-                                    .insert(packet.source_key, download_sender);
-                                if let Err(e) = client1
-                                    .new_incoming
-                                    .send(Session {
-                                        router_key: client1.router_key,
-                                        dialed_key: packet.source_key,
-                                        download: download_receiver,
-                                        upload: client1.upload.clone(),
-                                    })
-                                    .await
-                                {
-                                    warn!("new session could not be created: {:?}", e);
-                                }
+                                trace!("No tree session. Dropping {:?}", frame);
                             }
                         }
+                        Frame::Broadcast(broadcast) => {
+                            client1.deliver_broadcast(broadcast).await;
+                        }
                         e => {
                             trace!("Received protocol frame on client download channel {:?}", e);
                         }
@@ -98,11 +153,21 @@ impl Client {
             }
             debug!("Stopped client download loop");
         });
+        client
+            .router
+            .spawn(client.clone().close_sessions(closed_receiver, idle_timeout));
         client.router.start().await;
-        (client, new_incoming_receiver)
+        (
+            client,
+            SessionListener {
+                events: new_incoming_receiver,
+            },
+        )
     }
-    pub async fn stop(&self) {
-        self.router.stop().await;
+    /// Stops the router. Resolves once all of its tasks have stopped or fails with
+    /// [`RouterError::Timeout`] if that takes longer than `timeout`.
+    pub async fn stop(&self, timeout: Duration) -> Result<(), RouterError> {
+        self.router.stop(timeout).await
     }
     /// Directly connects a peer with the router using the given connections.
     ///
@@ -119,41 +184,1002 @@ impl Client {
     pub async fn disconnect_peer(&self, peer_key: PublicKey) {
         self.router.disconnect_peer(peer_key).await;
     }
+    /// Returns the public key of the router.
+    pub fn public_key(&self) -> PublicKey {
+        self.router_key
+    }
+    /// Returns the keys of all currently connected peers.
+    pub async fn peers(&self) -> Result<Vec<PublicKey>, RouterError> {
+        self.router.peers().await
+    }
+    /// Like [`Client::peers`] but also returns the ports of the peers.
+    pub async fn peer_infos(&self) -> Result<Vec<PeerInfo>, RouterError> {
+        self.router.peer_infos().await
+    }
+    /// Returns the position of the router in the spanning tree.
+    pub async fn tree_info(&self) -> Result<TreeInfo, RouterError> {
+        self.router.tree_info().await
+    }
+    /// Returns the neighbours of the router in the SNEK.
+    pub async fn snek_info(&self) -> Result<SnekInfo, RouterError> {
+        self.router.snek_info().await
+    }
+    /// Returns a snapshot of the counters of the router.
+    pub fn metrics(&self) -> Metrics {
+        self.router.metrics()
+    }
+    /// Returns the current coordinates of this node in the spanning tree.
+    pub async fn coordinates(&self) -> Result<Coordinates, RouterError> {
+        self.router.coordinates().await
+    }
+    /// Asks the node with `public_key` for its current coordinates in the spanning tree.
+    /// Answers are cached until they expire or the root of the tree changes.
+    ///
+    /// Fails with [`RouterError::Timeout`] if the node doesn't answer in time.
+    pub async fn lookup_coordinates(
+        &self,
+        public_key: PublicKey,
+    ) -> Result<Coordinates, RouterError> {
+        self.router.lookup_coordinates(public_key).await
+    }
+    /// Measures the round trip time to the node with `public_key`.
+    ///
+    /// Fails with [`RouterError::Timeout`] if the node doesn't answer in time.
+    pub async fn ping(&self, public_key: PublicKey) -> Result<Duration, RouterError> {
+        let hops = self.router.probe(public_key, false).await?;
+        Ok(hops.last().map(|hop| hop.rtt).unwrap_or_default())
+    }
+    /// Lists the nodes that frames to `public_key` are routed through, together with
+    /// the ports they are forwarded on. The last [`Hop`] is the node with `public_key`
+    /// if it answered in time.
+    ///
+    /// Fails with [`RouterError::Timeout`] if no node answers in time.
+    pub async fn traceroute(&self, public_key: PublicKey) -> Result<Vec<Hop>, RouterError> {
+        self.router.probe(public_key, true).await
+    }
+    /// Stores `value` under `key` in the DHT for `ttl`, or for
+    /// [`RouterConfig::dht_max_ttl`] if that is shorter. The record is signed by this
+    /// node and replaces the record it put under `key` before.
+    ///
+    /// The record is kept by the node whose public key follows the hash of `key` and
+    /// by [`RouterConfig::dht_replicas`] nodes after it. Puts aren't acknowledged, and
+    /// records have to be put again to survive changes of these nodes. Keys are at most
+    /// 255 bytes long and values at most 16 KiB large.
+    pub async fn dht_put(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<DhtRecord, RouterError> {
+        self.router.dht_put(key.to_vec(), value, ttl).await
+    }
+    /// Returns the records that nodes put under `key` in the DHT, at most one per
+    /// writer. Records that weren't signed by their writer are left out.
+    ///
+    /// Fails with [`RouterError::Timeout`] if the node that keeps them doesn't answer
+    /// in time.
+    pub async fn dht_get(&self, key: &[u8]) -> Result<Vec<DhtRecord>, RouterError> {
+        self.router.dht_get(key.to_vec()).await
+    }
+    /// Claims `name` for this node for `ttl`, or for [`RouterConfig::dht_max_ttl`] if
+    /// that is shorter. Names are 1 to 63 lowercase ASCII letters, digits and dashes.
+    ///
+    /// The name only becomes ours if no other node claimed it before. Like other
+    /// DHT records, claims aren't acknowledged and have to be renewed before they
+    /// expire. Whether the name was taken shows when resolving it.
+    pub async fn register_name(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<NameRecord, RouterError> {
+        check_name(name)?;
+        let record = self
+            .router
+            .dht_put(name_key(name), self.router_key.as_bytes().to_vec(), ttl)
+            .await?;
+        Ok(NameRecord {
+            name: String::from(name),
+            record,
+        })
+    }
+    /// Publishes a name record that was signed with [`NameRecord::new`], for example
+    /// by an authority.
+    pub async fn publish_name(&self, record: &NameRecord) -> Result<(), RouterError> {
+        self.router.dht_put_record(record.record.clone()).await
+    }
+    /// Returns the key of the node that `name` belongs to.
+    ///
+    /// Names are looked up in the hosts that were set with [`Client::set_hosts`]
+    /// first, then in the names that were resolved before and finally in the DHT.
+    /// Fails with [`RouterError::UnknownName`] if no node has the name.
+    pub async fn resolve(&self, name: &str) -> Result<PublicKey, RouterError> {
+        check_name(name)?;
+        if let Some(public_key) = self.names.write().await.get(name) {
+            return Ok(public_key);
+        }
+        let records = self.router.dht_get(name_key(name)).await?;
+        let record =
+            select_record(records, name, &self.name_authorities).ok_or(RouterError::UnknownName)?;
+        let now = unix_time();
+        let ttl =
+            Duration::from_secs(record.expires().saturating_sub(now)).min(self.name_cache_ttl);
+        self.names.write().await.resolved.insert(
+            String::from(name),
+            (record.public_key(), Instant::now() + ttl),
+        );
+        Ok(record.public_key())
+    }
+    /// Replaces the names that are resolved locally, without asking the network.
+    /// They take precedence over the names of other nodes, like the entries of
+    /// `/etc/hosts`. See [`load_hosts_file`](crate::load_hosts_file).
+    pub async fn set_hosts(&self, hosts: HashMap<String, PublicKey>) {
+        self.names.write().await.hosts = hosts;
+    }
+    /// Resolves `name` and dials the node it belongs to like [`Client::dial`].
+    pub async fn dial_name(&self, name: &str) -> Result<Session, RouterError> {
+        let public_key = self.resolve(name).await?;
+        self.dial(public_key).await
+    }
+    /// Sends `payload` over the spanning tree to the node at `coordinates`.
+    ///
+    /// Tree routed data that arrives at this node is handed to the [`TreeSession`].
+    pub async fn send_tree(
+        &self,
+        coordinates: Coordinates,
+        payload: Vec<u8>,
+    ) -> Result<(), RouterError> {
+        self.upload
+            .send(tree_frame(coordinates, Default::default(), payload))
+            .await
+            .map_err(|_| RouterError::Stopped)
+    }
+    /// Creates a [`TreeSession`] that receives all tree routed data for this node.
+    ///
+    /// There can be only one TreeSession at a time. A new one can be created
+    /// once the previous one was dropped.
+    pub async fn tree_session(&self) -> Result<TreeSession, RouterError> {
+        let mut tree_sender = self.tree_sender.write().await;
+        if let Some(sender) = &*tree_sender {
+            if !sender.is_closed() {
+                return Err(RouterError::SessionAlreadyExists);
+            }
+        }
+        let (download_sender, download_receiver) = channel(self.session_queue_size);
+        *tree_sender = Some(download_sender);
+        Ok(TreeSession {
+            router_key: self.router_key,
+            traffic_class: Default::default(),
+            download: download_receiver,
+            upload: self.upload.clone(),
+        })
+    }
+    /// Sends `payload` to all nodes in the network that subscribed to `topic`.
+    /// Topics are at most 255 bytes long and both together must fit into a frame.
+    pub async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), RouterError> {
+        if topic.len() > MAX_TOPIC_LENGTH {
+            return Err(RouterError::EncodingError("Topic is too long"));
+        }
+        if topic.len() + payload.len() > MAX_PUBLICATION_SIZE {
+            return Err(RouterError::EncodingError("Publication is too large"));
+        }
+        let broadcast = Broadcast {
+            source_key: self.router_key,
+            id: rand::random(),
+            // The router signs the broadcast.
+            signature: Signature::from([0; 64]),
+            topic: String::from(topic),
+            traffic_class: Default::default(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            payload,
+        };
+        self.upload
+            .send(Frame::Broadcast(broadcast))
+            .await
+            .map_err(|_| RouterError::Stopped)
+    }
+    /// Creates a [`Subscription`] that receives what other nodes publish to `topic`.
+    /// A topic can have any number of subscriptions.
+    pub async fn subscribe(&self, topic: &str) -> Subscription {
+        let (sender, publications) = channel(SUBSCRIPTION_BUFFER);
+        let mut subscriptions = self.subscriptions.write().await;
+        subscriptions.retain(|_, senders| {
+            senders.retain(|sender| !sender.is_closed());
+            !senders.is_empty()
+        });
+        subscriptions
+            .entry(String::from(topic))
+            .or_default()
+            .push(sender);
+        Subscription {
+            topic: String::from(topic),
+            publications,
+        }
+    }
+    /// Sends `payload` to the node with `public_key`, which receives it in its
+    /// [`Inbox`]. If that node is offline, the node that follows its key holds the
+    /// mail until it is back, provided that it is a mailbox (see
+    /// [`RouterConfig::mailbox`]). The returned status tells which of both happened.
+    ///
+    /// The payload is sealed for the destination, so mailboxes can't read it, and
+    /// the mail is signed, so that nobody can send mail in the name of this node.
+    /// Payloads are at most 65,349 bytes.
+    pub async fn send_mail(
+        &self,
+        public_key: PublicKey,
+        payload: Vec<u8>,
+    ) -> Result<MailStatus, RouterError> {
+        self.router.send_mail(public_key, payload).await
+    }
+    /// Takes the [`Inbox`] that receives the mail of this node, including the mail
+    /// that mailboxes held for it while it was offline. Mail is kept until the
+    /// inbox is taken.
+    ///
+    /// There is only one inbox. Fails with [`RouterError::InboxTaken`] if it was
+    /// taken before.
+    pub async fn inbox(&self) -> Result<Inbox, RouterError> {
+        self.router.inbox()
+    }
+    /// Hands a broadcast to the subscriptions of its topic. It is dropped for
+    /// subscriptions that are full.
+    async fn deliver_broadcast(&self, broadcast: &Broadcast) {
+        let subscriptions = self.subscriptions.read().await;
+        let Some(senders) = subscriptions.get(&broadcast.topic) else {
+            trace!("No subscription for topic {:?}", broadcast.topic);
+            return;
+        };
+        let publication = Publication {
+            source_key: broadcast.source_key,
+            topic: broadcast.topic.clone(),
+            payload: broadcast.payload.clone(),
+        };
+        for sender in senders {
+            if let Err(TrySendError::Full(_)) = sender.try_send(publication.clone()) {
+                debug!(
+                    "Subscription of {:?} is full. Dropping publication",
+                    broadcast.topic
+                );
+            }
+        }
+    }
     /// Dials a node with the given public key in the network and creates a [`SendSession`] for it.
     /// This doesn't communicate with the actual node so the session is created
     /// regardless of weather this node is actually reachable or not.
     ///
     /// SendSessions can be created multiple times for a given key.
     pub async fn dial_send(&self, public_key: PublicKey) -> SendSession {
+        self.dial_send_service(public_key, 0).await
+    }
+    /// Like [`Client::dial_send`] but for `service` of the node. The data is sent from
+    /// the same service of this node.
+    pub async fn dial_send_service(
+        &self,
+        public_key: PublicKey,
+        service: ServiceId,
+    ) -> SendSession {
         SendSession {
             router_key: self.router_key,
             dialed_key: public_key,
-            upload: self.upload.clone(),
+            local_service: service,
+            remote_service: service,
+            traffic_class: Default::default(),
+            upload: PollSender::new(self.upload.clone()),
+            state: Arc::new(SessionState::new()),
         }
     }
     /// Dials a node with the given public key in the network and creates a [`Session`] for it.
     /// This doesn't communicate with the actual node so the session is created
     /// regardless of weather this node is actually reachable or not.
     ///
-    /// Sessions can be created only once for a given key.
+    /// Sessions can be created only once for a given key. Use [`Client::dial_service`]
+    /// for more sessions with the same node.
     pub async fn dial(&self, public_key: PublicKey) -> Result<Session, RouterError> {
-        if self.session_senders.read().await.contains_key(&public_key) {
+        let key = SessionKey {
+            public_key,
+            local_service: 0,
+            remote_service: 0,
+        };
+        let mut senders = self.session_senders.write().await;
+        if senders.contains_key(&key) {
             return Err(RouterError::SessionAlreadyExists);
         }
-        let (download_sender, download_receiver) = channel(100);
-        self.session_senders
-            .write()
-            .await
-            .insert(public_key, download_sender);
-        Ok(Session {
+        Ok(self.new_session(&mut senders, key, None).1)
+    }
+    /// Creates a [`Session`] with `service` of the node with `public_key`. The local
+    /// side of the session gets a service that isn't used for a session with that node
+    /// yet, so this can be called any number of times.
+    pub async fn dial_service(
+        &self,
+        public_key: PublicKey,
+        service: ServiceId,
+    ) -> Result<Session, RouterError> {
+        let mut senders = self.session_senders.write().await;
+        for _ in EPHEMERAL_SERVICES {
+            let local_service = self.next_ephemeral_service.fetch_add(1, Ordering::Relaxed);
+            if !EPHEMERAL_SERVICES.contains(&local_service) {
+                self.next_ephemeral_service
+                    .store(*EPHEMERAL_SERVICES.start(), Ordering::Relaxed);
+                continue;
+            }
+            let key = SessionKey {
+                public_key,
+                local_service,
+                remote_service: service,
+            };
+            if !senders.contains_key(&key) {
+                return Ok(self.new_session(&mut senders, key, None).1);
+            }
+        }
+        Err(RouterError::ServiceInUse)
+    }
+    /// Returns a [`SessionListener`] for the sessions that other nodes open with
+    /// `service` of this node.
+    ///
+    /// Fails with [`RouterError::ServiceInUse`] if the service already has a listener.
+    /// Sessions with service 0 are handed to the listener that was returned by
+    /// [`Client::new`]. The service can be listened on again once the listener
+    /// was dropped.
+    pub async fn listen(&self, service: ServiceId) -> Result<SessionListener, RouterError> {
+        let mut listeners = self.service_listeners.write().await;
+        if service == 0 || listeners.get(&service).is_some_and(|l| !l.is_closed()) {
+            return Err(RouterError::ServiceInUse);
+        }
+        let (sender, events) = channel(100);
+        listeners.insert(service, sender);
+        Ok(SessionListener { events })
+    }
+    fn new_session(
+        &self,
+        senders: &mut HashMap<SessionKey, SessionEntry>,
+        key: SessionKey,
+        listener: Option<Sender<SessionEvent>>,
+    ) -> (Sender<Frame>, Session) {
+        let (download_sender, download_receiver) = channel(self.session_queue_size);
+        let state = Arc::new(SessionState::new());
+        senders.insert(
+            key,
+            SessionEntry {
+                sender: download_sender.clone(),
+                state: state.clone(),
+                listener,
+            },
+        );
+        let session = Session {
             router_key: self.router_key,
-            dialed_key: public_key,
+            dialed_key: key.public_key,
+            local_service: key.local_service,
+            remote_service: key.remote_service,
+            traffic_class: Default::default(),
             download: download_receiver,
-            upload: self.upload.clone(),
-        })
+            upload: PollSender::new(self.upload.clone()),
+            state,
+            closed: self.closed_sessions.clone(),
+        };
+        (download_sender, session)
+    }
+    /// Hands a data `frame` to the session of `key` or accepts a new session for it.
+    async fn receive_data(&self, key: SessionKey, frame: Frame) {
+        let sender = self
+            .session_senders
+            .read()
+            .await
+            .get(&key)
+            .map(|entry| entry.sender.clone());
+        if let Some(sender) = sender {
+            if sender.send(frame).await.is_err() {
+                debug!("Session for {:?} was closed. Removing sender", key);
+                self.session_senders.write().await.remove(&key);
+            }
+        } else {
+            self.accept_session(key, frame).await;
+        }
+    }
+    /// Closes the session of `key` because the other side closed it.
+    async fn remote_close(&self, key: SessionKey) {
+        let Some(entry) = self.session_senders.write().await.remove(&key) else {
+            trace!("No session for {:?} to close", key);
+            return;
+        };
+        debug!("Session for {:?} was closed by the other side", key);
+        entry.state.close();
+        if let Some(listener) = entry.listener {
+            let _ = listener
+                .send(SessionEvent::Closed {
+                    public_key: key.public_key,
+                    local_service: key.local_service,
+                    remote_service: key.remote_service,
+                })
+                .await;
+        }
+    }
+    /// Forgets the sessions that were closed on this side and tells the other sides.
+    /// Sessions that were idle for longer than `idle_timeout` are closed as well.
+    async fn close_sessions(
+        self,
+        mut closed: UnboundedReceiver<(SessionKey, Arc<SessionState>)>,
+        idle_timeout: Option<Duration>,
+    ) {
+        let mut idle_check =
+            idle_timeout.map(|timeout| interval((timeout / 2).max(Duration::from_millis(1))));
+        loop {
+            let keys = tokio::select! {
+                Some((key, state)) = closed.recv() => {
+                    let mut senders = self.session_senders.write().await;
+                    // The key may already belong to a newer session.
+                    if senders.get(&key).is_some_and(|e| Arc::ptr_eq(&e.state, &state)) {
+                        senders.remove(&key);
+                    }
+                    vec![key]
+                }
+                _ = async { idle_check.as_mut().unwrap().tick().await }, if idle_check.is_some() => {
+                    let timeout = idle_timeout.unwrap();
+                    let mut keys = vec![];
+                    self.session_senders.write().await.retain(|key, entry| {
+                        if entry.state.idle_time() < timeout {
+                            return true;
+                        }
+                        debug!("Session for {:?} was idle for too long", key);
+                        entry.state.close();
+                        keys.push(*key);
+                        false
+                    });
+                    keys
+                }
+            };
+            for key in keys {
+                let frame = session_frame(
+                    key.public_key,
+                    self.router_key,
+                    key.remote_service,
+                    key.local_service,
+                    Default::default(),
+                    SESSION_CLOSE,
+                    &[],
+                );
+                if self.upload.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    /// Creates a session for the first `frame` of another node and hands it to the
+    /// listener of its service. The frame is dropped if nobody listens.
+    async fn accept_session(&self, key: SessionKey, frame: Frame) {
+        let listener = if key.local_service == 0 {
+            Some((*self.new_incoming).clone())
+        } else {
+            self.service_listeners
+                .read()
+                .await
+                .get(&key.local_service)
+                .cloned()
+        };
+        let Some(listener) = listener else {
+            trace!("No listener for {:?}. Dropping frame", key);
+            return;
+        };
+        trace!("No session for {:?}. Creating new one", key);
+        let (download_sender, session) = self.new_session(
+            &mut *self.session_senders.write().await,
+            key,
+            Some(listener.clone()),
+        );
+        let _ = download_sender.try_send(frame);
+        if let Err(e) = listener.send(SessionEvent::Opened(session)).await {
+            warn!("new session could not be created: {:?}", e.0);
+        }
     }
 }
-/// Channel Receiver of new incoming [`Session`]s.
+/// Something that happened to the sessions of a [`SessionListener`].
+#[allow(unused)]
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// Another node opened a session.
+    Opened(Session),
+    /// The other node closed a session that was opened by it before.
+    Closed {
+        public_key: PublicKey,
+        local_service: ServiceId,
+        remote_service: ServiceId,
+    },
+}
+/// Receiver of new incoming [`Session`]s.
 ///
-/// Handed out when creating a new Client.
-pub type SessionListener = Receiver<Session>;
+/// Handed out when creating a new Client and by [`Client::listen`].
+#[derive(Debug)]
+pub struct SessionListener {
+    events: Receiver<SessionEvent>,
+}
+#[allow(unused)]
+impl SessionListener {
+    /// Receives the next session that another node opened and skips the
+    /// [`SessionEvent::Closed`] events. Returns `None` once the [`Client`] was dropped.
+    pub async fn recv(&mut self) -> Option<Session> {
+        loop {
+            if let SessionEvent::Opened(session) = self.events.recv().await? {
+                return Some(session);
+            }
+        }
+    }
+    /// Receives the next event. Returns `None` once the [`Client`] was dropped.
+    pub async fn next_event(&mut self) -> Option<SessionEvent> {
+        self.events.recv().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::{connected_clients, new_memory_connection, wait_for_snek};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test(start_paused = true)]
+    async fn send_over_tree() {
+        let (client1, client2, _) = connected_clients(Default::default()).await;
+        assert_eq!(client1.peers().await.unwrap(), vec![client2.router_key]);
+        assert_eq!(client2.peers().await.unwrap(), vec![client1.router_key]);
+        // Wait until both nodes agree on the root of the tree.
+        let (coordinates1, coordinates2) = loop {
+            let coordinates1 = client1.coordinates().await.unwrap();
+            let coordinates2 = client2.coordinates().await.unwrap();
+            if coordinates1.ports().len() + coordinates2.ports().len() == 1 {
+                break (coordinates1, coordinates2);
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let mut session2 = client2.tree_session().await.unwrap();
+        assert!(matches!(
+            client2.tree_session().await,
+            Err(RouterError::SessionAlreadyExists)
+        ));
+        client1
+            .send_tree(coordinates2.clone(), vec![1, 2, 3])
+            .await
+            .unwrap();
+        let (from, payload) = session2.recv_from().await.unwrap();
+        assert_eq!(from, coordinates1);
+        assert_eq!(payload, vec![1, 2, 3]);
+
+        let mut session1 = client1.tree_session().await.unwrap();
+        session2.send_to(from, vec![4]).await.unwrap();
+        let (from, payload) = session1.recv_from().await.unwrap();
+        assert_eq!(from, coordinates2);
+        assert_eq!(payload, vec![4]);
+
+        drop(session2);
+        assert!(client2.tree_session().await.is_ok());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn multiplex_services() {
+        let (client1, client2, mut listener2) = connected_clients(Default::default()).await;
+        let mut services2 = client2.listen(7).await.unwrap();
+        assert!(matches!(
+            client2.listen(7).await,
+            Err(RouterError::ServiceInUse)
+        ));
+        assert!(matches!(
+            client2.listen(0).await,
+            Err(RouterError::ServiceInUse)
+        ));
+
+        let mut default1 = client1.dial(client2.router_key).await.unwrap();
+        let mut first1 = client1.dial_service(client2.router_key, 7).await.unwrap();
+        let mut second1 = client1.dial_service(client2.router_key, 7).await.unwrap();
+        assert_ne!(first1.local_service(), second1.local_service());
+        assert_eq!(first1.remote_service(), 7);
+        default1.write_all(b"default").await.unwrap();
+        first1.write_all(b"first").await.unwrap();
+        second1.write_all(b"second").await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let mut default2 = listener2.recv().await.unwrap();
+        let len = default2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"default");
+        let mut first2 = services2.recv().await.unwrap();
+        let len = first2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"first");
+        assert_eq!(first2.local_service(), 7);
+        assert_eq!(first2.remote_service(), first1.local_service());
+        let mut second2 = services2.recv().await.unwrap();
+        let len = second2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"second");
+
+        // Replies go back to the session they belong to.
+        second2.write_all(b"reply").await.unwrap();
+        let len = second1.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"reply");
+
+        drop(services2);
+        assert!(client2.listen(7).await.is_ok());
+    }
+    #[tokio::test]
+    async fn close_sessions() {
+        let (client1, client2, mut listener2) = connected_clients(Default::default()).await;
+        let mut buf = [0u8; 16];
+
+        // Dropping a session closes it on both sides.
+        let mut session1 = client1.dial(client2.router_key).await.unwrap();
+        session1.write_all(b"hello").await.unwrap();
+        let Some(SessionEvent::Opened(mut session2)) = listener2.next_event().await else {
+            panic!("No session was opened");
+        };
+        drop(session1);
+        let len = session2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(session2.read(&mut buf).await.unwrap(), 0);
+        assert!(session2.is_closed());
+        assert!(session2.write_all(b"late").await.is_err());
+        assert!(matches!(
+            listener2.next_event().await,
+            Some(SessionEvent::Closed { public_key, local_service: 0, remote_service: 0 })
+                if public_key == client1.router_key
+        ));
+        assert!(client2.session_senders.read().await.is_empty());
+        assert!(client1.session_senders.read().await.is_empty());
+
+        // The key can be dialed again and shutting a session down closes it as well.
+        let mut session1 = client1.dial(client2.router_key).await.unwrap();
+        session1.write_all(b"again").await.unwrap();
+        let mut session2 = listener2.recv().await.unwrap();
+        let len = session2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"again");
+        session2.shutdown().await.unwrap();
+        assert_eq!(session1.read(&mut buf).await.unwrap(), 0);
+        assert!(session1.write_all(b"late").await.is_err());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn close_idle_sessions() {
+        let config = RouterConfig {
+            session_idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let (client1, client2, mut listener2) = connected_clients(config).await;
+        let mut buf = [0u8; 16];
+        let mut session1 = client1.dial(client2.router_key).await.unwrap();
+        session1.write_all(b"hello").await.unwrap();
+        let mut session2 = listener2.recv().await.unwrap();
+        let len = session2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        let idle = tokio::time::timeout(Duration::from_secs(5), session1.read(&mut buf));
+        assert_eq!(idle.await.unwrap().unwrap(), 0);
+        assert_eq!(session2.read(&mut buf).await.unwrap(), 0);
+        assert!(session1.is_closed());
+        assert!(session2.is_closed());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn lookup_coordinates() {
+        let (client1, client2, _) = connected_clients(Default::default()).await;
+        let coordinates2 = loop {
+            let coordinates1 = client1.coordinates().await.unwrap();
+            let coordinates2 = client2.coordinates().await.unwrap();
+            if coordinates1.ports().len() + coordinates2.ports().len() == 1 {
+                break coordinates2;
+            }
+            sleep(Duration::from_millis(10)).await;
+        };
+        let looked_up = client1
+            .lookup_coordinates(client2.router_key)
+            .await
+            .unwrap();
+        assert_eq!(looked_up, coordinates2);
+        // The second lookup is answered from the cache.
+        client2.stop(Duration::from_secs(1)).await.unwrap();
+        let cached = client1
+            .lookup_coordinates(client2.router_key)
+            .await
+            .unwrap();
+        assert_eq!(cached, coordinates2);
+        assert!(matches!(
+            client1.lookup_coordinates(PublicKey::from([3; 32])).await,
+            Err(RouterError::NoRoute)
+        ));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn traceroute() {
+        let config = RouterConfig {
+            probe_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut clients = vec![];
+        for key in 1..=3 {
+            let (client, listener) =
+                Client::with_config(SigningKey::from([key; 32]), config.clone()).await;
+            clients.push((client, listener));
+        }
+        for i in 0..2 {
+            let (u1, d1, u2, d2) = new_memory_connection();
+            let (peer1, peer2) = tokio::join!(
+                clients[i].0.connect_peer(u1, d1),
+                clients[i + 1].0.connect_peer(u2, d2)
+            );
+            peer1.unwrap();
+            peer2.unwrap();
+        }
+        let (first, middle, last) = (&clients[0].0, &clients[1].0, &clients[2].0);
+        // Wait until the network converged.
+        let hops = loop {
+            if let Ok(hops) = first.traceroute(last.router_key).await {
+                if hops.len() == 2 && hops[1].public_key == last.router_key {
+                    break hops;
+                }
+            }
+            sleep(Duration::from_millis(100)).await;
+        };
+        assert_eq!(hops[0].public_key, middle.router_key);
+        assert_ne!(hops[0].port, 0);
+        assert_eq!(hops[1].port, 0);
+        assert!(hops[0].rtt <= hops[1].rtt);
+        assert!(first.ping(last.router_key).await.is_ok());
+        assert!(first.ping(first.router_key).await.is_ok());
+        assert!(matches!(
+            middle.ping(PublicKey::from([0; 32])).await,
+            Err(RouterError::Timeout | RouterError::NoRoute)
+        ));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn publish_over_tree() {
+        let mut clients = vec![];
+        for key in 1..=4 {
+            let (client, _) = Client::new(SigningKey::from([key; 32])).await;
+            clients.push(client);
+        }
+        // A triangle with a leaf, so broadcasts could go around in circles.
+        for (i, j) in [(0, 1), (1, 2), (2, 0), (2, 3)] {
+            let (u1, d1, u2, d2) = new_memory_connection();
+            let (peer1, peer2) = tokio::join!(
+                clients[i].connect_peer(u1, d1),
+                clients[j].connect_peer(u2, d2)
+            );
+            peer1.unwrap();
+            peer2.unwrap();
+        }
+        loop {
+            let mut roots = vec![];
+            for client in &clients {
+                roots.push(client.tree_info().await.unwrap().root);
+            }
+            if roots.iter().all(|root| *root == roots[0]) {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        sleep(Duration::from_millis(500)).await;
+
+        let mut own = clients[0].subscribe("news").await;
+        let mut subscriptions = vec![];
+        for client in &clients[1..] {
+            subscriptions.push(client.subscribe("news").await);
+        }
+        let mut other = clients[3].subscribe("other").await;
+        assert_eq!(other.topic(), "other");
+        clients[0].publish("news", vec![1, 2, 3]).await.unwrap();
+        for subscription in &mut subscriptions {
+            let publication = subscription.recv().await.unwrap();
+            assert_eq!(publication.source_key, clients[0].router_key);
+            assert_eq!(publication.topic, "news");
+            assert_eq!(publication.payload, vec![1, 2, 3]);
+        }
+        // Every subscription gets the publication exactly once.
+        sleep(Duration::from_millis(300)).await;
+        for subscription in &mut subscriptions {
+            assert!(subscription.publications.try_recv().is_err());
+        }
+        assert!(own.publications.try_recv().is_err());
+        assert!(other.publications.try_recv().is_err());
+
+        assert!(matches!(
+            clients[0].publish(&"a".repeat(256), vec![]).await,
+            Err(RouterError::EncodingError(_))
+        ));
+    }
+    /// Gets the records of `key` until there are some, retrying lookups that time
+    /// out while the network changes.
+    async fn dht_get_records(client: &Client, key: &[u8]) -> Vec<DhtRecord> {
+        loop {
+            match client.dht_get(key).await {
+                Ok(records) if !records.is_empty() => return records,
+                _ => sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+    #[tokio::test(start_paused = true)]
+    async fn dht_put_and_get() {
+        let mut clients = vec![];
+        for key in 1..=4 {
+            let config = RouterConfig {
+                dht_timeout: Duration::from_secs(1),
+                ..Default::default()
+            };
+            let (client, _) = Client::with_config(SigningKey::from([key; 32]), config).await;
+            clients.push(client);
+        }
+        // A ring, so that the others stay connected when any node goes away.
+        for (i, j) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            let (u1, d1, u2, d2) = new_memory_connection();
+            let (peer1, peer2) = tokio::join!(
+                clients[i].connect_peer(u1, d1),
+                clients[j].connect_peer(u2, d2)
+            );
+            peer1.unwrap();
+            peer2.unwrap();
+        }
+        wait_for_snek(&clients).await;
+
+        // The home node of the key follows its position, or has the highest key.
+        let position = crate::dht::dht_position(b"name");
+        let home = clients
+            .iter()
+            .filter(|client| client.router_key >= position)
+            .min_by_key(|client| client.router_key)
+            .or_else(|| clients.iter().max_by_key(|client| client.router_key))
+            .unwrap()
+            .clone();
+        let others: Vec<_> = clients
+            .iter()
+            .filter(|client| client.router_key != home.router_key)
+            .cloned()
+            .collect();
+        let (writer, reader) = (&others[0], &others[1]);
+
+        assert!(reader.dht_get(b"name").await.unwrap().is_empty());
+        let old = writer
+            .dht_put(b"name", b"old".to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        let record = writer
+            .dht_put(b"name", b"new".to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(record.sequence > old.sequence);
+        assert_eq!(record.writer, writer.router_key);
+        assert!(record.verify());
+        // Puts aren't acknowledged, so the old record may still be found for a moment.
+        timeout(Duration::from_secs(5), async {
+            while dht_get_records(reader, b"name").await != vec![record.clone()] {}
+        })
+        .await
+        .unwrap();
+
+        // Copies of the record answer once its home node is gone.
+        home.stop(Duration::from_secs(1)).await.unwrap();
+        timeout(Duration::from_secs(20), async {
+            while dht_get_records(reader, b"name").await != vec![record.clone()] {}
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            writer
+                .dht_put(&[0; 256], vec![], Duration::from_secs(60))
+                .await,
+            Err(RouterError::EncodingError(_))
+        ));
+        assert!(matches!(
+            writer
+                .dht_put(b"name", vec![0; 16 * 1024 + 1], Duration::from_secs(60))
+                .await,
+            Err(RouterError::EncodingError(_))
+        ));
+    }
+    /// Resolves `name` until it belongs to `public_key`, ignoring the cache.
+    async fn wait_for_name(client: &Client, name: &str, public_key: PublicKey) {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                client.names.write().await.resolved.clear();
+                if client.resolve(name).await.ok() == Some(public_key) {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+    #[tokio::test(start_paused = true)]
+    async fn resolve_names() {
+        let authority = SigningKey::from([3; 32]);
+        let config = RouterConfig {
+            name_authorities: vec![PublicKey::from(&authority)],
+            ..Default::default()
+        };
+        let (client1, client2, mut listener2) = connected_clients(config).await;
+        let ttl = Duration::from_secs(60);
+
+        // The first node that claims a name gets it.
+        let record = client2.register_name("alice", ttl).await.unwrap();
+        assert!(record.is_self_signed());
+        assert_eq!(record.name(), "alice");
+        wait_for_name(&client1, "alice", client2.router_key).await;
+        client1.register_name("alice", ttl).await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        wait_for_name(&client2, "alice", client2.router_key).await;
+
+        let mut session1 = client1.dial_name("alice").await.unwrap();
+        assert_eq!(session1.peer_key(), client2.router_key);
+        session1.write_all(b"hello").await.unwrap();
+        let mut session2 = listener2.recv().await.unwrap();
+        let mut buf = [0u8; 16];
+        let len = session2.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // Authorities take precedence over claims.
+        client2.register_name("printer", ttl).await.unwrap();
+        wait_for_name(&client1, "printer", client2.router_key).await;
+        let granted = NameRecord::new("printer", client1.router_key, ttl, &authority).unwrap();
+        assert!(!granted.is_self_signed());
+        client2.publish_name(&granted).await.unwrap();
+        wait_for_name(&client1, "printer", client1.router_key).await;
+
+        // Hosts take precedence over the network.
+        let other = PublicKey::from([9; 32]);
+        client1
+            .set_hosts(HashMap::from([(String::from("alice"), other)]))
+            .await;
+        assert_eq!(client1.resolve("alice").await.unwrap(), other);
+
+        assert!(matches!(
+            client1.resolve("nobody").await,
+            Err(RouterError::UnknownName)
+        ));
+        assert!(matches!(
+            client1.resolve("Not a name").await,
+            Err(RouterError::EncodingError(_))
+        ));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn hold_mail_for_offline_node() {
+        let mut clients = vec![];
+        for key in 1..=3 {
+            let config = RouterConfig {
+                mailbox: true,
+                mailbox_quota: 100,
+                ..Default::default()
+            };
+            let (client, _) = Client::with_config(SigningKey::from([key; 32]), config).await;
+            clients.push(client);
+        }
+        for (i, j) in [(0, 1), (1, 2)] {
+            let (u1, d1, u2, d2) = new_memory_connection();
+            let (peer1, peer2) = tokio::join!(
+                clients[i].connect_peer(u1, d1),
+                clients[j].connect_peer(u2, d2)
+            );
+            peer1.unwrap();
+            peer2.unwrap();
+        }
+        wait_for_snek(&clients).await;
+        let (offline, _) = Client::new(SigningKey::from([4; 32])).await;
+
+        let mut inbox = clients[2].inbox().await.unwrap();
+        assert!(matches!(
+            clients[2].inbox().await,
+            Err(RouterError::InboxTaken)
+        ));
+        let status = clients[0].send_mail(clients[2].router_key, b"hi".to_vec());
+        assert_eq!(status.await.unwrap(), MailStatus::Delivered);
+        let mail = inbox.recv().await.unwrap();
+        assert_eq!(mail.source_key, clients[0].router_key);
+        assert_eq!(mail.payload, b"hi".to_vec());
+
+        let status = clients[0].send_mail(offline.router_key, b"held".to_vec());
+        assert_eq!(status.await.unwrap(), MailStatus::Stored);
+        let status = clients[1].send_mail(offline.router_key, vec![0; 101]);
+        assert_eq!(status.await.unwrap(), MailStatus::Refused);
+
+        let (u1, d1, u2, d2) = new_memory_connection();
+        let (peer1, peer2) = tokio::join!(
+            clients[2].connect_peer(u1, d1),
+            offline.connect_peer(u2, d2)
+        );
+        peer1.unwrap();
+        peer2.unwrap();
+        let mut inbox = offline.inbox().await.unwrap();
+        let mail = timeout(Duration::from_secs(10), inbox.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(mail.source_key, clients[0].router_key);
+        assert_eq!(mail.payload, b"held".to_vec());
+    }
+}
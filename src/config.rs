@@ -0,0 +1,189 @@
+use crate::public_key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Settings of a router. [`Default`] gives the settings that are used by
+/// [`Client::new`](crate::Client::new).
+///
+/// Durations are (de)serialized in a human readable form like `"5s"` or `"1m 30s"`
+/// and missing fields take their default value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouterConfig {
+    /// Number of frames that can be queued for sending to a single peer.
+    pub peer_queue_size: usize,
+    /// Number of frames that sessions can write before they have to wait for the
+    /// router to take them.
+    pub session_upload_queue_size: usize,
+    /// Number of received frames that a single session can hold until they are read.
+    /// Further frames wait for the session, which holds up all other sessions.
+    pub session_download_queue_size: usize,
+    /// What to do with frames for a peer whose queue is full.
+    pub drop_policy: DropPolicy,
+    /// How many frames a single peer may send.
+    pub rate_limits: RateLimits,
+    /// Number of paths that may go through this router for a single origin key.
+    /// Further setups from that key are rejected.
+    pub max_paths_per_origin: usize,
+    /// How long the coordinates of other nodes are cached. The cache is
+    /// emptied earlier if the root of the tree changes.
+    #[serde(with = "humantime_serde")]
+    pub coordinates_cache_ttl: Duration,
+    /// How long to wait for the answer to a coordinate lookup.
+    #[serde(with = "humantime_serde")]
+    pub coordinates_lookup_timeout: Duration,
+    /// Whether session traffic is routed over the tree once the coordinates of the
+    /// other side are known. This often gives shorter paths than SNEK routing.
+    pub hybrid_routing: bool,
+    /// How long pings and traceroutes wait for answers.
+    #[serde(with = "humantime_serde")]
+    pub probe_timeout: Duration,
+    /// Sessions that neither sent nor received data for this long are closed on
+    /// both sides. Sessions are never closed for being idle if this is `None`.
+    #[serde(with = "humantime_serde")]
+    pub session_idle_timeout: Option<Duration>,
+    /// Number of nodes after the home node of a key that keep copies of its records
+    /// in the DHT, so that they survive when the home node goes away.
+    pub dht_replicas: u8,
+    /// Number of DHT records this node keeps for others. Further records are rejected.
+    pub dht_max_records: usize,
+    /// Longest time DHT records live. Longer times to live of records that are put
+    /// by this node are shortened, records of others that live longer are rejected.
+    #[serde(with = "humantime_serde")]
+    pub dht_max_ttl: Duration,
+    /// How long to wait for the answer to a DHT get.
+    #[serde(with = "humantime_serde")]
+    pub dht_timeout: Duration,
+    /// Keys whose name records decide who names belong to, in order of precedence.
+    /// Without authorities names belong to the node that claimed them first.
+    pub name_authorities: Vec<PublicKey>,
+    /// How long resolved names are cached, unless their records expire earlier.
+    #[serde(with = "humantime_serde")]
+    pub name_cache_ttl: Duration,
+    /// Whether this node holds mail for offline nodes whose keys it follows, until
+    /// they come back.
+    pub mailbox: bool,
+    /// Bytes of mail this node holds for a single offline node.
+    pub mailbox_quota: usize,
+    /// Bytes of mail this node holds for all offline nodes together.
+    pub mailbox_capacity: usize,
+    /// How long held mail is kept before it is dropped.
+    #[serde(with = "humantime_serde")]
+    pub mailbox_ttl: Duration,
+    /// How long to wait for the reply to sent mail.
+    #[serde(with = "humantime_serde")]
+    pub mail_timeout: Duration,
+}
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            peer_queue_size: 1000,
+            session_upload_queue_size: 100,
+            session_download_queue_size: 100,
+            drop_policy: DropPolicy::DropNewest,
+            rate_limits: RateLimits::default(),
+            max_paths_per_origin: 16,
+            coordinates_cache_ttl: Duration::from_secs(60),
+            coordinates_lookup_timeout: Duration::from_secs(5),
+            hybrid_routing: true,
+            probe_timeout: Duration::from_secs(5),
+            session_idle_timeout: None,
+            dht_replicas: 2,
+            dht_max_records: 10_000,
+            dht_max_ttl: Duration::from_secs(24 * 60 * 60),
+            dht_timeout: Duration::from_secs(5),
+            name_authorities: vec![],
+            name_cache_ttl: Duration::from_secs(5 * 60),
+            mailbox: false,
+            mailbox_quota: 1024 * 1024,
+            mailbox_capacity: 16 * 1024 * 1024,
+            mailbox_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            mail_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Limits of the frames that are received from each peer. Frames over a limit
+/// are dropped and peers that exceed their limits too often are disconnected.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub tree_announcement: RateLimit,
+    pub snek_bootstrap: RateLimit,
+    pub snek_setup: RateLimit,
+    /// Limit of all other protocol frames, that is acknowledgements and teardowns.
+    pub other_protocol: RateLimit,
+    /// Limit of the records and requests of the DHT.
+    pub dht: RateLimit,
+    /// Limit of mail and its replies.
+    pub mail: RateLimit,
+    /// Limit of broadcasts. Every broadcast is forwarded to all neighbours in the
+    /// tree, so they are limited even if other traffic isn't.
+    pub broadcast: RateLimit,
+    /// Limit of tree and SNEK routed traffic. Traffic isn't limited if this is `None`.
+    pub traffic: Option<RateLimit>,
+    /// Frames over a limit a peer may send before it is disconnected.
+    pub violations: RateLimit,
+}
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            tree_announcement: RateLimit {
+                per_second: 20,
+                burst: 50,
+            },
+            snek_bootstrap: RateLimit {
+                per_second: 100,
+                burst: 200,
+            },
+            snek_setup: RateLimit {
+                per_second: 100,
+                burst: 200,
+            },
+            other_protocol: RateLimit {
+                per_second: 200,
+                burst: 400,
+            },
+            dht: RateLimit {
+                per_second: 100,
+                burst: 200,
+            },
+            mail: RateLimit {
+                per_second: 50,
+                burst: 100,
+            },
+            broadcast: RateLimit {
+                per_second: 50,
+                burst: 100,
+            },
+            traffic: None,
+            violations: RateLimit {
+                per_second: 10,
+                burst: 100,
+            },
+        }
+    }
+}
+
+/// A token bucket that allows `burst` frames at once and `per_second` frames
+/// every second on average.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// Decides which traffic frame is dropped when the queue of a peer is full.
+///
+/// Frames are always dropped from the source that has the most frames queued.
+/// Protocol frames are never dropped in favour of traffic. If the queue only
+/// contains protocol frames a new protocol frame is dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Drop the newest frame of the source.
+    DropNewest,
+    /// Drop the frame of the source that has been queued the longest.
+    DropOldest,
+}
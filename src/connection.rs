@@ -1,7 +1,15 @@
+#[cfg(test)]
+use crate::client::{Client, SessionListener};
+#[cfg(test)]
+use crate::config::RouterConfig;
 use crate::error::RouterError;
 use crate::frames::Frame;
 use crate::wire_frame::PineconeCodec;
+#[cfg(test)]
+use ed25519_consensus::SigningKey;
 use futures::SinkExt;
+#[cfg(test)]
+use tokio::io::{duplex, split, DuplexStream, ReadHalf, WriteHalf};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 #[cfg(test)]
 use tokio::net::{TcpListener, TcpStream};
@@ -81,3 +89,55 @@ pub(crate) async fn new_test_connection() -> (
         Box::new(FramedRead::new(s2d, PineconeCodec)),
     )
 }
+/// Like [`new_test_connection`] but over an in-memory pipe, so that it works in
+/// tests that pause the clock.
+#[cfg(test)]
+#[allow(clippy::type_complexity)]
+pub(crate) fn new_memory_connection() -> (
+    Box<FramedWrite<WriteHalf<DuplexStream>, PineconeCodec>>,
+    Box<FramedRead<ReadHalf<DuplexStream>, PineconeCodec>>,
+    Box<FramedWrite<WriteHalf<DuplexStream>, PineconeCodec>>,
+    Box<FramedRead<ReadHalf<DuplexStream>, PineconeCodec>>,
+) {
+    let (stream1, stream2) = duplex(64 * 1024);
+    let (s1d, s1u) = split(stream1);
+    let (s2d, s2u) = split(stream2);
+    (
+        Box::new(FramedWrite::new(s1u, PineconeCodec)),
+        Box::new(FramedRead::new(s1d, PineconeCodec)),
+        Box::new(FramedWrite::new(s2u, PineconeCodec)),
+        Box::new(FramedRead::new(s2d, PineconeCodec)),
+    )
+}
+/// Creates two clients with `config` that are peers of each other over an in-memory
+/// connection. Returns once their SNEK formed.
+#[cfg(test)]
+pub(crate) async fn connected_clients(config: RouterConfig) -> (Client, Client, SessionListener) {
+    let (client1, _) = Client::with_config(SigningKey::from([1; 32]), config.clone()).await;
+    let (client2, listener2) = Client::with_config(SigningKey::from([2; 32]), config).await;
+    let (u1, d1, u2, d2) = new_memory_connection();
+    let (peer2, peer1) = tokio::join!(client1.connect_peer(u1, d1), client2.connect_peer(u2, d2));
+    peer1.unwrap();
+    peer2.unwrap();
+    wait_for_snek(&[client1.clone(), client2.clone()]).await;
+    (client1, client2, listener2)
+}
+/// Waits until the SNEK of `clients` forms a line ordered by their keys.
+#[cfg(test)]
+pub(crate) async fn wait_for_snek(clients: &[Client]) {
+    let mut keys: Vec<_> = clients.iter().map(|client| client.public_key()).collect();
+    keys.sort();
+    loop {
+        let mut converged = true;
+        for client in clients {
+            let info = client.snek_info().await.unwrap();
+            let index = keys.binary_search(&client.public_key()).unwrap();
+            converged &= info.ascending == keys.get(index + 1).copied()
+                && info.descending == index.checked_sub(1).map(|i| keys[i]);
+        }
+        if converged {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
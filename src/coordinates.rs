@@ -1,8 +1,18 @@
 use crate::frames::TreeAnnouncement;
 use crate::router::Port;
+use crate::tree::Root;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
 
-#[derive(Clone, PartialEq, Debug)]
-pub(crate) struct Coordinates {
+/// The position of a node in the spanning tree. These are the ports that lead
+/// from the root of the tree down to the node.
+///
+/// Coordinates change whenever the tree is rebuilt, for example when the root
+/// changes, so they shouldn't be stored for long.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Coordinates {
     pub(crate) coordinates: Vec<Port>,
 }
 
@@ -26,9 +36,31 @@ impl Coordinates {
         c
     }
 
-    pub(crate) fn new(coordinates: Vec<Port>) -> Self {
+    pub fn new(coordinates: Vec<Port>) -> Self {
         Coordinates { coordinates }
     }
+    #[allow(unused)]
+    pub fn ports(&self) -> &[Port] {
+        &self.coordinates
+    }
+}
+
+/// Coordinates of another node that were learned with a coordinate lookup.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedCoordinates {
+    pub(crate) coordinates: Coordinates,
+    /// The root the coordinates are relative to.
+    pub(crate) root: Root,
+    pub(crate) expires: Instant,
+}
+
+/// A coordinate request that waits for its answer.
+#[derive(Debug)]
+pub(crate) struct PendingCoordinates {
+    /// The id of the request, which the answer has to repeat.
+    pub(crate) id: u64,
+    /// Callers that wait for the coordinates.
+    pub(crate) waiting: Vec<oneshot::Sender<Coordinates>>,
 }
 
 impl Default for Coordinates {
@@ -0,0 +1,503 @@
+#[cfg(unix)]
+use crate::admin::{bind_admin_socket, serve_admin};
+use crate::client::{Client, SessionListener};
+use crate::config::RouterConfig;
+use crate::error::RouterError;
+use crate::forward::{ForwardConfig, Gateway};
+use crate::key_file::load_or_create_key_file;
+use crate::names::{check_name, load_hosts_file};
+use crate::public_key::PublicKey;
+use crate::tun::{PacketSink, PacketStream, TunAdapter};
+use crate::wire_frame::PineconeCodec;
+use ed25519_consensus::SigningKey;
+use log::{debug, info, warn};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Settings of a router that runs without user interaction, read from a TOML file:
+///
+/// ```toml
+/// listen = ["0.0.0.0:7000"]
+/// peers = ["relay.example.org:7000"]
+/// key_file = "/var/lib/pinecone/key.pem"
+/// log_level = "info"
+/// admin_socket = "/run/pinecone/admin.sock"
+/// socks5 = "127.0.0.1:1080"
+/// names = ["relay"]
+/// hosts_file = "/etc/pinecone/hosts"
+///
+/// [services]
+/// ssh = "127.0.0.1:22"
+///
+/// [[forwards]]
+/// listen = "127.0.0.1:2222"
+/// remote = "<public key>"
+/// service = "ssh"
+///
+/// [timers]
+/// reconnect_interval = "5s"
+/// shutdown_timeout = "5s"
+///
+/// [router]
+/// peer_queue_size = 1000
+/// ```
+///
+/// Missing fields take their default value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Addresses on which incoming peer connections are accepted.
+    pub listen: Vec<SocketAddr>,
+    /// Addresses of peers that are kept connected. They may contain host names.
+    pub peers: Vec<String>,
+    /// Key file of the router. It is created if it doesn't exist. Without a key file
+    /// the router gets a new key on every start.
+    pub key_file: Option<PathBuf>,
+    /// Log filter in the syntax of `RUST_LOG`, for example `info` or
+    /// `info,rust_pinecone=trace`.
+    pub log_level: String,
+    /// Unix socket on which the JSON-RPC admin API is served. There is no admin API
+    /// if this isn't set.
+    pub admin_socket: Option<PathBuf>,
+    /// Name of a TUN device that carries IPv6 packets over the network. Without a
+    /// TUN device or services the daemon only relays traffic.
+    pub tun: Option<String>,
+    /// Local TCP services that other nodes can connect to through their
+    /// [`Gateway`], by name. The values are addresses like `127.0.0.1:22`.
+    pub services: HashMap<String, String>,
+    /// Local ports that are forwarded to services of other nodes.
+    pub forwards: Vec<ForwardConfig>,
+    /// Address of a SOCKS5 proxy to the services of other nodes.
+    pub socks5: Option<SocketAddr>,
+    /// Names that are claimed for this node and renewed while it runs.
+    pub names: Vec<String>,
+    /// File with the names of other nodes in the style of `/etc/hosts`, which are
+    /// resolved without asking the network. See [`load_hosts_file`].
+    pub hosts_file: Option<PathBuf>,
+    pub timers: DaemonTimers,
+    pub router: RouterConfig,
+}
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            peers: vec![],
+            key_file: None,
+            log_level: String::from("info"),
+            admin_socket: None,
+            tun: None,
+            services: HashMap::new(),
+            forwards: vec![],
+            socks5: None,
+            names: vec![],
+            hosts_file: None,
+            timers: Default::default(),
+            router: Default::default(),
+        }
+    }
+}
+impl DaemonConfig {
+    /// Reads the config from the TOML file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+impl std::str::FromStr for DaemonConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonTimers {
+    /// How often the connections to the static peers are checked and
+    /// reestablished if they were lost.
+    #[serde(with = "humantime_serde")]
+    pub reconnect_interval: Duration,
+    /// How long stopping the router may take.
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+    /// How often the names of the node are claimed again. Their records live for
+    /// three times as long.
+    #[serde(with = "humantime_serde")]
+    pub name_renew_interval: Duration,
+}
+impl Default for DaemonTimers {
+    fn default() -> Self {
+        Self {
+            reconnect_interval: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(5),
+            name_renew_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// A running router that accepts peers on the configured addresses and keeps
+/// the static peers connected.
+pub struct Daemon {
+    client: Client,
+    local_addrs: Vec<SocketAddr>,
+    shutdown_timeout: Duration,
+    admin_socket: Option<PathBuf>,
+    tun: Option<TunAdapter>,
+    gateway: Option<Gateway>,
+    tasks: Vec<JoinHandle<()>>,
+}
+#[allow(unused)]
+impl Daemon {
+    /// Loads the key and starts the router. Fails if the key file can't be read or
+    /// one of the listen addresses or the admin socket can't be bound.
+    pub async fn start(config: DaemonConfig) -> Result<Self, Error> {
+        let uses_gateway =
+            !config.services.is_empty() || !config.forwards.is_empty() || config.socks5.is_some();
+        let key = match &config.key_file {
+            Some(path) => load_or_create_key_file(path)?,
+            None => SigningKey::new(thread_rng()),
+        };
+        for name in &config.names {
+            check_name(name).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid name {}", name))
+            })?;
+        }
+        let hosts = match &config.hosts_file {
+            Some(path) => load_hosts_file(path)?,
+            None => HashMap::new(),
+        };
+        let mut listeners = vec![];
+        let mut local_addrs = vec![];
+        for addr in &config.listen {
+            let listener = TcpListener::bind(addr).await?;
+            local_addrs.push(listener.local_addr()?);
+            listeners.push(listener);
+        }
+        #[cfg(unix)]
+        let admin_listener = match &config.admin_socket {
+            Some(path) => Some(bind_admin_socket(path)?),
+            None => None,
+        };
+        let tun_device = match &config.tun {
+            Some(name) => Some(open_tun_device(name)?),
+            None => None,
+        };
+        let (client, session_listener) = Client::with_config(key, config.router).await;
+        info!("Router {}", client.public_key());
+        client.set_hosts(hosts).await;
+        let mut daemon = Self {
+            client: client.clone(),
+            local_addrs: local_addrs.clone(),
+            shutdown_timeout: config.timers.shutdown_timeout,
+            admin_socket: config.admin_socket,
+            tun: None,
+            gateway: None,
+            tasks: vec![],
+        };
+        daemon
+            .tasks
+            .push(tokio::spawn(close_sessions(session_listener)));
+        // Everything started so far has to be stopped again if the rest fails.
+        if let Err(e) = daemon
+            .start_adapters(
+                tun_device,
+                uses_gateway,
+                config.services,
+                config.forwards,
+                config.socks5,
+            )
+            .await
+        {
+            let _ = daemon.stop().await;
+            return Err(e);
+        }
+        #[cfg(unix)]
+        if let Some(listener) = admin_listener {
+            daemon
+                .tasks
+                .push(tokio::spawn(serve_admin(client.clone(), listener)));
+        }
+        for (listener, addr) in listeners.into_iter().zip(local_addrs) {
+            info!("Listening on {}", addr);
+            daemon
+                .tasks
+                .push(tokio::spawn(accept_peers(client.clone(), listener)));
+        }
+        if !config.names.is_empty() {
+            daemon.tasks.push(tokio::spawn(keep_names_registered(
+                client.clone(),
+                config.names,
+                config.timers.name_renew_interval,
+            )));
+        }
+        for addr in config.peers {
+            daemon.tasks.push(tokio::spawn(keep_connected(
+                client.clone(),
+                addr,
+                config.timers.reconnect_interval,
+            )));
+        }
+        Ok(daemon)
+    }
+    /// Starts the TUN adapter and the gateway with its forwards, as far as they are
+    /// configured.
+    async fn start_adapters(
+        &mut self,
+        tun_device: Option<(PacketSink, PacketStream)>,
+        uses_gateway: bool,
+        services: HashMap<String, String>,
+        forwards: Vec<ForwardConfig>,
+        socks5: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        if let Some((sink, stream)) = tun_device {
+            let tun = TunAdapter::start(self.client.clone(), sink, stream)
+                .await
+                .map_err(|e| Error::new(ErrorKind::AddrInUse, e.to_string()))?;
+            info!("IPv6 address {}", tun.address());
+            self.tun = Some(tun);
+        }
+        if uses_gateway {
+            let gateway = Gateway::start(self.client.clone(), services)
+                .await
+                .map_err(|e| Error::new(ErrorKind::AddrInUse, e.to_string()))?;
+            self.gateway = Some(gateway.clone());
+            start_forwards(&gateway, forwards, socks5).await?;
+        }
+        Ok(())
+    }
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+    /// The addresses the daemon is listening on. Unlike the configured addresses
+    /// these contain the actual port if port 0 was configured.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+    /// Stops accepting and reconnecting peers, closes the admin socket and stops
+    /// the router.
+    pub async fn stop(self) -> Result<(), RouterError> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        if let Some(tun) = &self.tun {
+            tun.stop();
+        }
+        if let Some(gateway) = &self.gateway {
+            gateway.stop();
+        }
+        if let Some(path) = &self.admin_socket {
+            if let Err(e) = std::fs::remove_file(path) {
+                debug!("Could not remove admin socket: {}", e);
+            }
+        }
+        self.client.stop(self.shutdown_timeout).await
+    }
+}
+
+/// Opens a TCP connection to `addr` and connects the node there as a peer.
+pub(crate) async fn connect_tcp(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+) -> Result<PublicKey, Error> {
+    let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+    client
+        .connect_peer(
+            Box::new(FramedWrite::new(writer, PineconeCodec)),
+            Box::new(FramedRead::new(reader, PineconeCodec)),
+        )
+        .await
+        .map_err(|e| Error::new(ErrorKind::ConnectionAborted, e.to_string()))
+}
+
+/// Connects every node that connects to `listener` as a peer.
+pub(crate) async fn accept_peers(client: Client, listener: TcpListener) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Errors like running out of file descriptors go away after a while.
+                warn!("Could not accept peer: {}", e);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        debug!("New connection from {}", addr);
+        let client = client.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = socket.into_split();
+            match client
+                .connect_peer(
+                    Box::new(FramedWrite::new(writer, PineconeCodec)),
+                    Box::new(FramedRead::new(reader, PineconeCodec)),
+                )
+                .await
+            {
+                Ok(key) => info!("Connected peer {} from {}", key, addr),
+                Err(e) => warn!("Could not connect peer from {}: {}", addr, e),
+            }
+        });
+    }
+}
+
+/// Connects the peer at `addr` and reconnects it whenever the connection was lost.
+async fn keep_connected(client: Client, addr: String, interval: Duration) {
+    let mut peer_key = None;
+    loop {
+        let connected = match peer_key {
+            Some(key) => match client.peers().await {
+                Ok(peers) => peers.contains(&key),
+                Err(_) => break,
+            },
+            None => false,
+        };
+        if !connected {
+            match connect_tcp(&client, addr.as_str()).await {
+                Ok(key) => {
+                    info!("Connected peer {} at {}", key, addr);
+                    peer_key = Some(key);
+                }
+                Err(e) => warn!("Could not connect to {}: {}", addr, e),
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+/// Claims `names` for the node every `interval`, so that their records don't expire.
+async fn keep_names_registered(client: Client, names: Vec<String>, interval: Duration) {
+    loop {
+        for name in &names {
+            match client.register_name(name, interval * 3).await {
+                Ok(_) => debug!("Registered name {}", name),
+                Err(RouterError::Stopped) => return,
+                Err(e) => warn!("Could not register name {}: {}", name, e),
+            }
+        }
+        sleep(interval).await;
+    }
+}
+
+async fn start_forwards(
+    gateway: &Gateway,
+    forwards: Vec<ForwardConfig>,
+    socks5: Option<SocketAddr>,
+) -> Result<(), Error> {
+    for forward in forwards {
+        gateway
+            .forward(forward.listen, forward.remote, forward.service)
+            .await?;
+    }
+    if let Some(addr) = socks5 {
+        gateway.socks5(addr).await?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn open_tun_device(name: &str) -> Result<(PacketSink, PacketStream), Error> {
+    crate::tun::open_tun(name)
+}
+#[cfg(not(target_os = "linux"))]
+fn open_tun_device(_name: &str) -> Result<(PacketSink, PacketStream), Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "TUN devices are only supported on Linux",
+    ))
+}
+
+/// Closes sessions that other nodes open with the default service of the daemon.
+/// Only the TUN device and the gateway use sessions.
+async fn close_sessions(mut session_listener: SessionListener) {
+    while let Some(session) = session_listener.recv().await {
+        debug!("Closing session with {}", session.peer_key());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::DropPolicy;
+
+    #[test]
+    fn parse_config() {
+        let config: DaemonConfig = r#"
+            listen = ["127.0.0.1:7000"]
+            peers = ["localhost:7001"]
+            log_level = "debug"
+            names = ["relay"]
+
+            [services]
+            ssh = "127.0.0.1:22"
+
+            [timers]
+            reconnect_interval = "1m 30s"
+
+            [router]
+            drop_policy = "drop_oldest"
+            probe_timeout = "500ms"
+
+            [router.rate_limits.traffic]
+            per_second = 10
+            burst = 20
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.listen, vec!["127.0.0.1:7000".parse().unwrap()]);
+        assert_eq!(config.peers, vec![String::from("localhost:7001")]);
+        assert_eq!(config.key_file, None);
+        assert_eq!(config.services["ssh"], "127.0.0.1:22");
+        assert_eq!(config.names, vec![String::from("relay")]);
+        assert_eq!(config.hosts_file, None);
+        assert_eq!(config.timers.reconnect_interval, Duration::from_secs(90));
+        assert_eq!(config.timers.shutdown_timeout, Duration::from_secs(5));
+        assert_eq!(config.router.drop_policy, DropPolicy::DropOldest);
+        assert_eq!(config.router.probe_timeout, Duration::from_millis(500));
+        assert_eq!(config.router.rate_limits.traffic.unwrap().burst, 20);
+        assert_eq!(config.router.peer_queue_size, 1000);
+
+        assert!("listen = [\"not an address\"]"
+            .parse::<DaemonConfig>()
+            .is_err());
+        assert!("unknown = 1".parse::<DaemonConfig>().is_err());
+    }
+    #[tokio::test]
+    async fn connect_static_peer() {
+        let relay = Daemon::start(DaemonConfig {
+            listen: vec!["127.0.0.1:0".parse().unwrap()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut config = DaemonConfig {
+            peers: vec![relay.local_addrs()[0].to_string()],
+            ..Default::default()
+        };
+        config.timers.reconnect_interval = Duration::from_millis(100);
+        let node = Daemon::start(config).await.unwrap();
+        let node_key = node.client().public_key();
+        let relay_key = relay.client().public_key();
+        for _ in 0..50 {
+            if relay.client().peers().await.unwrap().contains(&node_key) {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(relay.client().peers().await.unwrap(), vec![node_key]);
+
+        relay.client().disconnect_peer(node_key).await;
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(node.client().peers().await.unwrap(), vec![relay_key]);
+
+        node.stop().await.unwrap();
+        relay.stop().await.unwrap();
+    }
+}
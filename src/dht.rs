@@ -0,0 +1,268 @@
+use crate::public_key::PublicKey;
+#[cfg(doc)]
+use crate::{Client, RouterConfig};
+use bytes::{BufMut, BytesMut};
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Largest key of a record in bytes.
+pub(crate) const MAX_DHT_KEY_LENGTH: usize = u8::MAX as usize;
+/// Largest value of a record in bytes. This leaves room for a few records of the
+/// same key in the answer to a get.
+pub(crate) const MAX_DHT_VALUE_SIZE: usize = 16 * 1024;
+/// Size of an encoded record besides its key and value: the lengths of both, the
+/// writer, the sequence number, the expiry and the signature.
+pub(crate) const DHT_RECORD_OVERHEAD: usize = 1 + 2 + 32 + 8 + 8 + 64;
+/// How far the clocks of nodes may differ. Records that expire later than the
+/// longest time to live of this node allows, plus this, are rejected.
+pub(crate) const DHT_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Rules of a use of the DHT for the records under its keys, which the home node
+/// applies before it stores a record. The store itself takes any valid record.
+#[derive(Clone, Copy)]
+pub(crate) struct DhtPolicy {
+    /// The keys the policy applies to start with this.
+    pub(crate) prefix: &'static [u8],
+    /// Returns false if `record` must not be stored next to the `stored` records of
+    /// its key.
+    pub(crate) admit: fn(stored: &[DhtRecord], record: &DhtRecord) -> bool,
+}
+impl DhtPolicy {
+    /// Returns false if a policy in `policies` refuses `record`.
+    pub(crate) fn admits(policies: &[DhtPolicy], stored: &[DhtRecord], record: &DhtRecord) -> bool {
+        policies
+            .iter()
+            .filter(|policy| record.key.starts_with(policy.prefix))
+            .all(|policy| (policy.admit)(stored, record))
+    }
+}
+
+/// The position of `key` in the keyspace. Its records are stored at the node whose
+/// public key follows this position, the home node of the key.
+pub(crate) fn dht_position(key: &[u8]) -> PublicKey {
+    PublicKey::from(<[u8; 32]>::from(Sha256::digest(key)))
+}
+/// Seconds since the Unix epoch.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A value that a node stored in the DHT with [`Client::dht_put`].
+///
+/// Every writer has its own record for a key. Putting the key again replaces it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtRecord {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// The node that wrote and signed the record.
+    pub writer: PublicKey,
+    /// Records replace the records of their writer with a lower sequence number.
+    pub sequence: u64,
+    /// Seconds since the Unix epoch after which the record is dropped.
+    pub expires: u64,
+    pub(crate) signature: Signature,
+}
+impl DhtRecord {
+    /// Creates a record of the node with `signing_key` that lives for `ttl`, rounded
+    /// up to full seconds. Its sequence number is the current time in microseconds,
+    /// so that it replaces the earlier records of the node for `key`.
+    pub(crate) fn new(
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+        signing_key: &SigningKey,
+    ) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let ttl = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        let mut record = Self {
+            key,
+            value,
+            writer: PublicKey::from(signing_key),
+            sequence: now.as_micros() as u64,
+            expires: now.as_secs() + ttl,
+            signature: Signature::from([0; 64]),
+        };
+        record.signature = signing_key.sign(&record.signed_bytes());
+        record
+    }
+    fn signed_bytes(&self) -> BytesMut {
+        let mut unsigned = BytesMut::new();
+        unsigned.put_u8(self.key.len() as u8);
+        unsigned.put_slice(&self.key);
+        unsigned.put_u16(self.value.len() as u16);
+        unsigned.put_slice(&self.value);
+        unsigned.put_slice(self.writer.as_bytes());
+        unsigned.put_u64(self.sequence);
+        unsigned.put_u64(self.expires);
+        unsigned
+    }
+    /// Returns true if the record was signed by its writer and its key and value
+    /// aren't larger than allowed.
+    pub fn verify(&self) -> bool {
+        if self.key.len() > MAX_DHT_KEY_LENGTH || self.value.len() > MAX_DHT_VALUE_SIZE {
+            return false;
+        }
+        VerificationKey::try_from(self.writer.to_bytes())
+            .and_then(|key| key.verify(&self.signature, &self.signed_bytes()))
+            .is_ok()
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expires <= unix_time()
+    }
+    /// Size of the record in a frame.
+    pub(crate) fn encoded_len(&self) -> usize {
+        DHT_RECORD_OVERHEAD + self.key.len() + self.value.len()
+    }
+}
+
+/// The records that this node holds, because it is the home node of their keys or
+/// keeps copies for the home node.
+#[derive(Debug, Default)]
+pub(crate) struct DhtStore {
+    /// The records of every key by writer, with the order in which the first record
+    /// of the writer was seen.
+    records: HashMap<Vec<u8>, HashMap<PublicKey, (u64, DhtRecord)>>,
+    len: usize,
+    next_order: u64,
+}
+impl DhtStore {
+    /// Stores `record` unless its writer already has a record for the key with the
+    /// same or a higher sequence number, or the store is full. Returns whether the
+    /// record was stored.
+    pub(crate) fn insert(&mut self, record: DhtRecord, max_records: usize) -> bool {
+        let stored = self
+            .records
+            .get(&record.key)
+            .and_then(|records| records.get(&record.writer));
+        let order = match stored {
+            Some((_, stored)) if stored.sequence >= record.sequence => return false,
+            // A new record of the writer keeps the place of its first one.
+            Some((order, _)) => *order,
+            None if self.len >= max_records => return false,
+            None => {
+                self.len += 1;
+                self.next_order += 1;
+                self.next_order
+            }
+        };
+        self.records
+            .entry(record.key.clone())
+            .or_default()
+            .insert(record.writer, (order, record));
+        true
+    }
+    /// The records of `key` that didn't expire, in the order their writers were first
+    /// seen.
+    pub(crate) fn get(&self, key: &[u8]) -> Vec<DhtRecord> {
+        let mut records: Vec<_> = self
+            .records
+            .get(key)
+            .map(|records| {
+                records
+                    .values()
+                    .filter(|(_, record)| !record.is_expired())
+                    .collect()
+            })
+            .unwrap_or_default();
+        records.sort_by_key(|(order, _)| *order);
+        records
+            .into_iter()
+            .map(|(_, record)| record.clone())
+            .collect()
+    }
+    pub(crate) fn expire(&mut self) {
+        let now = unix_time();
+        for records in self.records.values_mut() {
+            records.retain(|_, (_, record)| record.expires > now);
+        }
+        self.records.retain(|_, records| !records.is_empty());
+        self.len = self.records.values().map(HashMap::len).sum();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_record() {
+        let key = SigningKey::from([1; 32]);
+        let record = DhtRecord::new(
+            b"key".to_vec(),
+            b"value".to_vec(),
+            Duration::from_secs(10),
+            &key,
+        );
+        assert!(record.verify());
+        assert!(!record.is_expired());
+        let mut changed = record.clone();
+        changed.value = b"other".to_vec();
+        assert!(!changed.verify());
+        let mut forged = record.clone();
+        forged.writer = PublicKey::from(&SigningKey::from([2; 32]));
+        assert!(!forged.verify());
+    }
+    #[test]
+    fn policies_apply_to_their_prefix() {
+        let policies = [DhtPolicy {
+            prefix: b"fixed:",
+            admit: |stored, _| stored.is_empty(),
+        }];
+        let key = SigningKey::from([1; 32]);
+        let ttl = Duration::from_secs(10);
+        let fixed = DhtRecord::new(b"fixed:a".to_vec(), b"value".to_vec(), ttl, &key);
+        let other = DhtRecord::new(b"other".to_vec(), b"value".to_vec(), ttl, &key);
+        assert!(DhtPolicy::admits(&policies, &[], &fixed));
+        assert!(!DhtPolicy::admits(
+            &policies,
+            std::slice::from_ref(&fixed),
+            &fixed
+        ));
+        assert!(DhtPolicy::admits(
+            &policies,
+            std::slice::from_ref(&other),
+            &other
+        ));
+    }
+    #[test]
+    fn replace_records_of_writer() {
+        let writer1 = SigningKey::from([1; 32]);
+        let writer2 = SigningKey::from([2; 32]);
+        let ttl = Duration::from_secs(10);
+        let mut old = DhtRecord::new(b"key".to_vec(), b"old".to_vec(), ttl, &writer1);
+        let mut new = DhtRecord::new(b"key".to_vec(), b"new".to_vec(), ttl, &writer1);
+        old.sequence = 1;
+        new.sequence = 2;
+        let mut store = DhtStore::default();
+        assert!(store.insert(new.clone(), 2));
+        assert!(!store.insert(old, 2));
+        assert!(!store.insert(new.clone(), 2));
+        assert_eq!(store.get(b"key"), vec![new.clone()]);
+
+        let other = DhtRecord::new(b"key".to_vec(), b"other".to_vec(), ttl, &writer2);
+        assert!(store.insert(other.clone(), 2));
+        assert_eq!(store.get(b"key"), vec![new.clone(), other.clone()]);
+        // The store is full, so only records that replace others are taken.
+        let full = DhtRecord::new(b"full".to_vec(), vec![], ttl, &writer1);
+        assert!(!store.insert(full, 2));
+        new.sequence = 3;
+        assert!(store.insert(new.clone(), 2));
+        // The writer that was seen first stays first.
+        assert_eq!(store.get(b"key"), vec![new, other]);
+
+        let mut expired = DhtRecord::new(b"expired".to_vec(), vec![], ttl, &writer1);
+        expired.expires = unix_time() - 1;
+        assert!(store.insert(expired, 3));
+        assert!(store.get(b"expired").is_empty());
+        store.expire();
+        assert_eq!(store.len, 2);
+        assert!(store.get(b"unknown").is_empty());
+    }
+}
@@ -9,6 +9,33 @@ pub enum RouterError {
     DecodingError(&'static str),
     EncodingError(&'static str),
     SessionAlreadyExists,
+    /// The [`Inbox`](crate::Inbox) was taken before.
+    InboxTaken,
+    /// All services that could be used are taken, or a service already has a listener.
+    ServiceInUse,
+    Timeout,
+    /// No node has the name that was resolved.
+    UnknownName,
+    /// A frame referred to a peer that isn't connected (anymore).
+    UnknownPeer,
+    /// There is no next hop for a frame that has to be forwarded.
+    NoRoute,
+    /// The channel to the local [`Client`](crate::Client) was closed.
+    LocalChannelClosed,
+    /// The router was stopped.
+    Stopped,
+}
+impl RouterError {
+    /// Returns true if the error was caused by a peer sending frames it shouldn't send.
+    /// Such peers get disconnected.
+    pub fn is_caused_by_peer(&self) -> bool {
+        matches!(
+            self,
+            RouterError::MissingSignature
+                | RouterError::InvalidFrame
+                | RouterError::DecodingError(_)
+        )
+    }
 }
 impl Display for RouterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -0,0 +1,690 @@
+use crate::client::{Client, SessionListener};
+use crate::error::RouterError;
+use crate::public_key::PublicKey;
+use crate::session::{SendSession, ServiceId, Session};
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+
+/// Largest number of bytes in a data segment.
+const SEGMENT_SIZE: usize = 4096;
+/// Number of segments of a stream that may be unacknowledged at once.
+const WINDOW: usize = 32;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+/// Retransmissions without progress after which a stream is reset.
+const MAX_RETRANSMITS: u32 = 10;
+/// Service that the gateways of the nodes talk to each other on.
+pub const GATEWAY_SERVICE: ServiceId = 2;
+/// Set in the stream ids of messages that are sent by the side that accepted the stream.
+const ACCEPTOR_BIT: u32 = 1 << 31;
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const RESET: u8 = 2;
+
+/// A message of the stream protocol inside session payloads.
+///
+/// Every TCP connection is carried in a stream of numbered data segments that are
+/// retransmitted until they are acknowledged. The first segment of a stream holds
+/// the name of the service it is for and an empty segment ends the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Message {
+    Data {
+        stream: u32,
+        seq: u32,
+        payload: Vec<u8>,
+    },
+    /// Acknowledges all segments before `next`.
+    Ack {
+        stream: u32,
+        next: u32,
+    },
+    Reset {
+        stream: u32,
+    },
+}
+impl Message {
+    fn stream(&self) -> u32 {
+        match self {
+            Message::Data { stream, .. }
+            | Message::Ack { stream, .. }
+            | Message::Reset { stream } => *stream,
+        }
+    }
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Message::Data {
+                stream,
+                seq,
+                payload,
+            } => {
+                bytes.push(DATA);
+                bytes.extend_from_slice(&stream.to_be_bytes());
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(payload);
+            }
+            Message::Ack { stream, next } => {
+                bytes.push(ACK);
+                bytes.extend_from_slice(&stream.to_be_bytes());
+                bytes.extend_from_slice(&next.to_be_bytes());
+            }
+            Message::Reset { stream } => {
+                bytes.push(RESET);
+                bytes.extend_from_slice(&stream.to_be_bytes());
+            }
+        }
+        bytes
+    }
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| Some(u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+        let stream = u32_at(1)?;
+        match bytes[0] {
+            DATA => Some(Message::Data {
+                stream,
+                seq: u32_at(5)?,
+                payload: bytes[9..].to_vec(),
+            }),
+            ACK => Some(Message::Ack {
+                stream,
+                next: u32_at(5)?,
+            }),
+            RESET => Some(Message::Reset { stream }),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies a stream within the session with one node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct StreamKey {
+    opened_locally: bool,
+    id: u32,
+}
+impl StreamKey {
+    fn from_wire(stream: u32) -> Self {
+        Self {
+            opened_locally: stream & ACCEPTOR_BIT != 0,
+            id: stream & !ACCEPTOR_BIT,
+        }
+    }
+    fn to_wire(self) -> u32 {
+        if self.opened_locally {
+            self.id
+        } else {
+            self.id | ACCEPTOR_BIT
+        }
+    }
+}
+
+/// The session with one node and the streams that are carried in it.
+#[derive(Clone)]
+struct Link {
+    sender: SendSession,
+    streams: Arc<std::sync::Mutex<HashMap<StreamKey, Sender<Message>>>>,
+}
+impl Link {
+    async fn send(&mut self, message: Message) -> Result<(), Error> {
+        self.sender.write_all(&message.encode()).await
+    }
+}
+
+/// Forwards TCP connections to services of other nodes.
+///
+/// Local TCP connections are accepted on the addresses given to
+/// [`Gateway::forward`] and [`Gateway::socks5`]. Each one is carried to the
+/// gateway of the remote node, which connects to the address of the service.
+#[derive(Clone)]
+pub struct Gateway {
+    inner: Arc<Inner>,
+}
+struct Inner {
+    client: Client,
+    /// Addresses of the local services by name.
+    services: HashMap<String, String>,
+    /// Links that this gateway opened to other gateways.
+    links: Mutex<HashMap<PublicKey, Link>>,
+    next_stream: AtomicU32,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+#[allow(unused)]
+impl Gateway {
+    /// Starts a gateway that offers the local `services` to other nodes. Fails if
+    /// something else already listens on [`GATEWAY_SERVICE`].
+    pub async fn start(
+        client: Client,
+        services: HashMap<String, String>,
+    ) -> Result<Self, RouterError> {
+        let sessions = client.listen(GATEWAY_SERVICE).await?;
+        let gateway = Self {
+            inner: Arc::new(Inner {
+                client,
+                services,
+                links: Default::default(),
+                next_stream: AtomicU32::new(0),
+                tasks: Default::default(),
+            }),
+        };
+        let task = tokio::spawn(gateway.clone().accept_sessions(sessions));
+        gateway.inner.tasks.lock().unwrap().push(task);
+        Ok(gateway)
+    }
+    /// Forwards every connection to `listen` to `service` of the node with `remote`.
+    /// Returns the address that is listened on.
+    pub async fn forward(
+        &self,
+        listen: impl ToSocketAddrs,
+        remote: PublicKey,
+        service: String,
+    ) -> Result<SocketAddr, Error> {
+        check_service_name(&service)?;
+        let listener = TcpListener::bind(listen).await?;
+        let addr = listener.local_addr()?;
+        info!("Forwarding {} to {} of {}", addr, service, remote);
+        let gateway = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((tcp, _)) => {
+                        if let Err(e) = gateway.open_stream(tcp, remote, &service).await {
+                            warn!("Could not forward connection to {}: {}", remote, e);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Could not accept connection: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        self.inner.tasks.lock().unwrap().push(task);
+        Ok(addr)
+    }
+    /// Runs a SOCKS5 proxy on `listen`. Connections go to the service and node given
+    /// by the domain name `<service>.<public key>`, optionally followed by `.pinecone`.
+    /// If the domain is just the key the port is used as service name. Names that
+    /// [`Client::resolve`] knows can be used instead of the key.
+    /// Returns the address that is listened on.
+    pub async fn socks5(&self, listen: impl ToSocketAddrs) -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind(listen).await?;
+        let addr = listener.local_addr()?;
+        info!("SOCKS5 proxy on {}", addr);
+        let gateway = self.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((tcp, _)) => {
+                        let gateway = gateway.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = gateway.socks5_connect(tcp).await {
+                                debug!("SOCKS5 connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Could not accept connection: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        self.inner.tasks.lock().unwrap().push(task);
+        Ok(addr)
+    }
+    /// Stops accepting connections. Connections that are already forwarded stay open.
+    pub fn stop(&self) {
+        for task in self.inner.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    async fn socks5_connect(&self, mut tcp: TcpStream) -> Result<(), Error> {
+        let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+        let mut header = [0u8; 2];
+        tcp.read_exact(&mut header).await?;
+        if header[0] != 5 {
+            return Err(invalid("Not SOCKS5"));
+        }
+        let mut methods = vec![0u8; header[1] as usize];
+        tcp.read_exact(&mut methods).await?;
+        // No authentication
+        tcp.write_all(&[5, 0]).await?;
+        let mut request = [0u8; 4];
+        tcp.read_exact(&mut request).await?;
+        if request[1] != 1 {
+            tcp.write_all(&socks5_reply(7)).await?;
+            return Err(invalid("Only CONNECT is supported"));
+        }
+        if request[3] != 3 {
+            tcp.write_all(&socks5_reply(8)).await?;
+            return Err(invalid("Only domain names are supported"));
+        }
+        let len = tcp.read_u8().await? as usize;
+        let mut domain = vec![0u8; len];
+        tcp.read_exact(&mut domain).await?;
+        let port = tcp.read_u16().await?;
+        let domain = String::from_utf8_lossy(&domain);
+        let domain = domain.strip_suffix(".pinecone").unwrap_or(&domain);
+        let (service, key) = match domain.rsplit_once('.') {
+            Some((service, key)) => (service.to_string(), key),
+            None => (port.to_string(), domain),
+        };
+        let remote = match key.parse::<PublicKey>() {
+            Ok(remote) => remote,
+            Err(_) => match self.inner.client.resolve(key).await {
+                Ok(remote) => remote,
+                Err(_) => {
+                    tcp.write_all(&socks5_reply(4)).await?;
+                    return Err(invalid("Domain doesn't contain a public key or known name"));
+                }
+            },
+        };
+        if let Err(e) = check_service_name(&service) {
+            tcp.write_all(&socks5_reply(4)).await?;
+            return Err(e);
+        }
+        tcp.write_all(&socks5_reply(0)).await?;
+        self.open_stream(tcp, remote, &service).await
+    }
+
+    /// Carries `tcp` to `service` of the node with `remote`.
+    async fn open_stream(
+        &self,
+        tcp: TcpStream,
+        remote: PublicKey,
+        service: &str,
+    ) -> Result<(), Error> {
+        let link = self.link(remote).await?;
+        let id = self.inner.next_stream.fetch_add(1, Ordering::Relaxed) & !ACCEPTOR_BIT;
+        let key = StreamKey {
+            opened_locally: true,
+            id,
+        };
+        let (sender, incoming) = channel(WINDOW * 2);
+        link.streams.lock().unwrap().insert(key, sender);
+        let mut header = vec![service.len() as u8];
+        header.extend_from_slice(service.as_bytes());
+        debug!("Opening stream {} to {} of {}", id, service, remote);
+        tokio::spawn(run_stream(
+            tcp,
+            link,
+            key,
+            incoming,
+            VecDeque::from([(0, header)]),
+            1,
+            0,
+        ));
+        Ok(())
+    }
+    /// Returns the link to `remote` and opens a session for it if there is none.
+    async fn link(&self, remote: PublicKey) -> Result<Link, Error> {
+        let mut links = self.inner.links.lock().await;
+        if let Some(link) = links.get(&remote) {
+            return Ok(link.clone());
+        }
+        let session = self
+            .inner
+            .client
+            .dial_service(remote, GATEWAY_SERVICE)
+            .await
+            .map_err(|e| Error::new(ErrorKind::AddrInUse, e.to_string()))?;
+        let link = self.add_link(session);
+        links.insert(remote, link.clone());
+        Ok(link)
+    }
+    fn add_link(&self, session: Session) -> Link {
+        let link = Link {
+            sender: session.sender(),
+            streams: Default::default(),
+        };
+        tokio::spawn(self.clone().receive(session, link.clone()));
+        link
+    }
+    async fn accept_sessions(self, mut sessions: SessionListener) {
+        while let Some(session) = sessions.recv().await {
+            self.add_link(session);
+        }
+    }
+    /// Hands the messages of `session` to their streams and accepts new streams.
+    async fn receive(self, mut session: Session, mut link: Link) {
+        let remote = session.peer_key();
+        let mut buf = vec![0u8; SEGMENT_SIZE + 16];
+        loop {
+            let len = match session.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    debug!("Session with {} failed: {}", remote, e);
+                    break;
+                }
+            };
+            let Some(message) = Message::decode(&buf[..len]) else {
+                trace!("Dropping invalid message from {}", remote);
+                continue;
+            };
+            let key = StreamKey::from_wire(message.stream());
+            let stream = link.streams.lock().unwrap().get(&key).cloned();
+            match (stream, message) {
+                // A stream that doesn't keep up must not hold up the others on the
+                // link. What is dropped here gets retransmitted.
+                (Some(stream), message) => match stream.try_send(message) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        trace!("Stream {:?} of {} is busy, dropping message", key, remote);
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        link.streams.lock().unwrap().remove(&key);
+                    }
+                },
+                (
+                    None,
+                    Message::Data {
+                        seq: 0, payload, ..
+                    },
+                ) if !key.opened_locally => {
+                    let (sender, incoming) = channel(WINDOW * 2);
+                    link.streams.lock().unwrap().insert(key, sender);
+                    tokio::spawn(
+                        self.clone()
+                            .accept_stream(link.clone(), key, incoming, payload),
+                    );
+                }
+                (None, Message::Reset { .. }) => {}
+                (None, _) => {
+                    let reset = Message::Reset {
+                        stream: key.to_wire(),
+                    };
+                    if link.send(reset).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        let mut links = self.inner.links.lock().await;
+        if links
+            .get(&remote)
+            .is_some_and(|l| Arc::ptr_eq(&l.streams, &link.streams))
+        {
+            links.remove(&remote);
+        }
+    }
+    /// Connects a stream that was opened by another node to the service named in `header`.
+    async fn accept_stream(
+        self,
+        mut link: Link,
+        key: StreamKey,
+        incoming: Receiver<Message>,
+        header: Vec<u8>,
+    ) {
+        let service = header
+            .split_first()
+            .filter(|(len, name)| **len as usize == name.len())
+            .map(|(_, name)| String::from_utf8_lossy(name).to_string());
+        let target = service
+            .as_ref()
+            .and_then(|service| self.inner.services.get(service));
+        let tcp = match target {
+            Some(target) => TcpStream::connect(target).await,
+            None => Err(Error::new(ErrorKind::NotFound, "Unknown service")),
+        };
+        match tcp {
+            Ok(tcp) => {
+                debug!("Accepted stream {} for {:?}", key.id, service);
+                let ack = Message::Ack {
+                    stream: key.to_wire(),
+                    next: 1,
+                };
+                if link.send(ack).await.is_ok() {
+                    run_stream(tcp, link, key, incoming, VecDeque::new(), 0, 1).await;
+                }
+            }
+            Err(e) => {
+                debug!("Could not connect stream for {:?}: {}", service, e);
+                link.streams.lock().unwrap().remove(&key);
+                let _ = link
+                    .send(Message::Reset {
+                        stream: key.to_wire(),
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+fn check_service_name(service: &str) -> Result<(), Error> {
+    if service.is_empty() || service.len() > u8::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Service names have to be 1 to 255 bytes long",
+        ));
+    }
+    Ok(())
+}
+
+fn socks5_reply(status: u8) -> [u8; 10] {
+    [5, status, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+/// Copies the bytes of `tcp` into data segments and the data segments of the other
+/// side into `tcp` until both sides ended the stream or it was reset.
+///
+/// `unacked` holds the segments that were queued before, like the header.
+async fn run_stream(
+    tcp: TcpStream,
+    mut link: Link,
+    key: StreamKey,
+    mut incoming: Receiver<Message>,
+    mut unacked: VecDeque<(u32, Vec<u8>)>,
+    mut send_next: u32,
+    mut receive_next: u32,
+) {
+    let stream = key.to_wire();
+    let (mut tcp_read, mut tcp_write) = tcp.into_split();
+    let mut buf = vec![0u8; SEGMENT_SIZE];
+    let mut sent_end = false;
+    let mut received_end = false;
+    let mut retransmits = 0;
+    let mut deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+    for (seq, payload) in unacked.clone() {
+        let data = Message::Data {
+            stream,
+            seq,
+            payload,
+        };
+        if link.send(data).await.is_err() {
+            return;
+        }
+    }
+    let reset = loop {
+        if sent_end && received_end && unacked.is_empty() {
+            break false;
+        }
+        tokio::select! {
+            read = tcp_read.read(&mut buf), if !sent_end && unacked.len() < WINDOW => {
+                // Read errors end the stream like the end of the connection.
+                let payload = buf[..read.unwrap_or(0)].to_vec();
+                sent_end = payload.is_empty();
+                if unacked.is_empty() {
+                    deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+                }
+                unacked.push_back((send_next, payload.clone()));
+                let data = Message::Data { stream, seq: send_next, payload };
+                send_next += 1;
+                if link.send(data).await.is_err() {
+                    break true;
+                }
+            }
+            message = incoming.recv() => match message {
+                Some(Message::Data { seq, payload, .. }) => {
+                    if seq == receive_next {
+                        receive_next += 1;
+                        if payload.is_empty() {
+                            received_end = true;
+                            let _ = tcp_write.shutdown().await;
+                        } else if tcp_write.write_all(&payload).await.is_err() {
+                            break true;
+                        }
+                    }
+                    let ack = Message::Ack { stream, next: receive_next };
+                    if link.send(ack).await.is_err() {
+                        break true;
+                    }
+                }
+                Some(Message::Ack { next, .. }) => {
+                    let len = unacked.len();
+                    unacked.retain(|(seq, _)| *seq >= next);
+                    if unacked.len() < len {
+                        retransmits = 0;
+                        deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+                    }
+                }
+                Some(Message::Reset { .. }) | None => break false,
+            },
+            _ = sleep_until(deadline), if !unacked.is_empty() => {
+                retransmits += 1;
+                if retransmits > MAX_RETRANSMITS {
+                    debug!("Stream {} timed out", key.id);
+                    break true;
+                }
+                trace!("Retransmitting {} segments of stream {}", unacked.len(), key.id);
+                for (seq, payload) in unacked.clone() {
+                    let data = Message::Data { stream, seq, payload };
+                    if link.send(data).await.is_err() {
+                        break;
+                    }
+                }
+                deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+            }
+        }
+    };
+    link.streams.lock().unwrap().remove(&key);
+    if reset {
+        let _ = link.send(Message::Reset { stream }).await;
+    }
+    trace!("Stream {} ended", key.id);
+}
+
+/// A local port that is forwarded to a service of another node.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardConfig {
+    pub listen: SocketAddr,
+    pub remote: PublicKey,
+    pub service: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::connected_clients;
+
+    async fn connected_gateways(services: HashMap<String, String>) -> (Gateway, Gateway) {
+        let (client1, client2, _) = connected_clients(Default::default()).await;
+        (
+            Gateway::start(client1, HashMap::new()).await.unwrap(),
+            Gateway::start(client2, services).await.unwrap(),
+        )
+    }
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = tcp.into_split();
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                    write.shutdown().await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn encode_messages() {
+        for message in [
+            Message::Data {
+                stream: 1 | ACCEPTOR_BIT,
+                seq: 7,
+                payload: b"data".to_vec(),
+            },
+            Message::Ack { stream: 2, next: 3 },
+            Message::Reset { stream: 4 },
+        ] {
+            assert_eq!(Message::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(Message::decode(&[DATA, 0, 0]), None);
+    }
+    #[tokio::test]
+    async fn forward_connection() {
+        let echo = echo_server().await;
+        let services = HashMap::from([(String::from("echo"), echo.to_string())]);
+        let (gateway1, gateway2) = connected_gateways(services).await;
+        let remote = gateway2.inner.client.public_key();
+        let addr = gateway1
+            .forward("127.0.0.1:0", remote, String::from("echo"))
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let (mut read, mut write) = tcp.split();
+        let mut echoed = vec![];
+        let (written, _) = tokio::join!(
+            async {
+                write.write_all(&data).await.unwrap();
+                write.shutdown().await
+            },
+            read.read_to_end(&mut echoed)
+        );
+        written.unwrap();
+        assert_eq!(echoed, data);
+
+        // Unknown services reset the connection.
+        let addr = gateway1
+            .forward("127.0.0.1:0", remote, String::from("unknown"))
+            .await
+            .unwrap();
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let mut buf = vec![];
+        assert_eq!(tcp.read_to_end(&mut buf).await.unwrap_or(0), 0);
+    }
+    #[tokio::test]
+    async fn socks5() {
+        let echo = echo_server().await;
+        let services = HashMap::from([(String::from("echo"), echo.to_string())]);
+        let (gateway1, gateway2) = connected_gateways(services).await;
+        let addr = gateway1.socks5("127.0.0.1:0").await.unwrap();
+
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        tcp.write_all(&[5, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 2];
+        tcp.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0]);
+        let domain = format!("echo.{}.pinecone", gateway2.inner.client.public_key());
+        let mut request = vec![5, 1, 0, 3, domain.len() as u8];
+        request.extend_from_slice(domain.as_bytes());
+        request.extend_from_slice(&80u16.to_be_bytes());
+        tcp.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        tcp.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, socks5_reply(0));
+
+        tcp.write_all(b"hello").await.unwrap();
+        let mut echoed = [0u8; 5];
+        tcp.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+    }
+}
@@ -1,11 +1,18 @@
 use crate::coordinates::Coordinates;
-use crate::router::{Port, PublicKey, SequenceNumber, SnekPathId};
+use crate::dht::DhtRecord;
+use crate::mailbox::MailStatus;
+use crate::public_key::PublicKey;
+use crate::router::{Port, SequenceNumber, SnekPathId};
 use crate::tree::{Root, RootAnnouncementSignature};
 use bytes::{BufMut, BytesMut};
-use ed25519_consensus::{SigningKey, VerificationKey};
+use ed25519_consensus::{Signature, SigningKey, VerificationKey};
 use log::trace;
 use std::fmt::{Display, Formatter};
-use std::time::SystemTime;
+use tokio::time::Instant;
+
+/// Number of hops a routed frame may take before it is dropped. This ends
+/// forwarding loops that are caused by routers with inconsistent routing state.
+pub(crate) const DEFAULT_HOP_LIMIT: u8 = 64;
 
 #[derive(Debug, Clone)]
 pub enum Frame {
@@ -17,38 +24,147 @@ pub enum Frame {
     SnekSetup(SnekSetup),
     SnekSetupACK(SnekSetupAck),
     SnekTeardown(SnekTeardown),
+    CoordinatesRequest(CoordinatesRequest),
+    CoordinatesResponse(CoordinatesResponse),
+    HybridRouted(HybridPacket),
+    Probe(Probe),
+    ProbeReply(ProbeReply),
+    Broadcast(Broadcast),
+    DhtPut(DhtPut),
+    DhtGet(DhtGet),
+    DhtGetResponse(DhtGetResponse),
+    Mail(MailPacket),
+    MailReply(MailReply),
 }
 #[derive(Debug, Clone, PartialEq)]
 pub struct SnekPacket {
     pub destination_key: PublicKey,
     pub source_key: PublicKey,
+    pub traffic_class: TrafficClass,
+    /// Hops that the packet may still take.
+    pub hop_limit: u8,
     pub payload: Vec<u8>,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub struct TreePacket {
     pub(crate) source_coordinates: Coordinates,
     pub(crate) destination_coordinates: Coordinates,
+    pub(crate) traffic_class: TrafficClass,
+    /// Hops that the packet may still take.
+    pub(crate) hop_limit: u8,
     pub(crate) payload: Vec<u8>,
 }
+impl Frame {
+    /// The hop limit of frames that are routed towards a destination, over the tree
+    /// or over SNEK. Frames that only travel between peers or along the paths of
+    /// SNEK have none.
+    pub(crate) fn hop_limit_mut(&mut self) -> Option<&mut u8> {
+        match self {
+            Frame::TreeRouted(packet) => Some(&mut packet.hop_limit),
+            Frame::SnekRouted(packet) => Some(&mut packet.hop_limit),
+            Frame::HybridRouted(packet) => Some(&mut packet.hop_limit),
+            Frame::Probe(probe) => Some(&mut probe.hop_limit),
+            Frame::ProbeReply(reply) => Some(&mut reply.hop_limit),
+            Frame::Broadcast(broadcast) => Some(&mut broadcast.hop_limit),
+            Frame::SnekBootstrap(bootstrap) => Some(&mut bootstrap.hop_limit),
+            Frame::CoordinatesRequest(request) => Some(&mut request.hop_limit),
+            Frame::CoordinatesResponse(response) => Some(&mut response.hop_limit),
+            Frame::DhtPut(put) => Some(&mut put.hop_limit),
+            Frame::DhtGet(get) => Some(&mut get.hop_limit),
+            Frame::DhtGetResponse(response) => Some(&mut response.hop_limit),
+            Frame::Mail(mail) => Some(&mut mail.hop_limit),
+            Frame::MailReply(reply) => Some(&mut reply.hop_limit),
+            Frame::TreeAnnouncement(_)
+            | Frame::SnekBootstrapACK(_)
+            | Frame::SnekSetup(_)
+            | Frame::SnekSetupACK(_)
+            | Frame::SnekTeardown(_) => None,
+        }
+    }
+}
+/// A [`SnekPacket`] that is routed over the spanning tree towards the coordinates
+/// its destination had when they were looked up. Routers that can't get it closer
+/// to these coordinates route it further as a [`SnekPacket`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HybridPacket {
+    pub(crate) destination_coordinates: Coordinates,
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) traffic_class: TrafficClass,
+    /// Hops that the packet may still take.
+    pub(crate) hop_limit: u8,
+    pub(crate) payload: Vec<u8>,
+}
+impl HybridPacket {
+    pub(crate) fn new(packet: SnekPacket, destination_coordinates: Coordinates) -> Self {
+        Self {
+            destination_coordinates,
+            destination_key: packet.destination_key,
+            source_key: packet.source_key,
+            traffic_class: packet.traffic_class,
+            hop_limit: packet.hop_limit,
+            payload: packet.payload,
+        }
+    }
+    pub(crate) fn into_snek(self) -> SnekPacket {
+        SnekPacket {
+            destination_key: self.destination_key,
+            source_key: self.source_key,
+            traffic_class: self.traffic_class,
+            hop_limit: self.hop_limit,
+            payload: self.payload,
+        }
+    }
+}
+/// How traffic is treated by the routers it passes when their connections are
+/// congested. Traffic of a higher class gets a larger share of the bandwidth,
+/// but no class can starve the others.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// Traffic that can wait, like file transfers.
+    Bulk,
+    #[default]
+    BestEffort,
+    /// Traffic that is sensitive to latency, like chat messages or calls.
+    Interactive,
+}
+impl TrafficClass {
+    /// The value of the class in the frame header. Unknown values are read as
+    /// [`TrafficClass::BestEffort`].
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            TrafficClass::BestEffort => 0,
+            TrafficClass::Bulk => 1,
+            TrafficClass::Interactive => 2,
+        }
+    }
+    pub(crate) fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => TrafficClass::Bulk,
+            2 => TrafficClass::Interactive,
+            _ => TrafficClass::BestEffort,
+        }
+    }
+}
 #[derive(Debug, Clone, PartialEq)]
 pub struct TreeAnnouncement {
     pub(crate) root: Root,
     pub(crate) signatures: Vec<RootAnnouncementSignature>,
-    pub(crate) receive_time: SystemTime,
+    pub(crate) receive_time: Instant,
     pub(crate) receive_order: SequenceNumber,
 }
 impl TreeAnnouncement {
     pub(crate) fn append_signature(&mut self, keypair: SigningKey, destination_port: Port) {
         let mut unsigned = BytesMut::new();
-        let public_key = keypair.verification_key().to_bytes();
-        unsigned.put_slice(&self.root.public_key);
+        let public_key = PublicKey::from(keypair.verification_key());
+        unsigned.put_slice(self.root.public_key.as_bytes());
         unsigned.put_u64(self.root.sequence_number);
         for sig in &self.signatures {
-            unsigned.put_slice(&sig.signing_public_key);
+            unsigned.put_slice(sig.signing_public_key.as_bytes());
             unsigned.put_u64(sig.destination_port);
             unsigned.put_slice(&sig.signature.to_bytes());
         }
-        unsigned.put_slice(&public_key);
+        unsigned.put_slice(public_key.as_bytes());
         unsigned.put_u64(destination_port);
         let signature = keypair.sign(unsigned.as_ref());
         self.signatures.push(RootAnnouncementSignature {
@@ -58,6 +174,10 @@ impl TreeAnnouncement {
         });
     }
     pub(crate) fn is_clean(&self, peer_public_key: &PublicKey) -> bool {
+        if self.signatures.is_empty() {
+            trace!("announcement has no signatures");
+            return false;
+        }
         if self.root.public_key != self.signatures.get(0).unwrap().signing_public_key {
             trace!("root_public_key doesn't match the first signing_public_key");
             return false;
@@ -92,12 +212,12 @@ impl TreeAnnouncement {
             }
         }
         let mut to_verify = BytesMut::new();
-        to_verify.put_slice(&self.root.public_key);
+        to_verify.put_slice(self.root.public_key.as_bytes());
         to_verify.put_u64(self.root.sequence_number);
         for sig in &self.signatures {
-            to_verify.put_slice(&sig.signing_public_key);
+            to_verify.put_slice(sig.signing_public_key.as_bytes());
             to_verify.put_u64(sig.destination_port);
-            if let Ok(key) = VerificationKey::try_from(sig.signing_public_key) {
+            if let Ok(key) = VerificationKey::try_from(sig.signing_public_key.to_bytes()) {
                 match key.verify(&sig.signature, to_verify.as_ref()) {
                     Ok(_) => {
                         to_verify.put_slice(sig.signature.to_bytes().as_ref());
@@ -152,6 +272,7 @@ pub struct SnekBootstrap {
     pub(crate) destination_key: PublicKey,
     pub(crate) source: Coordinates,
     pub(crate) path_id: SnekPathId,
+    pub(crate) hop_limit: u8,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub struct SnekBootstrapAck {
@@ -182,6 +303,266 @@ pub struct SnekTeardown {
     pub(crate) destination_key: PublicKey,
     pub(crate) path_id: SnekPathId,
 }
+/// Asks the node with `destination_key` for its current coordinates.
+/// Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoordinatesRequest {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    /// Random, so that only the nodes that saw the request can answer it.
+    pub(crate) id: u64,
+    pub(crate) hop_limit: u8,
+}
+/// The answer to a [`CoordinatesRequest`] with `id`. `source_key` is the node that
+/// the coordinates belong to and signs them, so that no other node can redirect
+/// traffic for it. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoordinatesResponse {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) signature: Signature,
+    pub(crate) root: Root,
+    pub(crate) coordinates: Coordinates,
+    pub(crate) hop_limit: u8,
+}
+impl CoordinatesResponse {
+    /// Creates the answer of the node with `signing_key` to the request with `id`
+    /// of `destination_key`.
+    pub(crate) fn new(
+        destination_key: PublicKey,
+        id: u64,
+        root: Root,
+        coordinates: Coordinates,
+        signing_key: &SigningKey,
+    ) -> Self {
+        let mut response = Self {
+            destination_key,
+            source_key: PublicKey::from(signing_key),
+            id,
+            signature: Signature::from([0; 64]),
+            root,
+            coordinates,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        response.signature = signing_key.sign(&response.signed_bytes());
+        response
+    }
+    fn signed_bytes(&self) -> BytesMut {
+        let mut unsigned = BytesMut::new();
+        unsigned.put_slice(self.destination_key.as_bytes());
+        unsigned.put_slice(self.source_key.as_bytes());
+        unsigned.put_u64(self.id);
+        unsigned.put_slice(self.root.public_key.as_bytes());
+        unsigned.put_u64(self.root.sequence_number);
+        for coord in &self.coordinates.coordinates {
+            unsigned.put_u64(*coord);
+        }
+        unsigned
+    }
+    /// Returns true if the node that the coordinates belong to signed them.
+    pub(crate) fn verify(&self) -> bool {
+        VerificationKey::try_from(self.source_key.to_bytes())
+            .and_then(|key| key.verify(&self.signature, &self.signed_bytes()))
+            .is_ok()
+    }
+}
+/// Measures the path to `destination_key`, which answers with a [`ProbeReply`].
+/// If `trace` is set, every node that forwards the probe answers as well.
+/// Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) trace: bool,
+    pub(crate) hop_limit: u8,
+}
+/// The answer of `source_key` to a [`Probe`]. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeReply {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    /// Number of hops the probe took to get to `source_key`.
+    pub(crate) distance: u8,
+    /// The port `source_key` forwarded the probe through, or 0 if it was the
+    /// destination of the probe.
+    pub(crate) port: Port,
+    pub(crate) hop_limit: u8,
+}
+/// Data of `source_key` for every node that subscribed to `topic`. Flooded along the
+/// edges of the spanning tree. Routers forward every broadcast only once and drop
+/// the copies that reach them over other edges by their `id`.
+///
+/// The source signs the broadcast, so that nobody can publish in its name or
+/// suppress its broadcasts by sending others with the same id first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Broadcast {
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) signature: Signature,
+    pub(crate) topic: String,
+    pub(crate) traffic_class: TrafficClass,
+    pub(crate) hop_limit: u8,
+    pub(crate) payload: Vec<u8>,
+}
+impl Broadcast {
+    /// Makes the node with `signing_key` the source of the broadcast and signs it.
+    pub(crate) fn sign(&mut self, signing_key: &SigningKey) {
+        self.source_key = PublicKey::from(signing_key);
+        self.signature = signing_key.sign(&self.signed_bytes());
+    }
+    fn signed_bytes(&self) -> BytesMut {
+        let mut unsigned = BytesMut::new();
+        unsigned.put_slice(self.source_key.as_bytes());
+        unsigned.put_u64(self.id);
+        unsigned.put_u8(self.topic.len() as u8);
+        unsigned.put_slice(self.topic.as_bytes());
+        unsigned.put_slice(&self.payload);
+        unsigned
+    }
+    /// Returns true if the source signed the broadcast.
+    pub(crate) fn verify(&self) -> bool {
+        VerificationKey::try_from(self.source_key.to_bytes())
+            .and_then(|key| key.verify(&self.signature, &self.signed_bytes()))
+            .is_ok()
+    }
+}
+/// Stores `record` at the home node of its key, the node that follows the position
+/// `destination_key` of the key. Copies of the record are addressed to the public key
+/// of the node that keeps them instead. Every node that stores the record places
+/// copies at `replicas` further nodes. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhtPut {
+    pub(crate) destination_key: PublicKey,
+    /// The writer of the record, or the node that placed this copy of it.
+    pub(crate) source_key: PublicKey,
+    pub(crate) replicas: u8,
+    pub(crate) record: Box<DhtRecord>,
+    pub(crate) hop_limit: u8,
+}
+/// Asks the home node of `key`, which follows its position `destination_key`, for
+/// the records of the key. The source signs the request, so that nobody can have
+/// the records sent to another node. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhtGet {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) signature: Signature,
+    pub(crate) key: Vec<u8>,
+    pub(crate) hop_limit: u8,
+}
+impl DhtGet {
+    /// Creates the request of the node with `signing_key` for the records of `key`.
+    pub(crate) fn new(
+        destination_key: PublicKey,
+        id: u64,
+        key: Vec<u8>,
+        signing_key: &SigningKey,
+    ) -> Self {
+        let mut get = Self {
+            destination_key,
+            source_key: PublicKey::from(signing_key),
+            id,
+            signature: Signature::from([0; 64]),
+            key,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        get.signature = signing_key.sign(&get.signed_bytes());
+        get
+    }
+    fn signed_bytes(&self) -> BytesMut {
+        let mut unsigned = BytesMut::new();
+        unsigned.put_slice(self.destination_key.as_bytes());
+        unsigned.put_slice(self.source_key.as_bytes());
+        unsigned.put_u64(self.id);
+        unsigned.put_slice(&self.key);
+        unsigned
+    }
+    /// Returns true if the source signed the request.
+    pub(crate) fn verify(&self) -> bool {
+        VerificationKey::try_from(self.source_key.to_bytes())
+            .and_then(|key| key.verify(&self.signature, &self.signed_bytes()))
+            .is_ok()
+    }
+}
+/// The answer to a [`DhtGet`]. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DhtGetResponse {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) records: Vec<DhtRecord>,
+    pub(crate) hop_limit: u8,
+}
+/// Mail for `destination_key`. It is routed over SNEK like protocol frames, so that it
+/// ends at the node that follows `destination_key` if that node is offline. That
+/// node holds it if it is a mailbox. Mailboxes send the mail on once the node
+/// with `destination_key` is back.
+///
+/// The payload is sealed for `destination_key`, so only the destination can open
+/// it. The source signs the mail, so that mailboxes can check who sent it without
+/// opening it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailPacket {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    /// The node that the [`MailReply`] goes to: the source, or the mailbox that sends
+    /// on mail it held. It isn't signed, as mailboxes change it.
+    pub(crate) reply_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) signature: Signature,
+    pub(crate) hop_limit: u8,
+}
+impl MailPacket {
+    /// Creates mail of the node with `signing_key` with a `payload` that was sealed
+    /// already.
+    pub(crate) fn new(
+        destination_key: PublicKey,
+        id: u64,
+        payload: Vec<u8>,
+        signing_key: &SigningKey,
+    ) -> Self {
+        let mut mail = Self {
+            destination_key,
+            source_key: PublicKey::from(signing_key),
+            reply_key: PublicKey::from(signing_key),
+            id,
+            payload,
+            signature: Signature::from([0; 64]),
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        mail.signature = signing_key.sign(&mail.signed_bytes());
+        mail
+    }
+    fn signed_bytes(&self) -> BytesMut {
+        let mut unsigned = BytesMut::new();
+        unsigned.put_slice(self.destination_key.as_bytes());
+        unsigned.put_slice(self.source_key.as_bytes());
+        unsigned.put_u64(self.id);
+        unsigned.put_slice(&self.payload);
+        unsigned
+    }
+    /// Returns true if the source signed the mail.
+    pub(crate) fn verify(&self) -> bool {
+        VerificationKey::try_from(self.source_key.to_bytes())
+            .and_then(|key| key.verify(&self.signature, &self.signed_bytes()))
+            .is_ok()
+    }
+}
+/// Tells `destination_key`, the reply key of the [`MailPacket`] with `id`, what became
+/// of it. `source_key` is the node that received the mail. Routed over SNEK.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailReply {
+    pub(crate) destination_key: PublicKey,
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) status: MailStatus,
+    pub(crate) hop_limit: u8,
+}
 
 impl Display for TreeAnnouncement {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -1,21 +1,64 @@
 //! A rust implementation of the pinecone peer-to-peer overlay routing mechanism originally
 //! implemented in [Pinecone](https://github.com/matrix-org/pinecone).
 
+#[cfg(unix)]
+mod admin;
 mod client;
+mod config;
 mod connection;
 mod coordinates;
+mod daemon;
+mod dht;
 mod error;
+mod forward;
 mod frames;
+mod key_file;
+mod logger;
+mod mailbox;
+mod metrics;
+mod names;
+mod probe;
+mod public_key;
+mod pubsub;
+mod queue;
+mod rate_limit;
 mod router;
 mod session;
+mod shutdown;
 mod snek;
+mod status;
 mod tree;
+mod tun;
 mod wait_timer;
 mod wire_frame;
 
+#[cfg(unix)]
+pub use crate::admin::{admin_request, DEFAULT_ADMIN_SOCKET};
 pub use crate::client::Client;
-pub use crate::client::SessionListener;
+pub use crate::client::{SessionEvent, SessionListener};
+pub use crate::config::{DropPolicy, RateLimit, RateLimits, RouterConfig};
+pub use crate::coordinates::Coordinates;
+pub use crate::daemon::{Daemon, DaemonConfig, DaemonTimers};
+pub use crate::dht::DhtRecord;
+pub use crate::error::RouterError;
+pub use crate::forward::{ForwardConfig, Gateway, GATEWAY_SERVICE};
+pub use crate::frames::TrafficClass;
+pub use crate::key_file::{load_key_file, load_or_create_key_file, save_key_file, KeyFormat};
+pub use crate::logger::{init_logger, set_log_filters};
+pub use crate::mailbox::{Inbox, Mail, MailStatus};
+pub use crate::metrics::Metrics;
+pub use crate::names::{load_hosts_file, NameRecord};
+pub use crate::probe::Hop;
+pub use crate::public_key::{ParsePublicKeyError, PublicKey};
+pub use crate::pubsub::{Publication, Subscription};
 pub use crate::session::*;
+pub use crate::status::{PeerInfo, SnekInfo, TreeInfo};
+#[cfg(target_os = "linux")]
+pub use crate::tun::open_tun;
+pub use crate::tun::{
+    ipv6_address, FakeTun, PacketSink, PacketStream, TunAdapter, ADDRESS_PREFIX, TUN_MTU,
+    TUN_SERVICE,
+};
 pub use crate::wire_frame::PineconeCodec;
 
 #[cfg(test)]
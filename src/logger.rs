@@ -0,0 +1,58 @@
+use env_logger::{Logger, WriteStyle};
+use log::{Log, Metadata, Record, SetLoggerError};
+use std::io::{Error, ErrorKind};
+use std::sync::{OnceLock, RwLock};
+
+/// Wraps an [`env_logger`] logger so that its filters can be replaced while running.
+struct ReloadableLogger {
+    inner: RwLock<Logger>,
+}
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+/// The logger once it was installed successfully.
+static LOGGER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+fn build_logger(filters: &str) -> Logger {
+    env_logger::builder()
+        .write_style(WriteStyle::Always)
+        .format_timestamp(None)
+        .parse_filters(filters)
+        .build()
+}
+
+/// Installs a logger that writes to stderr. `filters` use the syntax of `RUST_LOG`,
+/// for example `info` or `info,rust_pinecone=trace`. They can be changed later with
+/// [`set_log_filters`].
+pub fn init_logger(filters: &str) -> Result<(), SetLoggerError> {
+    let logger = Box::leak(Box::new(ReloadableLogger {
+        inner: RwLock::new(build_logger(filters)),
+    }));
+    log::set_logger(logger)?;
+    log::set_max_level(logger.inner.read().unwrap().filter());
+    let _ = LOGGER.set(logger);
+    Ok(())
+}
+
+/// Replaces the filters of the logger that was installed with [`init_logger`].
+pub fn set_log_filters(filters: &str) -> Result<(), Error> {
+    let logger = LOGGER.get().ok_or_else(|| {
+        Error::new(
+            ErrorKind::Unsupported,
+            "The logger of the router isn't installed",
+        )
+    })?;
+    let new_logger = build_logger(filters);
+    log::set_max_level(new_logger.filter());
+    *logger.inner.write().unwrap() = new_logger;
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use crate::public_key::PublicKey;
+#[cfg(doc)]
+use crate::Client;
+use crypto_box::aead::{Aead, AeadCore, OsRng};
+use crypto_box::SalsaBox;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_consensus::{Signature, SigningKey};
+use sha2::{Digest, Sha512};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+
+/// Number of received mails that are held until they are taken from the [`Inbox`].
+/// Further mails are dropped.
+pub(crate) const INBOX_BUFFER: usize = 100;
+/// What sealing adds to a payload: the nonce and the tag of the box.
+const SEAL_OVERHEAD: usize = 24 + 16;
+/// Largest payload of a mail. Once it is sealed it has to fit into a single frame
+/// next to its header, the keys, the id and the signature.
+pub(crate) const MAX_MAIL_SIZE: usize =
+    u16::MAX as usize - (10 + 32 + 32 + 32 + 8 + 64) - SEAL_OVERHEAD;
+
+/// The X25519 key of the node with `signing_key`, the way libsodium derives it from
+/// an ed25519 key.
+fn box_secret_key(signing_key: &SigningKey) -> crypto_box::SecretKey {
+    let hash = Sha512::digest(signing_key.as_bytes());
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(&hash[..32]);
+    crypto_box::SecretKey::from(bytes)
+}
+/// The X25519 key of the node with `public_key`. Fails if it isn't a valid point.
+fn box_public_key(public_key: &PublicKey) -> Option<crypto_box::PublicKey> {
+    let point = CompressedEdwardsY(public_key.to_bytes()).decompress()?;
+    Some(crypto_box::PublicKey::from(point.to_montgomery()))
+}
+/// Seals `payload` so that only `destination` can open it, and only with the key of
+/// the node with `signing_key`. The nonce is put in front of the box.
+pub(crate) fn seal(
+    payload: &[u8],
+    signing_key: &SigningKey,
+    destination: &PublicKey,
+) -> Option<Vec<u8>> {
+    let sealer = SalsaBox::new(&box_public_key(destination)?, &box_secret_key(signing_key));
+    let nonce = SalsaBox::generate_nonce(&mut OsRng);
+    let sealed = sealer.encrypt(&nonce, payload).ok()?;
+    Some([nonce.as_slice(), &sealed].concat())
+}
+/// Opens a payload that `source` sealed for the node with `signing_key`. Fails if
+/// it was sealed by another node or changed.
+pub(crate) fn open(sealed: &[u8], signing_key: &SigningKey, source: &PublicKey) -> Option<Vec<u8>> {
+    if sealed.len() < SEAL_OVERHEAD {
+        return None;
+    }
+    let (nonce, sealed) = sealed.split_at(24);
+    let opener = SalsaBox::new(&box_public_key(source)?, &box_secret_key(signing_key));
+    opener.decrypt(nonce.into(), sealed).ok()
+}
+
+/// What became of mail that was sent with [`Client::send_mail`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailStatus {
+    /// The destination was online and received the mail.
+    Delivered,
+    /// The destination is offline. A mailbox holds the mail until it comes back.
+    Stored,
+    /// The destination is offline and the node that follows its key either isn't a
+    /// mailbox or holds as much mail as it may for the destination. Also if the
+    /// destination is online but its inbox is full.
+    Refused,
+}
+impl MailStatus {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            MailStatus::Delivered => 0,
+            MailStatus::Stored => 1,
+            MailStatus::Refused => 2,
+        }
+    }
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(MailStatus::Delivered),
+            1 => Some(MailStatus::Stored),
+            2 => Some(MailStatus::Refused),
+            _ => None,
+        }
+    }
+}
+
+/// Mail that another node sent to this node with [`Client::send_mail`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mail {
+    pub source_key: PublicKey,
+    pub payload: Vec<u8>,
+}
+
+/// Receives the mail of this node. This is being given out by [`Client::inbox`].
+#[derive(Debug)]
+pub struct Inbox {
+    pub(crate) mail: Receiver<Mail>,
+}
+#[allow(unused)]
+impl Inbox {
+    /// Receives the next mail. Returns `None` once the router was stopped.
+    pub async fn recv(&mut self) -> Option<Mail> {
+        self.mail.recv().await
+    }
+}
+
+/// Mail that a mailbox holds for an offline node. The payload stays sealed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoredMail {
+    pub(crate) source_key: PublicKey,
+    pub(crate) id: u64,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) signature: Signature,
+    pub(crate) expires: Instant,
+    /// When the mail was last sent on to its destination.
+    pub(crate) sent: Option<Instant>,
+}
+
+/// The mail that this node holds for offline nodes whose keys it follows, by
+/// destination in the order it arrived.
+#[derive(Debug, Default)]
+pub(crate) struct Mailbox {
+    mail: BTreeMap<PublicKey, VecDeque<StoredMail>>,
+    /// Size of all payloads together.
+    size: usize,
+}
+impl Mailbox {
+    /// Holds `mail` for `destination` unless the payloads for `destination` would
+    /// exceed `quota` bytes or all payloads together would exceed `capacity` bytes.
+    /// Returns whether the mail was taken.
+    pub(crate) fn insert(
+        &mut self,
+        destination: PublicKey,
+        mail: StoredMail,
+        quota: usize,
+        capacity: usize,
+    ) -> bool {
+        let held: usize = self
+            .mail
+            .get(&destination)
+            .map(|mails| mails.iter().map(|mail| mail.payload.len()).sum())
+            .unwrap_or_default();
+        let size = mail.payload.len();
+        if held + size > quota || self.size + size > capacity {
+            return false;
+        }
+        self.size += size;
+        self.mail.entry(destination).or_default().push_back(mail);
+        true
+    }
+    /// The destinations that mail is held for.
+    pub(crate) fn destinations(&self) -> Vec<PublicKey> {
+        self.mail.keys().copied().collect()
+    }
+    /// Returns the mail for `destination` that wasn't sent on within `retry` and
+    /// marks it as sent. It is held until the destination confirms it.
+    pub(crate) fn due(&mut self, destination: &PublicKey, retry: Duration) -> Vec<StoredMail> {
+        let now = Instant::now();
+        let Some(mails) = self.mail.get_mut(destination) else {
+            return vec![];
+        };
+        mails
+            .iter_mut()
+            .filter(|mail| mail.sent.is_none_or(|sent| sent + retry <= now))
+            .map(|mail| {
+                mail.sent = Some(now);
+                mail.clone()
+            })
+            .collect()
+    }
+    /// Drops the mail with `id` for `destination` once the destination confirmed it.
+    /// Returns false if there is no such mail.
+    pub(crate) fn confirm(&mut self, destination: &PublicKey, id: u64) -> bool {
+        let Some(mails) = self.mail.get_mut(destination) else {
+            return false;
+        };
+        let Some(index) = mails.iter().position(|mail| mail.id == id) else {
+            return false;
+        };
+        let mail = mails.remove(index).unwrap();
+        self.size -= mail.payload.len();
+        if mails.is_empty() {
+            self.mail.remove(destination);
+        }
+        true
+    }
+    pub(crate) fn expire(&mut self) {
+        let now = Instant::now();
+        for mails in self.mail.values_mut() {
+            mails.retain(|mail| mail.expires > now);
+        }
+        self.mail.retain(|_, mails| !mails.is_empty());
+        self.size = self
+            .mail
+            .values()
+            .flatten()
+            .map(|mail| mail.payload.len())
+            .sum();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mail(size: usize, ttl: Duration) -> StoredMail {
+        StoredMail {
+            source_key: PublicKey::from([0; 32]),
+            id: size as u64,
+            payload: vec![0; size],
+            signature: Signature::from([0; 64]),
+            expires: Instant::now() + ttl,
+            sent: None,
+        }
+    }
+    #[test]
+    fn quotas() {
+        let ttl = Duration::from_secs(60);
+        let mut mailbox = Mailbox::default();
+        assert!(mailbox.insert(PublicKey::from([1; 32]), mail(6, ttl), 10, 15));
+        assert!(!mailbox.insert(PublicKey::from([1; 32]), mail(6, ttl), 10, 15));
+        assert!(mailbox.insert(PublicKey::from([1; 32]), mail(4, ttl), 10, 15));
+        assert!(mailbox.insert(PublicKey::from([2; 32]), mail(5, ttl), 10, 15));
+        assert!(!mailbox.insert(PublicKey::from([3; 32]), mail(1, ttl), 10, 15));
+        assert_eq!(mailbox.size, 15);
+    }
+    #[test]
+    fn take_and_expire_mail() {
+        let ttl = Duration::from_secs(60);
+        let mut mailbox = Mailbox::default();
+        for key in [3, 1, 2, 1] {
+            assert!(mailbox.insert(PublicKey::from([key; 32]), mail(key as usize, ttl), 10, 100));
+        }
+        assert_eq!(
+            mailbox.destinations(),
+            vec![
+                PublicKey::from([1; 32]),
+                PublicKey::from([2; 32]),
+                PublicKey::from([3; 32])
+            ]
+        );
+        assert!(mailbox.confirm(&PublicKey::from([3; 32]), 3));
+        assert!(!mailbox.confirm(&PublicKey::from([3; 32]), 3));
+        assert_eq!(mailbox.size, 4);
+        assert_eq!(mailbox.destinations().len(), 2);
+
+        assert!(mailbox.insert(PublicKey::from([4; 32]), mail(4, Duration::ZERO), 10, 100));
+        mailbox.expire();
+        assert_eq!(mailbox.size, 4);
+        assert_eq!(mailbox.destinations().len(), 2);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn resend_until_confirmed() {
+        let retry = Duration::from_secs(5);
+        let destination = PublicKey::from([1; 32]);
+        let mut mailbox = Mailbox::default();
+        assert!(mailbox.insert(destination, mail(1, Duration::from_secs(60)), 10, 100));
+        assert_eq!(mailbox.due(&destination, retry).len(), 1);
+        assert!(mailbox.due(&destination, retry).is_empty());
+        assert!(mailbox.insert(destination, mail(2, Duration::from_secs(60)), 10, 100));
+        assert_eq!(mailbox.due(&destination, retry)[0].id, 2);
+        tokio::time::advance(retry).await;
+        assert_eq!(mailbox.due(&destination, retry).len(), 2);
+        assert!(mailbox.confirm(&destination, 1));
+        tokio::time::advance(retry).await;
+        assert_eq!(mailbox.due(&destination, retry).len(), 1);
+        assert!(mailbox.due(&PublicKey::from([2; 32]), retry).is_empty());
+    }
+    #[test]
+    fn seal_for_destination() {
+        let source = SigningKey::from([1; 32]);
+        let destination = SigningKey::from([2; 32]);
+        let other = SigningKey::from([3; 32]);
+        let sealed = seal(b"hi", &source, &PublicKey::from(&destination)).unwrap();
+        assert_eq!(sealed.len(), 2 + SEAL_OVERHEAD);
+        let source_key = PublicKey::from(&source);
+        assert_eq!(
+            open(&sealed, &destination, &source_key),
+            Some(b"hi".to_vec())
+        );
+        assert_eq!(open(&sealed, &other, &source_key), None);
+        assert_eq!(open(&sealed, &destination, &PublicKey::from(&other)), None);
+        let mut changed = sealed.clone();
+        changed[30] ^= 1;
+        assert_eq!(open(&changed, &destination, &source_key), None);
+    }
+}
@@ -1,65 +1,109 @@
 use crate::client::Client;
-use crate::wire_frame::PineconeCodec;
-use ed25519_consensus::{SigningKey, VerificationKey, VerificationKeyBytes};
-use env_logger::WriteStyle;
-use log::{debug, info, warn, LevelFilter};
+use crate::daemon::{accept_peers, connect_tcp, Daemon, DaemonConfig};
+use crate::error::RouterError;
+use crate::key_file::{load_key_file, load_or_create_key_file, save_key_file, KeyFormat};
+use crate::logger::init_logger;
+use crate::public_key::{ParsePublicKeyError, PublicKey};
+use ed25519_consensus::SigningKey;
+use log::{debug, info, warn};
 use rand::thread_rng;
 use std::env::args;
 use std::io::Write;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio::net::TcpListener;
 
+#[cfg(unix)]
+mod admin;
 mod client;
+mod config;
 mod connection;
 mod coordinates;
+mod daemon;
+mod dht;
 mod error;
+mod forward;
 mod frames;
+mod key_file;
+mod logger;
+mod mailbox;
+mod metrics;
+mod names;
+mod probe;
+mod public_key;
+mod pubsub;
+mod queue;
+mod rate_limit;
 mod router;
 mod session;
+mod shutdown;
 mod snek;
+mod status;
 mod tree;
+mod tun;
 mod wait_timer;
 mod wire_frame;
 
 #[tokio::main]
 async fn main() {
-    let _ = env_logger::builder()
-        .write_style(WriteStyle::Always)
-        .format_timestamp(None)
-        .filter_level(LevelFilter::Debug)
-        //.filter_module("rust_pinecone", LevelFilter::Trace)
-        .init();
-
-    let signing_key = SigningKey::new(thread_rng());
-    let verification_key = signing_key.verification_key();
+    let mut args: Vec<String> = args().collect();
+    let program = args.remove(0);
+    #[cfg(unix)]
+    if program.ends_with("pineconectl") {
+        args.insert(0, String::from("ctl"));
+    }
+    match args.first().map(String::as_str) {
+        #[cfg(unix)]
+        Some("ctl") => {
+            if let Err(e) = ctl_command(&args[1..]).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("key") => {
+            let _ = init_logger("debug");
+            if let Err(e) = key_command(&args[1..]) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("daemon") => {
+            if let Err(e) = daemon_command(&args[1..]).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {
+            let _ = init_logger("debug");
+        }
+    }
+    let signing_key = match take_option(&mut args, "--key-file") {
+        Some(path) => load_or_create_key_file(&path)
+            .unwrap_or_else(|e| panic!("Could not load key file {}: {}", path, e)),
+        None => SigningKey::new(thread_rng()),
+    };
+    let public_key = PublicKey::from(&signing_key);
     let (client, mut session_listener) = Client::new(signing_key).await;
-    info!(
-        "Router {}",
-        serde_json::to_string(&VerificationKeyBytes::from(verification_key)).unwrap()
-    );
+    info!("Router {}", public_key);
 
-    let listen_addr = args()
-        .nth(1)
-        .or_else(|| Some(String::from("127.0.0.1:0")))
-        .unwrap();
-    let listener = TcpListener::bind(listen_addr).await.unwrap();
-    info!("Listening on {}", listener.local_addr().unwrap());
-    let client1 = client.clone();
-    tokio::spawn(async move {
-        loop {
-            let (socket, addr) = listener.accept().await.unwrap();
-            let (reader, writer) = socket.into_split();
-            info!("New Client: {:?}", addr);
-            client1
-                .connect_peer(
-                    Box::new(FramedWrite::new(writer, PineconeCodec)),
-                    Box::new(FramedRead::new(reader, PineconeCodec)),
-                )
-                .await
-                .unwrap();
+    let listen_addr = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("127.0.0.1:0"));
+    let listener = match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
         }
-    });
+    };
+    if let Ok(addr) = listener.local_addr() {
+        info!("Listening on {}", addr);
+    }
+    tokio::spawn(accept_peers(client.clone(), listener));
     tokio::spawn(async move {
         loop {
             match session_listener.recv().await {
@@ -82,7 +126,7 @@ async fn main() {
                             let message = String::from_utf8(buf.to_vec())
                                 .or_else::<(), _>(|_| Ok(String::from("Message was not UTF-8")))
                                 .unwrap();
-                            println!("{:?}> {}", session.peer_key(), message);
+                            println!("{}> {}", session.peer_key(), message);
                         }
                     });
                 }
@@ -100,31 +144,23 @@ async fn main() {
         println!("2) Chat with peer");
         println!("3) Disconnect peer");
         println!("4) Stop router");
+        println!("5) Ping node");
+        println!("6) Traceroute to node");
+        println!("7) Register name");
         match read_stdin_line().await.as_str() {
             "1" => {
                 println!("Address of peer:");
                 let connect_addr = read_stdin_line().await;
                 info!("Connecting to {}", connect_addr);
-                let socket = TcpStream::connect(connect_addr).await.unwrap();
-                let (reader, writer) = socket.into_split();
-                match client
-                    .connect_peer(
-                        Box::new(FramedWrite::new(writer, PineconeCodec)),
-                        Box::new(FramedRead::new(reader, PineconeCodec)),
-                    )
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        info!("Could not connect to peer: {:?}", e);
-                    }
+                if let Err(e) = connect_tcp(&client, connect_addr.as_str()).await {
+                    info!("Could not connect to peer: {}", e);
                 }
             }
             "2" => {
-                println!("Target key:");
-                if let Ok(target_key) = read_public_key().await {
+                println!("Target key or name:");
+                if let Ok(target_key) = read_target(&client).await {
                     println!("Entered chat");
-                    let mut send_session = client.dial_send(target_key.to_bytes()).await;
+                    let mut send_session = client.dial_send(target_key).await;
                     loop {
                         print!("> ");
                         std::io::stdout().flush().unwrap();
@@ -135,25 +171,215 @@ async fn main() {
                         send_session.write_all(input.as_bytes()).await.unwrap();
                     }
                 } else {
-                    println!("Invalid key");
+                    println!("Unknown key or name");
                 }
             }
             "3" => {
                 println!("Public Key:");
                 if let Ok(target_key) = read_public_key().await {
-                    client.disconnect_peer(target_key.to_bytes()).await;
+                    client.disconnect_peer(target_key).await;
                 } else {
                     println!("Invalid key");
                 }
             }
             "4" => {
-                client.stop().await;
+                if let Err(e) = client.stop(Duration::from_secs(5)).await {
+                    warn!("Router didn't stop cleanly: {:?}", e);
+                }
                 break;
             }
+            "5" => {
+                println!("Target key or name:");
+                if let Ok(target_key) = read_target(&client).await {
+                    match client.ping(target_key).await {
+                        Ok(rtt) => println!("Answer after {:?}", rtt),
+                        Err(e) => println!("No answer: {:?}", e),
+                    }
+                } else {
+                    println!("Unknown key or name");
+                }
+            }
+            "6" => {
+                println!("Target key or name:");
+                if let Ok(target_key) = read_target(&client).await {
+                    match client.traceroute(target_key).await {
+                        Ok(hops) => {
+                            for (i, hop) in hops.iter().enumerate() {
+                                println!(
+                                    "{}) {} port {} after {:?}",
+                                    i + 1,
+                                    hop.public_key,
+                                    hop.port,
+                                    hop.rtt
+                                );
+                            }
+                        }
+                        Err(e) => println!("No answer: {:?}", e),
+                    }
+                } else {
+                    println!("Unknown key or name");
+                }
+            }
+            "7" => {
+                println!("Name:");
+                let name = read_stdin_line().await;
+                match client
+                    .register_name(name.trim(), Duration::from_secs(60 * 60))
+                    .await
+                {
+                    Ok(_) => println!("Claimed {} for an hour", name.trim()),
+                    Err(e) => println!("Could not claim name: {:?}", e),
+                }
+            }
             _ => {}
         }
     }
 }
+/// Runs the router with the config file given in `args` until SIGTERM or SIGINT.
+async fn daemon_command(args: &[String]) -> std::io::Result<()> {
+    let [path] = args else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Usage: daemon <config file>",
+        ));
+    };
+    let config = DaemonConfig::load(path)?;
+    let _ = init_logger(&config.log_level);
+    let daemon = Daemon::start(config).await?;
+    shutdown_signal().await?;
+    info!("Stopping");
+    if let Err(e) = daemon.stop().await {
+        warn!("Router didn't stop cleanly: {}", e);
+    }
+    Ok(())
+}
+/// Resolves once the process receives SIGTERM or SIGINT.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => Ok(()),
+            result = tokio::signal::ctrl_c() => result,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+#[cfg(unix)]
+const CTL_USAGE: &str = "Usage: ctl [--socket <path>] <command>
+  add-peer <address>       Connects the peer at the address
+  remove-peer <key>        Disconnects a peer
+  peers                    Lists the connected peers
+  tree                     Shows the position in the spanning tree
+  snek                     Shows the neighbours in the SNEK
+  log-level <filters>      Changes the log filters, for example info,rust_pinecone=trace
+
+The binary also acts as ctl when it is called pineconectl.";
+
+/// Sends a command to the admin socket of a running daemon and prints the result.
+#[cfg(unix)]
+async fn ctl_command(args: &[String]) -> std::io::Result<()> {
+    use crate::admin::{admin_request, DEFAULT_ADMIN_SOCKET};
+    use serde_json::{json, Value};
+
+    let mut args = args.to_vec();
+    let socket =
+        take_option(&mut args, "--socket").unwrap_or_else(|| String::from(DEFAULT_ADMIN_SOCKET));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (method, params) = match args.as_slice() {
+        ["add-peer", address] => ("add_peer", json!({ "address": address })),
+        ["remove-peer", key] => {
+            let public_key: PublicKey = key
+                .parse()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            ("remove_peer", json!({ "public_key": public_key }))
+        }
+        ["peers"] => ("list_peers", Value::Null),
+        ["tree"] => ("tree", Value::Null),
+        ["snek"] => ("snek", Value::Null),
+        ["log-level", level] => ("set_log_level", json!({ "level": level })),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                CTL_USAGE,
+            ))
+        }
+    };
+    let result = admin_request(socket, method, params).await?;
+    if !result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
+    Ok(())
+}
+const KEY_USAGE: &str = "Usage:
+  key create <file> [--raw]           Creates a new key file
+  key import <source> <file> [--raw]  Copies a key file or a hex seed into a new key file
+  key export <file> [--raw]           Prints the key as PEM or as hex seed
+  key show <file> [--json]            Prints the public key as hex or JSON";
+
+/// Handles the `key` subcommands that manage key files.
+fn key_command(args: &[String]) -> std::io::Result<()> {
+    let mut args = args.to_vec();
+    let raw = take_flag(&mut args, "--raw");
+    let json = take_flag(&mut args, "--json");
+    let format = if raw { KeyFormat::Raw } else { KeyFormat::Pem };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["create", file] => {
+            let key = SigningKey::new(thread_rng());
+            save_key_file(file, &key, format)?;
+            println!("{}", PublicKey::from(&key));
+        }
+        ["import", source, file] => {
+            let key = match std::fs::read(source) {
+                Ok(bytes) => KeyFormat::decode(&bytes)?,
+                Err(e) => {
+                    let seed = hex::decode(source).map_err(|_| e)?;
+                    KeyFormat::decode(&seed)?
+                }
+            };
+            save_key_file(file, &key, format)?;
+            println!("{}", PublicKey::from(&key));
+        }
+        ["export", file] => {
+            let key = load_key_file(file)?;
+            if raw {
+                println!("{}", hex::encode(key.as_bytes()));
+            } else {
+                print!("{}", String::from_utf8_lossy(&KeyFormat::Pem.encode(&key)));
+            }
+        }
+        ["show", file] => {
+            let public_key = PublicKey::from(&load_key_file(file)?);
+            if json {
+                println!("{}", serde_json::to_string(&public_key).unwrap());
+            } else {
+                println!("{}", public_key);
+            }
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                KEY_USAGE,
+            ))
+        }
+    }
+    Ok(())
+}
+/// Removes `flag` from `args` and returns whether it was there.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+/// Removes `option` and the value following it from `args` and returns the value.
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == option)?;
+    args.remove(index);
+    (index < args.len()).then(|| args.remove(index))
+}
 async fn read_stdin_line() -> String {
     tokio::task::spawn_blocking(|| {
         let mut result = String::new();
@@ -163,7 +389,15 @@ async fn read_stdin_line() -> String {
     .await
     .unwrap()
 }
-async fn read_public_key() -> serde_json::Result<VerificationKey> {
+/// Reads a public key in hex, base32 or base58, or a name that is resolved to one.
+async fn read_target(client: &Client) -> Result<PublicKey, RouterError> {
     let input = read_stdin_line().await;
-    serde_json::from_str(input.as_str())
+    match input.trim().parse() {
+        Ok(public_key) => Ok(public_key),
+        Err(_) => client.resolve(input.trim()).await,
+    }
+}
+/// Reads a public key in hex, base32 or base58.
+async fn read_public_key() -> Result<PublicKey, ParsePublicKeyError> {
+    read_stdin_line().await.trim().parse()
 }
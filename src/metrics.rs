@@ -0,0 +1,63 @@
+use crate::error::RouterError;
+use std::sync::{Arc, Mutex};
+
+/// Counters of events in the router that are worth monitoring.
+///
+/// A snapshot of them can be taken with `metrics` on [`Client`](crate::Client).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Frames that referred to a peer that isn't connected.
+    pub unknown_peer_errors: u64,
+    /// Frames that were dropped because there was no next hop for them.
+    pub no_route_errors: u64,
+    /// Frames for the local client that were dropped because its channel was closed.
+    pub local_channel_closed_errors: u64,
+    /// Frames that couldn't be decoded or were invalid.
+    pub invalid_frame_errors: u64,
+    /// All other errors that occurred while handling frames.
+    pub other_errors: u64,
+    /// Peers that were disconnected because they sent invalid frames.
+    pub misbehaving_peers_disconnected: u64,
+    /// Frames that were dropped because the queue of the peer they were sent to was full.
+    pub congestion_drops: u64,
+    /// Frames that were dropped because the peer that sent them exceeded its rate limit.
+    pub rate_limited_frames: u64,
+    /// Peers that were disconnected because they exceeded their rate limits too often.
+    pub rate_limited_peers_disconnected: u64,
+    /// Path setups that were rejected because their origin already had too many paths.
+    pub path_limit_rejections: u64,
+    /// Frames that were routed over the tree but had to be routed further over SNEK,
+    /// because the coordinates of their destination were outdated.
+    pub tree_routing_fallbacks: u64,
+    /// Frames that were dropped because they reached their hop limit.
+    pub hop_limit_drops: u64,
+    /// Frames that were dropped because they were too long to be encoded.
+    pub oversize_drops: u64,
+    /// Frames for the local client that were dropped because it didn't take them
+    /// fast enough.
+    pub local_queue_drops: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MetricsRecorder {
+    metrics: Arc<Mutex<Metrics>>,
+}
+impl MetricsRecorder {
+    pub(crate) fn record(&self, update: impl FnOnce(&mut Metrics)) {
+        update(&mut self.metrics.lock().unwrap());
+    }
+    pub(crate) fn record_error(&self, error: &RouterError) {
+        self.record(|metrics| match error {
+            RouterError::UnknownPeer => metrics.unknown_peer_errors += 1,
+            RouterError::NoRoute => metrics.no_route_errors += 1,
+            RouterError::LocalChannelClosed => metrics.local_channel_closed_errors += 1,
+            RouterError::MissingSignature
+            | RouterError::InvalidFrame
+            | RouterError::DecodingError(_) => metrics.invalid_frame_errors += 1,
+            _ => metrics.other_errors += 1,
+        });
+    }
+    pub(crate) fn snapshot(&self) -> Metrics {
+        self.metrics.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,271 @@
+use crate::dht::{DhtPolicy, DhtRecord};
+use crate::error::RouterError;
+use crate::public_key::PublicKey;
+#[cfg(doc)]
+use crate::{Client, RouterConfig};
+use ed25519_consensus::SigningKey;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Prefix of the DHT keys under which the records of names are stored.
+const NAME_KEY_PREFIX: &[u8] = b"name:";
+/// Longest name in bytes, like a DNS label.
+pub(crate) const MAX_NAME_LENGTH: usize = 63;
+
+/// Checks that `name` is 1 to 63 lowercase ASCII letters, digits and dashes that
+/// doesn't start or end with a dash, so that it can be used as a DNS label.
+pub(crate) fn check_name(name: &str) -> Result<(), RouterError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-');
+    if !valid {
+        return Err(RouterError::EncodingError("Invalid name"));
+    }
+    Ok(())
+}
+/// The DHT key of the records of `name`.
+pub(crate) fn name_key(name: &str) -> Vec<u8> {
+    [NAME_KEY_PREFIX, name.as_bytes()].concat()
+}
+
+/// A signed claim that a name belongs to the node with a public key.
+///
+/// Names are claimed by the nodes themselves with [`Client::register_name`]. The first
+/// node that claims a name keeps it as long as it registers it again before its
+/// record expires. Records of the authorities in [`RouterConfig::name_authorities`]
+/// take precedence over such claims and can give names to any key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameRecord {
+    pub(crate) name: String,
+    pub(crate) record: DhtRecord,
+}
+#[allow(unused)]
+impl NameRecord {
+    /// Signs a record that gives `name` to `public_key` for `ttl`. The record can be
+    /// published by any node with [`Client::publish_name`].
+    pub fn new(
+        name: &str,
+        public_key: PublicKey,
+        ttl: Duration,
+        signing_key: &SigningKey,
+    ) -> Result<Self, RouterError> {
+        check_name(name)?;
+        let record = DhtRecord::new(
+            name_key(name),
+            public_key.as_bytes().to_vec(),
+            ttl,
+            signing_key,
+        );
+        Ok(Self {
+            name: String::from(name),
+            record,
+        })
+    }
+    /// Reads the name record out of a DHT record. Returns `None` if it isn't one.
+    pub(crate) fn from_dht(record: DhtRecord) -> Option<Self> {
+        let name = std::str::from_utf8(record.key.strip_prefix(NAME_KEY_PREFIX)?).ok()?;
+        check_name(name).ok()?;
+        if record.value.len() != 32 {
+            return None;
+        }
+        Some(Self {
+            name: String::from(name),
+            record,
+        })
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// The key that the name belongs to.
+    pub fn public_key(&self) -> PublicKey {
+        let key: [u8; 32] = self.record.value.as_slice().try_into().unwrap();
+        PublicKey::from(key)
+    }
+    /// The node or authority that signed the record.
+    pub fn signer(&self) -> PublicKey {
+        self.record.writer
+    }
+    /// Returns true if the node that the name belongs to signed the record itself.
+    pub fn is_self_signed(&self) -> bool {
+        self.public_key() == self.signer()
+    }
+    /// Seconds since the Unix epoch after which the record is dropped.
+    pub fn expires(&self) -> u64 {
+        self.record.expires
+    }
+}
+
+/// The policy of the records of names: a name belongs to the first node that claims
+/// it, so the home node of the name refuses later claims of other nodes.
+pub(crate) const NAME_POLICY: DhtPolicy = DhtPolicy {
+    prefix: NAME_KEY_PREFIX,
+    admit: is_first_claim,
+};
+/// Returns false if `record` claims a name that another node claimed for itself
+/// before.
+fn is_first_claim(stored: &[DhtRecord], record: &DhtRecord) -> bool {
+    let is_claim = |record: &DhtRecord| {
+        NameRecord::from_dht(record.clone()).is_some_and(|name| name.is_self_signed())
+    };
+    !is_claim(record)
+        || stored
+            .iter()
+            .all(|other| other.writer == record.writer || !is_claim(other))
+}
+/// Picks the record that decides who `name` belongs to out of the records of its
+/// DHT key: the one of the first authority in `authorities` that has a record, or
+/// else the claim of a node for itself that the home node saw first.
+///
+/// `records` have to be in the order of the answer of the home node. The sequence
+/// numbers of the claims don't matter, as their writers choose them.
+pub(crate) fn select_record(
+    records: Vec<DhtRecord>,
+    name: &str,
+    authorities: &[PublicKey],
+) -> Option<NameRecord> {
+    let records: Vec<_> = records
+        .into_iter()
+        .filter_map(NameRecord::from_dht)
+        .filter(|record| record.name == name)
+        .collect();
+    for authority in authorities {
+        if let Some(record) = records.iter().find(|record| record.signer() == *authority) {
+            return Some(record.clone());
+        }
+    }
+    records.into_iter().find(NameRecord::is_self_signed)
+}
+
+/// Names that were resolved before and names that are set locally.
+#[derive(Debug, Default)]
+pub(crate) struct NameCache {
+    pub(crate) hosts: HashMap<String, PublicKey>,
+    /// Resolved names with the time they expire.
+    pub(crate) resolved: HashMap<String, (PublicKey, Instant)>,
+}
+impl NameCache {
+    pub(crate) fn get(&mut self, name: &str) -> Option<PublicKey> {
+        if let Some(public_key) = self.hosts.get(name) {
+            return Some(*public_key);
+        }
+        match self.resolved.get(name) {
+            Some((public_key, expires)) if *expires > Instant::now() => Some(*public_key),
+            Some(_) => {
+                self.resolved.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Reads names from a file in the style of `/etc/hosts`. Every line holds a public key
+/// followed by the names that belong to it, separated by whitespace. Everything
+/// after a `#` is a comment.
+pub fn load_hosts_file(path: impl AsRef<Path>) -> Result<HashMap<String, PublicKey>, Error> {
+    parse_hosts(&std::fs::read_to_string(path)?)
+}
+fn parse_hosts(hosts: &str) -> Result<HashMap<String, PublicKey>, Error> {
+    let mut names = HashMap::new();
+    for (number, line) in hosts.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(key) = fields.next() else {
+            continue;
+        };
+        let invalid = |message: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Line {}: {}", number + 1, message),
+            )
+        };
+        let public_key: PublicKey = key.parse().map_err(|_| invalid("Not a public key"))?;
+        let mut empty = true;
+        for name in fields {
+            check_name(name).map_err(|_| invalid("Invalid name"))?;
+            names.insert(String::from(name), public_key);
+            empty = false;
+        }
+        if empty {
+            return Err(invalid("No names for the key"));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_names() {
+        assert!(check_name("alice").is_ok());
+        assert!(check_name("node-7").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("Alice").is_err());
+        assert!(check_name("-alice").is_err());
+        assert!(check_name("alice.bob").is_err());
+        assert!(check_name(&"a".repeat(64)).is_err());
+    }
+    #[test]
+    fn select_owner() {
+        let alice = SigningKey::from([1; 32]);
+        let bob = SigningKey::from([2; 32]);
+        let authority = SigningKey::from([3; 32]);
+        let ttl = Duration::from_secs(60);
+        let alice_claim = NameRecord::new("alice", PublicKey::from(&alice), ttl, &alice).unwrap();
+        let bob_claim = NameRecord::new("alice", PublicKey::from(&bob), ttl, &bob).unwrap();
+        let forged = NameRecord::new("alice", PublicKey::from(&alice), ttl, &bob).unwrap();
+        let granted = NameRecord::new("alice", PublicKey::from(&bob), ttl, &authority).unwrap();
+        assert!(alice_claim.is_self_signed());
+        assert!(!forged.is_self_signed());
+
+        // Only the first node that claims a name for itself gets it.
+        let stored = vec![alice_claim.record.clone()];
+        assert!(!is_first_claim(&stored, &bob_claim.record));
+        assert!(is_first_claim(&stored, &alice_claim.record));
+        assert!(is_first_claim(&stored, &granted.record));
+        assert!(is_first_claim(&[], &bob_claim.record));
+
+        // A later claim with a lower sequence number doesn't take the name.
+        let mut bob_claim = bob_claim.record;
+        bob_claim.sequence = 0;
+        let records = vec![
+            forged.record.clone(),
+            granted.record.clone(),
+            alice_claim.record.clone(),
+            bob_claim,
+        ];
+        let selected = select_record(records.clone(), "alice", &[]).unwrap();
+        assert_eq!(selected.public_key(), PublicKey::from(&alice));
+        let authorities = [PublicKey::from([4; 32]), PublicKey::from(&authority)];
+        let selected = select_record(records.clone(), "alice", &authorities).unwrap();
+        assert_eq!(selected.public_key(), PublicKey::from(&bob));
+        assert!(select_record(records, "bob", &[]).is_none());
+        assert!(select_record(vec![forged.record], "alice", &[]).is_none());
+    }
+    #[test]
+    fn parse_hosts_file() {
+        let alice = PublicKey::from([1; 32]);
+        let bob = PublicKey::from([2; 32]);
+        let hosts = format!(
+            "# Nodes of the office\n{} alice alice-laptop\n\n  {}\tbob # The printer\n",
+            alice, bob
+        );
+        let names = parse_hosts(&hosts).unwrap();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names["alice"], alice);
+        assert_eq!(names["alice-laptop"], alice);
+        assert_eq!(names["bob"], bob);
+        assert!(parse_hosts("nokey alice").is_err());
+        assert!(parse_hosts(&format!("{}", alice)).is_err());
+        assert!(parse_hosts(&format!("{} Alice", alice)).is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use crate::public_key::PublicKey;
+use crate::router::Port;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+
+/// A node that answered a ping or traceroute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hop {
+    pub public_key: PublicKey,
+    /// The port through which the node forwarded the probe. This is 0 for the
+    /// destination of the probe.
+    pub port: Port,
+    /// Time from sending the probe until the answer of this node arrived.
+    pub rtt: Duration,
+}
+
+/// An answer to a probe that was sent by this node.
+#[derive(Debug)]
+pub(crate) struct ProbeAnswer {
+    /// Number of hops between this node and the node that answered.
+    pub(crate) distance: u8,
+    pub(crate) hop: Hop,
+    /// Whether the answer came from the destination of the probe.
+    pub(crate) reached: bool,
+}
+
+/// A probe that this node sent and is waiting for answers to.
+#[derive(Debug)]
+pub(crate) struct PendingProbe {
+    pub(crate) sent: Instant,
+    pub(crate) answers: UnboundedSender<ProbeAnswer>,
+}
@@ -0,0 +1,146 @@
+use data_encoding::BASE32_NOPAD;
+use ed25519_consensus::{SigningKey, VerificationKey, VerificationKeyBytes};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+/// The public key of a node, which is also its address in the network.
+///
+/// It is displayed as hex and can be parsed from hex, base32 or base58.
+/// Keys are ordered by their bytes, which is the order of the SNEK keyspace.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PublicKey([u8; 32]);
+impl PublicKey {
+    pub const fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+    /// Lowercase base32 without padding as in RFC 4648.
+    pub fn to_base32(self) -> String {
+        BASE32_NOPAD.encode(&self.0).to_lowercase()
+    }
+    pub fn to_base58(self) -> String {
+        bs58::encode(&self.0).into_string()
+    }
+}
+impl From<[u8; 32]> for PublicKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+impl From<PublicKey> for [u8; 32] {
+    fn from(key: PublicKey) -> Self {
+        key.0
+    }
+}
+impl From<VerificationKey> for PublicKey {
+    fn from(key: VerificationKey) -> Self {
+        Self(key.to_bytes())
+    }
+}
+impl From<VerificationKeyBytes> for PublicKey {
+    fn from(key: VerificationKeyBytes) -> Self {
+        Self(key.to_bytes())
+    }
+}
+impl From<&SigningKey> for PublicKey {
+    fn from(key: &SigningKey) -> Self {
+        key.verification_key().into()
+    }
+}
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+/// The error when a string isn't a [`PublicKey`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsePublicKeyError;
+impl Display for ParsePublicKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Not a public key in hex, base32 or base58")
+    }
+}
+impl std::error::Error for ParsePublicKeyError {}
+
+impl FromStr for PublicKey {
+    type Err = ParsePublicKeyError;
+
+    /// The encoding is told apart by the length of `s`: 64 characters are hex,
+    /// 52 are base32 and everything else is tried as base58.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = match s.len() {
+            64 => hex::decode(s).map_err(|_| ParsePublicKeyError)?,
+            52 => BASE32_NOPAD
+                .decode(s.to_uppercase().as_bytes())
+                .map_err(|_| ParsePublicKeyError)?,
+            _ => bs58::decode(s)
+                .into_vec()
+                .map_err(|_| ParsePublicKeyError)?,
+        };
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| ParsePublicKeyError)?;
+        Ok(Self(bytes))
+    }
+}
+
+/// Keys are serialized as hex strings in human readable formats like JSON and
+/// as 32 bytes otherwise.
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(D::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        let key = PublicKey::from(&SigningKey::from([1; 32]));
+        for encoded in [key.to_string(), key.to_base32(), key.to_base58()] {
+            assert_eq!(encoded.parse::<PublicKey>(), Ok(key));
+        }
+        assert_eq!(key.to_string().to_uppercase().parse::<PublicKey>(), Ok(key));
+        assert_eq!(key.to_base32().to_uppercase().parse::<PublicKey>(), Ok(key));
+        assert!("".parse::<PublicKey>().is_err());
+        assert!("0g".repeat(32).parse::<PublicKey>().is_err());
+        assert!(bs58::encode([1; 31])
+            .into_string()
+            .parse::<PublicKey>()
+            .is_err());
+    }
+    #[test]
+    fn serde() {
+        let key = PublicKey::from([0xab; 32]);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(json, format!("\"{}\"", "ab".repeat(32)));
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), key);
+    }
+}
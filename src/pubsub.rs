@@ -0,0 +1,44 @@
+use crate::public_key::PublicKey;
+#[cfg(doc)]
+use crate::Client;
+use tokio::sync::mpsc::Receiver;
+
+/// Number of publications that a [`Subscription`] holds until they are received.
+/// Further publications to the subscription are dropped.
+pub(crate) const SUBSCRIPTION_BUFFER: usize = 100;
+/// Largest topic name in bytes.
+pub(crate) const MAX_TOPIC_LENGTH: usize = u8::MAX as usize;
+/// Largest size of the topic and payload of a publication together. They have to fit
+/// into a single frame next to its header, the source key, the id, the signature and
+/// the topic length.
+pub(crate) const MAX_PUBLICATION_SIZE: usize = u16::MAX as usize - (10 + 32 + 8 + 64 + 1);
+
+/// Data that a node published to a topic with [`Client::publish`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publication {
+    /// The node that published the data.
+    pub source_key: PublicKey,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+/// Receives what other nodes publish to a topic. This is being given out by
+/// [`Client::subscribe`].
+///
+/// Publications are flooded over the spanning tree and may be lost like any other
+/// traffic. They are not delivered to the node that published them.
+#[derive(Debug)]
+pub struct Subscription {
+    pub(crate) topic: String,
+    pub(crate) publications: Receiver<Publication>,
+}
+#[allow(unused)]
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+    /// Receives the next publication. Returns `None` once the router was stopped.
+    pub async fn recv(&mut self) -> Option<Publication> {
+        self.publications.recv().await
+    }
+}
@@ -0,0 +1,424 @@
+use crate::config::DropPolicy;
+use crate::coordinates::Coordinates;
+use crate::error::RouterError;
+use crate::frames::{Frame, TrafficClass};
+use crate::public_key::PublicKey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Bytes a flow of [`TrafficClass::Bulk`] may send per round. Other classes get
+/// a multiple of it.
+const QUANTUM: usize = 1024;
+
+/// Creates a bounded queue of frames for one peer. Protocol frames are always
+/// handed out before traffic. Traffic is handed out with deficit round robin
+/// over its flows, so every source gets its share no matter how much others send.
+///
+/// Unlike a channel, pushing never waits. If the queue is full a frame is
+/// dropped according to `drop_policy`.
+pub(crate) fn peer_queue(capacity: usize, drop_policy: DropPolicy) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            protocol: VecDeque::new(),
+            traffic: FairQueue::default(),
+            capacity,
+            drop_policy,
+            closed: false,
+        }),
+        notify: Notify::new(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+struct Queue {
+    protocol: VecDeque<Frame>,
+    traffic: FairQueue,
+    capacity: usize,
+    drop_policy: DropPolicy,
+    /// Set once either side is dropped.
+    closed: bool,
+}
+impl Queue {
+    fn len(&self) -> usize {
+        self.protocol.len() + self.traffic.len
+    }
+    fn is_protocol(frame: &Frame) -> bool {
+        matches!(
+            frame,
+            Frame::TreeAnnouncement(_)
+                | Frame::SnekBootstrap(_)
+                | Frame::SnekBootstrapACK(_)
+                | Frame::SnekSetup(_)
+                | Frame::SnekSetupACK(_)
+                | Frame::SnekTeardown(_)
+                | Frame::CoordinatesRequest(_)
+                | Frame::CoordinatesResponse(_)
+                | Frame::Probe(_)
+                | Frame::ProbeReply(_)
+                | Frame::MailReply(_)
+        )
+    }
+    fn pop(&mut self) -> Option<Frame> {
+        self.protocol.pop_front().or_else(|| self.traffic.pop())
+    }
+}
+
+/// Traffic of one source in one [`TrafficClass`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum FlowId {
+    Snek(PublicKey, TrafficClass),
+    Tree(Coordinates, TrafficClass),
+    /// Records and requests of the DHT, which get the share of bulk traffic.
+    Dht(PublicKey),
+    /// Mail, which gets the share of bulk traffic too.
+    Mail(PublicKey),
+}
+impl FlowId {
+    fn of(frame: &Frame) -> Self {
+        match frame {
+            Frame::SnekRouted(packet) => FlowId::Snek(packet.source_key, packet.traffic_class),
+            Frame::HybridRouted(packet) => FlowId::Snek(packet.source_key, packet.traffic_class),
+            Frame::Broadcast(packet) => FlowId::Snek(packet.source_key, packet.traffic_class),
+            Frame::TreeRouted(packet) => {
+                FlowId::Tree(packet.source_coordinates.clone(), packet.traffic_class)
+            }
+            Frame::DhtPut(put) => FlowId::Dht(put.source_key),
+            Frame::DhtGet(get) => FlowId::Dht(get.source_key),
+            Frame::DhtGetResponse(response) => FlowId::Dht(response.source_key),
+            Frame::Mail(mail) => FlowId::Mail(mail.source_key),
+            _ => unreachable!("Protocol frames don't belong to a flow"),
+        }
+    }
+    fn quantum(&self) -> usize {
+        let class = match self {
+            FlowId::Snek(_, class) | FlowId::Tree(_, class) => class,
+            FlowId::Dht(_) | FlowId::Mail(_) => &TrafficClass::Bulk,
+        };
+        QUANTUM
+            * match class {
+                TrafficClass::Bulk => 1,
+                TrafficClass::BestEffort => 2,
+                TrafficClass::Interactive => 4,
+            }
+    }
+}
+struct Flow {
+    frames: VecDeque<Frame>,
+    /// Bytes the flow may still send in this round.
+    deficit: usize,
+}
+/// Deficit round robin over the flows of traffic.
+#[derive(Default)]
+struct FairQueue {
+    flows: HashMap<FlowId, Flow>,
+    /// Flows that have frames queued, in the order they are served.
+    active: VecDeque<FlowId>,
+    len: usize,
+}
+impl FairQueue {
+    fn cost(frame: &Frame) -> usize {
+        match frame {
+            Frame::SnekRouted(packet) => packet.payload.len() + 1,
+            Frame::TreeRouted(packet) => packet.payload.len() + 1,
+            Frame::HybridRouted(packet) => packet.payload.len() + 1,
+            Frame::Broadcast(packet) => packet.payload.len() + 1,
+            Frame::DhtPut(_) | Frame::DhtGet(_) | Frame::DhtGetResponse(_) | Frame::Mail(_) => {
+                frame.encoded_len()
+            }
+            _ => 1,
+        }
+    }
+    fn push(&mut self, frame: Frame) {
+        let id = FlowId::of(&frame);
+        let flow = self.flows.entry(id.clone()).or_insert_with(|| Flow {
+            frames: VecDeque::new(),
+            deficit: id.quantum(),
+        });
+        if flow.frames.is_empty() {
+            self.active.push_back(id);
+        }
+        flow.frames.push_back(frame);
+        self.len += 1;
+    }
+    fn pop(&mut self) -> Option<Frame> {
+        loop {
+            let id = self.active.front()?;
+            let flow = self.flows.get_mut(id).unwrap();
+            let cost = Self::cost(flow.frames.front().unwrap());
+            if flow.deficit < cost {
+                // The flow used up its share of this round.
+                flow.deficit += id.quantum();
+                self.active.rotate_left(1);
+                continue;
+            }
+            flow.deficit -= cost;
+            let frame = flow.frames.pop_front();
+            if flow.frames.is_empty() {
+                self.flows.remove(id);
+                self.active.pop_front();
+            }
+            self.len -= 1;
+            return frame;
+        }
+    }
+    /// Drops a frame of the flow with the most queued frames to make room for
+    /// `incoming`. Returns `None` if `incoming` should be dropped instead.
+    fn drop_for(&mut self, incoming: &Frame, drop_policy: DropPolicy) -> Option<Frame> {
+        let (mut id, longest) = self
+            .flows
+            .iter()
+            .map(|(id, flow)| (id.clone(), flow.frames.len()))
+            .max_by_key(|(_, len)| *len)?;
+        if !Queue::is_protocol(incoming) {
+            let incoming_id = FlowId::of(incoming);
+            let queued = self
+                .flows
+                .get(&incoming_id)
+                .map_or(0, |flow| flow.frames.len());
+            if queued + 1 >= longest {
+                // The flow of the incoming frame is the one that takes the most room.
+                if drop_policy == DropPolicy::DropNewest || queued == 0 {
+                    return None;
+                }
+                id = incoming_id;
+            }
+        }
+        let flow = self.flows.get_mut(&id).unwrap();
+        let dropped = match drop_policy {
+            DropPolicy::DropNewest => flow.frames.pop_back(),
+            DropPolicy::DropOldest => flow.frames.pop_front(),
+        };
+        if flow.frames.is_empty() {
+            self.flows.remove(&id);
+            self.active.retain(|active| active != &id);
+        }
+        self.len -= 1;
+        dropped
+    }
+}
+
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+impl QueueSender {
+    /// Queues `frame` and returns the frame that was dropped to make room for it,
+    /// or `frame` itself if it couldn't be queued. Fails if the receiver was dropped.
+    pub(crate) fn push(&self, frame: Frame) -> Result<Option<Frame>, RouterError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return Err(RouterError::ConnectionClosed);
+        }
+        let mut dropped = None;
+        if queue.len() >= queue.capacity {
+            let drop_policy = queue.drop_policy;
+            match queue.traffic.drop_for(&frame, drop_policy) {
+                Some(frame) => dropped = Some(frame),
+                None => return Ok(Some(frame)),
+            }
+        }
+        if Queue::is_protocol(&frame) {
+            queue.protocol.push_back(frame);
+        } else {
+            queue.traffic.push(frame);
+        }
+        drop(queue);
+        self.shared.notify.notify_one();
+        Ok(dropped)
+    }
+}
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+impl QueueReceiver {
+    /// Waits for the next frame. Returns `None` once the sender was dropped and
+    /// all queued frames were taken.
+    pub(crate) async fn pop(&mut self) -> Option<Frame> {
+        loop {
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(frame) = queue.pop() {
+                    return Some(frame);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+    /// Takes the next frame if there is one queued already.
+    pub(crate) fn try_pop(&mut self) -> Option<Frame> {
+        self.shared.queue.lock().unwrap().pop()
+    }
+}
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frames::{DhtGet, MailPacket, SnekPacket, SnekTeardown, DEFAULT_HOP_LIMIT};
+    use crate::tree::Root;
+
+    fn traffic(id: u8) -> Frame {
+        traffic_from(0, TrafficClass::BestEffort, id)
+    }
+    fn traffic_from(source: u8, traffic_class: TrafficClass, id: u8) -> Frame {
+        Frame::SnekRouted(SnekPacket {
+            destination_key: PublicKey::from([0; 32]),
+            source_key: PublicKey::from([source; 32]),
+            traffic_class,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            payload: vec![id; 100],
+        })
+    }
+    fn protocol() -> Frame {
+        Frame::SnekTeardown(SnekTeardown {
+            root: Root {
+                public_key: PublicKey::from([0; 32]),
+                sequence_number: 0,
+            },
+            destination_key: PublicKey::from([0; 32]),
+            path_id: 0,
+        })
+    }
+    fn payload(frame: Option<Frame>) -> u8 {
+        match frame {
+            Some(Frame::SnekRouted(packet)) => packet.payload[0],
+            frame => panic!("Expected traffic but got {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_frames_first() {
+        let (sender, mut receiver) = peer_queue(10, DropPolicy::DropNewest);
+        sender.push(traffic(1)).unwrap();
+        sender.push(protocol()).unwrap();
+        assert!(matches!(receiver.pop().await, Some(Frame::SnekTeardown(_))));
+        assert_eq!(payload(receiver.pop().await), 1);
+        drop(sender);
+        assert!(receiver.pop().await.is_none());
+    }
+    #[tokio::test]
+    async fn drop_newest() {
+        let (sender, mut receiver) = peer_queue(2, DropPolicy::DropNewest);
+        sender.push(traffic(1)).unwrap();
+        sender.push(traffic(2)).unwrap();
+        assert_eq!(payload(sender.push(traffic(3)).unwrap()), 3);
+        // Protocol frames replace traffic.
+        assert_eq!(payload(sender.push(protocol()).unwrap()), 2);
+        assert!(matches!(receiver.pop().await, Some(Frame::SnekTeardown(_))));
+        assert_eq!(payload(receiver.try_pop()), 1);
+        assert!(receiver.try_pop().is_none());
+    }
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (sender, mut receiver) = peer_queue(2, DropPolicy::DropOldest);
+        sender.push(traffic(1)).unwrap();
+        sender.push(traffic(2)).unwrap();
+        assert_eq!(payload(sender.push(traffic(3)).unwrap()), 1);
+        assert_eq!(payload(receiver.pop().await), 2);
+        assert_eq!(payload(receiver.pop().await), 3);
+        drop(receiver);
+        assert!(matches!(
+            sender.push(traffic(4)),
+            Err(RouterError::ConnectionClosed)
+        ));
+    }
+    #[tokio::test]
+    async fn sources_share_the_queue_fairly() {
+        let (sender, mut receiver) = peer_queue(100, DropPolicy::DropNewest);
+        for id in 0..50 {
+            sender
+                .push(traffic_from(1, TrafficClass::BestEffort, id))
+                .unwrap();
+        }
+        sender
+            .push(traffic_from(2, TrafficClass::BestEffort, 100))
+            .unwrap();
+        // The frame of the second source doesn't have to wait for all frames of the first.
+        let position = (0..51)
+            .position(|_| payload(receiver.try_pop()) == 100)
+            .unwrap();
+        assert!(position < 25);
+    }
+    #[tokio::test]
+    async fn drop_from_heaviest_source() {
+        let (sender, mut receiver) = peer_queue(3, DropPolicy::DropNewest);
+        sender
+            .push(traffic_from(1, TrafficClass::BestEffort, 1))
+            .unwrap();
+        sender
+            .push(traffic_from(1, TrafficClass::BestEffort, 2))
+            .unwrap();
+        sender
+            .push(traffic_from(2, TrafficClass::BestEffort, 3))
+            .unwrap();
+        assert_eq!(
+            payload(
+                sender
+                    .push(traffic_from(3, TrafficClass::BestEffort, 4))
+                    .unwrap()
+            ),
+            2
+        );
+        let mut received: Vec<u8> = (0..3).map(|_| payload(receiver.try_pop())).collect();
+        received.sort();
+        assert_eq!(received, vec![1, 3, 4]);
+    }
+    #[tokio::test]
+    async fn classes_are_weighted() {
+        let (sender, mut receiver) = peer_queue(200, DropPolicy::DropNewest);
+        for _ in 0..100 {
+            sender.push(traffic_from(1, TrafficClass::Bulk, 1)).unwrap();
+            sender
+                .push(traffic_from(2, TrafficClass::Interactive, 2))
+                .unwrap();
+        }
+        let interactive = (0..100)
+            .filter(|_| payload(receiver.try_pop()) == 2)
+            .count();
+        // Interactive traffic gets four times the share of bulk traffic.
+        assert_eq!(interactive, 80);
+    }
+    #[tokio::test]
+    async fn dht_frames_and_mail_are_traffic() {
+        let (sender, mut receiver) = peer_queue(10, DropPolicy::DropNewest);
+        sender.push(traffic(1)).unwrap();
+        sender
+            .push(Frame::DhtGet(DhtGet::new(
+                PublicKey::from([0; 32]),
+                0,
+                vec![0; 100],
+                &ed25519_consensus::SigningKey::from([2; 32]),
+            )))
+            .unwrap();
+        let key = ed25519_consensus::SigningKey::from([3; 32]);
+        let mail = MailPacket::new(PublicKey::from([0; 32]), 0, vec![0; 100], &key);
+        sender.push(Frame::Mail(mail)).unwrap();
+        // Unlike protocol frames they wait for their turn.
+        assert_eq!(payload(receiver.try_pop()), 1);
+        assert!(matches!(receiver.try_pop(), Some(Frame::DhtGet(_))));
+        assert!(matches!(receiver.try_pop(), Some(Frame::Mail(_))));
+    }
+}
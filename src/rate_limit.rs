@@ -0,0 +1,168 @@
+use crate::config::{RateLimit, RateLimits};
+use crate::frames::Frame;
+use tokio::time::Instant;
+
+/// A token bucket that holds up to `burst` tokens and is refilled with
+/// `per_second` tokens every second.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+    /// Takes a token if there is one.
+    pub(crate) fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens =
+            (self.tokens + elapsed * self.limit.per_second as f64).min(self.limit.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with a frame that was received from a peer.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Accept,
+    Drop,
+    /// The peer exceeded its limits too often.
+    Disconnect,
+}
+
+/// The rate limits of frames received from one peer.
+#[derive(Debug)]
+pub(crate) struct PeerLimiter {
+    tree_announcement: TokenBucket,
+    snek_bootstrap: TokenBucket,
+    snek_setup: TokenBucket,
+    other_protocol: TokenBucket,
+    dht: TokenBucket,
+    mail: TokenBucket,
+    broadcast: TokenBucket,
+    traffic: Option<TokenBucket>,
+    /// Frames that may still be dropped before the peer gets disconnected.
+    violations: TokenBucket,
+}
+impl PeerLimiter {
+    pub(crate) fn new(limits: &RateLimits) -> Self {
+        Self {
+            tree_announcement: TokenBucket::new(limits.tree_announcement),
+            snek_bootstrap: TokenBucket::new(limits.snek_bootstrap),
+            snek_setup: TokenBucket::new(limits.snek_setup),
+            other_protocol: TokenBucket::new(limits.other_protocol),
+            dht: TokenBucket::new(limits.dht),
+            mail: TokenBucket::new(limits.mail),
+            broadcast: TokenBucket::new(limits.broadcast),
+            traffic: limits.traffic.map(TokenBucket::new),
+            violations: TokenBucket::new(limits.violations),
+        }
+    }
+    pub(crate) fn check(&mut self, frame: &Frame) -> Verdict {
+        let bucket = match frame {
+            Frame::TreeAnnouncement(_) => &mut self.tree_announcement,
+            Frame::SnekBootstrap(_) => &mut self.snek_bootstrap,
+            Frame::SnekSetup(_) => &mut self.snek_setup,
+            Frame::SnekBootstrapACK(_)
+            | Frame::SnekSetupACK(_)
+            | Frame::SnekTeardown(_)
+            | Frame::CoordinatesRequest(_)
+            | Frame::CoordinatesResponse(_)
+            | Frame::Probe(_)
+            | Frame::ProbeReply(_) => &mut self.other_protocol,
+            Frame::DhtPut(_) | Frame::DhtGet(_) | Frame::DhtGetResponse(_) => &mut self.dht,
+            Frame::Mail(_) | Frame::MailReply(_) => &mut self.mail,
+            Frame::Broadcast(_) => &mut self.broadcast,
+            Frame::TreeRouted(_) | Frame::SnekRouted(_) | Frame::HybridRouted(_) => {
+                match &mut self.traffic {
+                    Some(bucket) => bucket,
+                    None => return Verdict::Accept,
+                }
+            }
+        };
+        if bucket.try_take() {
+            Verdict::Accept
+        } else if self.violations.try_take() {
+            Verdict::Drop
+        } else {
+            Verdict::Disconnect
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::public_key::PublicKey;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 2,
+            burst: 3,
+        });
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn disconnect_after_violations() {
+        let limit = RateLimit {
+            per_second: 1,
+            burst: 1,
+        };
+        let mut limiter = PeerLimiter::new(&RateLimits {
+            tree_announcement: limit,
+            snek_bootstrap: limit,
+            snek_setup: limit,
+            other_protocol: limit,
+            dht: limit,
+            mail: limit,
+            broadcast: limit,
+            traffic: None,
+            violations: RateLimit {
+                per_second: 1,
+                burst: 2,
+            },
+        });
+        let frame = Frame::SnekRouted(crate::frames::SnekPacket {
+            destination_key: PublicKey::from([0; 32]),
+            source_key: PublicKey::from([0; 32]),
+            traffic_class: Default::default(),
+            hop_limit: crate::frames::DEFAULT_HOP_LIMIT,
+            payload: vec![],
+        });
+        assert!((0..10).all(|_| limiter.check(&frame) == Verdict::Accept));
+        let teardown = Frame::SnekTeardown(crate::frames::SnekTeardown {
+            root: crate::tree::Root {
+                public_key: PublicKey::from([0; 32]),
+                sequence_number: 0,
+            },
+            destination_key: PublicKey::from([0; 32]),
+            path_id: 0,
+        });
+        assert_eq!(limiter.check(&teardown), Verdict::Accept);
+        assert_eq!(limiter.check(&teardown), Verdict::Drop);
+        assert_eq!(limiter.check(&teardown), Verdict::Drop);
+        assert_eq!(limiter.check(&teardown), Verdict::Disconnect);
+    }
+}
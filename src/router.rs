@@ -1,31 +1,56 @@
-use crate::coordinates::Coordinates;
+use crate::config::RouterConfig;
+use crate::coordinates::{CachedCoordinates, Coordinates, PendingCoordinates};
+use crate::dht::{
+    dht_position, unix_time, DhtPolicy, DhtRecord, DhtStore, DHT_CLOCK_SKEW, MAX_DHT_KEY_LENGTH,
+    MAX_DHT_VALUE_SIZE,
+};
 use crate::error::RouterError;
 use crate::frames::TreeAnnouncement;
 use crate::frames::{
-    Frame, SnekBootstrap, SnekBootstrapAck, SnekSetup, SnekSetupAck, SnekTeardown,
+    Broadcast, CoordinatesRequest, CoordinatesResponse, DhtGet, DhtGetResponse, DhtPut, Frame,
+    HybridPacket, MailPacket, MailReply, Probe, ProbeReply, SnekBootstrap, SnekBootstrapAck,
+    SnekPacket, SnekSetup, SnekSetupAck, SnekTeardown, DEFAULT_HOP_LIMIT,
+};
+use crate::mailbox::{
+    open, seal, Inbox, Mail, MailStatus, Mailbox, StoredMail, INBOX_BUFFER, MAX_MAIL_SIZE,
 };
+use crate::metrics::{Metrics, MetricsRecorder};
+use crate::names::NAME_POLICY;
+use crate::probe::{Hop, PendingProbe, ProbeAnswer};
+use crate::public_key::PublicKey;
+use crate::queue::{peer_queue, QueueReceiver, QueueSender};
+use crate::rate_limit::{PeerLimiter, Verdict};
+use crate::shutdown::Shutdown;
 use crate::snek::{SnekPath, SnekPathIndex, SnekRouted};
+use crate::status::{PeerInfo, SnekInfo, TreeInfo};
 use crate::tree::{Root, TreeRouted};
 use crate::wait_timer::WaitTimer;
+use crate::wire_frame::MAX_FRAME_LENGTH;
 use ed25519_consensus::SigningKey;
 use futures::SinkExt;
 use futures_sink::Sink;
 use log::{debug, info, trace};
 use rand::{thread_rng, Rng};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::future::Future;
 use std::ops::Add;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
-use tokio::time::{interval_at, sleep, Instant};
+use tokio::time::{interval_at, sleep_until, timeout_at, Instant};
 use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
 
 pub type Port = u64;
 pub type SequenceNumber = u64;
 pub type SnekPathId = u64;
-pub type PublicKey = [u8; 32];
+
+type Upload = Box<dyn Sink<Frame, Error = RouterError> + Send + Unpin>;
+type Download = Box<dyn Stream<Item = Result<Frame, RouterError>> + Send + Unpin>;
 
 pub(crate) const SNEK_EXPIRY_PERIOD: Duration = Duration::from_secs(60 * 60);
 
@@ -33,136 +58,316 @@ pub(crate) const ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(45 * 60);
 pub(crate) const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(30 * 60); // 30 min
 pub(crate) const REPARENT_WAIT_TIME: Duration = Duration::from_secs(1); //   1 sec
 pub(crate) const MAINTAIN_SNEK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the ids of broadcasts are remembered to drop copies of them.
+const BROADCAST_MEMORY: Duration = Duration::from_secs(60);
+/// Number of events that can be queued for the state task before peers and
+/// callers have to wait.
+const EVENT_BUFFER: usize = 1000;
 
-#[derive(Clone)]
-pub struct Router {
-    private_key: SigningKey,
-    public_key: Arc<PublicKey>,
-    running: Arc<RwLock<bool>>,
-
-    upload: Arc<Mutex<Receiver<Frame>>>,
-    download: Arc<Sender<Frame>>,
-    upload_connections: Arc<
-        RwLock<
-            HashMap<
-                PublicKey,
-                Arc<Mutex<Box<dyn Sink<Frame, Error = RouterError> + Send + Unpin>>>,
-            >,
-        >,
-    >,
-    download_connections: Arc<
-        RwLock<
-            HashMap<
-                PublicKey,
-                Arc<Mutex<Box<dyn Stream<Item = Result<Frame, RouterError>> + Send + Unpin>>>,
-            >,
-        >,
-    >,
-    ports: Arc<RwLock<HashMap<Port, Option<PublicKey>>>>,
+/// Everything the state task of the router reacts to besides its timers.
+enum Event {
+    /// A frame was received from the peer that is connected on `port`.
+    Frame {
+        frame: Frame,
+        from: PublicKey,
+        port: Port,
+    },
+    /// The connection to the peer on `port` broke or was closed by the peer.
+    PeerClosed {
+        peer: PublicKey,
+        port: Port,
+        error: Option<RouterError>,
+    },
+    /// Runs a closure on the state of the router.
+    Call(Box<dyn FnOnce(&mut State) + Send>),
+}
 
-    parent: Arc<RwLock<PublicKey>>,
-    announcements: Arc<RwLock<HashMap<PublicKey, TreeAnnouncement>>>,
-    sequence: Arc<RwLock<SequenceNumber>>,
-    ordering: Arc<RwLock<SequenceNumber>>,
-    reparent_timer: Arc<RwLock<Option<WaitTimer>>>,
+/// Result of starting a coordinate lookup in the state task.
+enum CoordinatesLookup {
+    Cached(Coordinates),
+    Pending {
+        response: oneshot::Receiver<Coordinates>,
+        deadline: Instant,
+    },
+}
+/// A [`DhtGet`] that waits for its answer.
+struct PendingDhtGet {
+    key: Vec<u8>,
+    response: oneshot::Sender<Vec<DhtRecord>>,
+}
 
-    ascending_path: Arc<RwLock<Option<SnekPath>>>,
-    descending_path: Arc<RwLock<Option<SnekPath>>>,
-    paths: Arc<RwLock<HashMap<SnekPathIndex, SnekPath>>>,
-    candidate: Arc<RwLock<Option<SnekPath>>>,
+/// Handle to a router.
+///
+/// All state of the router is owned by a single task that processes frames, timer
+/// events and calls of the handle one after another, so no locks are needed for it.
+/// Reading from and writing to peers happens in separate tasks per peer.
+#[derive(Clone)]
+pub struct Router {
+    events: Sender<Event>,
+    unstarted: Arc<std::sync::Mutex<Option<Unstarted>>>,
+    /// Receiver of the mail of this node until it is taken with [`Router::inbox`].
+    inbox: Arc<std::sync::Mutex<Option<Receiver<Mail>>>>,
+    shutdown: Shutdown,
+    metrics: MetricsRecorder,
+}
+/// Everything that is handed over to the tasks of the router once it gets started.
+struct Unstarted {
+    state: State,
+    events: Receiver<Event>,
+    upload: Receiver<Frame>,
 }
 impl Router {
-    pub fn new(key: SigningKey, download: Sender<Frame>, upload: Receiver<Frame>) -> Self {
-        Self {
-            private_key: key.clone(),
-            upload: Arc::new(Mutex::new(upload)),
-            public_key: Arc::new(key.verification_key().to_bytes()),
-            download: Arc::new(download),
-            upload_connections: Arc::new(Default::default()),
-            download_connections: Arc::new(Default::default()),
+    pub fn new(
+        key: SigningKey,
+        config: RouterConfig,
+        download: Sender<Frame>,
+        upload: Receiver<Frame>,
+    ) -> Self {
+        let public_key = PublicKey::from(key.verification_key());
+        let (events, events_receiver) = channel(EVENT_BUFFER);
+        let shutdown = Shutdown::new();
+        let metrics = MetricsRecorder::default();
+        let (inbox, inbox_receiver) = channel(INBOX_BUFFER);
+        let state = State {
+            private_key: key,
+            public_key,
+            config,
+            local: download,
+            events: events.clone(),
+            shutdown: shutdown.clone(),
+            metrics: metrics.clone(),
+            peers: Default::default(),
             ports: Default::default(),
-            parent: Arc::new(RwLock::new(key.verification_key().to_bytes())),
+            parent: public_key,
             announcements: Default::default(),
-            sequence: Arc::new(RwLock::new(0)),
-            ordering: Arc::new(RwLock::new(0)),
-            reparent_timer: Arc::new(RwLock::new(None)),
-            ascending_path: Arc::new(RwLock::new(None)),
-            descending_path: Arc::new(RwLock::new(None)),
-            paths: Arc::new(Default::default()),
-            candidate: Arc::new(RwLock::new(None)),
-            running: Arc::new(RwLock::new(false)),
-        }
-    }
-    /// Starts the router instance. Finishing of the JoinHandle doesn't mean that the router stopped.
-    /// Only the local connection was closed.
-    pub async fn start(&self) -> JoinHandle<Result<Self, RouterError>> {
-        let mut running = self.running.write().await;
-        *running = true;
-        drop(running);
-        let router = self.clone();
-        tokio::spawn(async move {
-            let mut ticker = interval_at(
-                Instant::now().add(ANNOUNCEMENT_INTERVAL),
-                ANNOUNCEMENT_INTERVAL,
-            );
-            loop {
-                ticker.tick().await;
-                let running = router.running.read().await;
-                if !(*running) {
-                    break;
-                }
-                drop(running);
-
-                router.maintain_tree().await;
-            }
-            trace!("Stopped maintaining the tree");
+            sequence: 0,
+            ordering: 0,
+            reparent_timer: None,
+            pending_reparent: None,
+            ascending_path: None,
+            descending_path: None,
+            paths: Default::default(),
+            candidate: None,
+            coordinates_cache: Default::default(),
+            coordinates_lookups: Default::default(),
+            probes: Default::default(),
+            broadcasts: Default::default(),
+            dht: Default::default(),
+            dht_policies: vec![NAME_POLICY],
+            dht_gets: Default::default(),
+            mailbox: Default::default(),
+            mail_sends: Default::default(),
+            inbox,
+            delivered_mail: Default::default(),
+        };
+        Self {
+            events,
+            inbox: Arc::new(std::sync::Mutex::new(Some(inbox_receiver))),
+            unstarted: Arc::new(std::sync::Mutex::new(Some(Unstarted {
+                state,
+                events: events_receiver,
+                upload,
+            }))),
+            shutdown,
+            metrics,
+        }
+    }
+    /// Starts the task that owns the state of the router. The returned JoinHandle
+    /// resolves to `None` once the router was stopped.
+    ///
+    /// # Panics
+    /// If the router was already started.
+    pub async fn start(&self) -> JoinHandle<Option<()>> {
+        let Unstarted {
+            state,
+            events,
+            upload,
+        } = self
+            .unstarted
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Router was already started");
+        self.spawn(state.run(events, upload))
+    }
+    /// Stops the router. Our ascending and descending paths are torn down so that
+    /// the nodes along them learn about it, all peer connections are flushed and
+    /// closed and all tasks of the router get cancelled.
+    ///
+    /// Resolves once everything has stopped or fails with [`RouterError::Timeout`]
+    /// if that takes longer than `timeout`.
+    pub async fn stop(&self, timeout: Duration) -> Result<(), RouterError> {
+        trace!("Stopping the router");
+        let deadline = Instant::now().add(timeout);
+        let notify_peers = self.call(|state| {
+            state.teardown_own_paths();
+            state.close_connections();
         });
-
-        let router = self.clone();
-        tokio::spawn(async move {
-            let mut ticker = interval_at(
-                Instant::now().add(MAINTAIN_SNEK_INTERVAL),
-                MAINTAIN_SNEK_INTERVAL,
-            );
-            loop {
-                ticker.tick().await;
-                let running = router.running.read().await;
-                if !(*running) {
-                    break;
-                }
-                drop(running);
-
-                router.maintain_snek().await;
-            }
-            trace!("Stopped maintaining the snek");
+        let notified = timeout_at(deadline, notify_peers).await;
+        self.shutdown.trigger();
+        match notified {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => debug!("Router was already stopped"),
+            Err(_) => {
+                debug!("Timed out while notifying peers about shutdown");
+                return Err(RouterError::Timeout);
+            }
+        }
+        self.shutdown
+            .wait(deadline.saturating_duration_since(Instant::now()))
+            .await?;
+        debug!("Router stopped");
+        Ok(())
+    }
+    /// Spawns a task that gets cancelled when the router is stopped.
+    pub(crate) fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.shutdown.spawn(future)
+    }
+    /// Runs `f` on the state task of the router and returns its result. Fails with
+    /// [`RouterError::Stopped`] if the router was stopped.
+    pub(crate) async fn call<R, F>(&self, f: F) -> Result<R, RouterError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut State) -> R + Send + 'static,
+    {
+        let (result_sender, result) = oneshot::channel();
+        let call = Box::new(move |state: &mut State| {
+            let _ = result_sender.send(f(state));
         });
-
-        let router = self.clone();
-        tokio::spawn(async move {
-            let mut upload = router.upload.lock().await;
-            loop {
-                let running = router.running.read().await;
-                if !(*running) {
-                    break;
-                }
-                drop(running);
-                if let Some(frame) = upload.recv().await {
-                    router.handle_frame(frame, *router.public_key).await?;
-                } else {
-                    debug!("Local event channel closed. Only handling peer frames.");
-                    break;
+        self.events
+            .send(Event::Call(call))
+            .await
+            .map_err(|_| RouterError::Stopped)?;
+        result.await.map_err(|_| RouterError::Stopped)
+    }
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+    pub(crate) async fn coordinates(&self) -> Result<Coordinates, RouterError> {
+        self.call(|state| state.coordinates()).await
+    }
+    pub(crate) async fn peers(&self) -> Result<Vec<PublicKey>, RouterError> {
+        self.call(|state| state.peers()).await
+    }
+    pub(crate) async fn peer_infos(&self) -> Result<Vec<PeerInfo>, RouterError> {
+        self.call(|state| state.peer_infos()).await
+    }
+    pub(crate) async fn tree_info(&self) -> Result<TreeInfo, RouterError> {
+        self.call(|state| state.tree_info()).await
+    }
+    pub(crate) async fn snek_info(&self) -> Result<SnekInfo, RouterError> {
+        self.call(|state| state.snek_info()).await
+    }
+    /// Returns the current coordinates of the node with `public_key`. They are
+    /// asked from the node itself unless they are cached.
+    pub(crate) async fn lookup_coordinates(
+        &self,
+        public_key: PublicKey,
+    ) -> Result<Coordinates, RouterError> {
+        match self
+            .call(move |state| state.lookup_coordinates(public_key))
+            .await??
+        {
+            CoordinatesLookup::Cached(coordinates) => Ok(coordinates),
+            CoordinatesLookup::Pending { response, deadline } => {
+                match timeout_at(deadline, response).await {
+                    Ok(Ok(coordinates)) => Ok(coordinates),
+                    Ok(Err(_)) => Err(RouterError::Stopped),
+                    Err(_) => Err(RouterError::Timeout),
                 }
             }
-            drop(upload);
-            Ok(router)
-        })
+        }
     }
-    pub async fn stop(&self) {
-        trace!("Stopping the router");
-        *self.running.write().await = false;
-        for peer in self.peers().await {
-            self.disconnect_peer(peer).await;
+    /// Sends a probe to `public_key` and collects the answers until the destination
+    /// answered or the probe timeout passed. If `trace` is set every node on the
+    /// path answers. The hops are ordered by their distance from this node.
+    pub(crate) async fn probe(
+        &self,
+        public_key: PublicKey,
+        trace: bool,
+    ) -> Result<Vec<Hop>, RouterError> {
+        let (answers_sender, mut answers) = unbounded_channel();
+        let (id, deadline) = self
+            .call(move |state| state.send_probe(public_key, trace, answers_sender))
+            .await??;
+        let mut hops = vec![];
+        let mut reached = false;
+        while !reached {
+            match timeout_at(deadline, answers.recv()).await {
+                Ok(Some(answer)) => {
+                    reached = answer.reached;
+                    hops.push((answer.distance, answer.hop));
+                }
+                Ok(None) => return Err(RouterError::Stopped),
+                Err(_) => break,
+            }
         }
+        self.call(move |state| state.probes.remove(&id)).await?;
+        if hops.is_empty() {
+            return Err(RouterError::Timeout);
+        }
+        hops.sort_by_key(|(distance, _)| *distance);
+        Ok(hops.into_iter().map(|(_, hop)| hop).collect())
+    }
+    /// Stores `value` under `key` in the DHT and returns the record that was put.
+    pub(crate) async fn dht_put(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<DhtRecord, RouterError> {
+        self.call(move |state| state.dht_put(key, value, ttl))
+            .await?
+    }
+    /// Stores a record that was signed before in the DHT.
+    pub(crate) async fn dht_put_record(&self, record: DhtRecord) -> Result<(), RouterError> {
+        self.call(move |state| state.put_dht_record(record)).await?
+    }
+    /// Asks the home node of `key` for its records.
+    pub(crate) async fn dht_get(&self, key: Vec<u8>) -> Result<Vec<DhtRecord>, RouterError> {
+        let (id, response, deadline) = self.call(move |state| state.dht_get(key)).await??;
+        match timeout_at(deadline, response).await {
+            Ok(Ok(records)) => Ok(records),
+            Ok(Err(_)) => Err(RouterError::Stopped),
+            Err(_) => {
+                self.call(move |state| state.dht_gets.remove(&id)).await?;
+                Err(RouterError::Timeout)
+            }
+        }
+    }
+    /// Sends `payload` to the node with `public_key`, or to the mailbox that holds
+    /// mail for it while it is offline.
+    pub(crate) async fn send_mail(
+        &self,
+        public_key: PublicKey,
+        payload: Vec<u8>,
+    ) -> Result<MailStatus, RouterError> {
+        let (id, reply, deadline) = self
+            .call(move |state| state.send_mail(public_key, payload))
+            .await??;
+        match timeout_at(deadline, reply).await {
+            Ok(Ok(status)) => Ok(status),
+            Ok(Err(_)) => Err(RouterError::Stopped),
+            Err(_) => {
+                self.call(move |state| state.mail_sends.remove(&id)).await?;
+                Err(RouterError::Timeout)
+            }
+        }
+    }
+    /// Takes the [`Inbox`] that receives the mail of this node. Fails with
+    /// [`RouterError::InboxTaken`] if it was taken before.
+    pub(crate) fn inbox(&self) -> Result<Inbox, RouterError> {
+        let mail = self
+            .inbox
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(RouterError::InboxTaken)?;
+        Ok(Inbox { mail })
     }
 
     /// This is for accepting incoming connections where the public_key is not known
@@ -171,385 +376,1313 @@ impl Router {
     /// can the public_key be read out of the first announcement.
     pub async fn connect(
         &self,
-        mut upload: Box<dyn Sink<Frame, Error = RouterError> + Send + Unpin>,
-        mut download: Box<dyn Stream<Item = Result<Frame, RouterError>> + Send + Unpin>,
+        mut upload: Upload,
+        mut download: Download,
     ) -> Result<PublicKey, RouterError> {
-        let port = self.get_new_port().await;
-        let mut announcement = self.current_announcement().await;
-        announcement.append_signature(self.private_key.clone(), port);
+        let (port, announcement) = self
+            .call(|state| {
+                let port = state.get_new_port();
+                let mut announcement = state.current_announcement();
+                announcement.append_signature(state.private_key.clone(), port);
+                (port, announcement)
+            })
+            .await?;
+        let ann = match Self::exchange_announcements(&mut upload, &mut download, announcement).await
+        {
+            Ok(ann) => ann,
+            Err(e) => {
+                self.call(move |state| state.free_port(port)).await?;
+                return Err(e);
+            }
+        };
+        let public_key = ann
+            .signatures
+            .last()
+            .ok_or(RouterError::MissingSignature)?
+            .signing_public_key;
+        self.call(move |state| {
+            state.add_peer(public_key, port, upload, download, false);
+            state.handle_frame(Frame::TreeAnnouncement(ann), public_key)
+        })
+        .await??;
+        Ok(public_key)
+    }
+    async fn exchange_announcements(
+        upload: &mut Upload,
+        download: &mut Download,
+        announcement: TreeAnnouncement,
+    ) -> Result<TreeAnnouncement, RouterError> {
         upload.send(Frame::TreeAnnouncement(announcement)).await?;
-        return match download.next().await {
-            Some(decoding_result) => match decoding_result {
-                Ok(Frame::TreeAnnouncement(ann)) => {
-                    return if let Some(signature) = ann.signatures.last() {
-                        let public_key = signature.signing_public_key;
-                        let mut ports = self.ports.write().await;
-                        ports.insert(port, Some(public_key));
-                        drop(ports);
-                        self.add_peer(public_key, port, upload, download, false)
-                            .await;
-                        self.handle_frame(Frame::TreeAnnouncement(ann), public_key)
-                            .await?;
-                        Ok(public_key)
+        match download.next().await {
+            Some(Ok(Frame::TreeAnnouncement(ann))) => {
+                if ann.signatures.is_empty() {
+                    Err(RouterError::MissingSignature)
+                } else {
+                    Ok(ann)
+                }
+            }
+            Some(Ok(_)) => Err(RouterError::InvalidFrame),
+            Some(Err(e)) => Err(e),
+            None => Err(RouterError::ConnectionClosed),
+        }
+    }
+    pub async fn disconnect_peer(&self, peer: PublicKey) {
+        if let Err(e) = self.call(move |state| state.disconnect_peer(peer)).await {
+            debug!("Could not disconnect {:?}: {}", peer, e);
+        }
+    }
+}
+
+/// A connected peer as seen by the state task.
+struct Peer {
+    port: Port,
+    /// Frames that the writer task of the peer sends to it.
+    queue: QueueSender,
+    /// Stops the reader task of the peer.
+    closed: CancellationToken,
+    limiter: PeerLimiter,
+}
+
+/// The state of the router. It is owned by the state task which is the only one
+/// accessing it.
+pub(crate) struct State {
+    private_key: SigningKey,
+    public_key: PublicKey,
+    config: RouterConfig,
+    /// Frames for the local client.
+    local: Sender<Frame>,
+    events: Sender<Event>,
+    shutdown: Shutdown,
+    metrics: MetricsRecorder,
+
+    peers: HashMap<PublicKey, Peer>,
+    ports: HashMap<Port, Option<PublicKey>>,
+
+    parent: PublicKey,
+    announcements: HashMap<PublicKey, TreeAnnouncement>,
+    sequence: SequenceNumber,
+    ordering: SequenceNumber,
+    reparent_timer: Option<WaitTimer>,
+    /// When a reparent that was deferred with `reparent(true)` is due.
+    pending_reparent: Option<Instant>,
+
+    ascending_path: Option<SnekPath>,
+    descending_path: Option<SnekPath>,
+    paths: HashMap<SnekPathIndex, SnekPath>,
+    candidate: Option<SnekPath>,
+    coordinates_cache: HashMap<PublicKey, CachedCoordinates>,
+    /// Coordinate requests that wait for their answer, by the key they ask for.
+    coordinates_lookups: HashMap<PublicKey, PendingCoordinates>,
+    probes: HashMap<u64, PendingProbe>,
+    /// Broadcasts that were forwarded by source and id, with the time they are forgotten.
+    broadcasts: HashMap<(PublicKey, u64), Instant>,
+    dht: DhtStore,
+    /// Rules of the uses of the DHT for the records this node stores.
+    dht_policies: Vec<DhtPolicy>,
+    dht_gets: HashMap<u64, PendingDhtGet>,
+    /// Mail that this node holds for offline nodes.
+    mailbox: Mailbox,
+    /// Sent mail that waits for its reply, by id.
+    mail_sends: HashMap<u64, oneshot::Sender<MailStatus>>,
+    /// Mail that was delivered to this node, until it is taken from the [`Inbox`].
+    inbox: Sender<Mail>,
+    /// Ids of the mail that was delivered by source, with the time they are forgotten.
+    delivered_mail: HashMap<PublicKey, HashMap<u64, Instant>>,
+}
+impl State {
+    async fn run(mut self, mut events: Receiver<Event>, mut upload: Receiver<Frame>) {
+        let mut tree_ticker = interval_at(
+            Instant::now().add(ANNOUNCEMENT_INTERVAL),
+            ANNOUNCEMENT_INTERVAL,
+        );
+        let mut snek_ticker = interval_at(
+            Instant::now().add(MAINTAIN_SNEK_INTERVAL),
+            MAINTAIN_SNEK_INTERVAL,
+        );
+        let mut local_open = true;
+        loop {
+            let pending_reparent = self.pending_reparent;
+            tokio::select! {
+                Some(event) = events.recv() => self.handle_event(event),
+                frame = upload.recv(), if local_open => match frame {
+                    Some(frame) => self.handle_local_frame(frame),
+                    None => {
+                        debug!("Local event channel closed. Only handling peer frames.");
+                        local_open = false;
+                    }
+                },
+                _ = tree_ticker.tick() => self.maintain_tree(),
+                _ = snek_ticker.tick() => {
+                    self.maintain_snek();
+                    self.expire_coordinates();
+                    self.expire_broadcasts();
+                    self.dht.expire();
+                    self.mailbox.expire();
+                    self.expire_delivered_mail();
+                    self.forward_mail();
+                }
+                _ = sleep_until(pending_reparent.unwrap_or_else(Instant::now)),
+                    if pending_reparent.is_some() =>
+                {
+                    self.pending_reparent = None;
+                    self.reparent(false);
+                }
+            }
+        }
+    }
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Frame {
+                mut frame,
+                from,
+                port,
+            } => {
+                if self.port(from) != Some(port) {
+                    trace!("Dropping frame from old connection to {:?}", from);
+                    return;
+                }
+                trace!("Received {:?}", frame);
+                if let Some(hop_limit) = frame.hop_limit_mut() {
+                    *hop_limit = hop_limit.saturating_sub(1);
+                }
+                match self
+                    .peers
+                    .get_mut(&from)
+                    .map(|peer| peer.limiter.check(&frame))
+                {
+                    Some(Verdict::Accept) | None => {}
+                    Some(Verdict::Drop) => {
+                        trace!("{:?} exceeded its rate limit. Dropping frame", from);
+                        self.metrics
+                            .record(|metrics| metrics.rate_limited_frames += 1);
+                        return;
+                    }
+                    Some(Verdict::Disconnect) => {
+                        debug!("Disconnecting {:?} for exceeding its rate limits", from);
+                        self.metrics.record(|metrics| {
+                            metrics.rate_limited_frames += 1;
+                            metrics.rate_limited_peers_disconnected += 1;
+                        });
+                        self.disconnect_peer(from);
+                        return;
+                    }
+                }
+                if let Err(e) = self.handle_frame(frame, from) {
+                    self.metrics.record_error(&e);
+                    if e.is_caused_by_peer() {
+                        debug!("Disconnecting misbehaving peer {:?}: {}", from, e);
+                        self.metrics
+                            .record(|metrics| metrics.misbehaving_peers_disconnected += 1);
+                        self.disconnect_peer(from);
                     } else {
-                        Err(RouterError::MissingSignature)
+                        debug!("Could not handle frame from {:?}: {}", from, e);
                     }
                 }
-                Ok(_e) => Err(RouterError::InvalidFrame),
-                Err(e) => Err(e),
-            },
-            None => Err(RouterError::ConnectionClosed),
-        };
+            }
+            Event::PeerClosed { peer, port, error } => {
+                if self.port(peer) != Some(port) {
+                    return;
+                }
+                if let Some(e) = error {
+                    trace!("{}", e);
+                    self.metrics.record_error(&e);
+                    if e.is_caused_by_peer() {
+                        self.metrics
+                            .record(|metrics| metrics.misbehaving_peers_disconnected += 1);
+                    }
+                }
+                debug!("Stopping peer {:?}", peer);
+                self.disconnect_peer(peer);
+            }
+            Event::Call(call) => call(self),
+        }
     }
-    async fn add_peer(
-        &self,
+    fn teardown_own_paths(&mut self) {
+        let ascending = self.ascending_path.clone();
+        let descending = self.descending_path.clone();
+        for path in ascending.iter().chain(descending.iter()) {
+            trace!("Tearing down {:?} because of shutdown", path.index);
+            if let Err(e) =
+                self.teardown_existing_path(0, path.index.public_key, path.index.path_id)
+            {
+                debug!("Could not tear down {:?}: {}", path.index, e);
+            }
+        }
+    }
+    /// Stops reading from all peers. Their writer tasks send all frames that are
+    /// still queued and close the connections afterwards.
+    fn close_connections(&mut self) {
+        for (_, peer) in self.peers.drain() {
+            peer.closed.cancel();
+        }
+        self.ports.clear();
+    }
+
+    pub(crate) fn add_peer(
+        &mut self,
         peer: PublicKey,
         port: Port,
-        upload: Box<dyn Sink<Frame, Error = RouterError> + Send + Unpin>,
-        download: Box<dyn Stream<Item = Result<Frame, RouterError>> + Send + Unpin>,
+        upload: Upload,
+        download: Download,
         send_first_announcement: bool,
     ) {
-        let mut upload_connections = self.upload_connections.write().await;
-        let mut download_connections = self.download_connections.write().await;
-        if download_connections.contains_key(&peer) {
+        if self.peers.contains_key(&peer) {
             info!("Couldn't add {:?} because it already exists", peer);
+            self.free_port(port);
             return;
         }
-        if upload_connections.contains_key(&peer) {
-            info!("Couldn't add {:?} because it already exists", peer);
-            return;
-        }
-        upload_connections.insert(peer, Arc::new(Mutex::new(upload)));
-        download_connections.insert(peer, Arc::new(Mutex::new(download)));
-        drop(upload_connections);
-        drop(download_connections);
+        let (queue, outgoing) = peer_queue(self.config.peer_queue_size, self.config.drop_policy);
+        let closed = CancellationToken::new();
+        self.peers.insert(
+            peer,
+            Peer {
+                port,
+                queue,
+                closed: closed.clone(),
+                limiter: PeerLimiter::new(&self.config.rate_limits),
+            },
+        );
+        self.ports.insert(port, Some(peer));
         info!("Added peer {:?}", peer);
 
-        self.ports.write().await.insert(port, Some(peer));
         if send_first_announcement {
-            self.send_tree_announcement(peer, self.current_announcement().await)
-                .await;
+            self.send_tree_announcement(peer, self.current_announcement());
         }
-        self.spawn_peer(peer).await;
-    }
-    async fn spawn_peer(&self, peer: PublicKey) {
-        let router = self.clone();
-        tokio::spawn(async move {
-            loop {
-                let running = router.running.read().await;
-                if !(*running) {
-                    break;
-                }
-                drop(running);
-                match router.poll_peer(peer).await {
-                    Ok(_) => continue,
-                    Err(e) => {
-                        trace!("{}", e);
-                        break;
-                    }
-                }
+        self.spawn_writer(peer, port, upload, outgoing);
+        self.spawn_reader(peer, port, download, closed);
+    }
+    /// Spawns the task that sends the frames queued for `peer`. It isn't cancelled
+    /// on shutdown so that it can flush the queue, but stops once the queue is closed.
+    fn spawn_writer(
+        &self,
+        peer: PublicKey,
+        port: Port,
+        mut upload: Upload,
+        mut outgoing: QueueReceiver,
+    ) {
+        let events = self.events.clone();
+        self.shutdown.track(async move {
+            if let Err(e) = Self::write_frames(&mut upload, &mut outgoing).await {
+                debug!("Could not send to {:?}: {}", peer, e);
+                let closed = Event::PeerClosed {
+                    peer,
+                    port,
+                    error: None,
+                };
+                let _ = events.send(closed).await;
             }
-            debug!("Stopping peer {:?}", peer);
-            router.disconnect_peer(peer).await;
         });
     }
-    async fn poll_peer(&self, peer: PublicKey) -> Result<(), RouterError> {
-        let sockets = self.download_connections.read().await;
-        return if let Some(socket) = sockets.get(&peer).cloned() {
-            drop(sockets);
-            if let Some(decode_result) = Self::poll_download_connection(socket).await {
-                match decode_result {
-                    Ok(frame) => {
-                        trace!("Received {:?}", frame);
-                        self.handle_frame(frame, peer).await?;
-                        Ok(())
-                    }
-                    Err(e) => {
-                        debug!("Could not decode {:?}", e);
-                        Ok(())
-                    }
-                }
-            } else {
-                Err(RouterError::ConnectionClosed)
+    async fn write_frames(
+        upload: &mut Upload,
+        outgoing: &mut QueueReceiver,
+    ) -> Result<(), RouterError> {
+        while let Some(frame) = outgoing.pop().await {
+            Self::write_frame(upload, frame).await?;
+            // Send everything that is queued already before flushing.
+            while let Some(frame) = outgoing.try_pop() {
+                Self::write_frame(upload, frame).await?;
             }
-        } else {
-            Err(RouterError::ConnectionClosed)
-        };
-    }
-    async fn poll_download_connection(
-        socket: Arc<Mutex<Box<dyn Stream<Item = Result<Frame, RouterError>> + Send + Unpin>>>,
-    ) -> Option<Result<Frame, RouterError>> {
-        let mut socket = socket.lock().await;
-        socket.next().await
+            upload.flush().await?;
+        }
+        upload.close().await
     }
-
-    async fn get_new_port(&self) -> Port {
-        for i in 1.. {
-            let mut ports = self.ports.write().await;
-            if !ports.contains_key(&i) {
-                ports.insert(i, None);
-                return i;
+    /// Buffers `frame` to be sent. A frame that can't be encoded is dropped, as it
+    /// says nothing about the connection, which stays usable.
+    async fn write_frame(upload: &mut Upload, frame: Frame) -> Result<(), RouterError> {
+        match upload.feed(frame).await {
+            Err(RouterError::EncodingError(e)) => {
+                debug!("Dropping frame that can't be encoded: {}", e);
+                Ok(())
             }
+            result => result,
         }
-        unreachable!("Reached port limit of {}", Port::MAX);
     }
-    pub async fn disconnect_peer(&self, peer: PublicKey) {
-        let port = self.port(peer).await;
+    /// Spawns the task that hands the frames received from `peer` to the state task.
+    fn spawn_reader(
+        &self,
+        peer: PublicKey,
+        port: Port,
+        mut download: Download,
+        closed: CancellationToken,
+    ) {
+        let events = self.events.clone();
+        self.shutdown.spawn(async move {
+            let error = loop {
+                let received = tokio::select! {
+                    _ = closed.cancelled() => return,
+                    received = download.next() => received,
+                };
+                match received {
+                    Some(Ok(frame)) => {
+                        let frame = Event::Frame {
+                            frame,
+                            from: peer,
+                            port,
+                        };
+                        if events.send(frame).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Err(e)) => break Some(e),
+                    None => break None,
+                }
+            };
+            let _ = events.send(Event::PeerClosed { peer, port, error }).await;
+        });
+    }
+    /// Releases a port that was reserved with `get_new_port` but never got a peer.
+    fn free_port(&mut self, port: Port) {
+        if let Some(None) = self.ports.get(&port) {
+            self.ports.remove(&port);
+        }
+    }
+
+    pub(crate) fn disconnect_peer(&mut self, peer: PublicKey) {
         let mut bootstrap = false;
-        if let Some(port) = port {
+        if let Some(connection) = self.peers.remove(&peer) {
+            let port = connection.port;
+            connection.closed.cancel();
             // Scan the local DHT table for any routes that transited this now-dead
             // peering. If we find any then we need to send teardowns in the opposite
             // direction, so that nodes further along the path will learn that the
             // path was broken.
-            for (key, value) in self.paths.read().await.clone() {
+            for (key, value) in self.paths.clone() {
                 if value.destination == port || value.source == port {
-                    self.send_teardown_for_existing_path(port, key.public_key, key.path_id)
-                        .await;
+                    self.send_teardown_for_existing_path(port, key.public_key, key.path_id);
                 }
             }
 
             // If the ascending path was also lost because it went via the now-dead
             // peering then clear that path (although we can't send a teardown) and
             // then bootstrap again.
-            let ascending = self.ascending_path.read().await;
-            if let Some(asc) = ascending.clone() {
-                drop(ascending);
+            if let Some(asc) = self.ascending_path.clone() {
                 if asc.destination == port {
-                    self.teardown_path(0, asc.index.public_key, asc.index.path_id)
-                        .await;
+                    self.teardown_path(0, asc.index.public_key, asc.index.path_id);
                     bootstrap = true;
                 }
             }
 
             // If the descending path was lost because it went via the now-dead
             // peering then clear that path (although we can't send a teardown) and
-            // wait for another incoming setup.
-            let descending = self.descending_path.read().await;
-            if let Some(desc) = &descending.clone() {
-                drop(descending);
-                if desc.destination == port {
-                    self.teardown_path(0, desc.index.public_key, desc.index.path_id)
-                        .await;
+            // wait for another incoming setup. The descending path ends at this node,
+            // so it arrived through its `source` port; its `destination` is 0.
+            if let Some(desc) = self.descending_path.clone() {
+                if desc.source == port {
+                    self.teardown_path(0, desc.index.public_key, desc.index.path_id);
                 }
             }
-            self.ports.write().await.remove(&port);
+            self.ports.remove(&port);
+            info!("Removed peer {:?}", peer);
         } else {
             debug!("No port for peer that is being disconnected.");
         }
-        self.announcements.write().await.remove(&peer);
-        self.upload_connections.write().await.remove(&peer);
-        self.download_connections.write().await.remove(&peer);
+        self.announcements.remove(&peer);
 
         // If the peer that died was our chosen tree parent, then we will need to
         // select a new parent. If we successfully choose a new parent (as in, we
         // don't end up promoting ourselves to a root) then we will also need to
         // send a new bootstrap into the network.
-        if self.parent().await == peer {
-            bootstrap = bootstrap || self.parent_selection().await;
+        if self.parent() == peer {
+            bootstrap = bootstrap || self.parent_selection();
         }
 
         if bootstrap {
-            self.bootstrap_now().await;
+            self.bootstrap_now();
         }
     }
 
-    async fn tree_announcement(&self, of: PublicKey) -> Option<TreeAnnouncement> {
-        self.announcements.read().await.get(&of).cloned()
+    fn tree_announcement(&self, of: PublicKey) -> Option<TreeAnnouncement> {
+        self.announcements.get(&of).cloned()
     }
-    async fn set_tree_announcement(&self, of: PublicKey, announcement: TreeAnnouncement) {
-        self.announcements.write().await.insert(of, announcement);
+    fn set_tree_announcement(&mut self, of: PublicKey, announcement: TreeAnnouncement) {
+        self.announcements.insert(of, announcement);
     }
-    async fn port(&self, of: PublicKey) -> Option<Port> {
-        if *self.public_key == of {
+    fn port(&self, of: PublicKey) -> Option<Port> {
+        if self.public_key == of {
             return Some(0);
         }
-        let ports = self.ports.read().await;
-        for (port, peer) in &*ports {
-            match peer {
-                None => {}
-                Some(peer) => {
-                    if peer == &of {
-                        return Some(*port);
-                    }
-                }
-            }
-        }
-        None
+        self.peers.get(&of).map(|peer| peer.port)
     }
-    async fn peers(&self) -> Vec<PublicKey> {
-        let mut peers = Vec::new();
-        for peer in self.ports.read().await.values() {
-            match peer {
-                None => {}
-                Some(peer) => {
-                    peers.push(*peer);
-                }
-            }
-        }
-        peers
+    pub(crate) fn peers(&self) -> Vec<PublicKey> {
+        self.peers.keys().copied().collect()
     }
-    async fn parent(&self) -> PublicKey {
-        *self.parent.read().await
+    fn parent(&self) -> PublicKey {
+        self.parent
     }
-    async fn set_parent(&self, peer: PublicKey) {
+    fn set_parent(&mut self, peer: PublicKey) {
         trace!("Setting parent to {:?}", peer);
-        *self.parent.write().await = peer;
+        self.parent = peer;
     }
     fn public_key(&self) -> PublicKey {
-        *self.public_key
+        self.public_key
     }
-    async fn get_peer_on_port(&self, port: Port) -> Option<PublicKey> {
+    fn get_peer_on_port(&self, port: Port) -> Option<PublicKey> {
         if port == 0 {
             return Some(self.public_key());
         }
-        return match self.ports.read().await.get(&port) {
-            None => None,
-            Some(peer) => peer.as_ref().copied(),
-        };
+        self.ports.get(&port).copied().flatten()
     }
-    async fn current_sequence(&self) -> SequenceNumber {
-        *self.sequence.read().await
-    }
-    async fn next_sequence(&self) -> SequenceNumber {
-        {
-            let mut sequence = self.sequence.write().await;
-            *sequence += 1;
+    fn get_new_port(&mut self) -> Port {
+        for i in 1.. {
+            if let Entry::Vacant(entry) = self.ports.entry(i) {
+                entry.insert(None);
+                return i;
+            }
         }
-        *self.sequence.read().await
+        unreachable!("Reached port limit of {}", Port::MAX);
     }
-    async fn current_ordering(&self) -> SequenceNumber {
-        *self.ordering.read().await
+    fn current_sequence(&self) -> SequenceNumber {
+        self.sequence
     }
-    async fn next_ordering(&self) -> SequenceNumber {
-        {
-            let mut ordering = self.ordering.write().await;
-            *ordering += 1;
-        }
-        *self.ordering.read().await
+    fn next_sequence(&mut self) -> SequenceNumber {
+        self.sequence += 1;
+        self.sequence
+    }
+    fn current_ordering(&self) -> SequenceNumber {
+        self.ordering
+    }
+    fn next_ordering(&mut self) -> SequenceNumber {
+        self.ordering += 1;
+        self.ordering
     }
-    async fn reparent_timer_expired(&self) -> bool {
-        let timer = self.reparent_timer.read().await;
-        if let Some(timer) = &*timer {
+    fn reparent_timer_expired(&self) -> bool {
+        if let Some(timer) = &self.reparent_timer {
             timer.is_expired()
         } else {
             true
         }
     }
-    async fn set_reparent_timer(&self) {
+    fn set_reparent_timer(&mut self) {
         trace!("Reparent in {:?}", REPARENT_WAIT_TIME);
-        *self.reparent_timer.write().await = Some(WaitTimer::new(REPARENT_WAIT_TIME));
+        self.reparent_timer = Some(WaitTimer::new(REPARENT_WAIT_TIME));
     }
-    async fn send_to_local(&self, frame: Frame) {
-        self.download.send(frame).await.unwrap();
+    /// Hands `frame` to the local client. It is dropped if the client doesn't keep
+    /// up, so that remote nodes can't fill up the memory of this node.
+    fn send_to_local(&self, frame: Frame) -> Result<(), RouterError> {
+        match self.local.try_send(frame) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(frame)) => {
+                trace!("Local client is busy. Dropping {:?}", frame);
+                self.metrics
+                    .record(|metrics| metrics.local_queue_drops += 1);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(RouterError::LocalChannelClosed),
+        }
     }
-    async fn send(&self, frame: Frame, to: PublicKey) -> Result<(), RouterError> {
+    fn send(&self, mut frame: Frame, to: PublicKey) -> Result<(), RouterError> {
         if to == self.public_key() {
             return match frame {
-                Frame::SnekRouted(_) => {
-                    self.send_to_local(frame).await;
-                    Ok(())
-                }
-                Frame::TreeRouted(_) => {
-                    self.send_to_local(frame).await;
-                    Ok(())
-                }
+                Frame::SnekRouted(_) => self.send_to_local(frame),
+                Frame::TreeRouted(_) => self.send_to_local(frame),
                 _ => {
                     // Don't send protocol frames to self. Drop them.
                     Ok(())
                 }
             };
         }
-        let upload_connections = self.upload_connections.read().await;
-        let socket = upload_connections.get(&to);
-        return if let Some(socket) = socket {
+        if let Some(peer) = self.peers.get(&to) {
+            if frame
+                .hop_limit_mut()
+                .is_some_and(|hop_limit| *hop_limit == 0)
+            {
+                debug!("Hop limit reached. Dropping {:?}", frame);
+                self.metrics.record(|metrics| metrics.hop_limit_drops += 1);
+                return Ok(());
+            }
+            // The writer of the peer couldn't encode it, so it is dropped here.
+            if frame.encoded_len() > MAX_FRAME_LENGTH {
+                debug!("Dropping frame that is too long: {:?}", frame);
+                self.metrics.record(|metrics| metrics.oversize_drops += 1);
+                return Ok(());
+            }
             trace!("Sending {:?}", frame);
-            let mut socket = socket.lock().await;
-            socket.send(frame).await
+            if let Some(dropped) = peer.queue.push(frame)? {
+                debug!("Queue of {:?} is full. Dropping {:?}", to, dropped);
+                self.metrics.record(|metrics| metrics.congestion_drops += 1);
+            }
+            Ok(())
         } else {
             // Ignore frames that are sent to unknown peer
             debug!("No Socket for {:?}", to);
             Ok(())
-        };
+        }
     }
 
-    async fn handle_frame(&self, frame: Frame, from: PublicKey) -> Result<(), RouterError> {
+    /// Handles a frame that was sent by the client.
+    fn handle_local_frame(&mut self, mut frame: Frame) {
+        if let Frame::TreeRouted(packet) = &mut frame {
+            // The client doesn't know our current coordinates so we fill them in.
+            packet.source_coordinates = self.coordinates();
+        }
+        if let Frame::Broadcast(broadcast) = &mut frame {
+            broadcast.sign(&self.private_key);
+        }
+        if let Frame::SnekRouted(packet) = frame {
+            frame = self.upgrade_to_tree(packet);
+        }
+        let public_key = self.public_key;
+        if let Err(e) = self.handle_frame(frame, public_key) {
+            debug!("Could not handle local frame: {}", e);
+            self.metrics.record_error(&e);
+        }
+    }
+    pub(crate) fn handle_frame(
+        &mut self,
+        frame: Frame,
+        from: PublicKey,
+    ) -> Result<(), RouterError> {
         match frame {
             Frame::TreeRouted(packet) => {
-                if let Some(peer) = self.next_tree_hop(&packet, from).await {
-                    let peer = peer;
-                    if peer == self.public_key() {
-                        self.send_to_local(Frame::TreeRouted(packet)).await;
-                    } else {
-                        self.send(Frame::TreeRouted(packet), peer).await?;
-                    }
+                let peer = self
+                    .next_tree_hop(&packet, from)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key() {
+                    self.send_to_local(Frame::TreeRouted(packet))?;
+                } else {
+                    self.send(Frame::TreeRouted(packet), peer)?;
                 }
             }
             Frame::SnekRouted(packet) => {
-                if let Some(peer) = self.next_snek_hop(&packet, false, true).await {
-                    let peer = peer;
-                    if peer == *self.public_key {
-                        self.send_to_local(Frame::SnekRouted(packet)).await;
-                        return Ok(());
-                    } else {
-                        self.send(Frame::SnekRouted(packet), peer).await?;
-                    }
+                let peer = self
+                    .next_snek_hop(&packet, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.send_to_local(Frame::SnekRouted(packet))?;
                 } else {
-                    trace!("No next hop for SnekPacket.");
+                    self.send(Frame::SnekRouted(packet), peer)?;
                 }
             }
             Frame::TreeAnnouncement(announcement) => {
-                self.handle_tree_announcement(announcement, from).await;
+                self.handle_tree_announcement(announcement, from);
             }
 
             Frame::SnekBootstrap(bootstrap) => {
-                let next_hop = self.next_snek_hop(&bootstrap, true, false).await.unwrap();
-                if next_hop == *self.public_key {
-                    self.handle_bootstrap(bootstrap).await;
+                let next_hop = self
+                    .next_snek_hop(&bootstrap, true, false)
+                    .ok_or(RouterError::NoRoute)?;
+                if next_hop == self.public_key {
+                    self.handle_bootstrap(bootstrap);
                 } else {
                     trace!("Forwarding SnekBootstrap.");
-                    self.send(Frame::SnekBootstrap(bootstrap), next_hop).await?;
+                    self.send(Frame::SnekBootstrap(bootstrap), next_hop)?;
                 }
             }
             Frame::SnekBootstrapACK(ack) => {
-                let next_hop = self.next_tree_hop(&ack, from).await;
-                match next_hop {
-                    Some(next_hop) => {
-                        if next_hop == *self.public_key {
-                            self.handle_bootstrap_ack(ack).await;
-                        } else {
-                            trace!("Forwarding SnekBootstrapAck.");
-                            self.send(Frame::SnekBootstrapACK(ack), next_hop).await?;
-                        }
-                    }
-                    None => {
-                        trace!("No next hop for SnekBootstrapAck.");
-                    }
+                let next_hop = self.next_tree_hop(&ack, from).ok_or(RouterError::NoRoute)?;
+                if next_hop == self.public_key {
+                    self.handle_bootstrap_ack(ack)?;
+                } else {
+                    trace!("Forwarding SnekBootstrapAck.");
+                    self.send(Frame::SnekBootstrapACK(ack), next_hop)?;
                 }
             }
             Frame::SnekSetup(setup) => {
-                let from_port = self.port(from).await.unwrap();
-                let next_hop = self.next_tree_hop(&setup, from).await.unwrap();
-                let next_hop_port = self.port(next_hop).await.unwrap();
-                self.handle_setup(from_port, setup, next_hop_port).await;
+                let from_port = self.port(from).ok_or(RouterError::UnknownPeer)?;
+                // If there is no next hop the setup is handled as if it had to be
+                // forwarded to ourselves, which rejects the path.
+                let next_hop_port = match self.next_tree_hop(&setup, from) {
+                    Some(next_hop) => self.port(next_hop).ok_or(RouterError::UnknownPeer)?,
+                    None => 0,
+                };
+                self.handle_setup(from_port, setup, next_hop_port)?;
             }
             Frame::SnekSetupACK(ack) => {
-                let port = self.port(from).await.unwrap();
-                self.handle_setup_ack(port, ack).await;
+                let port = self.port(from).ok_or(RouterError::UnknownPeer)?;
+                self.handle_setup_ack(port, ack)?;
             }
             Frame::SnekTeardown(teardown) => {
-                let port = self.port(from).await.unwrap();
-                self.handle_teardown(port, teardown).await;
+                let port = self.port(from).ok_or(RouterError::UnknownPeer)?;
+                self.handle_teardown(port, teardown);
+            }
+            Frame::CoordinatesRequest(request) => {
+                let peer = self
+                    .next_snek_hop(&request, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_coordinates_request(request)?;
+                } else {
+                    self.send(Frame::CoordinatesRequest(request), peer)?;
+                }
+            }
+            Frame::HybridRouted(packet) => {
+                if packet.destination_key == self.public_key {
+                    return self.send_to_local(Frame::SnekRouted(packet.into_snek()));
+                }
+                match self.next_tree_hop(&packet, from) {
+                    Some(peer) if peer != self.public_key => {
+                        self.send(Frame::HybridRouted(packet), peer)?;
+                    }
+                    _ => {
+                        // The coordinates don't lead to the destination anymore.
+                        trace!("Routing {:?} further over SNEK", packet);
+                        self.metrics
+                            .record(|metrics| metrics.tree_routing_fallbacks += 1);
+                        return self.handle_frame(Frame::SnekRouted(packet.into_snek()), from);
+                    }
+                }
+            }
+            Frame::Probe(probe) => {
+                let peer = self
+                    .next_snek_hop(&probe, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.reply_to_probe(&probe, 0)?;
+                } else {
+                    if probe.trace && from != self.public_key {
+                        let port = self.port(peer).ok_or(RouterError::UnknownPeer)?;
+                        if let Err(e) = self.reply_to_probe(&probe, port) {
+                            debug!("Could not answer probe of {:?}: {}", probe.source_key, e);
+                        }
+                    }
+                    self.send(Frame::Probe(probe), peer)?;
+                }
+            }
+            Frame::ProbeReply(reply) => {
+                let peer = self
+                    .next_snek_hop(&reply, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_probe_reply(reply);
+                } else {
+                    self.send(Frame::ProbeReply(reply), peer)?;
+                }
+            }
+            Frame::CoordinatesResponse(response) => {
+                let peer = self
+                    .next_snek_hop(&response, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_coordinates_response(response);
+                } else {
+                    self.send(Frame::CoordinatesResponse(response), peer)?;
+                }
+            }
+            Frame::Broadcast(broadcast) => self.handle_broadcast(broadcast, from)?,
+            Frame::DhtPut(put) => {
+                // The node that no other node follows more closely is the home node.
+                let peer = self
+                    .next_snek_hop(&put, false, false)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_dht_put(put)?;
+                } else {
+                    self.send(Frame::DhtPut(put), peer)?;
+                }
+            }
+            Frame::DhtGet(get) => {
+                let peer = self
+                    .next_snek_hop(&get, false, false)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_dht_get(get)?;
+                } else {
+                    self.send(Frame::DhtGet(get), peer)?;
+                }
+            }
+            Frame::DhtGetResponse(response) => {
+                let peer = self
+                    .next_snek_hop(&response, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_dht_get_response(response);
+                } else {
+                    self.send(Frame::DhtGetResponse(response), peer)?;
+                }
+            }
+            Frame::Mail(mail) => {
+                // Mail for an offline node ends at the node that follows its key.
+                let peer = self
+                    .next_snek_hop(&mail, false, false)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_mail(mail)?;
+                } else {
+                    self.send(Frame::Mail(mail), peer)?;
+                }
+            }
+            Frame::MailReply(reply) => {
+                let peer = self
+                    .next_snek_hop(&reply, false, true)
+                    .ok_or(RouterError::NoRoute)?;
+                if peer == self.public_key {
+                    self.handle_mail_reply(reply);
+                } else {
+                    self.send(Frame::MailReply(reply), peer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Forwards a broadcast to the parent and the children of this node, except to the
+    /// peer it came from, and hands it to the client. Copies of broadcasts that were
+    /// handled before are dropped.
+    fn handle_broadcast(
+        &mut self,
+        broadcast: Broadcast,
+        from: PublicKey,
+    ) -> Result<(), RouterError> {
+        let id = (broadcast.source_key, broadcast.id);
+        if self.broadcasts.contains_key(&id) {
+            trace!("Dropping copy of broadcast {:?}", id);
+            return Ok(());
+        }
+        if !broadcast.verify() {
+            trace!("Dropping broadcast {:?} with a bad signature", id);
+            return Ok(());
+        }
+        self.broadcasts
+            .insert(id, Instant::now() + BROADCAST_MEMORY);
+        for peer in self.tree_neighbours() {
+            if peer == from {
+                continue;
+            }
+            if let Err(e) = self.send(Frame::Broadcast(broadcast.clone()), peer) {
+                debug!("Could not forward broadcast to {:?}: {}", peer, e);
+            }
+        }
+        if from != self.public_key {
+            self.send_to_local(Frame::Broadcast(broadcast))?;
+        }
+        Ok(())
+    }
+    /// The peers that are the parent or a child of this node in the spanning tree.
+    /// A peer is a child if its last announcement was signed by this node before it.
+    fn tree_neighbours(&self) -> Vec<PublicKey> {
+        let root = self.current_root();
+        self.peers()
+            .into_iter()
+            .filter(|peer| {
+                *peer == self.parent()
+                    || self.announcements.get(peer).is_some_and(|announcement| {
+                        let signatures = &announcement.signatures;
+                        announcement.root.public_key == root.public_key
+                            && signatures.len() >= 2
+                            && signatures[signatures.len() - 2].signing_public_key
+                                == self.public_key
+                    })
+            })
+            .collect()
+    }
+    fn expire_broadcasts(&mut self) {
+        let now = Instant::now();
+        self.broadcasts.retain(|_, forget| *forget > now);
+    }
+    fn dht_put(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<DhtRecord, RouterError> {
+        if key.len() > MAX_DHT_KEY_LENGTH {
+            return Err(RouterError::EncodingError("Key is too long"));
+        }
+        if value.len() > MAX_DHT_VALUE_SIZE {
+            return Err(RouterError::EncodingError("Value is too large"));
+        }
+        let ttl = ttl.min(self.config.dht_max_ttl);
+        let record = DhtRecord::new(key, value, ttl, &self.private_key);
+        self.put_dht_record(record.clone())?;
+        Ok(record)
+    }
+    fn put_dht_record(&mut self, record: DhtRecord) -> Result<(), RouterError> {
+        if !record.verify() {
+            return Err(RouterError::EncodingError("Invalid record"));
+        }
+        let put = DhtPut {
+            destination_key: dht_position(&record.key),
+            source_key: self.public_key,
+            replicas: self.config.dht_replicas,
+            record: Box::new(record),
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        self.handle_frame(Frame::DhtPut(put), own_key)
+    }
+    /// Stores the record of a put that arrived at its home node or at a node that
+    /// keeps a copy, and places the next copy.
+    ///
+    /// Copies are placed at the successors of the home node, or at its predecessors
+    /// if it has the highest key. Nodes that already have the record don't pass it
+    /// on, which ends the placement when there are fewer nodes than copies.
+    fn handle_dht_put(&mut self, put: DhtPut) -> Result<(), RouterError> {
+        let record = put.record;
+        let max_expiry = unix_time() + (self.config.dht_max_ttl + DHT_CLOCK_SKEW).as_secs();
+        if !record.verify() || record.is_expired() || record.expires > max_expiry {
+            debug!("Dropping invalid DHT record of {:?}", record.writer);
+            return Ok(());
+        }
+        if !DhtPolicy::admits(&self.dht_policies, &self.dht.get(&record.key), &record) {
+            debug!(
+                "Dropping DHT record of {:?} that a policy refused",
+                record.writer
+            );
+            return Ok(());
+        }
+        if !self
+            .dht
+            .insert(*record.clone(), self.config.dht_max_records)
+        {
+            trace!("Not storing DHT record of {:?}", record.writer);
+            return Ok(());
+        }
+        let replicas = put.replicas.min(self.config.dht_replicas);
+        if replicas == 0 {
+            return Ok(());
+        }
+        let successor = self.ascending_path.as_ref().map(|path| path.target);
+        let predecessor = self.descending_path.as_ref().map(|path| path.origin);
+        let next = if put.destination_key != self.public_key {
+            successor.or(predecessor)
+        } else if put.source_key < self.public_key {
+            successor
+        } else {
+            predecessor
+        };
+        let Some(next) = next else {
+            return Ok(());
+        };
+        let copy = DhtPut {
+            destination_key: next,
+            source_key: self.public_key,
+            replicas: replicas - 1,
+            record,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        self.handle_frame(Frame::DhtPut(copy), own_key)
+    }
+    fn dht_get(
+        &mut self,
+        key: Vec<u8>,
+    ) -> Result<(u64, oneshot::Receiver<Vec<DhtRecord>>, Instant), RouterError> {
+        if key.len() > MAX_DHT_KEY_LENGTH {
+            return Err(RouterError::EncodingError("Key is too long"));
+        }
+        let id = thread_rng().gen();
+        let (sender, response) = oneshot::channel();
+        let get = DhtGet::new(dht_position(&key), id, key.clone(), &self.private_key);
+        self.dht_gets.insert(
+            id,
+            PendingDhtGet {
+                key,
+                response: sender,
+            },
+        );
+        let own_key = self.public_key;
+        if let Err(e) = self.handle_frame(Frame::DhtGet(get), own_key) {
+            self.dht_gets.remove(&id);
+            return Err(e);
+        }
+        Ok((id, response, Instant::now() + self.config.dht_timeout))
+    }
+    /// Answers a get with as many records of its key as fit into a frame, in the order
+    /// their writers were first seen.
+    fn handle_dht_get(&mut self, get: DhtGet) -> Result<(), RouterError> {
+        // The answer can be much larger than the request, so it only goes to nodes
+        // that asked for it themselves.
+        if !get.verify() {
+            trace!("Dropping DHT get {} with a bad signature", get.id);
+            return Ok(());
+        }
+        let mut space = u16::MAX as usize - (10 + 32 + 32 + 8);
+        let records = self
+            .dht
+            .get(&get.key)
+            .into_iter()
+            .filter(|record| {
+                let fits = record.encoded_len() <= space;
+                if fits {
+                    space -= record.encoded_len();
+                }
+                fits
+            })
+            .collect();
+        let response = DhtGetResponse {
+            destination_key: get.source_key,
+            source_key: self.public_key,
+            id: get.id,
+            records,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        self.handle_frame(Frame::DhtGetResponse(response), own_key)
+    }
+    fn handle_dht_get_response(&mut self, response: DhtGetResponse) {
+        let Some(get) = self.dht_gets.remove(&response.id) else {
+            trace!("Dropping answer to unknown DHT get {}", response.id);
+            return;
+        };
+        let records = response
+            .records
+            .into_iter()
+            .filter(|record| record.key == get.key && record.verify() && !record.is_expired())
+            .collect();
+        let _ = get.response.send(records);
+    }
+    fn send_mail(
+        &mut self,
+        public_key: PublicKey,
+        payload: Vec<u8>,
+    ) -> Result<(u64, oneshot::Receiver<MailStatus>, Instant), RouterError> {
+        if payload.len() > MAX_MAIL_SIZE {
+            return Err(RouterError::EncodingError("Mail is too large"));
+        }
+        let sealed = seal(&payload, &self.private_key, &public_key).ok_or(
+            RouterError::EncodingError("Mail can't be sealed for this key"),
+        )?;
+        let id = thread_rng().gen();
+        let (sender, reply) = oneshot::channel();
+        let mail = MailPacket::new(public_key, id, sealed, &self.private_key);
+        self.mail_sends.insert(id, sender);
+        let own_key = self.public_key;
+        if let Err(e) = self.handle_frame(Frame::Mail(mail), own_key) {
+            self.mail_sends.remove(&id);
+            return Err(e);
+        }
+        Ok((id, reply, Instant::now() + self.config.mail_timeout))
+    }
+    /// Puts mail for this node into the inbox. Mail for an offline node is held if
+    /// this node is a mailbox and has room for it. The sender is told either way.
+    /// Mail that its source didn't sign is dropped without a reply.
+    fn handle_mail(&mut self, mail: MailPacket) -> Result<(), RouterError> {
+        if !mail.verify() {
+            debug!(
+                "Dropping mail with an invalid signature of {:?}",
+                mail.source_key
+            );
+            return Ok(());
+        }
+        let is_duplicate = self
+            .delivered_mail
+            .get(&mail.source_key)
+            .is_some_and(|ids| ids.contains_key(&mail.id));
+        let status = if mail.destination_key == self.public_key && is_duplicate {
+            // Mailboxes send mail again until they get the reply, which may have been
+            // lost. The mail itself is only delivered once.
+            trace!("Dropping copy of mail {} of {:?}", mail.id, mail.source_key);
+            MailStatus::Delivered
+        } else if mail.destination_key == self.public_key {
+            match open(&mail.payload, &self.private_key, &mail.source_key) {
+                Some(payload) => {
+                    let delivered = Mail {
+                        source_key: mail.source_key,
+                        payload,
+                    };
+                    // Only mail that the inbox took counts as delivered.
+                    if self.inbox.try_send(delivered).is_ok() {
+                        self.delivered_mail
+                            .entry(mail.source_key)
+                            .or_default()
+                            .insert(mail.id, Instant::now() + self.config.mailbox_ttl);
+                        MailStatus::Delivered
+                    } else {
+                        debug!("Inbox is full. Refusing mail of {:?}", mail.source_key);
+                        MailStatus::Refused
+                    }
+                }
+                None => {
+                    debug!("Mail {} of {:?} can't be opened", mail.id, mail.source_key);
+                    MailStatus::Refused
+                }
+            }
+        } else if !self.config.mailbox {
+            MailStatus::Refused
+        } else {
+            let stored = StoredMail {
+                source_key: mail.source_key,
+                id: mail.id,
+                payload: mail.payload,
+                signature: mail.signature,
+                expires: Instant::now() + self.config.mailbox_ttl,
+                sent: None,
+            };
+            let quota = self.config.mailbox_quota;
+            let capacity = self.config.mailbox_capacity;
+            if self
+                .mailbox
+                .insert(mail.destination_key, stored, quota, capacity)
+            {
+                MailStatus::Stored
+            } else {
+                MailStatus::Refused
+            }
+        };
+        trace!(
+            "Mail {} of {:?} for {:?}: {:?}",
+            mail.id,
+            mail.source_key,
+            mail.destination_key,
+            status
+        );
+        let reply = MailReply {
+            destination_key: mail.reply_key,
+            source_key: self.public_key,
+            id: mail.id,
+            status,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        self.handle_frame(Frame::MailReply(reply), own_key)
+    }
+    fn expire_delivered_mail(&mut self) {
+        let now = Instant::now();
+        for ids in self.delivered_mail.values_mut() {
+            ids.retain(|_, forget| *forget > now);
+        }
+        self.delivered_mail.retain(|_, ids| !ids.is_empty());
+    }
+    fn handle_mail_reply(&mut self, reply: MailReply) {
+        // The destination of held mail took it, or another mailbox holds it now.
+        if reply.status != MailStatus::Refused && self.mailbox.confirm(&reply.source_key, reply.id)
+        {
+            debug!("Held mail {} reached {:?}", reply.id, reply.source_key);
+            return;
+        }
+        let Some(sender) = self.mail_sends.remove(&reply.id) else {
+            trace!("Dropping reply to unknown mail {}", reply.id);
+            return;
+        };
+        let _ = sender.send(reply.status);
+    }
+    /// Sends held mail on once its destination is back, that is once it is a peer
+    /// of this node or the node before or after it on the SNEK. The mail is sent
+    /// again every `mail_timeout` until the destination confirms it or it expires.
+    fn forward_mail(&mut self) {
+        let own_key = self.public_key;
+        let successor = self.ascending_path.as_ref().map(|path| path.target);
+        let predecessor = self.descending_path.as_ref().map(|path| path.origin);
+        for destination in self.mailbox.destinations() {
+            let is_back = self.peers.contains_key(&destination)
+                || successor == Some(destination)
+                || predecessor == Some(destination);
+            if !is_back {
+                continue;
+            }
+            for stored in self.mailbox.due(&destination, self.config.mail_timeout) {
+                debug!("Forwarding held mail {} to {:?}", stored.id, destination);
+                let mail = MailPacket {
+                    destination_key: destination,
+                    source_key: stored.source_key,
+                    reply_key: own_key,
+                    id: stored.id,
+                    payload: stored.payload,
+                    signature: stored.signature,
+                    hop_limit: DEFAULT_HOP_LIMIT,
+                };
+                if let Err(e) = self.handle_frame(Frame::Mail(mail), own_key) {
+                    debug!("Could not forward mail to {:?}: {}", destination, e);
+                }
+            }
+        }
+    }
+    /// Routes session traffic over the tree if the coordinates of its destination
+    /// are known. Otherwise they are looked up for the following frames.
+    fn upgrade_to_tree(&mut self, packet: SnekPacket) -> Frame {
+        if !self.config.hybrid_routing || packet.destination_key == self.public_key {
+            return Frame::SnekRouted(packet);
+        }
+        if let Some(coordinates) = self.cached_coordinates(&packet.destination_key) {
+            let hybrid = Frame::HybridRouted(HybridPacket::new(packet, coordinates));
+            // The coordinates make the frame longer, which may not fit anymore.
+            return match hybrid {
+                Frame::HybridRouted(packet) if hybrid.encoded_len() > MAX_FRAME_LENGTH => {
+                    Frame::SnekRouted(packet.into_snek())
+                }
+                hybrid => hybrid,
+            };
+        }
+        if let Err(e) = self.request_coordinates(packet.destination_key) {
+            trace!(
+                "Could not look up coordinates of {:?}: {}",
+                packet.destination_key,
+                e
+            );
+        }
+        Frame::SnekRouted(packet)
+    }
+    fn send_probe(
+        &mut self,
+        public_key: PublicKey,
+        trace: bool,
+        answers: UnboundedSender<ProbeAnswer>,
+    ) -> Result<(u64, Instant), RouterError> {
+        let id = thread_rng().gen();
+        let sent = Instant::now();
+        self.probes.insert(id, PendingProbe { sent, answers });
+        let probe = Probe {
+            destination_key: public_key,
+            source_key: self.public_key,
+            id,
+            trace,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        if let Err(e) = self.handle_frame(Frame::Probe(probe), own_key) {
+            self.probes.remove(&id);
+            return Err(e);
+        }
+        Ok((id, sent + self.config.probe_timeout))
+    }
+    /// Answers a probe that is being forwarded through `port`, or that arrived
+    /// at its destination if `port` is 0.
+    fn reply_to_probe(&mut self, probe: &Probe, port: Port) -> Result<(), RouterError> {
+        let reply = ProbeReply {
+            destination_key: probe.source_key,
+            source_key: self.public_key,
+            id: probe.id,
+            distance: DEFAULT_HOP_LIMIT.saturating_sub(probe.hop_limit),
+            port,
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        let own_key = self.public_key;
+        self.handle_frame(Frame::ProbeReply(reply), own_key)
+    }
+    fn handle_probe_reply(&mut self, reply: ProbeReply) {
+        let Some(probe) = self.probes.get(&reply.id) else {
+            trace!("Dropping reply to unknown probe {}", reply.id);
+            return;
+        };
+        let answer = ProbeAnswer {
+            distance: reply.distance,
+            hop: Hop {
+                public_key: reply.source_key,
+                port: reply.port,
+                rtt: probe.sent.elapsed(),
+            },
+            reached: reply.port == 0,
+        };
+        let _ = probe.answers.send(answer);
+    }
+    fn handle_coordinates_request(
+        &mut self,
+        request: CoordinatesRequest,
+    ) -> Result<(), RouterError> {
+        trace!("Answering coordinate request of {:?}", request.source_key);
+        let response = CoordinatesResponse::new(
+            request.source_key,
+            request.id,
+            self.current_root(),
+            self.coordinates(),
+            &self.private_key,
+        );
+        let public_key = self.public_key;
+        self.handle_frame(Frame::CoordinatesResponse(response), public_key)
+    }
+    fn handle_coordinates_response(&mut self, response: CoordinatesResponse) {
+        let root = self.current_root();
+        // Only accept answers that we asked for so that nobody can fill our cache,
+        // and only from the node itself so that nobody can redirect its traffic.
+        let Entry::Occupied(entry) = self.coordinates_lookups.entry(response.source_key) else {
+            trace!(
+                "Dropping unrequested coordinates of {:?}",
+                response.source_key
+            );
+            return;
+        };
+        if response.id != entry.get().id || !response.verify() {
+            trace!(
+                "Dropping coordinates of {:?} that don't answer our request",
+                response.source_key
+            );
+            return;
+        }
+        if response.root != root {
+            trace!(
+                "Dropping coordinates of {:?} for a different root",
+                response.source_key
+            );
+            return;
+        }
+        let waiting = entry.remove().waiting;
+        self.coordinates_cache.insert(
+            response.source_key,
+            CachedCoordinates {
+                coordinates: response.coordinates.clone(),
+                root: response.root,
+                expires: Instant::now() + self.config.coordinates_cache_ttl,
+            },
+        );
+        for sender in waiting {
+            let _ = sender.send(response.coordinates.clone());
+        }
+    }
+    fn lookup_coordinates(
+        &mut self,
+        public_key: PublicKey,
+    ) -> Result<CoordinatesLookup, RouterError> {
+        if public_key == self.public_key {
+            return Ok(CoordinatesLookup::Cached(self.coordinates()));
+        }
+        if let Some(coordinates) = self.cached_coordinates(&public_key) {
+            return Ok(CoordinatesLookup::Cached(coordinates));
+        }
+        self.request_coordinates(public_key)?;
+        let (sender, response) = oneshot::channel();
+        if let Some(pending) = self.coordinates_lookups.get_mut(&public_key) {
+            pending.waiting.push(sender);
+        }
+        Ok(CoordinatesLookup::Pending {
+            response,
+            deadline: Instant::now() + self.config.coordinates_lookup_timeout,
+        })
+    }
+    /// Returns the cached coordinates of `public_key` if they haven't expired and
+    /// are relative to the current root.
+    fn cached_coordinates(&mut self, public_key: &PublicKey) -> Option<Coordinates> {
+        let cached = self.coordinates_cache.get(public_key)?;
+        if cached.root == self.current_root() && cached.expires > Instant::now() {
+            return Some(cached.coordinates.clone());
+        }
+        self.coordinates_cache.remove(public_key);
+        None
+    }
+    /// Asks the node with `public_key` for its coordinates unless that was already
+    /// done and the answer is still outstanding.
+    fn request_coordinates(&mut self, public_key: PublicKey) -> Result<(), RouterError> {
+        if let Entry::Vacant(entry) = self.coordinates_lookups.entry(public_key) {
+            let id = thread_rng().gen();
+            entry.insert(PendingCoordinates {
+                id,
+                waiting: vec![],
+            });
+            let request = CoordinatesRequest {
+                destination_key: public_key,
+                source_key: self.public_key,
+                id,
+                hop_limit: DEFAULT_HOP_LIMIT,
+            };
+            let own_key = self.public_key;
+            if let Err(e) = self.handle_frame(Frame::CoordinatesRequest(request), own_key) {
+                debug!("Could not send coordinate request: {}", e);
+                self.coordinates_lookups.remove(&public_key);
+                return Err(e);
             }
         }
         Ok(())
     }
-    async fn next_tree_hop(&self, frame: &impl TreeRouted, from: PublicKey) -> Option<PublicKey> {
-        if frame.destination_coordinates() == self.coordinates().await {
+    /// Forgets cached coordinates that expired or that are relative to an old root
+    /// and lookups whose callers stopped waiting. Requests that were sent without a
+    /// caller waiting for them are forgotten as well so that they are sent again.
+    fn expire_coordinates(&mut self) {
+        let root = self.current_root();
+        let now = Instant::now();
+        self.coordinates_cache
+            .retain(|_, cached| cached.root == root && cached.expires > now);
+        self.coordinates_lookups.retain(|_, pending| {
+            pending.waiting.retain(|sender| !sender.is_closed());
+            !pending.waiting.is_empty()
+        });
+    }
+    fn next_tree_hop(&self, frame: &impl TreeRouted, from: PublicKey) -> Option<PublicKey> {
+        let destination = frame.destination_coordinates();
+        let current_announcement = self.current_announcement();
+        let coordinates = current_announcement.coords();
+        if destination == coordinates {
             return Some(self.public_key());
         }
-        let our_distance = frame
-            .destination_coordinates()
-            .distance_to(&self.coordinates().await);
+        let our_distance = destination.distance_to(&coordinates);
         if our_distance == 0 {
             return Some(self.public_key());
         }
@@ -557,15 +1690,16 @@ impl Router {
         let mut best_peer = None;
         let mut best_distance = our_distance;
         let mut best_ordering = SequenceNumber::MAX;
-        for peer in self.peers().await {
+        for (peer, announcement) in &self.announcements {
+            let peer = *peer;
             if peer == from {
                 continue; // don't route back where the packet came from
             }
-            if let None = self.tree_announcement(peer).await {
-                continue; // ignore peers that haven't sent us announcements
+            if !self.peers.contains_key(&peer) {
+                continue; // ignore announcements of peers that are being disconnected
             }
-            if let Some(announcement) = self.tree_announcement(peer).await {
-                if self.current_root().await != announcement.root {
+            {
+                if current_announcement.root != announcement.root {
                     continue; // ignore peers that are following a different root or seq
                 }
 
@@ -607,16 +1741,16 @@ impl Router {
         }
         better_candidate
     }
-    async fn handle_tree_announcement(&self, mut frame: TreeAnnouncement, from: PublicKey) {
-        frame.receive_time = SystemTime::now();
-        frame.receive_order = self.next_ordering().await;
+    fn handle_tree_announcement(&mut self, mut frame: TreeAnnouncement, from: PublicKey) {
+        frame.receive_time = Instant::now();
+        frame.receive_order = self.next_ordering();
 
         if !frame.is_clean(&from) {
             debug!("Announcement integrity check failed. Dropping");
             return;
         }
 
-        if let Some(announcement) = self.tree_announcement(from).await {
+        if let Some(announcement) = self.tree_announcement(from) {
             if frame.has_same_root_key(&announcement) {
                 if frame.replayed_old_sequence(&announcement) {
                     debug!("Announcement replayed old sequence. Dropping");
@@ -625,48 +1759,44 @@ impl Router {
             }
         }
         trace!("Storing announcement {:?}", frame);
-        self.set_tree_announcement(from, frame.clone()).await;
-        if !self.reparent_timer_expired().await {
+        self.set_tree_announcement(from, frame.clone());
+        if !self.reparent_timer_expired() {
             debug!("Waiting to reparent");
             return;
         }
-        if from == self.parent().await {
+        if from == self.parent() {
             trace!("Announcement came from parent");
             if frame.is_loop_of_child(&self.public_key()) {
                 // SelectNewParentWithWait
                 trace!("Announcement contains loop");
-                self.become_root().await;
-                self.reparent(true).await;
+                self.become_root();
+                self.reparent(true);
                 return;
             }
-            if frame.root.public_key < self.current_announcement().await.root.public_key {
+            if frame.root.public_key < self.current_announcement().root.public_key {
                 // SelectNewParentWithWait
                 debug!("Announcement has weaker root");
-                self.become_root().await;
-                self.reparent(true).await;
+                self.become_root();
+                self.reparent(true);
                 return;
             }
-            if frame.root.public_key > self.current_announcement().await.root.public_key {
+            if frame.root.public_key > self.current_announcement().root.public_key {
                 // AcceptUpdate
                 debug!("Announcement has stronger root. Forwarding to peers");
-                self.send_tree_announcements_to_all(self.current_announcement().await)
-                    .await;
+                self.send_tree_announcements_to_all(self.current_announcement());
                 return;
             }
-            if frame.root.public_key == self.current_announcement().await.root.public_key {
-                if frame.root.sequence_number
-                    > self.current_announcement().await.root.sequence_number
-                {
+            if frame.root.public_key == self.current_announcement().root.public_key {
+                if frame.root.sequence_number > self.current_announcement().root.sequence_number {
                     // AcceptUpdate
                     trace!("Announcement has higher sequence. Forwarding to peers");
-                    self.send_tree_announcements_to_all(self.current_announcement().await)
-                        .await;
+                    self.send_tree_announcements_to_all(self.current_announcement());
                     return;
                 }
                 // SelectNewParentWithWait
                 trace!("Announcement replayed current sequence");
-                self.become_root().await;
-                self.reparent(true).await;
+                self.become_root();
+                self.reparent(true);
                 return;
             }
         } else {
@@ -676,86 +1806,119 @@ impl Router {
                 trace!("Announcement contains loop. Dropping");
                 return;
             }
-            if frame.root.public_key > self.current_announcement().await.root.public_key {
+            if frame.root.public_key > self.current_announcement().root.public_key {
                 // AcceptNewParent
                 trace!("Announcement has stronger root. Forwarding to peers");
-                self.set_parent(from.clone()).await;
-                let announcement = self.current_announcement().await;
-                self.send_tree_announcements_to_all(announcement).await;
+                self.set_parent(from.clone());
+                let announcement = self.current_announcement();
+                self.send_tree_announcements_to_all(announcement);
                 return;
             }
-            if frame.root.public_key < self.current_announcement().await.root.public_key {
+            if frame.root.public_key < self.current_announcement().root.public_key {
                 // InformPeerOfStrongerRoot
                 trace!("Announcement has weaker root. Sending my announcement");
-                self.send_tree_announcement(from, self.current_announcement().await)
-                    .await;
+                self.send_tree_announcement(from, self.current_announcement());
                 return;
             }
-            if frame.root.public_key == self.current_announcement().await.root.public_key {
+            if frame.root.public_key == self.current_announcement().root.public_key {
                 // SelectNewParent
                 trace!("Announcement has same root");
-                self.reparent(false).await;
+                self.reparent(false);
                 return;
             }
         }
     }
-    async fn current_announcement(&self) -> TreeAnnouncement {
-        if let Some(announcement) = self.tree_announcement(self.parent().await).await {
+    fn current_announcement(&self) -> TreeAnnouncement {
+        if let Some(announcement) = self.tree_announcement(self.parent()) {
             announcement.clone()
         } else {
             TreeAnnouncement {
                 root: Root {
                     public_key: self.public_key(),
-                    sequence_number: self.current_sequence().await,
+                    sequence_number: self.current_sequence(),
                 },
                 signatures: vec![],
-                receive_time: SystemTime::now(),
-                receive_order: self.current_ordering().await,
+                receive_time: Instant::now(),
+                receive_order: self.current_ordering(),
             }
         }
     }
-    async fn coordinates(&self) -> Coordinates {
-        self.current_announcement().await.coords()
+    fn coordinates(&self) -> Coordinates {
+        self.current_announcement().coords()
+    }
+    fn peer_infos(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| PeerInfo {
+                public_key: *public_key,
+                port: peer.port,
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.port);
+        peers
+    }
+    fn tree_info(&self) -> TreeInfo {
+        let announcement = self.current_announcement();
+        TreeInfo {
+            root: announcement.root.public_key,
+            root_sequence: announcement.root.sequence_number,
+            parent: self.parent(),
+            coordinates: announcement.coords(),
+        }
+    }
+    fn snek_info(&self) -> SnekInfo {
+        SnekInfo {
+            ascending: self.ascending_path.as_ref().map(|path| path.target),
+            descending: self.descending_path.as_ref().map(|path| path.origin),
+            paths: self.paths.len(),
+        }
     }
-    async fn send_tree_announcements_to_all(&self, announcement: TreeAnnouncement) {
+    fn send_tree_announcements_to_all(&self, announcement: TreeAnnouncement) {
         trace!("Sending tree announcements to all peers");
-        for peer in self.peers().await {
-            self.send_tree_announcement(peer, announcement.clone())
-                .await;
+        for peer in self.peers() {
+            self.send_tree_announcement(peer, announcement.clone());
         }
     }
-    async fn send_tree_announcement(&self, to: PublicKey, announcement: TreeAnnouncement) {
-        let port = self.port(to).await.unwrap();
+    fn send_tree_announcement(&self, to: PublicKey, announcement: TreeAnnouncement) {
+        let port = match self.port(to) {
+            Some(port) => port,
+            None => {
+                debug!("Could not send announcement to unknown peer {:?}", to);
+                self.metrics.record_error(&RouterError::UnknownPeer);
+                return;
+            }
+        };
         let mut announcement = announcement;
         announcement.append_signature(self.private_key.clone(), port);
         trace!("Sending tree announcement to port {}", port);
-        if let Err(e) = self.send(Frame::TreeAnnouncement(announcement), to).await {
+        if let Err(e) = self.send(Frame::TreeAnnouncement(announcement), to) {
             debug!("Could not send announcement: {:?}", e);
         }
     }
-    async fn new_tree_announcement(&self) -> TreeAnnouncement {
+    fn new_tree_announcement(&mut self) -> TreeAnnouncement {
         TreeAnnouncement {
             root: Root {
                 public_key: self.public_key(),
-                sequence_number: self.next_sequence().await,
+                sequence_number: self.next_sequence(),
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         }
     }
-    async fn parent_selection(&self) -> bool {
+    fn parent_selection(&mut self) -> bool {
         trace!("Running parent selection...");
-        if self.public_key() > self.current_root().await.public_key {
+        if self.public_key() > self.current_root().public_key {
             debug!("My key is stronger than current root");
-            self.become_root().await;
+            self.become_root();
         }
-        let mut best_root = self.current_root().await;
+        let mut best_root = self.current_root();
         let mut best_peer = None;
         let mut best_order = SequenceNumber::MAX;
-        for peer in self.peers().await {
-            if let Some(announcement) = self.tree_announcement(peer).await {
-                if announcement.receive_time.elapsed().unwrap() > ANNOUNCEMENT_TIMEOUT {
+        for peer in self.peers() {
+            if let Some(announcement) = self.tree_announcement(peer) {
+                if announcement.receive_time.elapsed() > ANNOUNCEMENT_TIMEOUT {
                     continue;
                 }
                 if announcement.is_loop_of_child(&self.public_key()) {
@@ -778,75 +1941,75 @@ impl Router {
         }
         return match best_peer {
             Some(best_peer) => {
-                if best_peer == self.parent().await {
+                if best_peer == self.parent() {
                     debug!("Current parent is the best available parent");
                     return false;
                 }
                 let best_peer = best_peer.clone();
-                self.set_parent(best_peer).await;
-                self.send_tree_announcements_to_all(self.current_announcement().await)
-                    .await;
+                self.set_parent(best_peer);
+                self.send_tree_announcements_to_all(self.current_announcement());
                 true
             }
             None => {
                 trace!("I am root");
-                self.become_root().await;
+                self.become_root();
                 false
             }
         };
     }
-    async fn become_root(&self) {
+    fn become_root(&mut self) {
         trace!("Becoming root");
-        self.set_parent(self.public_key().clone()).await;
+        self.set_parent(self.public_key().clone());
     }
-    async fn reparent(&self, wait: bool) {
-        let router = self.clone();
-        tokio::spawn(async move {
-            if wait {
+    /// Runs parent selection. If `wait` is set, parent selection is deferred until
+    /// `REPARENT_WAIT_TIME` has passed, unless such a deferred run is already pending.
+    fn reparent(&mut self, wait: bool) {
+        if wait {
+            if self.pending_reparent.is_none() {
                 trace!("Waiting to reparent");
-                router.set_reparent_timer().await;
-                sleep(REPARENT_WAIT_TIME).await;
-            }
-            trace!("Re-parenting");
-            if router.parent_selection().await {
-                router.bootstrap_now().await;
+                self.set_reparent_timer();
+                self.pending_reparent = Some(Instant::now() + REPARENT_WAIT_TIME);
             }
-        });
+            return;
+        }
+        trace!("Re-parenting");
+        if self.parent_selection() {
+            self.bootstrap_now();
+        }
     }
-    async fn current_root(&self) -> Root {
-        self.current_announcement().await.root
+    fn current_root(&self) -> Root {
+        self.current_announcement().root
     }
-    async fn maintain_tree(&self) {
-        if self.i_am_root().await {
-            let announcement = self.new_tree_announcement().await;
-            self.send_tree_announcements_to_all(announcement).await;
+    fn maintain_tree(&mut self) {
+        if self.i_am_root() {
+            let announcement = self.new_tree_announcement();
+            self.send_tree_announcements_to_all(announcement);
         }
-        self.reparent(true).await;
+        self.reparent(true);
     }
-    async fn i_am_root(&self) -> bool {
-        self.public_key() == self.parent().await
+    fn i_am_root(&self) -> bool {
+        self.public_key() == self.parent()
     }
 
     /// `maintain_snake` is responsible for working out if we need to send bootstraps
     /// or to clean up any old paths.
-    async fn maintain_snek(&self) {
+    fn maintain_snek(&mut self) {
         // Work out if we are able to bootstrap. If we are the root node then
         // we don't send bootstraps, since there's nowhere for them to go —
         // bootstraps are sent up to the next ascending node, but as the root,
         // we already have the highest key on the network.
-        let root_announcement = self.current_announcement().await;
-        let can_bootstrap = self.parent().await != self.public_key()
+        let root_announcement = self.current_announcement();
+        let can_bootstrap = self.parent() != self.public_key()
             && root_announcement.root.public_key != self.public_key();
         let mut will_bootstrap = false;
 
         // The ascending node is the node with the next highest key.
-        if let Some(asc) = &*self.ascending_path.read().await {
+        if let Some(asc) = self.ascending_path.clone() {
             if !asc.valid() {
                 // The ascending path entry has expired, so tear it down and then
                 // see if we can bootstrap again.
                 trace!("Ascending path expired. Tearing down and potentially bootstrapping.");
-                self.send_teardown_for_existing_path(0, asc.index.public_key, asc.index.path_id)
-                    .await;
+                self.send_teardown_for_existing_path(0, asc.index.public_key, asc.index.path_id);
                 will_bootstrap = can_bootstrap;
             }
             if asc.root != root_announcement.root {
@@ -868,46 +2031,43 @@ impl Router {
         }
 
         // The descending node is the node with the next lowest key.
-        if let Some(desc) = &*self.descending_path.read().await {
+        if let Some(desc) = self.descending_path.clone() {
             if !desc.valid() {
                 // The descending path has expired, so tear it down and then that should
                 // prompt the remote side into sending a new bootstrap to set up a new
                 // path, if they are still alive.
                 trace!("Tearing down expired descending path. Wait for bootstrap.");
-                self.send_teardown_for_existing_path(0, desc.index.public_key, desc.index.path_id)
-                    .await;
+                self.send_teardown_for_existing_path(0, desc.index.public_key, desc.index.path_id);
             }
         }
 
         // Clean up any paths that were installed more than 5 seconds ago but haven't
         // been activated by a setup ACK.
-        for (index, path) in &*self.paths.read().await {
-            if !path.active && path.last_seen.elapsed().unwrap() > Duration::from_secs(5) {
-                trace!("Tearing down old inactive path. {:?}", path);
-                let router = self.clone();
-                let index = index.clone();
-                tokio::spawn(async move {
-                    router
-                        .send_teardown_for_existing_path(0, index.public_key, index.path_id)
-                        .await;
-                });
-            }
+        let inactive_paths: Vec<SnekPathIndex> = self
+            .paths
+            .iter()
+            .filter(|(_, path)| !path.active && path.last_seen.elapsed() > Duration::from_secs(5))
+            .map(|(index, _)| index.clone())
+            .collect();
+        for index in inactive_paths {
+            trace!("Tearing down old inactive path. {:?}", index);
+            self.send_teardown_for_existing_path(0, index.public_key, index.path_id);
         }
 
         // If one of the previous conditions means that we need to bootstrap, then
         // send the actual bootstrap message into the network.
         if will_bootstrap {
-            self.bootstrap_now().await;
+            self.bootstrap_now();
         }
     }
 
     /// `bootstrap_now` is responsible for sending a bootstrap massage to the network
-    async fn bootstrap_now(&self) {
+    fn bootstrap_now(&mut self) {
         trace!("Bootstrapping ...");
         // If we are the root node then there's no point in trying to bootstrap. We
         // already have the highest public key on the network so a bootstrap won't be
         // able to go anywhere in ascending order.
-        if self.parent().await == self.public_key() {
+        if self.parent() == self.public_key() {
             trace!("Not bootstrapping because I am root");
             return;
         }
@@ -917,8 +2077,8 @@ impl Router {
         // the path was set up) then we don't need to send another bootstrap message just
         // yet. We'll either wait for the path to be torn down, expire or for the tree to
         // change.
-        let announcement = self.current_announcement().await;
-        if let Some(asc) = &*self.ascending_path.read().await {
+        let announcement = self.current_announcement();
+        if let Some(asc) = &self.ascending_path {
             if asc.root == announcement.root {
                 trace!("Not bootstrapping because a valid ascending path is set");
                 return;
@@ -929,15 +2089,16 @@ impl Router {
         // number in the update so that the remote side can determine if we are both using
         // the same root node when processing the update.
         let frame = SnekBootstrap {
-            root: self.current_root().await,
+            root: self.current_root(),
             destination_key: self.public_key(),
-            source: self.coordinates().await,
+            source: self.coordinates(),
             path_id: thread_rng().gen(),
+            hop_limit: DEFAULT_HOP_LIMIT,
         };
 
-        if let Some(peer) = self.next_snek_hop(&frame, true, false).await {
+        if let Some(peer) = self.next_snek_hop(&frame, true, false) {
             trace!("Bootstrapping path {} ", frame.path_id);
-            if let Err(e) = self.send(Frame::SnekBootstrap(frame), peer).await {
+            if let Err(e) = self.send(Frame::SnekBootstrap(frame), peer) {
                 debug!("Could not send bootstrap: {:?}", e);
             }
         } else {
@@ -945,7 +2106,7 @@ impl Router {
         }
     }
 
-    async fn next_snek_hop(
+    fn next_snek_hop(
         &self,
         frame: &impl SnekRouted,
         bootstrap: bool,
@@ -969,47 +2130,38 @@ impl Router {
         // Check if we can use the path to the root via our parent as a starting
         // point. We can't do this if we are the root node as there would be no
         // parent or ascending paths.
-        if self.parent().await != self.public_key() {
+        if self.parent() != self.public_key() {
+            let announcement = self.current_announcement();
             if bootstrap && best_key == destination_key {
                 // Bootstraps always start working towards their root so that they
                 // go somewhere rather than getting stuck.
-                best_key = self.current_announcement().await.root.public_key;
-                best_peer = Some(self.parent().await)
-            }
-            if Self::dht_ordered(
-                &best_key,
-                &destination_key,
-                &self.current_announcement().await.root.public_key,
-            ) {
+                best_key = announcement.root.public_key;
+                best_peer = Some(self.parent())
+            }
+            if Self::dht_ordered(&best_key, &destination_key, &announcement.root.public_key) {
                 // The destination key is higher than our own key, so start using
                 // the path to the root as the first candidate.
-                best_key = self.current_announcement().await.root.public_key;
-                best_peer = Some(self.parent().await)
+                best_key = announcement.root.public_key;
+                best_peer = Some(self.parent())
             }
 
             // Check our direct ancestors in the tree, that is, all nodes between
             // ourselves and the root node via the parent port.
-            for ancestor in self
-                .current_announcement()
-                .await
-                .signatures
-                .iter()
-                .map(|x| x.signing_public_key)
-            {
+            for ancestor in announcement.signatures.iter().map(|x| x.signing_public_key) {
                 if !bootstrap && ancestor == destination_key && best_key != destination_key {
                     best_key = ancestor;
-                    best_peer = Some(self.parent().await);
+                    best_peer = Some(self.parent());
                 }
                 if Self::dht_ordered(&destination_key, &ancestor, &best_key) {
                     best_key = ancestor;
-                    best_peer = Some(self.parent().await);
+                    best_peer = Some(self.parent());
                 }
             }
         }
 
         // Check all of the ancestors of our direct peers too, that is, all nodes
         // between our direct peer and the root node.
-        for (peer, announcement) in &*self.announcements.read().await {
+        for (peer, announcement) in &self.announcements {
             for hop in &announcement.signatures {
                 if !bootstrap
                     && hop.signing_public_key == destination_key
@@ -1030,31 +2182,33 @@ impl Router {
         // example, only in this case it would make more sense to route directly
         // to the peer via our peering with them as opposed to routing via our
         // parent port.
-        for peer in self.peers().await {
-            if best_key == peer {
-                best_key = peer;
-                best_peer = Some(peer);
-            }
+        if self.peers.contains_key(&best_key) {
+            best_peer = Some(best_key);
         }
 
         // Check our DHT entries. In particular, we are only looking at the source
         // side of the DHT paths. Since setups travel from the lower key to the
         // higher one, this is effectively looking for paths that descend through
         // keyspace toward lower keys rather than ascend toward higher ones.
-        for (key, entry) in &*self.paths.read().await {
+        for (key, entry) in &self.paths {
             if !entry.valid() || entry.source == 0 {
                 continue;
             }
             if !bootstrap && !entry.active {
                 continue;
             }
+            // Skip paths via peers that are being disconnected right now.
+            let source = match self.get_peer_on_port(entry.source) {
+                Some(source) => source,
+                None => continue,
+            };
             if !bootstrap && key.public_key == destination_key && best_key != destination_key {
                 best_key = key.public_key;
-                best_peer = Some(self.get_peer_on_port(entry.source).await.unwrap());
+                best_peer = Some(source);
             }
             if Self::dht_ordered(&destination_key, &key.public_key, &best_key) {
                 best_key = key.public_key;
-                best_peer = Some(self.get_peer_on_port(entry.source).await.unwrap());
+                best_peer = Some(source);
             }
         }
         best_peer
@@ -1062,11 +2216,11 @@ impl Router {
 
     /// `handle_bootstrap` is called in response to receiving a bootstrap packet.
     /// This function will send a bootstrap ACK back to the sender.
-    async fn handle_bootstrap(&self, frame: SnekBootstrap) {
+    fn handle_bootstrap(&self, frame: SnekBootstrap) {
         // Check that the root key and sequence number in the update match our
         // current root, otherwise we won't be able to route back to them using
         // tree routing anyway. If they don't match, silently drop the bootstrap.
-        if self.current_root().await == frame.root {
+        if self.current_root() == frame.root {
             // In response to a bootstrap, we'll send back a bootstrap ACK packet to
             // the sender. We'll include our own root details in the ACK.
             let frame = SnekBootstrapAck {
@@ -1075,14 +2229,14 @@ impl Router {
                 // destination of the ACK packet to that.
                 destination_coordinates: frame.source.clone(),
                 destination_key: frame.destination_key,
-                source_coordinates: self.coordinates().await,
+                source_coordinates: self.coordinates(),
                 source_key: self.public_key(),
-                root: self.current_root().await,
+                root: self.current_root(),
                 path_id: frame.path_id,
             };
-            if let Some(peer) = self.next_tree_hop(&frame, self.public_key()).await {
+            if let Some(peer) = self.next_tree_hop(&frame, self.public_key()) {
                 trace!("Responding to SnekBootstrap with Ack.");
-                if let Err(e) = self.send(Frame::SnekBootstrapACK(frame), peer).await {
+                if let Err(e) = self.send(Frame::SnekBootstrapACK(frame), peer) {
                     debug!("Could not send BootstrapAck: {:?}", e);
                 }
             } else {
@@ -1097,9 +2251,7 @@ impl Router {
     /// packet. This function will work out whether the remote node is a suitable
     /// candidate to set up an outbound path to, and if so, will send path setup
     /// packets to the network.
-    async fn handle_bootstrap_ack(&self, ack: SnekBootstrapAck) {
-        let ascending_path = self.ascending_path.read().await;
-        let mut paths = self.paths.write().await;
+    fn handle_bootstrap_ack(&mut self, ack: SnekBootstrapAck) -> Result<(), RouterError> {
         let mut update = false;
         if ack.source_key == self.public_key() {
             // We received a bootstrap ACK from ourselves. This shouldn't happen,
@@ -1107,12 +2259,12 @@ impl Router {
             // a routing loop has occurred somewhere. Don't act on the bootstrap
             // in that case.
             trace!("Received own bootstrap ack. Dropping");
-        } else if ack.root != self.current_root().await {
+        } else if ack.root != self.current_root() {
             // The root key in the bootstrap ACK doesn't match our own key, or the
             // sequence doesn't match, so it is quite possible that routing setup packets
             // using tree routing would fail.
             trace!("Bootstrap-ack doesn't have same root. Dropping");
-        } else if let Some(asc) = &*ascending_path {
+        } else if let Some(asc) = &self.ascending_path {
             if asc.valid() {
                 // We already have an ascending entry and it hasn't expired yet.
                 if asc.origin == ack.source_key && ack.path_id != asc.index.path_id {
@@ -1141,7 +2293,7 @@ impl Router {
                     update = true;
                 }
             }
-        } else if None == *ascending_path {
+        } else if self.ascending_path.is_none() {
             // We don't have an ascending entry
             if self.public_key() < ack.source_key {
                 // We don't know about an ascending node and at the moment we don't know
@@ -1157,7 +2309,7 @@ impl Router {
             trace!("Dropping non-valid bootstrap-ack.");
         }
         if !update {
-            return;
+            return Ok(());
         }
         // Setup messages routed using tree routing. The destination key is set in the
         // header so that a node can determine if the setup message arrived at the
@@ -1165,13 +2317,13 @@ impl Router {
         // public key, since this is the lower of the two keys that intermediate nodes
         // will populate into their routing tables.
         let setup = SnekSetup {
-            root: self.current_root().await,
+            root: self.current_root(),
             destination: ack.source_coordinates,
             destination_key: ack.source_key.clone(),
             source_key: self.public_key(),
             path_id: ack.path_id,
         };
-        let next_hop = self.next_tree_hop(&setup, self.public_key()).await;
+        let next_hop = self.next_tree_hop(&setup, self.public_key());
 
         // Importantly, we will only create a DHT entry if it appears as though our next
         // hop has actually accepted the packet. Otherwise we'll create a path entry and
@@ -1180,15 +2332,15 @@ impl Router {
             None => {
                 // No peer was identified, which shouldn't happen.
                 debug!("No next tree hop for SnekSetup");
-                return;
+                Err(RouterError::NoRoute)
             }
             Some(next_peer) => {
                 if self.public_key() == next_peer {
                     // The peer is local, which shouldn't happen.
                     debug!("Next hop for SnekSetup is self. Dropping.");
-                    return;
+                    return Err(RouterError::NoRoute);
                 }
-                if let Err(e) = self.send(Frame::SnekSetup(setup), next_peer).await {
+                if let Err(e) = self.send(Frame::SnekSetup(setup), next_peer) {
                     debug!("Could not send SnekSetup: {:?}", e);
                 }
                 let index = SnekPathIndex {
@@ -1200,8 +2352,8 @@ impl Router {
                     origin: ack.source_key,
                     target: ack.source_key,
                     source: 0,
-                    destination: self.port(next_peer).await.unwrap().clone(),
-                    last_seen: SystemTime::now(),
+                    destination: self.port(next_peer).ok_or(RouterError::UnknownPeer)?,
+                    last_seen: Instant::now(),
                     root: ack.root.clone(),
                     active: false,
                 };
@@ -1209,22 +2361,24 @@ impl Router {
                 // we do want to make sure we don't have any old paths to other nodes
                 // that *aren't* the new ascending node lying around. This helps to avoid
                 // routing loops.
-                for (dht_key, entry) in &*paths {
-                    if entry.source == 0 && dht_key.public_key != ack.source_key {
-                        self.send_teardown_for_existing_path(
-                            0,
-                            dht_key.public_key,
-                            dht_key.path_id,
-                        )
-                        .await;
-                    }
+                let old_paths: Vec<SnekPathIndex> = self
+                    .paths
+                    .iter()
+                    .filter(|(dht_key, entry)| {
+                        entry.source == 0 && dht_key.public_key != ack.source_key
+                    })
+                    .map(|(dht_key, _)| dht_key.clone())
+                    .collect();
+                for dht_key in old_paths {
+                    self.send_teardown_for_existing_path(0, dht_key.public_key, dht_key.path_id);
                 }
                 // Install the new route into the DHT.
                 trace!("Adding route {:?} to DHT", index);
-                paths.insert(index, entry.clone());
+                self.paths.insert(index, entry.clone());
                 let mut entry = entry;
                 entry.active = true;
-                *self.candidate.write().await = Some(entry);
+                self.candidate = Some(entry);
+                Ok(())
             }
         }
     }
@@ -1232,13 +2386,15 @@ impl Router {
     /// `handle_setup` is called in response to receiving setup packets. Note that
     /// these packets are handled even as we forward them, as setup packets should be
     /// processed by each node on the path.
-    async fn handle_setup(&self, from: Port, rx: SnekSetup, next_hop: Port) {
-        let mut descending_path = self.descending_path.write().await;
-        let mut paths = self.paths.write().await;
-        if self.current_root().await != rx.root {
+    fn handle_setup(
+        &mut self,
+        from: Port,
+        rx: SnekSetup,
+        next_hop: Port,
+    ) -> Result<(), RouterError> {
+        if self.current_root() != rx.root {
             trace!("SnekSetup has different root. Responding with Teardown");
-            self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from)
-                .await;
+            self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from)?;
         }
         let index = SnekPathIndex {
             public_key: rx.source_key,
@@ -1249,19 +2405,33 @@ impl Router {
         // then send back a teardown to the sender notifying them that there was a
         // problem. This will probably trigger a new setup, but that's OK, it should
         // have a new path ID.
-        if paths.contains_key(&index) {
+        if self.paths.contains_key(&index) {
             trace!("Trigger new SnekSetup because of already existing path.");
-            self.send_teardown_for_existing_path(0, rx.source_key, rx.path_id)
-                .await;
-            self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from)
-                .await;
-            return;
+            self.send_teardown_for_existing_path(0, rx.source_key, rx.path_id);
+            return self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from);
+        }
+        // A single key shouldn't be able to fill our routing table with paths.
+        if from != 0
+            && self
+                .paths
+                .keys()
+                .filter(|index| index.public_key == rx.source_key)
+                .count()
+                >= self.config.max_paths_per_origin
+        {
+            debug!(
+                "Too many paths for {:?}. Rejecting SnekSetup",
+                rx.source_key
+            );
+            self.metrics
+                .record(|metrics| metrics.path_limit_rejections += 1);
+            return self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from);
         }
         // If we're at the destination of the setup then update our predecessor
         // with information from the bootstrap.
         if rx.destination_key == self.public_key() {
             let mut update = false;
-            if self.current_root().await != rx.root {
+            if self.current_root() != rx.root {
                 // The root key in the bootstrap ACK doesn't match our own key, or the
                 // sequence doesn't match, so it is quite possible that routing setup packets
                 // using tree routing would fail.
@@ -1269,7 +2439,7 @@ impl Router {
             } else if !(rx.source_key < self.public_key()) {
                 // The bootstrapping key should be less than ours but it isn't.
                 trace!("Key of bootstrapping node is not less then self. Dropping.");
-            } else if let Some(desc) = &*descending_path {
+            } else if let Some(desc) = &self.descending_path {
                 if desc.valid() {
                     // We already have a descending entry and it hasn't expired.
                     if desc.index.public_key == rx.source_key && rx.path_id != desc.index.path_id {
@@ -1297,7 +2467,7 @@ impl Router {
                         trace!("Descending entry expired but received SnekSetup isn't dht-ordered. Dropping.");
                     }
                 }
-            } else if let None = *descending_path {
+            } else if self.descending_path.is_none() {
                 // We don't have a descending entry
                 if rx.source_key < self.public_key() {
                     // The bootstrapping key is less than ours so we'll acknowledge it.
@@ -1315,17 +2485,14 @@ impl Router {
                 trace!("Dropping non-valid SnekSetup.");
             }
             if !update {
-                self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from)
-                    .await;
-                return;
+                return self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from);
             }
-            if let Some(previous_path) = &descending_path.clone() {
+            if let Some(previous_path) = self.descending_path.clone() {
                 self.send_teardown_for_existing_path(
                     0,
                     previous_path.index.public_key,
                     previous_path.index.path_id,
-                )
-                .await;
+                );
             }
             let entry = SnekPath {
                 index: index.clone(),
@@ -1333,41 +2500,38 @@ impl Router {
                 target: rx.destination_key,
                 source: from.clone(),
                 destination: 0,
-                last_seen: SystemTime::now(),
+                last_seen: Instant::now(),
                 root: rx.root.clone(),
                 active: true,
             };
-            paths.insert(index.clone(), entry.clone());
-            *descending_path = Some(entry.clone());
+            self.paths.insert(index.clone(), entry.clone());
+            self.descending_path = Some(entry.clone());
             // Send back a setup ACK to the remote side
             let setup_ack = SnekSetupAck {
                 root: rx.root.clone(),
                 destination_key: rx.source_key,
                 path_id: index.path_id,
             };
-            if let Err(e) = self
-                .send(
-                    Frame::SnekSetupACK(setup_ack),
-                    self.get_peer_on_port(entry.source).await.unwrap(),
-                )
-                .await
-            {
+            let source = self
+                .get_peer_on_port(entry.source)
+                .ok_or(RouterError::UnknownPeer)?;
+            if let Err(e) = self.send(Frame::SnekSetupACK(setup_ack), source) {
                 debug!("Could not send SnekSetupAck: {:?}", e);
             }
-            return;
+            return Ok(());
         }
 
         // Try to forward the setup onto the next node first. If we
         // can't do that then there's no point in keeping the path.
-        let next_peer = self.get_peer_on_port(next_hop).await.unwrap();
+        let next_peer = self
+            .get_peer_on_port(next_hop)
+            .ok_or(RouterError::UnknownPeer)?;
         if next_peer == self.public_key() {
             debug!("Can't forward SnekSetup. Tearing down path.");
-            self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from)
-                .await;
-            return;
+            return self.send_teardown_for_rejected_path(rx.source_key, rx.path_id, from);
         } else {
             trace!("Forwarding SnekSetup.");
-            if let Err(e) = self.send(Frame::SnekSetup(rx.clone()), next_peer).await {
+            if let Err(e) = self.send(Frame::SnekSetup(rx.clone()), next_peer) {
                 debug!("Could not forward SnekSetup: {:?}", e);
             }
         }
@@ -1379,43 +2543,36 @@ impl Router {
             target: rx.destination_key,
             source: from,          // node with lower of the two keys
             destination: next_hop, // node with higher of the two keys
-            last_seen: SystemTime::now(),
+            last_seen: Instant::now(),
             root: rx.root,
             active: false,
         };
-        paths.insert(index, entry);
+        self.paths.insert(index, entry);
+        Ok(())
     }
 
     /// `handle_setup_ack` is called in response to a setup ACK
     /// packet from the network
-    async fn handle_setup_ack(&self, from: Port, rx: SnekSetupAck) {
-        let mut paths = self.paths.write().await;
+    fn handle_setup_ack(&mut self, from: Port, rx: SnekSetupAck) -> Result<(), RouterError> {
         // Look up to see if we have a matching route. The route must be not active
         // (i.e. we haven't received a setup ACK for it yet) and must have arrived
         // from the port that the entry was populated with.
-        for (key, entry) in &mut *paths {
+        let mut forward_to = vec![];
+        for (key, entry) in &mut self.paths {
             if entry.active || key.public_key != rx.destination_key || key.path_id != rx.path_id {
                 continue;
             }
             if from == entry.destination || from == 0 {
                 if entry.source != 0 {
-                    trace!("Forwarding SetupAck.");
-                    let entry_source = self.get_peer_on_port(entry.source).await.unwrap();
-                    if let Err(e) = self
-                        .send(Frame::SnekSetupACK(rx.clone()), entry_source)
-                        .await
-                    {
-                        debug!("Could not forward SetupAck {:?}", e);
-                    }
+                    forward_to.push(entry.source);
                 }
                 trace!("Activating Path {:?}", key);
                 entry.active = true;
-                let mut candidate = self.candidate.write().await;
-                if let Some(candidate_path) = &*candidate {
+                if let Some(candidate_path) = &self.candidate {
                     if entry == candidate_path {
                         trace!("Setting ascending path to {:?}", entry);
-                        *self.ascending_path.write().await = Some(entry.clone());
-                        *candidate = None;
+                        self.ascending_path = Some(entry.clone());
+                        self.candidate = None;
                     } else {
                         trace!("Path");
                         trace!("{:?}", entry);
@@ -1425,65 +2582,68 @@ impl Router {
                 }
             }
         }
+        for port in forward_to {
+            trace!("Forwarding SetupAck.");
+            let entry_source = self
+                .get_peer_on_port(port)
+                .ok_or(RouterError::UnknownPeer)?;
+            if let Err(e) = self.send(Frame::SnekSetupACK(rx.clone()), entry_source) {
+                debug!("Could not forward SetupAck {:?}", e);
+            }
+        }
+        Ok(())
     }
 
     /// `handle_teardown` is called in response to receiving a teardown
     /// packet from the network
-    async fn handle_teardown(&self, from: Port, rx: SnekTeardown) -> Vec<Port> {
+    fn handle_teardown(&mut self, from: Port, rx: SnekTeardown) -> Vec<Port> {
         self.teardown_path(from, rx.destination_key, rx.path_id)
-            .await
     }
 
     /// `teardown_path` processes a teardown message by tearing down any
     /// related routes, returning a slice of next-hop candidates that the
     /// teardown must be forwarded to.
-    async fn teardown_path(
-        &self,
-        from: Port,
-        path_key: PublicKey,
-        path_id: SnekPathId,
-    ) -> Vec<Port> {
-        let mut ascending_path = self.ascending_path.write().await;
-        let mut descending_path = self.descending_path.write().await;
-        let mut paths = self.paths.write().await;
-        if let Some(asc) = ascending_path.clone() {
+    fn teardown_path(&mut self, from: Port, path_key: PublicKey, path_id: SnekPathId) -> Vec<Port> {
+        if let Some(asc) = self.ascending_path.clone() {
             if asc.index.public_key == path_key && asc.index.path_id == path_id {
                 if from == asc.destination || from == 0 {
                     trace!("Removing ascending path.");
-                    paths.remove(&asc.index);
-                    *ascending_path = None;
+                    self.paths.remove(&asc.index);
+                    self.ascending_path = None;
                     return vec![asc.destination];
                 }
             }
         }
-        if let Some(desc) = descending_path.clone() {
+        if let Some(desc) = self.descending_path.clone() {
             if desc.index.public_key == path_key && desc.index.path_id == path_id {
-                if from == desc.destination || from == 0 {
+                // Teardowns of the descending path come from the node it starts at,
+                // so they arrive through its `source` port and go back there.
+                if from == desc.source || from == 0 {
                     trace!("Removing descending path.");
-                    paths.remove(&desc.index);
-                    *descending_path = None;
-                    return vec![desc.destination];
+                    self.paths.remove(&desc.index);
+                    self.descending_path = None;
+                    return vec![desc.source];
                 }
             }
         }
-        for (key, value) in paths.to_owned() {
+        for (key, value) in self.paths.clone() {
             if key.public_key == path_key && key.path_id == path_id {
                 if from == 0 {
                     // happens when we're tearing down an existing duplicate path
                     trace!("Removing duplicate route from DHT.");
-                    paths.remove(&key);
+                    self.paths.remove(&key);
                     return vec![value.destination, value.source];
                 }
                 if from == value.source {
                     // from network, return the opposite direction
                     trace!("Removing route from DHT.");
-                    paths.remove(&key);
+                    self.paths.remove(&key);
                     return vec![value.destination];
                 }
                 if from == value.destination {
                     // from network, return the opposite direction
                     trace!("Removing route from DHT.");
-                    paths.remove(&key);
+                    self.paths.remove(&key);
                     return vec![value.source];
                 }
             }
@@ -1491,39 +2651,51 @@ impl Router {
         return vec![];
     }
 
-    async fn send_teardown_for_existing_path(
-        &self,
+    fn send_teardown_for_existing_path(
+        &mut self,
         from: Port,
         path_key: PublicKey,
         path_id: SnekPathId,
     ) {
-        let router = self.clone();
-        tokio::spawn(async move {
-            let frame = router.get_teardown(path_key, path_id).await;
-            for next_hop in router.teardown_path(from, path_key, path_id).await {
-                let peer = router.get_peer_on_port(next_hop).await.unwrap();
-                if let Err(e) = router.send(Frame::SnekTeardown(frame.clone()), peer).await {
-                    debug!("Could not send Teardown: {:?}", e);
-                }
+        if let Err(e) = self.teardown_existing_path(from, path_key, path_id) {
+            debug!("Could not send Teardown: {:?}", e);
+            self.metrics.record_error(&e);
+        }
+    }
+    fn teardown_existing_path(
+        &mut self,
+        from: Port,
+        path_key: PublicKey,
+        path_id: SnekPathId,
+    ) -> Result<(), RouterError> {
+        let frame = self.get_teardown(path_key, path_id);
+        for next_hop in self.teardown_path(from, path_key, path_id) {
+            let peer = self
+                .get_peer_on_port(next_hop)
+                .ok_or(RouterError::UnknownPeer)?;
+            if let Err(e) = self.send(Frame::SnekTeardown(frame.clone()), peer) {
+                debug!("Could not send Teardown: {:?}", e);
             }
-        });
+        }
+        Ok(())
     }
-    async fn send_teardown_for_rejected_path(
+    fn send_teardown_for_rejected_path(
         &self,
         path_key: PublicKey,
         path_id: SnekPathId,
         via: Port,
-    ) {
-        let frame = self.get_teardown(path_key, path_id).await;
-        let peer = self.get_peer_on_port(via).await.unwrap();
-        if let Err(e) = self.send(Frame::SnekTeardown(frame), peer).await {
+    ) -> Result<(), RouterError> {
+        let frame = self.get_teardown(path_key, path_id);
+        let peer = self.get_peer_on_port(via).ok_or(RouterError::UnknownPeer)?;
+        if let Err(e) = self.send(Frame::SnekTeardown(frame), peer) {
             debug!("Could not send Teardown: {:?}", e);
         }
+        Ok(())
     }
 
-    async fn get_teardown(&self, path_key: PublicKey, path_id: SnekPathId) -> SnekTeardown {
+    fn get_teardown(&self, path_key: PublicKey, path_id: SnekPathId) -> SnekTeardown {
         SnekTeardown {
-            root: self.current_root().await,
+            root: self.current_root(),
             destination_key: path_key,
             path_id,
         }
@@ -1540,38 +2712,97 @@ impl Router {
 mod test {
     use super::*;
     use crate::connection::new_test_connection;
+    use crate::frames::DEFAULT_HOP_LIMIT;
     use crate::tree::RootAnnouncementSignature;
-    use crate::PineconeCodec;
+    use crate::wire_frame::PineconeCodec;
+    use ed25519_consensus::Signature;
     use env_logger::WriteStyle;
     use futures::{StreamExt, TryStreamExt};
     use log::{trace, LevelFilter};
-    use std::time::{Duration, SystemTime};
-    use tokio::net::tcp::OwnedReadHalf;
+    use std::time::Duration;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
     use tokio::sync::mpsc::channel;
     use tokio::sync::mpsc::error::TryRecvError;
     use tokio::time::sleep;
-    use tokio_util::codec::FramedRead;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    impl Router {
+        async fn add_peer(
+            &self,
+            peer: PublicKey,
+            port: Port,
+            upload: Upload,
+            download: Download,
+            send_first_announcement: bool,
+        ) {
+            self.call(move |state| {
+                state.add_peer(peer, port, upload, download, send_first_announcement)
+            })
+            .await
+            .unwrap();
+        }
+        async fn handle_frame(&self, frame: Frame, from: PublicKey) -> Result<(), RouterError> {
+            self.call(move |state| state.handle_frame(frame, from))
+                .await?
+        }
+        async fn bootstrap_now(&self) {
+            self.call(|state| state.bootstrap_now()).await.unwrap();
+        }
+        async fn set_parent(&self, parent: PublicKey) {
+            self.call(move |state| state.parent = parent).await.unwrap();
+        }
+        async fn current_root(&self) -> Root {
+            self.call(|state| state.current_root()).await.unwrap()
+        }
+        async fn path(&self, index: SnekPathIndex) -> Option<SnekPath> {
+            self.call(move |state| state.paths.get(&index).cloned())
+                .await
+                .unwrap()
+        }
+        async fn ascending_path(&self) -> Option<SnekPath> {
+            self.call(|state| state.ascending_path.clone())
+                .await
+                .unwrap()
+        }
+        async fn descending_path(&self) -> Option<SnekPath> {
+            self.call(|state| state.descending_path.clone())
+                .await
+                .unwrap()
+        }
+        async fn candidate(&self) -> Option<SnekPath> {
+            self.call(|state| state.candidate.clone()).await.unwrap()
+        }
+    }
 
     async fn get_test_router_with_peer(
         router_key: SigningKey,
         peer_key: SigningKey,
         send_first_announcement: bool,
-    ) -> (Router, Box<FramedRead<OwnedReadHalf, PineconeCodec>>) {
+    ) -> (
+        Router,
+        Box<FramedWrite<OwnedWriteHalf, PineconeCodec>>,
+        Box<FramedRead<OwnedReadHalf, PineconeCodec>>,
+    ) {
         let (r1_upload_sender, r1_upload_receiver) = channel(100);
         let (r1_download_sender, r1_download_receiver) = channel(100);
-        let router1 = Router::new(router_key, r1_download_sender, r1_upload_receiver);
+        let router1 = Router::new(
+            router_key,
+            RouterConfig::default(),
+            r1_download_sender,
+            r1_upload_receiver,
+        );
         let (r1_u, r1_d, mut r2_u, mut r2_d) = new_test_connection().await;
         let r1 = router1.start().await;
         router1
             .add_peer(
-                peer_key.verification_key().to_bytes(),
+                PublicKey::from(peer_key.verification_key()),
                 1,
                 r1_u,
                 r1_d,
                 send_first_announcement,
             )
             .await;
-        (router1, r2_d)
+        (router1, r2_u, r2_d)
     }
     #[tokio::test]
     async fn send_first_announcement() {
@@ -1583,9 +2814,9 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (r, mut rd) = get_test_router_with_peer(key1.clone(), key2.clone(), true).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (r, ru, mut rd) = get_test_router_with_peer(key1.clone(), key2.clone(), true).await;
         match rd.next().await {
             Some(Ok(Frame::TreeAnnouncement(ann))) => {
                 assert_eq!(
@@ -1617,16 +2848,16 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([2; 32]);
         let key2 = SigningKey::from([1; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (r, mut rd) = get_test_router_with_peer(key2, key1.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (r, ru, mut rd) = get_test_router_with_peer(key2, key1.clone(), false).await;
         let mut announcement = TreeAnnouncement {
             root: Root {
                 public_key: pub1,
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key1.clone(), 1);
@@ -1663,16 +2894,16 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([2; 32]);
         let key2 = SigningKey::from([1; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (r, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (r, ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
         let mut announcement = TreeAnnouncement {
             root: Root {
                 public_key: pub2,
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key2.clone(), 1);
@@ -1704,18 +2935,21 @@ mod test {
     async fn set_first_announcement(r: &mut Router, peer_key: SigningKey) {
         let mut announcement = TreeAnnouncement {
             root: Root {
-                public_key: peer_key.verification_key().to_bytes(),
+                public_key: PublicKey::from(peer_key.verification_key()),
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 1,
         };
         announcement.append_signature(peer_key.clone(), 1);
-        r.announcements
-            .write()
-            .await
-            .insert(peer_key.verification_key().to_bytes(), announcement);
+        r.call(move |state| {
+            state
+                .announcements
+                .insert(PublicKey::from(peer_key.verification_key()), announcement)
+        })
+        .await
+        .unwrap();
     }
     #[tokio::test]
     async fn drop_announcement_with_loop() {
@@ -1727,9 +2961,10 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
         set_first_announcement(&mut r, key1.clone()).await;
         let mut announcement = TreeAnnouncement {
             root: Root {
@@ -1737,13 +2972,14 @@ mod test {
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key2.clone(), 1);
         announcement.append_signature(key1.clone(), 1);
         let frame = Frame::TreeAnnouncement(announcement);
         r.handle_frame(frame, pub1).await;
+        drop(ru);
         assert!(rd.next().await.is_none());
     }
     #[tokio::test]
@@ -1756,11 +2992,16 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([2; 32]);
         let key2 = SigningKey::from([1; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
         let (r1_upload_sender, r1_upload_receiver) = channel(100);
         let (r1_download_sender, r1_download_receiver) = channel(100);
-        let router1 = Router::new(key1, r1_download_sender, r1_upload_receiver);
+        let router1 = Router::new(
+            key1,
+            RouterConfig::default(),
+            r1_download_sender,
+            r1_upload_receiver,
+        );
         let (r1_u, r1_d, mut r2_u, mut r2_d) = new_test_connection().await;
         let r1 = router1.start().await;
         router1.add_peer(pub2, 1, r1_u, r1_d, true).await;
@@ -1791,7 +3032,7 @@ mod test {
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key2.clone(), 1);
@@ -1873,7 +3114,7 @@ mod test {
             }
         }
         sleep(Duration::from_millis(100)).await;
-        assert!(router1.ascending_path.read().await.is_some());
+        assert!(router1.ascending_path().await.is_some());
     }
     #[tokio::test]
     async fn receive_replayed_sequence_number() {
@@ -1885,9 +3126,9 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
         set_first_announcement(&mut r, key2.clone()).await;
         let mut announcement = TreeAnnouncement {
             root: Root {
@@ -1895,12 +3136,13 @@ mod test {
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key2.clone(), 1);
         r.handle_frame(Frame::TreeAnnouncement(announcement.clone()), pub1)
             .await;
+        drop(ru);
         assert!(rd.next().await.is_none());
     }
     #[tokio::test]
@@ -1913,9 +3155,10 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
         set_first_announcement(&mut r, key1.clone()).await;
         let mut announcement = TreeAnnouncement {
             root: Root {
@@ -1923,31 +3166,36 @@ mod test {
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
         announcement.append_signature(key2.clone(), 1);
         announcement.append_signature(key1.clone(), 1);
         r.handle_frame(Frame::TreeAnnouncement(announcement), pub1)
             .await;
+        drop(ru);
         assert!(rd.next().await.is_none());
     }
     async fn set_second_announcement_for_root(r: &mut Router, peer_key: SigningKey) {
+        let private_key = r.call(|state| state.private_key.clone()).await.unwrap();
         let mut announcement = TreeAnnouncement {
             root: Root {
-                public_key: r.public_key(),
+                public_key: PublicKey::from(private_key.verification_key()),
                 sequence_number: 0,
             },
             signatures: vec![],
-            receive_time: SystemTime::now(),
+            receive_time: Instant::now(),
             receive_order: 0,
         };
-        announcement.append_signature(r.private_key.clone(), 1);
+        announcement.append_signature(private_key, 1);
         announcement.append_signature(peer_key.clone(), 1);
-        r.announcements
-            .write()
-            .await
-            .insert(peer_key.verification_key().to_bytes(), announcement);
+        r.call(move |state| {
+            state
+                .announcements
+                .insert(PublicKey::from(peer_key.verification_key()), announcement)
+        })
+        .await
+        .unwrap();
     }
     #[tokio::test]
     async fn bootstrap() {
@@ -1959,11 +3207,12 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key1.clone(), key2.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key1.clone(), key2.clone(), false).await;
         set_first_announcement(&mut r, key2).await;
-        *r.parent.write().await = pub2;
+        r.set_parent(pub2).await;
         r.bootstrap_now().await;
         let frame = rd.next().await;
         if let Some(Ok(Frame::SnekBootstrap(bootstrap))) = frame {
@@ -1990,10 +3239,12 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let (mut r, mut rd) = get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
         set_first_announcement(&mut r, key1.clone()).await;
-        *r.parent.write().await = key2.verification_key().to_bytes();
+        r.set_parent(PublicKey::from(key2.verification_key())).await;
         r.bootstrap_now().await;
+        drop(ru);
         let frame = rd.next().await;
         if let None = frame {
         } else {
@@ -2010,12 +3261,13 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key2.clone(), key1.clone(), false).await;
         set_first_announcement(&mut r, key1.clone()).await;
         set_second_announcement_for_root(&mut r, key1.clone()).await;
-        *r.parent.write().await = pub2;
+        r.set_parent(pub2).await;
         let bootstrap = SnekBootstrap {
             root: Root {
                 public_key: pub2,
@@ -2024,6 +3276,7 @@ mod test {
             destination_key: pub1,
             source: Coordinates::new(vec![1]),
             path_id: 0,
+            hop_limit: DEFAULT_HOP_LIMIT,
         };
         r.handle_frame(Frame::SnekBootstrap(bootstrap), pub1).await;
         let recv = rd.next().await;
@@ -2054,11 +3307,12 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([2; 32]);
         let key2 = SigningKey::from([1; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key1.clone(), key2.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) =
+            get_test_router_with_peer(key1.clone(), key2.clone(), false).await;
         set_first_announcement(&mut r, key2.clone()).await;
-        *r.parent.write().await = pub2;
+        r.set_parent(pub2).await;
         let ack = SnekBootstrapAck {
             destination_coordinates: Coordinates::new(vec![1]),
             destination_key: pub1,
@@ -2099,14 +3353,14 @@ mod test {
             target: pub2,
             source: 0,
             destination: 1,
-            last_seen: SystemTime::now(),
+            last_seen: Instant::now(),
             root: Root {
                 public_key: pub2,
                 sequence_number: 0,
             },
             active: false,
         };
-        let path = r.paths.read().await.get(&index).cloned().unwrap();
+        let path = r.path(index.clone()).await.unwrap();
         assert_eq!(path.active, entry.active);
         assert_eq!(path.index, entry.index);
         assert_eq!(path.root, entry.root);
@@ -2115,7 +3369,7 @@ mod test {
         assert_eq!(path.origin, entry.origin);
         assert_eq!(path.target, entry.target);
         entry.active = true;
-        let candidate = r.candidate.read().await.clone().unwrap();
+        let candidate = r.candidate().await.unwrap();
         assert_eq!(&candidate.active, &entry.active);
         assert_eq!(&candidate.index, &entry.index);
         assert_eq!(&candidate.root, &entry.root);
@@ -2134,12 +3388,12 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([2; 32]);
         let key2 = SigningKey::from([1; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key2, key1.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) = get_test_router_with_peer(key2, key1.clone(), false).await;
         set_first_announcement(&mut r, key1.clone()).await;
         set_second_announcement_for_root(&mut r, key1.clone()).await;
-        *r.parent.write().await = pub2;
+        r.set_parent(pub2).await;
         let setup = SnekSetup {
             root: Root {
                 public_key: pub2,
@@ -2175,14 +3429,14 @@ mod test {
             target: pub2,
             source: 1,
             destination: 0,
-            last_seen: SystemTime::now(),
+            last_seen: Instant::now(),
             root: Root {
                 public_key: pub2,
                 sequence_number: 0,
             },
             active: true,
         };
-        let path = r.paths.read().await.get(&index).cloned().unwrap();
+        let path = r.path(index.clone()).await.unwrap();
         assert_eq!(path.active, entry.active);
         assert_eq!(path.index, entry.index);
         assert_eq!(path.root, entry.root);
@@ -2191,7 +3445,7 @@ mod test {
         assert_eq!(path.origin, entry.origin);
         assert_eq!(path.target, entry.target);
         entry.active = true;
-        let descending = r.descending_path.read().await.clone().unwrap();
+        let descending = r.descending_path().await.unwrap();
         assert_eq!(&descending.active, &entry.active);
         assert_eq!(&descending.index, &entry.index);
         assert_eq!(&descending.root, &entry.root);
@@ -2201,6 +3455,231 @@ mod test {
         assert_eq!(&descending.target, &entry.target);
     }
     #[tokio::test]
+    async fn hybrid_routing() {
+        let key1 = SigningKey::from([1; 32]);
+        let key2 = SigningKey::from([2; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, _ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        set_first_announcement(&mut r, key2.clone()).await;
+        r.set_parent(pub2).await;
+        let packet = SnekPacket {
+            destination_key: pub2,
+            source_key: pub1,
+            traffic_class: Default::default(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            payload: vec![1],
+        };
+        // Without known coordinates the packet is routed over SNEK and a lookup is started.
+        let local = packet.clone();
+        r.call(move |state| state.handle_local_frame(Frame::SnekRouted(local)))
+            .await
+            .unwrap();
+        let recv = rd.next().await;
+        let Some(Ok(Frame::CoordinatesRequest(request))) = recv else {
+            panic!("Should have gotten CoordinatesRequest but got {:?}", recv);
+        };
+        let recv = rd.next().await;
+        assert!(
+            matches!(recv, Some(Ok(Frame::SnekRouted(_)))),
+            "Should have gotten SnekRouted but got {:?}",
+            recv
+        );
+        // Answers to other requests and answers that another node signed are ignored.
+        let root = r.current_root().await;
+        let response = |id, key: &SigningKey| {
+            let mut response =
+                CoordinatesResponse::new(pub1, id, root.clone(), Coordinates::new(vec![9]), key);
+            response.source_key = pub2;
+            response
+        };
+        for forged in [
+            response(request.id.wrapping_add(1), &key2),
+            response(request.id, &SigningKey::from([3; 32])),
+        ] {
+            r.handle_frame(Frame::CoordinatesResponse(forged), pub2)
+                .await
+                .unwrap();
+        }
+        assert!(!r
+            .call(move |state| state.coordinates_cache.contains_key(&pub2))
+            .await
+            .unwrap());
+        let response =
+            CoordinatesResponse::new(pub1, request.id, root, Coordinates::default(), &key2);
+        r.handle_frame(Frame::CoordinatesResponse(response), pub2)
+            .await
+            .unwrap();
+        // Now the packet is routed over the tree.
+        let local = packet.clone();
+        r.call(move |state| state.handle_local_frame(Frame::SnekRouted(local)))
+            .await
+            .unwrap();
+        let recv = rd.next().await;
+        if let Some(Ok(Frame::HybridRouted(hybrid))) = recv {
+            assert_eq!(hybrid.destination_coordinates, Coordinates::default());
+            assert_eq!(hybrid.into_snek(), packet);
+        } else {
+            panic!("Should have gotten HybridRouted but got {:?}", recv);
+        }
+        // Packets that only fit into a frame without coordinates stay on SNEK.
+        let mut full = packet.clone();
+        full.payload = vec![0; MAX_FRAME_LENGTH - Frame::SnekRouted(packet.clone()).encoded_len()];
+        full.payload.push(1);
+        let local = full.clone();
+        r.call(move |state| state.handle_local_frame(Frame::SnekRouted(local)))
+            .await
+            .unwrap();
+        let recv = rd.next().await;
+        if let Some(Ok(Frame::SnekRouted(snek))) = recv {
+            assert_eq!(snek, full);
+        } else {
+            panic!("Should have gotten SnekRouted but got {:?}", recv);
+        }
+        // Coordinates that lead to the wrong node make the packet fall back to SNEK.
+        let hybrid = HybridPacket::new(packet.clone(), Coordinates::new(vec![1]));
+        r.handle_frame(Frame::HybridRouted(hybrid), pub1)
+            .await
+            .unwrap();
+        let recv = rd.next().await;
+        if let Some(Ok(Frame::SnekRouted(snek))) = recv {
+            assert_eq!(snek, packet);
+        } else {
+            panic!("Should have gotten SnekRouted but got {:?}", recv);
+        }
+        assert_eq!(r.metrics().tree_routing_fallbacks, 1);
+    }
+    #[tokio::test]
+    async fn drop_frame_at_hop_limit() {
+        let key1 = SigningKey::from([1; 32]);
+        let key2 = SigningKey::from([2; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, mut ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        set_first_announcement(&mut r, key2).await;
+        r.set_parent(pub2).await;
+        let packet = |hop_limit| SnekPacket {
+            destination_key: pub2,
+            source_key: pub1,
+            traffic_class: Default::default(),
+            hop_limit,
+            payload: vec![hop_limit],
+        };
+        // The router routes both packets back to their destination, but the first
+        // one has no hops left after it arrived.
+        ru.send(Frame::SnekRouted(packet(1))).await.unwrap();
+        ru.send(Frame::SnekRouted(packet(3))).await.unwrap();
+        let recv = loop {
+            match rd.next().await {
+                Some(Ok(Frame::SnekBootstrap(_))) => continue,
+                recv => break recv,
+            }
+        };
+        if let Some(Ok(Frame::SnekRouted(received))) = recv {
+            assert_eq!(received.hop_limit, 2);
+            assert_eq!(received.payload, vec![3]);
+        } else {
+            panic!("Should have gotten SnekRouted but got {:?}", recv);
+        }
+        assert_eq!(r.metrics().hop_limit_drops, 1);
+    }
+    #[tokio::test]
+    async fn drop_frame_that_is_too_long() {
+        let key1 = SigningKey::from([1; 32]);
+        let key2 = SigningKey::from([2; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        set_first_announcement(&mut r, key2).await;
+        r.set_parent(pub2).await;
+        let packet = |size| SnekPacket {
+            destination_key: pub2,
+            source_key: pub1,
+            traffic_class: Default::default(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            payload: vec![0; size],
+        };
+        // The first packet doesn't fit into a frame, which must not cost the peer.
+        r.handle_frame(Frame::SnekRouted(packet(MAX_FRAME_LENGTH)), pub1)
+            .await
+            .unwrap();
+        r.handle_frame(Frame::SnekRouted(packet(1)), pub1)
+            .await
+            .unwrap();
+        let recv = loop {
+            match rd.next().await {
+                Some(Ok(Frame::SnekBootstrap(_))) => continue,
+                recv => break recv,
+            }
+        };
+        if let Some(Ok(Frame::SnekRouted(received))) = recv {
+            assert_eq!(received.payload.len(), 1);
+        } else {
+            panic!("Should have gotten SnekRouted but got {:?}", recv);
+        }
+        assert_eq!(r.metrics().oversize_drops, 1);
+    }
+    #[tokio::test]
+    async fn reject_setup_over_path_limit() {
+        let key1 = SigningKey::from([2; 32]);
+        let key2 = SigningKey::from([1; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, _ru, mut rd) = get_test_router_with_peer(key2, key1.clone(), false).await;
+        set_first_announcement(&mut r, key1.clone()).await;
+        set_second_announcement_for_root(&mut r, key1.clone()).await;
+        r.set_parent(pub2).await;
+        let root = Root {
+            public_key: pub2,
+            sequence_number: 0,
+        };
+        let path_root = root.clone();
+        r.call(move |state| {
+            for path_id in 0..state.config.max_paths_per_origin as SnekPathId {
+                let index = SnekPathIndex {
+                    public_key: pub1,
+                    path_id,
+                };
+                let path = SnekPath {
+                    index: index.clone(),
+                    origin: pub1,
+                    target: pub2,
+                    source: 1,
+                    destination: 0,
+                    last_seen: Instant::now(),
+                    root: path_root.clone(),
+                    active: true,
+                };
+                state.paths.insert(index, path);
+            }
+        })
+        .await
+        .unwrap();
+        let setup = SnekSetup {
+            root,
+            destination: Default::default(),
+            destination_key: pub2,
+            source_key: pub1,
+            path_id: 1000,
+        };
+        r.handle_frame(Frame::SnekSetup(setup), pub1).await.unwrap();
+        let recv = rd.next().await;
+        if let Some(Ok(Frame::SnekTeardown(teardown))) = recv {
+            assert_eq!(teardown.destination_key, pub1);
+            assert_eq!(teardown.path_id, 1000);
+        } else {
+            panic!("Should have gotten Teardown but got {:?}", recv);
+        }
+        assert!(r
+            .path(SnekPathIndex {
+                public_key: pub1,
+                path_id: 1000
+            })
+            .await
+            .is_none());
+        assert_eq!(r.metrics().path_limit_rejections, 1);
+    }
+    #[tokio::test]
     async fn receive_setup_ack() {
         /*let _ = env_logger::builder()
         .write_style(WriteStyle::Always)
@@ -2210,11 +3689,11 @@ mod test {
         .init();*/
         let key1 = SigningKey::from([1; 32]);
         let key2 = SigningKey::from([2; 32]);
-        let pub1 = key1.verification_key().to_bytes();
-        let pub2 = key2.verification_key().to_bytes();
-        let (mut r, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (mut r, ru, mut rd) = get_test_router_with_peer(key1, key2.clone(), false).await;
         set_first_announcement(&mut r, key2.clone()).await;
-        *r.parent.write().await = pub2;
+        r.set_parent(pub2).await;
         let ack = SnekSetupAck {
             root: Root {
                 public_key: pub2,
@@ -2233,20 +3712,27 @@ mod test {
             target: pub2,
             source: 0,
             destination: 1,
-            last_seen: SystemTime::now(),
+            last_seen: Instant::now(),
             root: Root {
                 public_key: pub2,
                 sequence_number: 0,
             },
             active: false,
         };
-        r.paths.write().await.insert(index.clone(), entry.clone());
         entry.active = true;
-        r.candidate.write().await.insert(entry.clone());
+        let candidate = entry.clone();
+        let mut path = entry.clone();
+        path.active = false;
+        r.call(move |state| {
+            state.paths.insert(path.index.clone(), path);
+            state.candidate = Some(candidate);
+        })
+        .await
+        .unwrap();
         r.handle_frame(Frame::SnekSetupACK(ack), pub2)
             .await
             .unwrap();
-        let path = r.paths.read().await.get(&index).cloned().unwrap();
+        let path = r.path(index.clone()).await.unwrap();
         assert_eq!(path.active, entry.active);
         assert_eq!(path.index, entry.index);
         assert_eq!(path.root, entry.root);
@@ -2254,7 +3740,7 @@ mod test {
         assert_eq!(path.destination, entry.destination);
         assert_eq!(path.origin, entry.origin);
         assert_eq!(path.target, entry.target);
-        let ascending = r.ascending_path.read().await.clone().unwrap();
+        let ascending = r.ascending_path().await.unwrap();
         assert_eq!(&ascending.active, &entry.active);
         assert_eq!(&ascending.index, &entry.index);
         assert_eq!(&ascending.root, &entry.root);
@@ -2262,6 +3748,297 @@ mod test {
         assert_eq!(&ascending.destination, &entry.destination);
         assert_eq!(&ascending.origin, &entry.origin);
         assert_eq!(&ascending.target, &entry.target);
-        assert!(r.candidate.read().await.is_none());
+        assert!(r.candidate().await.is_none());
+    }
+    #[tokio::test]
+    async fn stop_tears_down_paths_and_closes_connections() {
+        let key1 = SigningKey::from([1; 32]);
+        let key2 = SigningKey::from([2; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (r1_upload_sender, r1_upload_receiver) = channel(100);
+        let (r1_download_sender, r1_download_receiver) = channel(100);
+        let router1 = Router::new(
+            key1,
+            RouterConfig::default(),
+            r1_download_sender,
+            r1_upload_receiver,
+        );
+        let (r1_u, r1_d, mut r2_u, mut r2_d) = new_test_connection().await;
+        let r1 = router1.start().await;
+        router1.add_peer(pub2, 1, r1_u, r1_d, false).await;
+        let ascending = SnekPath {
+            index: SnekPathIndex {
+                public_key: pub1,
+                path_id: 0,
+            },
+            origin: pub2,
+            target: pub2,
+            source: 0,
+            destination: 1,
+            last_seen: Instant::now(),
+            root: Root {
+                public_key: pub2,
+                sequence_number: 0,
+            },
+            active: true,
+        };
+        router1
+            .call(move |state| {
+                state
+                    .paths
+                    .insert(ascending.index.clone(), ascending.clone());
+                state.ascending_path = Some(ascending);
+            })
+            .await
+            .unwrap();
+
+        assert!(router1.stop(Duration::from_secs(1)).await.is_ok());
+        let recv = r2_d.next().await;
+        if let Some(Ok(Frame::SnekTeardown(teardown))) = recv {
+            assert_eq!(teardown.destination_key, pub1);
+            assert_eq!(teardown.path_id, 0);
+        } else {
+            panic!("Should have gotten Teardown but got {:?}", recv);
+        }
+        assert!(r2_d.next().await.is_none());
+        assert!(matches!(
+            router1.call(|_| ()).await,
+            Err(RouterError::Stopped)
+        ));
+        assert!(matches!(r1.await, Ok(None)));
+    }
+    #[tokio::test]
+    async fn drop_frames_the_client_doesnt_take() {
+        let key = SigningKey::from([1; 32]);
+        let public_key = PublicKey::from(&key);
+        let (_upload, upload_receiver) = channel(100);
+        let (download, mut download_receiver) = channel(1);
+        let r = Router::new(key, RouterConfig::default(), download, upload_receiver);
+        r.start().await;
+        let packet = move |payload: u8| SnekPacket {
+            destination_key: public_key,
+            source_key: PublicKey::from([2; 32]),
+            traffic_class: Default::default(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            payload: vec![payload],
+        };
+        r.call(move |state| {
+            state.send_to_local(Frame::SnekRouted(packet(1))).unwrap();
+            state.send_to_local(Frame::SnekRouted(packet(2))).unwrap();
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            download_receiver.recv().await,
+            Some(Frame::SnekRouted(packet)) if packet.payload == vec![1]
+        ));
+        assert!(download_receiver.try_recv().is_err());
+        assert_eq!(r.metrics().local_queue_drops, 1);
+    }
+    #[tokio::test]
+    async fn deliver_mail_into_the_inbox() {
+        // The router mails itself, so that the replies come back to it.
+        let key = SigningKey::from([1; 32]);
+        let source = key.clone();
+        let public_key = PublicKey::from(&key);
+        let (_upload, upload_receiver) = channel(100);
+        let (download, _download_receiver) = channel(100);
+        let r = Router::new(key, RouterConfig::default(), download, upload_receiver);
+        r.start().await;
+        let mail = move |id: u64| {
+            let sealed = seal(b"hi", &source, &public_key).unwrap();
+            MailPacket::new(public_key, id, sealed, &source)
+        };
+        let mut statuses = vec![];
+        for id in 0..=INBOX_BUFFER as u64 + 1 {
+            let mut mail = mail(id);
+            if id == 0 {
+                // A forged mail gets no reply.
+                mail.source_key = PublicKey::from([3; 32]);
+            }
+            let status = r
+                .call(move |state| {
+                    let (sender, mut status) = oneshot::channel();
+                    state.mail_sends.insert(id, sender);
+                    state.handle_mail(mail).unwrap();
+                    status.try_recv().ok()
+                })
+                .await
+                .unwrap();
+            statuses.push(status);
+        }
+        assert_eq!(statuses[0], None);
+        assert!(statuses[1..=INBOX_BUFFER]
+            .iter()
+            .all(|status| *status == Some(MailStatus::Delivered)));
+        // Mail that doesn't fit into the inbox isn't delivered.
+        assert_eq!(statuses[INBOX_BUFFER + 1], Some(MailStatus::Refused));
+        // Copies of delivered mail are confirmed again, but not delivered again.
+        let copy = mail(1);
+        let status = r
+            .call(move |state| {
+                let (sender, mut status) = oneshot::channel();
+                state.mail_sends.insert(1, sender);
+                state.handle_mail(copy).unwrap();
+                status.try_recv().ok()
+            })
+            .await
+            .unwrap();
+        assert_eq!(status, Some(MailStatus::Delivered));
+
+        let mut inbox = r.inbox().unwrap();
+        assert!(matches!(r.inbox(), Err(RouterError::InboxTaken)));
+        let delivered = inbox.recv().await.unwrap();
+        assert_eq!(delivered.source_key, public_key);
+        assert_eq!(delivered.payload, b"hi".to_vec());
+        let mut received = 1;
+        while inbox.mail.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, INBOX_BUFFER);
+    }
+    #[tokio::test]
+    async fn deliver_only_signed_broadcasts() {
+        let (_upload, upload_receiver) = channel(100);
+        let (download, mut download_receiver) = channel(100);
+        let r = Router::new(
+            SigningKey::from([1; 32]),
+            RouterConfig::default(),
+            download,
+            upload_receiver,
+        );
+        r.start().await;
+        let source = SigningKey::from([2; 32]);
+        let broadcast = move |id: u64| {
+            let mut broadcast = Broadcast {
+                source_key: PublicKey::from([0; 32]),
+                id,
+                signature: Signature::from([0; 64]),
+                topic: String::from("topic"),
+                traffic_class: Default::default(),
+                hop_limit: DEFAULT_HOP_LIMIT,
+                payload: vec![1],
+            };
+            broadcast.sign(&source);
+            broadcast
+        };
+        let mut forged = broadcast(1);
+        forged.payload = vec![2];
+        for broadcast in [forged, broadcast(1)] {
+            r.handle_frame(Frame::Broadcast(broadcast), PublicKey::from([3; 32]))
+                .await
+                .unwrap();
+        }
+        match download_receiver.recv().await {
+            Some(Frame::Broadcast(received)) => assert_eq!(received, broadcast(1)),
+            received => panic!("Should have gotten Broadcast but got {:?}", received),
+        }
+        assert!(download_receiver.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn answer_only_signed_dht_gets() {
+        // The router asks itself, so that the answers come back to it.
+        let key = SigningKey::from([1; 32]);
+        let source = key.clone();
+        let (_upload, upload_receiver) = channel(100);
+        let (download, _download_receiver) = channel(100);
+        let r = Router::new(key, RouterConfig::default(), download, upload_receiver);
+        r.start().await;
+        let answered = |get: DhtGet| {
+            r.call(move |state| {
+                let (sender, mut response) = oneshot::channel();
+                let pending = PendingDhtGet {
+                    key: get.key.clone(),
+                    response: sender,
+                };
+                state.dht_gets.insert(get.id, pending);
+                state.handle_dht_get(get).unwrap();
+                response.try_recv().is_ok()
+            })
+        };
+        let mut forged = DhtGet::new(dht_position(b"key"), 1, b"key".to_vec(), &source);
+        forged.id = 2;
+        assert!(!answered(forged).await.unwrap());
+        let get = DhtGet::new(dht_position(b"key"), 3, b"key".to_vec(), &source);
+        assert!(answered(get).await.unwrap());
+    }
+    #[tokio::test]
+    async fn lose_descending_path_with_its_peer() {
+        let key1 = SigningKey::from([2; 32]);
+        let key2 = SigningKey::from([1; 32]);
+        let pub1 = PublicKey::from(key1.verification_key());
+        let pub2 = PublicKey::from(key2.verification_key());
+        let (r, ru, rd) = get_test_router_with_peer(key2, key1, false).await;
+        let descending = SnekPath {
+            index: SnekPathIndex {
+                public_key: pub1,
+                path_id: 0,
+            },
+            origin: pub1,
+            target: pub2,
+            source: 1,
+            destination: 0,
+            last_seen: Instant::now(),
+            root: Root {
+                public_key: pub2,
+                sequence_number: 0,
+            },
+            active: true,
+        };
+        let index = descending.index.clone();
+        r.call(move |state| {
+            state
+                .paths
+                .insert(descending.index.clone(), descending.clone());
+            state.descending_path = Some(descending);
+        })
+        .await
+        .unwrap();
+        r.disconnect_peer(pub1).await;
+        assert!(r.descending_path().await.is_none());
+        assert!(r.path(index).await.is_none());
+    }
+    #[tokio::test]
+    async fn frame_from_unknown_peer() {
+        let key1 = SigningKey::from([1; 32]);
+        let key2 = SigningKey::from([2; 32]);
+        let (r, ru, mut rd) = get_test_router_with_peer(key1.clone(), key2.clone(), false).await;
+        let teardown = SnekTeardown {
+            root: r.current_root().await,
+            destination_key: PublicKey::from(key2.verification_key()),
+            path_id: 0,
+        };
+        let result = r
+            .handle_frame(Frame::SnekTeardown(teardown), PublicKey::from([3; 32]))
+            .await;
+        assert!(matches!(result, Err(RouterError::UnknownPeer)));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn timers_follow_the_tokio_clock() {
+        let path = SnekPath {
+            index: SnekPathIndex {
+                public_key: PublicKey::from([1; 32]),
+                path_id: 0,
+            },
+            origin: PublicKey::from([2; 32]),
+            target: PublicKey::from([2; 32]),
+            source: 0,
+            destination: 1,
+            last_seen: Instant::now(),
+            root: Root {
+                public_key: PublicKey::from([2; 32]),
+                sequence_number: 0,
+            },
+            active: true,
+        };
+        let timer = WaitTimer::new(REPARENT_WAIT_TIME);
+        assert!(path.valid());
+        assert!(!timer.is_expired());
+        tokio::time::advance(REPARENT_WAIT_TIME * 2).await;
+        assert!(path.valid());
+        assert!(timer.is_expired());
+        tokio::time::advance(SNEK_EXPIRY_PERIOD).await;
+        assert!(!path.valid());
     }
 }
@@ -1,13 +1,78 @@
 #[cfg(doc)]
-use crate::client::Client;
-use crate::frames::{Frame, SnekPacket};
-use crate::router::PublicKey;
+use crate::client::{Client, SessionListener};
+use crate::coordinates::Coordinates;
+use crate::error::RouterError;
+use crate::frames::{Frame, SnekPacket, TrafficClass, TreePacket, DEFAULT_HOP_LIMIT};
+use crate::public_key::PublicKey;
+use crate::wire_frame::MAX_FRAME_LENGTH;
 use std::io::{Error, ErrorKind};
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender};
+use tokio::time::Instant;
+use tokio_util::sync::PollSender;
+
+/// Identifies a service of a node, like a port in TCP or UDP. Sessions connect a
+/// service of one node with a service of another one, so there can be several
+/// independent sessions between the same two nodes.
+///
+/// Service 0 is the default service of [`Client::dial`] and the [`SessionListener`]
+/// that is returned by [`Client::new`]. Listeners for other services are
+/// registered with [`Client::listen`].
+pub type ServiceId = u16;
+/// Services that are picked for the local side of [`Client::dial_service`].
+pub(crate) const EPHEMERAL_SERVICES: std::ops::RangeInclusive<ServiceId> = 49152..=65535;
+/// Every session payload starts with the destination and source service and the
+/// kind of the payload.
+pub(crate) const SESSION_HEADER_SIZE: usize = 5;
+/// Largest amount of data that fits into a single session frame, next to the
+/// header and the keys of the frame and the header of the session.
+pub(crate) const MAX_SESSION_DATA: usize = MAX_FRAME_LENGTH - (10 + 32 + 32) - SESSION_HEADER_SIZE;
+/// Payload kind of the data that is written into a session.
+pub(crate) const SESSION_DATA: u8 = 0;
+/// Payload kind that tells the other side that the session was closed.
+pub(crate) const SESSION_CLOSE: u8 = 1;
+
+/// Identifies a session by the other node and the services on both sides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct SessionKey {
+    pub(crate) public_key: PublicKey,
+    pub(crate) local_service: ServiceId,
+    pub(crate) remote_service: ServiceId,
+}
+/// The part of a [`Session`] that is shared with the [`Client`].
+#[derive(Debug)]
+pub(crate) struct SessionState {
+    /// When data was last written into or read from the session.
+    last_activity: Mutex<Instant>,
+    closed: AtomicBool,
+}
+impl SessionState {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_activity: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        }
+    }
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+    pub(crate) fn idle_time(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+    /// Marks the session as closed. Returns `false` if it already was.
+    pub(crate) fn close(&self) -> bool {
+        !self.closed.swap(true, Ordering::Relaxed)
+    }
+}
 
 /// A session with a node in the network. This is being given out
 /// by the `dial` method on [`Client`] and implements [`AsyncRead`]/[`AsyncWrite`].
@@ -16,12 +81,27 @@ use tokio::sync::mpsc::{Receiver, Sender};
 /// that is the node with the public key that this session was created for.
 /// If the node doesn't exist in the network or routing of data fails due to another reason
 /// the data that is being sent is dropped by the network.
+///
+/// The session is closed when it is dropped or shut down, when the other side closes
+/// it or when it was idle for longer than
+/// [`RouterConfig::session_idle_timeout`](crate::RouterConfig::session_idle_timeout).
+/// The other side is told about the close. Reading a closed session returns the
+/// data that was received before and then the end of the stream. Writing to it fails.
+///
+/// Every write is sent in a single frame, so writes of more than 65,456 bytes are
+/// cut short.
 #[derive(Debug)]
 pub struct Session {
     pub(crate) router_key: PublicKey,
     pub(crate) dialed_key: PublicKey,
+    pub(crate) local_service: ServiceId,
+    pub(crate) remote_service: ServiceId,
+    pub(crate) traffic_class: TrafficClass,
     pub(crate) download: Receiver<Frame>,
-    pub(crate) upload: Sender<Frame>,
+    pub(crate) upload: PollSender<Frame>,
+    pub(crate) state: Arc<SessionState>,
+    /// Tells the [`Client`] about sessions that were closed on this side.
+    pub(crate) closed: UnboundedSender<(SessionKey, Arc<SessionState>)>,
 }
 /// A session with a node in the network. This is being given out
 /// by the `dial_send` method on [`Client`] and implements [`AsyncWrite`].
@@ -30,10 +110,32 @@ pub struct Session {
 /// that is the node with the public key that this session was created for.
 /// If the node doesn't exist in the network or routing of data fails due to another reason
 /// the data that is being sent is dropped by the network.
+///
+/// A SendSession that was taken from a [`Session`] with [`Session::sender`] keeps the
+/// session from being closed for being idle while it is written to. Writing to it
+/// fails once the session was closed.
 #[derive(Clone, Debug)]
 pub struct SendSession {
     pub(crate) router_key: PublicKey,
     pub(crate) dialed_key: PublicKey,
+    pub(crate) local_service: ServiceId,
+    pub(crate) remote_service: ServiceId,
+    pub(crate) traffic_class: TrafficClass,
+    pub(crate) upload: PollSender<Frame>,
+    pub(crate) state: Arc<SessionState>,
+}
+/// Sends and receives datagrams that are routed over the spanning tree
+/// using [`Coordinates`] instead of public keys. This is being given out
+/// by the `tree_session` method on [`Client`].
+///
+/// Tree routing often takes shorter paths than SNEK routing but the coordinates
+/// of a node change when the tree changes. Datagrams to outdated coordinates
+/// are dropped by the network or reach the wrong node.
+#[derive(Debug)]
+pub struct TreeSession {
+    pub(crate) router_key: PublicKey,
+    pub(crate) traffic_class: TrafficClass,
+    pub(crate) download: Receiver<Frame>,
     pub(crate) upload: Sender<Frame>,
 }
 #[allow(unused)]
@@ -44,6 +146,72 @@ impl Session {
     pub fn router_key(&self) -> PublicKey {
         self.router_key
     }
+    pub fn local_service(&self) -> ServiceId {
+        self.local_service
+    }
+    pub fn remote_service(&self) -> ServiceId {
+        self.remote_service
+    }
+    /// Returns a [`SendSession`] that writes into this session.
+    pub fn sender(&self) -> SendSession {
+        SendSession {
+            router_key: self.router_key,
+            dialed_key: self.dialed_key,
+            local_service: self.local_service,
+            remote_service: self.remote_service,
+            traffic_class: self.traffic_class,
+            upload: self.upload.clone(),
+            state: self.state.clone(),
+        }
+    }
+    pub fn traffic_class(&self) -> TrafficClass {
+        self.traffic_class
+    }
+    /// Sets the [`TrafficClass`] of the data that is written from now on.
+    pub fn set_traffic_class(&mut self, traffic_class: TrafficClass) {
+        self.traffic_class = traffic_class;
+    }
+    /// Whether the session was closed by either side.
+    pub fn is_closed(&self) -> bool {
+        self.state.is_closed()
+    }
+    /// Sends `data` without waiting. Fails with [`ErrorKind::WouldBlock`] if the
+    /// router can't take more data right now and with [`ErrorKind::InvalidInput`] if
+    /// `data` doesn't fit into a single frame.
+    pub fn try_send(&self, data: &[u8]) -> Result<(), Error> {
+        if self.state.is_closed() {
+            return Err(session_closed());
+        }
+        try_send_frame(&self.upload, self.data_frame(data))?;
+        self.state.touch();
+        Ok(())
+    }
+    fn data_frame(&self, data: &[u8]) -> Frame {
+        session_frame(
+            self.dialed_key,
+            self.router_key,
+            self.remote_service,
+            self.local_service,
+            self.traffic_class,
+            SESSION_DATA,
+            data,
+        )
+    }
+    fn close(&self) {
+        if self.state.close() {
+            let key = SessionKey {
+                public_key: self.dialed_key,
+                local_service: self.local_service,
+                remote_service: self.remote_service,
+            };
+            let _ = self.closed.send((key, self.state.clone()));
+        }
+    }
+}
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.close();
+    }
 }
 #[allow(unused)]
 impl SendSession {
@@ -53,6 +221,121 @@ impl SendSession {
     pub fn router_key(&self) -> PublicKey {
         self.router_key
     }
+    pub fn local_service(&self) -> ServiceId {
+        self.local_service
+    }
+    pub fn remote_service(&self) -> ServiceId {
+        self.remote_service
+    }
+    pub fn traffic_class(&self) -> TrafficClass {
+        self.traffic_class
+    }
+    /// Sets the [`TrafficClass`] of the data that is written from now on.
+    pub fn set_traffic_class(&mut self, traffic_class: TrafficClass) {
+        self.traffic_class = traffic_class;
+    }
+    /// Sends `data` without waiting. Fails with [`ErrorKind::WouldBlock`] if the
+    /// router can't take more data right now and with [`ErrorKind::InvalidInput`] if
+    /// `data` doesn't fit into a single frame.
+    pub fn try_send(&self, data: &[u8]) -> Result<(), Error> {
+        if self.state.is_closed() {
+            return Err(session_closed());
+        }
+        try_send_frame(&self.upload, self.data_frame(data))?;
+        self.state.touch();
+        Ok(())
+    }
+    fn data_frame(&self, data: &[u8]) -> Frame {
+        session_frame(
+            self.dialed_key,
+            self.router_key,
+            self.remote_service,
+            self.local_service,
+            self.traffic_class,
+            SESSION_DATA,
+            data,
+        )
+    }
+}
+#[allow(unused)]
+impl TreeSession {
+    pub fn router_key(&self) -> PublicKey {
+        self.router_key
+    }
+    pub fn traffic_class(&self) -> TrafficClass {
+        self.traffic_class
+    }
+    /// Sets the [`TrafficClass`] of the datagrams that are sent from now on.
+    pub fn set_traffic_class(&mut self, traffic_class: TrafficClass) {
+        self.traffic_class = traffic_class;
+    }
+    /// Sends `payload` to the node at `coordinates`.
+    pub async fn send_to(
+        &self,
+        coordinates: Coordinates,
+        payload: Vec<u8>,
+    ) -> Result<(), RouterError> {
+        self.upload
+            .send(tree_frame(coordinates, self.traffic_class, payload))
+            .await
+            .map_err(|_| RouterError::Stopped)
+    }
+    /// Receives the next datagram together with the coordinates of its sender.
+    /// Returns `None` once the router was stopped.
+    pub async fn recv_from(&mut self) -> Option<(Coordinates, Vec<u8>)> {
+        loop {
+            if let Frame::TreeRouted(packet) = self.download.recv().await? {
+                return Some((packet.source_coordinates, packet.payload));
+            }
+        }
+    }
+}
+/// Creates a SNEK routed frame of `kind` from `local_service` of this node to
+/// `remote_service` of the node with `destination_key`.
+pub(crate) fn session_frame(
+    destination_key: PublicKey,
+    source_key: PublicKey,
+    remote_service: ServiceId,
+    local_service: ServiceId,
+    traffic_class: TrafficClass,
+    kind: u8,
+    data: &[u8],
+) -> Frame {
+    let mut payload = Vec::with_capacity(SESSION_HEADER_SIZE + data.len());
+    payload.extend_from_slice(&remote_service.to_be_bytes());
+    payload.extend_from_slice(&local_service.to_be_bytes());
+    payload.push(kind);
+    payload.extend_from_slice(data);
+    Frame::SnekRouted(SnekPacket {
+        destination_key,
+        source_key,
+        traffic_class,
+        hop_limit: DEFAULT_HOP_LIMIT,
+        payload,
+    })
+}
+/// Returns the destination and source service and the kind of a session payload.
+pub(crate) fn parse_session_header(payload: &[u8]) -> Option<(ServiceId, ServiceId, u8)> {
+    let header = payload.get(..SESSION_HEADER_SIZE)?;
+    Some((
+        ServiceId::from_be_bytes([header[0], header[1]]),
+        ServiceId::from_be_bytes([header[2], header[3]]),
+        header[4],
+    ))
+}
+/// Creates a tree routed frame. The source coordinates are filled in by the router.
+pub(crate) fn tree_frame(
+    destination_coordinates: Coordinates,
+    traffic_class: TrafficClass,
+    payload: Vec<u8>,
+) -> Frame {
+    Frame::TreeRouted(TreePacket {
+        source_coordinates: Coordinates::default(),
+        destination_coordinates,
+        traffic_class,
+        hop_limit: DEFAULT_HOP_LIMIT,
+        payload,
+    })
 }
 impl AsyncRead for Session {
     fn poll_read(
@@ -60,21 +343,22 @@ impl AsyncRead for Session {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        match self.get_mut().download.poll_recv(cx) {
+        let session = self.get_mut();
+        match session.download.poll_recv(cx) {
             Poll::Ready(result) => match result {
-                None => Poll::Ready(Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "Session download channel closed",
-                ))),
+                // The session was closed. Nothing is put into `buf` to signal the end.
+                None => Poll::Ready(Ok(())),
                 Some(frame) => {
                     if let Frame::SnekRouted(packet) = frame {
-                        if buf.remaining() < packet.payload.len() {
+                        session.state.touch();
+                        let data = &packet.payload[SESSION_HEADER_SIZE..];
+                        if buf.remaining() < data.len() {
                             return Poll::Ready(Err(Error::new(
                                 ErrorKind::OutOfMemory,
                                 "Buffer not large enough",
                             )));
                         }
-                        buf.put_slice(packet.payload.as_slice());
+                        buf.put_slice(data);
                     }
                     Poll::Ready(Ok(()))
                 }
@@ -86,57 +370,54 @@ impl AsyncRead for Session {
 impl AsyncWrite for Session {
     fn poll_write(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
-        let payload = Vec::from(buf);
-        let frame = Frame::SnekRouted(SnekPacket {
-            destination_key: self.dialed_key,
-            source_key: self.router_key,
-            payload,
-        });
-        match self.upload.try_send(frame) {
-            Ok(_) => Poll::Ready(Ok(buf.len())),
-            Err(e) => match e {
-                TrySendError::Full(_) => Poll::Pending,
-                TrySendError::Closed(_) => Poll::Ready(Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "Session upload channel closed",
-                ))),
-            },
+        let session = self.get_mut();
+        if session.state.is_closed() {
+            return Poll::Ready(Err(session_closed()));
         }
+        ready!(poll_reserve(&mut session.upload, cx))?;
+        session.state.touch();
+        // Every write becomes a single frame, so larger writes are cut short.
+        let buf = &buf[..buf.len().min(MAX_SESSION_DATA)];
+        let frame = session.data_frame(buf);
+        session
+            .upload
+            .send_item(frame)
+            .map_err(|_| upload_closed())?;
+        Poll::Ready(Ok(buf.len()))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         Poll::Ready(Ok(()))
     }
 
+    /// Closes the session on both sides.
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.close();
         Poll::Ready(Ok(()))
     }
 }
 impl AsyncWrite for SendSession {
     fn poll_write(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
-        let payload = Vec::from(buf);
-        let frame = Frame::SnekRouted(SnekPacket {
-            destination_key: self.dialed_key,
-            source_key: self.router_key,
-            payload,
-        });
-        match self.upload.try_send(frame) {
-            Ok(_) => Poll::Ready(Ok(buf.len())),
-            Err(e) => match e {
-                TrySendError::Full(_) => Poll::Pending,
-                TrySendError::Closed(_) => Poll::Ready(Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "Session upload channel closed",
-                ))),
-            },
+        let session = self.get_mut();
+        if session.state.is_closed() {
+            return Poll::Ready(Err(session_closed()));
         }
+        ready!(poll_reserve(&mut session.upload, cx))?;
+        session.state.touch();
+        let buf = &buf[..buf.len().min(MAX_SESSION_DATA)];
+        let frame = session.data_frame(buf);
+        session
+            .upload
+            .send_item(frame)
+            .map_err(|_| upload_closed())?;
+        Poll::Ready(Ok(buf.len()))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
@@ -147,3 +428,116 @@ impl AsyncWrite for SendSession {
         Poll::Ready(Ok(()))
     }
 }
+
+/// Waits until the router can take another frame. The task is woken once it can.
+fn poll_reserve(upload: &mut PollSender<Frame>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+    upload.poll_reserve(cx).map_err(|_| upload_closed())
+}
+fn try_send_frame(upload: &PollSender<Frame>, frame: Frame) -> Result<(), Error> {
+    if frame.encoded_len() > MAX_FRAME_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Data doesn't fit into a frame",
+        ));
+    }
+    let upload = upload.get_ref().ok_or_else(upload_closed)?;
+    upload.try_send(frame).map_err(|e| match e {
+        TrySendError::Full(_) => Error::new(ErrorKind::WouldBlock, "Session upload channel full"),
+        TrySendError::Closed(_) => upload_closed(),
+    })
+}
+fn upload_closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Session upload channel closed")
+}
+fn session_closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Session was closed")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc::{channel, unbounded_channel};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn wait_for_full_upload() {
+        let (upload, mut router) = channel(2);
+        let session = SendSession {
+            router_key: PublicKey::from([1; 32]),
+            dialed_key: PublicKey::from([2; 32]),
+            local_service: 0,
+            remote_service: 0,
+            traffic_class: Default::default(),
+            upload: PollSender::new(upload),
+            state: Arc::new(SessionState::new()),
+        };
+        session.try_send(b"1").unwrap();
+        session.try_send(b"2").unwrap();
+        let full = session.try_send(b"3").unwrap_err();
+        assert_eq!(full.kind(), ErrorKind::WouldBlock);
+
+        let mut writer = session.clone();
+        let mut write = tokio::spawn(async move { writer.write_all(b"3").await });
+        assert!(timeout(Duration::from_millis(100), &mut write)
+            .await
+            .is_err());
+        // Taking a frame wakes the writer up.
+        router.recv().await.unwrap();
+        timeout(Duration::from_secs(1), write)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Writes that don't fit into a frame are cut short.
+        router.recv().await.unwrap();
+        router.recv().await.unwrap();
+        let mut writer = session.clone();
+        let written = writer.write(&[0; MAX_SESSION_DATA + 1]).await.unwrap();
+        assert_eq!(written, MAX_SESSION_DATA);
+        let too_large = session.try_send(&[0; MAX_SESSION_DATA + 1]).unwrap_err();
+        assert_eq!(too_large.kind(), ErrorKind::InvalidInput);
+        let Some(Frame::SnekRouted(packet)) = router.recv().await else {
+            panic!("Should have gotten SnekRouted");
+        };
+        assert_eq!(Frame::SnekRouted(packet).encoded_len(), MAX_FRAME_LENGTH);
+
+        drop(router);
+        let closed = session.try_send(b"4").unwrap_err();
+        assert_eq!(closed.kind(), ErrorKind::BrokenPipe);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn sender_keeps_session_alive() {
+        let (upload, mut router) = channel(10);
+        let (_download, download) = channel(10);
+        let (closed, mut closed_sessions) = unbounded_channel();
+        let session = Session {
+            router_key: PublicKey::from([1; 32]),
+            dialed_key: PublicKey::from([2; 32]),
+            local_service: 0,
+            remote_service: 0,
+            traffic_class: Default::default(),
+            download,
+            upload: PollSender::new(upload),
+            state: Arc::new(SessionState::new()),
+            closed,
+        };
+        let mut sender = session.sender();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(session.state.idle_time(), Duration::from_secs(10));
+        sender.write_all(b"data").await.unwrap();
+        assert_eq!(session.state.idle_time(), Duration::ZERO);
+        assert!(router.recv().await.is_some());
+
+        drop(session);
+        assert!(closed_sessions.recv().await.is_some());
+        let closed = sender.write_all(b"late").await.unwrap_err();
+        assert_eq!(closed.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(
+            sender.try_send(b"late").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use crate::error::RouterError;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+/// Cancels and keeps track of all tasks that are spawned through it, so that
+/// it is possible to wait for every one of them to stop.
+///
+/// Every tracked task holds a clone of `alive`. Once the tracker drops its own
+/// sender and all tasks have finished, `finished` yields `None`.
+#[derive(Clone, Debug)]
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    alive: Arc<std::sync::Mutex<Option<Sender<()>>>>,
+    finished: Arc<Mutex<Receiver<()>>>,
+}
+impl Shutdown {
+    pub(crate) fn new() -> Self {
+        let (alive, finished) = channel(1);
+        Self {
+            token: CancellationToken::new(),
+            alive: Arc::new(std::sync::Mutex::new(Some(alive))),
+            finished: Arc::new(Mutex::new(finished)),
+        }
+    }
+    /// Spawns a task that runs until either the future completes or the
+    /// shutdown is triggered. In the latter case the task resolves to `None`.
+    pub(crate) fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let alive = self.alive.lock().unwrap().clone();
+        let token = self.token.clone();
+        tokio::spawn(async move {
+            let _alive = alive;
+            tokio::select! {
+                _ = token.cancelled() => None,
+                output = future => Some(output),
+            }
+        })
+    }
+    /// Spawns a task that is tracked but not cancelled by the shutdown. It has to
+    /// stop on its own, for example once the channel it is reading from is closed.
+    pub(crate) fn track<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let alive = self.alive.lock().unwrap().clone();
+        tokio::spawn(async move {
+            let _alive = alive;
+            future.await
+        })
+    }
+    /// Cancels all tracked tasks.
+    pub(crate) fn trigger(&self) {
+        self.token.cancel();
+    }
+    /// Waits until every tracked task has stopped. Fails with [`RouterError::Timeout`]
+    /// if that takes longer than `duration`.
+    pub(crate) async fn wait(&self, duration: Duration) -> Result<(), RouterError> {
+        self.alive.lock().unwrap().take();
+        let mut finished = self.finished.lock().await;
+        match timeout(duration, finished.recv()).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(RouterError::Timeout),
+        }
+    }
+}
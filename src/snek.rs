@@ -1,7 +1,11 @@
-use crate::frames::{SnekBootstrap, SnekPacket, SnekSetup};
-use crate::router::{Port, PublicKey, SnekPathId, SNEK_EXPIRY_PERIOD};
+use crate::frames::{
+    CoordinatesRequest, CoordinatesResponse, DhtGet, DhtGetResponse, DhtPut, MailPacket, MailReply,
+    Probe, ProbeReply, SnekBootstrap, SnekPacket, SnekSetup,
+};
+use crate::public_key::PublicKey;
+use crate::router::{Port, SnekPathId, SNEK_EXPIRY_PERIOD};
 use crate::tree::Root;
-use std::time::SystemTime;
+use tokio::time::Instant;
 
 #[derive(PartialEq, Eq, Clone, Debug, PartialOrd, Ord, Hash)]
 pub(crate) struct SnekPathIndex {
@@ -15,7 +19,7 @@ pub(crate) struct SnekPath {
     pub(crate) target: PublicKey,
     pub(crate) source: Port,
     pub(crate) destination: Port,
-    pub(crate) last_seen: SystemTime,
+    pub(crate) last_seen: Instant,
     pub(crate) root: Root,
     pub(crate) active: bool,
 }
@@ -24,7 +28,7 @@ impl SnekPath {
     /// required for updates to time out eventually, in the case that paths don't get
     /// torn down properly for some reason.
     pub(crate) fn valid(&self) -> bool {
-        self.last_seen.elapsed().unwrap() < SNEK_EXPIRY_PERIOD
+        self.last_seen.elapsed() < SNEK_EXPIRY_PERIOD
     }
 }
 pub(crate) trait SnekRouted {
@@ -45,3 +49,48 @@ impl SnekRouted for SnekBootstrap {
         self.destination_key
     }
 }
+impl SnekRouted for CoordinatesRequest {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for CoordinatesResponse {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for Probe {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for ProbeReply {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for DhtPut {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for DhtGet {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for DhtGetResponse {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for MailPacket {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
+impl SnekRouted for MailReply {
+    fn destination_key(&self) -> PublicKey {
+        self.destination_key
+    }
+}
@@ -0,0 +1,32 @@
+use crate::coordinates::Coordinates;
+use crate::public_key::PublicKey;
+use crate::router::{Port, SequenceNumber};
+use serde::Serialize;
+
+/// A connected peer and the port it is connected on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerInfo {
+    pub public_key: PublicKey,
+    pub port: Port,
+}
+
+/// The position of the router in the spanning tree.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TreeInfo {
+    pub root: PublicKey,
+    pub root_sequence: SequenceNumber,
+    /// The peer that leads towards the root. This is the router itself if it is the root.
+    pub parent: PublicKey,
+    pub coordinates: Coordinates,
+}
+
+/// The neighbours of the router in the SNEK.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SnekInfo {
+    /// The key that follows ours, if there is a path to it.
+    pub ascending: Option<PublicKey>,
+    /// The key that precedes ours, if there is a path from it.
+    pub descending: Option<PublicKey>,
+    /// Number of SNEK paths that go through the router.
+    pub paths: usize,
+}
@@ -1,6 +1,7 @@
 use crate::coordinates::Coordinates;
-use crate::frames::{SnekBootstrapAck, SnekSetup, TreePacket};
-use crate::router::{Port, PublicKey, SequenceNumber};
+use crate::frames::{HybridPacket, SnekBootstrapAck, SnekSetup, TreePacket};
+use crate::public_key::PublicKey;
+use crate::router::{Port, SequenceNumber};
 use ed25519_consensus::Signature;
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
@@ -50,6 +51,11 @@ impl TreeRouted for TreePacket {
         self.destination_coordinates.clone()
     }
 }
+impl TreeRouted for HybridPacket {
+    fn destination_coordinates(&self) -> Coordinates {
+        self.destination_coordinates.clone()
+    }
+}
 impl TreeRouted for SnekBootstrapAck {
     fn destination_coordinates(&self) -> Coordinates {
         self.destination_coordinates.clone()
@@ -0,0 +1,436 @@
+use crate::client::{Client, SessionListener};
+use crate::error::RouterError;
+use crate::public_key::PublicKey;
+use crate::session::{SendSession, ServiceId, Session, MAX_SESSION_DATA};
+use futures::{SinkExt, StreamExt};
+use futures_sink::Sink;
+use log::{debug, trace, warn};
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tokio_util::sync::PollSender;
+
+/// First byte of all addresses of nodes. It puts them into the unique local
+/// address range `fd00::/8`.
+pub const ADDRESS_PREFIX: u8 = 0xfd;
+/// Largest packet that is read from the TUN device.
+const MAX_PACKET_SIZE: usize = 65535;
+/// Largest packet that is carried, as every packet has to fit into a single session
+/// frame. The TUN device should be configured with this MTU. Larger packets are
+/// dropped.
+pub const TUN_MTU: usize = MAX_SESSION_DATA;
+const IPV6_HEADER_SIZE: usize = 40;
+/// Service that the packets are sent to and from.
+pub const TUN_SERVICE: ServiceId = 1;
+
+/// Packets that are written to the TUN device.
+pub type PacketSink = Box<dyn Sink<Vec<u8>, Error = Error> + Send + Unpin>;
+/// Packets that are read from the TUN device.
+pub type PacketStream = Box<dyn Stream<Item = Result<Vec<u8>, Error>> + Send + Unpin>;
+
+/// Derives the IPv6 address of the node with `public_key`.
+///
+/// Like in Yggdrasil the second byte counts the leading zero bits of the key and
+/// the remaining bytes hold the inverted bits that follow the first one bit. Keys
+/// with more leading zeros thus get more of their bits into the address. An
+/// address can't hold a whole key so the [`TunAdapter`] has to learn the keys
+/// of addresses.
+pub fn ipv6_address(public_key: PublicKey) -> Ipv6Addr {
+    let key = public_key.as_bytes();
+    let bit = |i: usize| key.get(i / 8).map_or(0, |byte| (byte >> (7 - i % 8)) & 1);
+    let leading_zeros = (0..256).take_while(|i| bit(*i) == 0).count();
+    let mut address = [0u8; 16];
+    address[0] = ADDRESS_PREFIX;
+    address[1] = leading_zeros.min(u8::MAX as usize) as u8;
+    for i in 0..(14 * 8) {
+        let inverted = 1 - bit(leading_zeros + 1 + i);
+        address[2 + i / 8] |= inverted << (7 - i % 8);
+    }
+    Ipv6Addr::from(address)
+}
+
+/// Carries IPv6 packets between a TUN device and the network. Each node gets the
+/// address from [`ipv6_address`] and packets are sent in [`Session`]s with the
+/// [`TUN_SERVICE`] of the node whose address is the destination of the packet.
+///
+/// The adapter only knows the keys of nodes that opened a session with this node
+/// or that were added with [`TunAdapter::add_node`]. Packets to other addresses
+/// are dropped. Packets with a source address that doesn't belong to the node
+/// that sent them are dropped as well.
+pub struct TunAdapter {
+    address: Ipv6Addr,
+    nodes: Arc<RwLock<HashMap<Ipv6Addr, PublicKey>>>,
+    task: JoinHandle<()>,
+}
+#[allow(unused)]
+impl TunAdapter {
+    /// Starts carrying packets between the device and the network. Fails if
+    /// something else already listens on [`TUN_SERVICE`].
+    pub async fn start(
+        client: Client,
+        device_sink: PacketSink,
+        device_stream: PacketStream,
+    ) -> Result<Self, RouterError> {
+        let sessions = client.listen(TUN_SERVICE).await?;
+        let address = ipv6_address(client.public_key());
+        let nodes: Arc<RwLock<HashMap<Ipv6Addr, PublicKey>>> = Default::default();
+        let forwarder = Forwarder {
+            client,
+            address,
+            nodes: nodes.clone(),
+            senders: Default::default(),
+            received: channel(100),
+        };
+        let task = tokio::spawn(forwarder.run(sessions, device_sink, device_stream));
+        Ok(Self {
+            address,
+            nodes,
+            task,
+        })
+    }
+    /// The address of this node.
+    pub fn address(&self) -> Ipv6Addr {
+        self.address
+    }
+    /// Makes packets to the address of `public_key` go to that node.
+    pub async fn add_node(&self, public_key: PublicKey) -> Ipv6Addr {
+        let address = ipv6_address(public_key);
+        self.nodes.write().await.insert(address, public_key);
+        address
+    }
+    /// Returns the key of the node with `address` if it is known.
+    pub async fn node(&self, address: Ipv6Addr) -> Option<PublicKey> {
+        self.nodes.read().await.get(&address).copied()
+    }
+    /// Stops forwarding packets.
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+/// The task that forwards the packets.
+struct Forwarder {
+    client: Client,
+    address: Ipv6Addr,
+    nodes: Arc<RwLock<HashMap<Ipv6Addr, PublicKey>>>,
+    senders: HashMap<PublicKey, SendSession>,
+    /// Packets from the network that have to be written to the device.
+    received: (Sender<Vec<u8>>, Receiver<Vec<u8>>),
+}
+impl Forwarder {
+    async fn run(
+        mut self,
+        mut sessions: SessionListener,
+        mut device_sink: PacketSink,
+        mut device_stream: PacketStream,
+    ) {
+        let mut sessions_open = true;
+        loop {
+            tokio::select! {
+                session = sessions.recv(), if sessions_open => match session {
+                    Some(session) => {
+                        let public_key = session.peer_key();
+                        self.nodes.write().await.insert(ipv6_address(public_key), public_key);
+                        self.receive_from(session);
+                    }
+                    None => sessions_open = false,
+                },
+                packet = device_stream.next() => match packet {
+                    Some(Ok(packet)) => self.send(packet).await,
+                    Some(Err(e)) => warn!("Could not read from TUN device: {}", e),
+                    None => {
+                        debug!("TUN device was closed");
+                        break;
+                    }
+                },
+                Some(packet) = self.received.1.recv() => {
+                    if let Err(e) = device_sink.send(packet).await {
+                        warn!("Could not write to TUN device: {}", e);
+                    }
+                }
+            }
+        }
+    }
+    /// Sends a packet from the device to the node with its destination address.
+    async fn send(&mut self, packet: Vec<u8>) {
+        if packet.len() > TUN_MTU {
+            trace!(
+                "Dropping packet of {} bytes that exceeds the MTU",
+                packet.len()
+            );
+            return;
+        }
+        let Some((source, destination)) = addresses(&packet) else {
+            trace!("Dropping packet that isn't IPv6");
+            return;
+        };
+        if source != self.address {
+            trace!("Dropping packet from foreign address {}", source);
+            return;
+        }
+        let Some(public_key) = self.nodes.read().await.get(&destination).copied() else {
+            debug!("Dropping packet to unknown address {}", destination);
+            return;
+        };
+        if !self.senders.contains_key(&public_key) {
+            let sender = self.client.dial_send_service(public_key, TUN_SERVICE).await;
+            self.senders.insert(public_key, sender);
+        }
+        let sender = self.senders.get_mut(&public_key).unwrap();
+        if let Err(e) = sender.write_all(&packet).await {
+            debug!("Could not send packet to {}: {}", public_key, e);
+            self.senders.remove(&public_key);
+        }
+    }
+    /// Spawns a task that hands the packets of `session` to the device. Packets that
+    /// weren't sent by the node of the session or aren't for this node are dropped.
+    fn receive_from(&mut self, mut session: Session) {
+        let address = self.address;
+        let peer_address = ipv6_address(session.peer_key());
+        let received = self.received.0.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            loop {
+                let len = match session.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(e) => {
+                        debug!("Session with {} failed: {}", session.peer_key(), e);
+                        break;
+                    }
+                };
+                match addresses(&buf[..len]) {
+                    Some((source, destination))
+                        if source == peer_address && destination == address =>
+                    {
+                        if received.send(buf[..len].to_vec()).await.is_err() {
+                            break;
+                        }
+                    }
+                    _ => trace!("Dropping packet from {}", session.peer_key()),
+                }
+            }
+        });
+    }
+}
+
+/// Returns the source and destination address of an IPv6 packet.
+fn addresses(packet: &[u8]) -> Option<(Ipv6Addr, Ipv6Addr)> {
+    if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+        return None;
+    }
+    let source: [u8; 16] = packet[8..24].try_into().unwrap();
+    let destination: [u8; 16] = packet[24..40].try_into().unwrap();
+    Some((Ipv6Addr::from(source), Ipv6Addr::from(destination)))
+}
+
+/// An in-memory TUN device. Packets that are sent to it can be read from the
+/// device and packets that are written to the device can be received from it.
+pub struct FakeTun {
+    to_device: Sender<Vec<u8>>,
+    from_device: Receiver<Vec<u8>>,
+}
+#[allow(unused)]
+impl FakeTun {
+    /// Creates the fake device together with the sink and stream that the
+    /// [`TunAdapter`] uses.
+    pub fn new() -> (Self, PacketSink, PacketStream) {
+        let (to_device, device_stream) = channel(100);
+        let (device_sink, from_device) = channel(100);
+        let device_sink = PollSender::new(device_sink)
+            .sink_map_err(|_| Error::new(std::io::ErrorKind::BrokenPipe, "FakeTun was dropped"));
+        let device_stream = ReceiverStream::new(device_stream).map(Ok);
+        (
+            Self {
+                to_device,
+                from_device,
+            },
+            Box::new(device_sink),
+            Box::new(device_stream),
+        )
+    }
+    /// Makes `packet` readable from the device, as if an application had sent it.
+    pub async fn send(&self, packet: Vec<u8>) -> Result<(), Error> {
+        self.to_device
+            .send(packet)
+            .await
+            .map_err(|_| Error::new(std::io::ErrorKind::BrokenPipe, "TUN device was closed"))
+    }
+    /// Receives the next packet that was written to the device.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_device.recv().await
+    }
+}
+
+/// Opens the TUN device with `name`, creating it if it doesn't exist. This usually
+/// requires the `CAP_NET_ADMIN` capability.
+///
+/// The device still has to be configured, for example with
+/// `ip addr add <address>/8 dev <name> && ip link set <name> mtu 65456 up`, where
+/// the MTU is [`TUN_MTU`].
+#[cfg(target_os = "linux")]
+pub fn open_tun(name: &str) -> Result<(PacketSink, PacketStream), Error> {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::unix::AsyncFd;
+
+    const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+    #[repr(C)]
+    struct InterfaceRequest {
+        name: [u8; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _padding: [u8; 22],
+    }
+
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Interface name is too long",
+        ));
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/net/tun")?;
+    let mut request = InterfaceRequest {
+        name: [0; libc::IFNAMSIZ],
+        flags: IFF_TUN | IFF_NO_PI,
+        _padding: [0; 22],
+    };
+    request.name[..name.len()].copy_from_slice(name.as_bytes());
+    // SAFETY: `request` has the layout of `struct ifreq` that TUNSETIFF expects.
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut request) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let device = Arc::new(AsyncFd::new(file)?);
+
+    let stream = futures::stream::unfold(device.clone(), |device: Arc<AsyncFd<File>>| async {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let result = loop {
+            let mut guard = match device.readable().await {
+                Ok(guard) => guard,
+                Err(e) => break Err(e),
+            };
+            if let Ok(result) = guard.try_io(|device| device.get_ref().read(&mut buf)) {
+                break result;
+            }
+        };
+        let packet = result.map(|len| {
+            buf.truncate(len);
+            buf
+        });
+        Some((packet, device))
+    });
+    let sink = futures::sink::unfold(
+        device,
+        |device: Arc<AsyncFd<File>>, packet: Vec<u8>| async move {
+            loop {
+                let mut guard = device.writable().await?;
+                if let Ok(result) = guard.try_io(|device| device.get_ref().write(&packet)) {
+                    result?;
+                    break;
+                }
+            }
+            Ok::<_, Error>(device)
+        },
+    );
+    Ok((Box::new(Box::pin(sink)), Box::new(Box::pin(stream))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::connected_clients;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn packet(source: Ipv6Addr, destination: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; IPV6_HEADER_SIZE];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet[6] = 59; // No next header
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&source.octets());
+        packet[24..40].copy_from_slice(&destination.octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn derive_address() {
+        let mut key = [0xff; 32];
+        assert_eq!(
+            ipv6_address(PublicKey::from(key)),
+            "fd00::".parse::<Ipv6Addr>().unwrap()
+        );
+        key[0] = 0b0001_0111;
+        assert_eq!(
+            ipv6_address(PublicKey::from(key)),
+            "fd03:8000::".parse::<Ipv6Addr>().unwrap()
+        );
+        let address = ipv6_address(PublicKey::from([1; 32]));
+        assert_eq!(address.octets()[0], ADDRESS_PREFIX);
+        assert_eq!(address.octets()[1], 7);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn packets_over_sessions() {
+        let (client1, client2, _) = connected_clients(Default::default()).await;
+
+        let (mut tun1, sink, stream) = FakeTun::new();
+        let adapter1 = TunAdapter::start(client1.clone(), sink, stream)
+            .await
+            .unwrap();
+        let (mut tun2, sink, stream) = FakeTun::new();
+        let adapter2 = TunAdapter::start(client2.clone(), sink, stream)
+            .await
+            .unwrap();
+        let address1 = adapter1.address();
+        let address2 = adapter1.add_node(client2.public_key()).await;
+        assert_eq!(address2, adapter2.address());
+
+        let request = packet(address1, address2, b"request");
+        tun1.send(request.clone()).await.unwrap();
+        let received = timeout(Duration::from_secs(5), tun2.recv()).await.unwrap();
+        assert_eq!(received.unwrap(), request);
+        // The second node learned the key of the first one from the session.
+        assert_eq!(adapter2.node(address1).await, Some(client1.public_key()));
+
+        let response = packet(address2, address1, b"response");
+        tun2.send(response.clone()).await.unwrap();
+        let received = timeout(Duration::from_secs(5), tun1.recv()).await.unwrap();
+        assert_eq!(received.unwrap(), response);
+
+        // Packets up to the MTU fit into a session frame, larger ones are dropped.
+        let largest = packet(address2, address1, &vec![1; TUN_MTU - IPV6_HEADER_SIZE]);
+        tun2.send(largest.clone()).await.unwrap();
+        let received = timeout(Duration::from_secs(5), tun1.recv()).await.unwrap();
+        assert_eq!(received.unwrap(), largest);
+        let too_large = packet(address2, address1, &vec![2; TUN_MTU + 1 - IPV6_HEADER_SIZE]);
+        tun2.send(too_large).await.unwrap();
+        tun2.send(response.clone()).await.unwrap();
+        let received = timeout(Duration::from_secs(5), tun1.recv()).await.unwrap();
+        assert_eq!(received.unwrap(), response);
+
+        // Spoofed source addresses are dropped.
+        let spoofed = packet("fd00::1".parse().unwrap(), address1, b"spoofed");
+        tun2.send(spoofed).await.unwrap();
+        assert!(timeout(Duration::from_millis(500), tun1.recv())
+            .await
+            .is_err());
+
+        adapter1.stop();
+        adapter2.stop();
+    }
+}
@@ -1,33 +1,25 @@
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
+use tokio::time::Instant;
 
 #[derive(Debug)]
 pub(crate) struct WaitTimer {
-    last_time: SystemTime,
+    last_time: Instant,
     duration: Duration,
 }
 impl WaitTimer {
     pub(crate) fn new(duration: Duration) -> Self {
         Self {
-            last_time: SystemTime::now(),
+            last_time: Instant::now(),
             duration,
         }
     }
     pub(crate) fn is_expired(&self) -> bool {
-        match SystemTime::now().duration_since(self.last_time) {
-            Ok(duration) => {
-                if duration > self.duration {
-                    true
-                } else {
-                    false
-                }
-            }
-            Err(_) => false,
-        }
+        self.last_time.elapsed() > self.duration
     }
     #[allow(unused)]
     pub(crate) fn new_expired() -> WaitTimer {
         WaitTimer {
-            last_time: SystemTime::now(),
+            last_time: Instant::now(),
             duration: Duration::ZERO,
         }
     }
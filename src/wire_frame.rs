@@ -1,14 +1,18 @@
 use crate::coordinates::Coordinates;
+use crate::dht::DhtRecord;
 use crate::error::RouterError;
 use crate::frames::{
-    Frame, SnekBootstrap, SnekBootstrapAck, SnekPacket, SnekSetup, SnekSetupAck, SnekTeardown,
-    TreeAnnouncement, TreePacket,
+    Broadcast, CoordinatesRequest, CoordinatesResponse, DhtGet, DhtGetResponse, DhtPut, Frame,
+    HybridPacket, MailPacket, MailReply, Probe, ProbeReply, SnekBootstrap, SnekBootstrapAck,
+    SnekPacket, SnekSetup, SnekSetupAck, SnekTeardown, TrafficClass, TreeAnnouncement, TreePacket,
+    DEFAULT_HOP_LIMIT,
 };
-use crate::router::PublicKey;
+use crate::mailbox::MailStatus;
+use crate::public_key::PublicKey;
 use crate::tree::{Root, RootAnnouncementSignature};
 use bytes::{Buf, BufMut, BytesMut};
 use ed25519_consensus::Signature;
-use std::time::SystemTime;
+use tokio::time::Instant;
 use tokio_util::codec::{Decoder, Encoder};
 
 #[allow(unused)]
@@ -20,22 +24,17 @@ const MAX_FRAME_SIZE: u32 = 65535 * 3 + 16;
 /// as a payload, not including headers.
 const MAX_PAYLOAD_SIZE: u16 = 65535;
 const FRAME_MAGIC_BYTES: [u8; 4] = [0x70, 0x69, 0x6e, 0x65];
-#[allow(unused)]
 /// 4 magic bytes, 1 byte version, 1 byte type, 2 bytes extra, 2 bytes frame length
 const FRAME_HEADER_LENGTH: u32 = 10;
 
-#[derive(Debug)]
-pub struct PineconeCodec;
-impl Encoder<Frame> for PineconeCodec {
-    type Error = RouterError;
+/// Longest frame that can be encoded, including its header, as the length of frames
+/// is encoded in 16 bits.
+pub(crate) const MAX_FRAME_LENGTH: usize = u16::MAX as usize;
 
-    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        if !dst.is_empty() {
-            return Err(Self::Error::EncodingError(
-                "Buffer to write to is not empty",
-            ));
-        }
-        let len = match &item {
+impl Frame {
+    /// Length of the frame once it is encoded, including its header.
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self {
             Frame::TreeRouted(packet) => {
                 10 + 2
                     + packet.destination_coordinates.coordinates.len() * 8
@@ -44,7 +43,9 @@ impl Encoder<Frame> for PineconeCodec {
                     + packet.payload.len()
             }
             Frame::SnekRouted(packet) => 10 + 32 + 32 + packet.payload.len(),
-            Frame::TreeAnnouncement(packet) => 10 + 32 + 8 + 2 + packet.signatures.len() * (32 + 8),
+            Frame::TreeAnnouncement(packet) => {
+                10 + 32 + 8 + 2 + packet.signatures.len() * (32 + 8 + 64)
+            }
             Frame::SnekBootstrap(packet) => {
                 10 + 32 + 2 + packet.source.coordinates.len() * 8 + 32 + 8 + 8
             }
@@ -64,288 +65,657 @@ impl Encoder<Frame> for PineconeCodec {
             }
             Frame::SnekSetupACK(_packet) => 10 + 32 + 32 + 8 + 8,
             Frame::SnekTeardown(_packet) => 10 + 32 + 32 + 8 + 8,
-        } as u16;
-        dst.reserve(len as usize);
+            Frame::CoordinatesRequest(_packet) => 10 + 32 + 32 + 8,
+            Frame::Probe(_packet) => 10 + 32 + 32 + 8 + 1,
+            Frame::ProbeReply(_packet) => 10 + 32 + 32 + 8 + 1 + 8,
+            Frame::Broadcast(packet) => {
+                10 + 32 + 8 + 64 + 1 + packet.topic.len() + packet.payload.len()
+            }
+            Frame::DhtPut(packet) => 10 + 32 + 32 + 1 + packet.record.encoded_len(),
+            Frame::DhtGet(packet) => 10 + 32 + 32 + 8 + 64 + 1 + packet.key.len(),
+            Frame::Mail(packet) => 10 + 32 + 32 + 32 + 8 + 64 + packet.payload.len(),
+            Frame::MailReply(_packet) => 10 + 32 + 32 + 8 + 1,
+            Frame::DhtGetResponse(packet) => {
+                10 + 32
+                    + 32
+                    + 8
+                    + packet
+                        .records
+                        .iter()
+                        .map(DhtRecord::encoded_len)
+                        .sum::<usize>()
+            }
+            Frame::HybridRouted(packet) => {
+                10 + 2
+                    + packet.destination_coordinates.coordinates.len() * 8
+                    + 32
+                    + 32
+                    + packet.payload.len()
+            }
+            Frame::CoordinatesResponse(packet) => {
+                10 + 32 + 32 + 8 + 64 + 32 + 8 + 2 + packet.coordinates.coordinates.len() * 8
+            }
+        }
+    }
+}
 
-        dst.put_slice(FRAME_MAGIC_BYTES.as_slice());
-        dst.put_u8(0); // FrameVersion
-        dst.put_u8(match &item {
-            // FrameType
-            Frame::TreeRouted(_) => 2,
-            Frame::SnekRouted(_) => 8,
-            Frame::TreeAnnouncement(_) => 1,
-            Frame::SnekBootstrap(_) => 3,
-            Frame::SnekBootstrapACK(_) => 4,
-            Frame::SnekSetup(_) => 5,
-            Frame::SnekSetupACK(_) => 6,
-            Frame::SnekTeardown(_) => 7,
-        });
-        dst.put_u16(0);
-        dst.put_u16(len);
-        match item {
-            Frame::TreeRouted(packet) => {
-                dst.put_u16(packet.destination_coordinates.coordinates.len() as u16);
-                for coord in packet.destination_coordinates.coordinates {
-                    dst.put_u64(coord);
-                }
-                dst.put_u16(packet.source_coordinates.coordinates.len() as u16);
-                for coord in packet.source_coordinates.coordinates {
-                    dst.put_u64(coord);
-                }
-                dst.put_slice(packet.payload.as_slice());
+#[derive(Debug)]
+pub struct PineconeCodec;
+impl Encoder<Frame> for PineconeCodec {
+    type Error = RouterError;
+
+    /// Either writes the whole frame to `dst` or nothing, so that a frame that can't
+    /// be encoded doesn't leave a part of it in the stream.
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+        let result = encode_frame(item, dst);
+        if result.is_err() {
+            dst.truncate(start);
+        }
+        result
+    }
+}
+fn encode_frame(item: Frame, dst: &mut BytesMut) -> Result<(), RouterError> {
+    let len = u16::try_from(item.encoded_len())
+        .map_err(|_| RouterError::EncodingError("Frame is too long"))?;
+    dst.reserve(len as usize);
+
+    dst.put_slice(FRAME_MAGIC_BYTES.as_slice());
+    dst.put_u8(0); // FrameVersion
+    dst.put_u8(match &item {
+        // FrameType
+        Frame::TreeRouted(_) => 2,
+        Frame::SnekRouted(_) => 8,
+        Frame::TreeAnnouncement(_) => 1,
+        Frame::SnekBootstrap(_) => 3,
+        Frame::SnekBootstrapACK(_) => 4,
+        Frame::SnekSetup(_) => 5,
+        Frame::SnekSetupACK(_) => 6,
+        Frame::SnekTeardown(_) => 7,
+        Frame::CoordinatesRequest(_) => 9,
+        Frame::CoordinatesResponse(_) => 10,
+        Frame::HybridRouted(_) => 11,
+        Frame::Probe(_) => 12,
+        Frame::ProbeReply(_) => 13,
+        Frame::Broadcast(_) => 14,
+        Frame::DhtPut(_) => 15,
+        Frame::DhtGet(_) => 16,
+        Frame::DhtGetResponse(_) => 17,
+        Frame::Mail(_) => 18,
+        Frame::MailReply(_) => 19,
+    });
+    dst.put_u8(match &item {
+        Frame::TreeRouted(packet) => packet.traffic_class.to_byte(),
+        Frame::SnekRouted(packet) => packet.traffic_class.to_byte(),
+        Frame::HybridRouted(packet) => packet.traffic_class.to_byte(),
+        Frame::Broadcast(packet) => packet.traffic_class.to_byte(),
+        _ => 0,
+    });
+    dst.put_u8(match &item {
+        Frame::TreeRouted(packet) => packet.hop_limit,
+        Frame::SnekRouted(packet) => packet.hop_limit,
+        Frame::HybridRouted(packet) => packet.hop_limit,
+        Frame::Probe(probe) => probe.hop_limit,
+        Frame::ProbeReply(reply) => reply.hop_limit,
+        Frame::Broadcast(packet) => packet.hop_limit,
+        Frame::SnekBootstrap(packet) => packet.hop_limit,
+        Frame::CoordinatesRequest(packet) => packet.hop_limit,
+        Frame::CoordinatesResponse(packet) => packet.hop_limit,
+        Frame::DhtPut(packet) => packet.hop_limit,
+        Frame::DhtGet(packet) => packet.hop_limit,
+        Frame::DhtGetResponse(packet) => packet.hop_limit,
+        Frame::Mail(packet) => packet.hop_limit,
+        Frame::MailReply(packet) => packet.hop_limit,
+        _ => 0,
+    });
+    dst.put_u16(len);
+    match item {
+        Frame::TreeRouted(packet) => {
+            dst.put_u16(packet.destination_coordinates.coordinates.len() as u16);
+            for coord in packet.destination_coordinates.coordinates {
+                dst.put_u64(coord);
             }
-            Frame::SnekRouted(packet) => {
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_slice(packet.source_key.as_slice());
-                dst.put_slice(packet.payload.as_slice());
+            dst.put_u16(packet.source_coordinates.coordinates.len() as u16);
+            for coord in packet.source_coordinates.coordinates {
+                dst.put_u64(coord);
             }
-            Frame::TreeAnnouncement(packet) => {
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u16(packet.signatures.len() as u16);
-                for sig in packet.signatures {
-                    dst.put_slice(sig.signing_public_key.as_slice());
-                    dst.put_u64(sig.destination_port);
-                    dst.put_slice(&sig.signature.to_bytes());
-                }
+            dst.put_slice(packet.payload.as_slice());
+        }
+        Frame::SnekRouted(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_slice(packet.payload.as_slice());
+        }
+        Frame::TreeAnnouncement(packet) => {
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u16(packet.signatures.len() as u16);
+            for sig in packet.signatures {
+                dst.put_slice(sig.signing_public_key.as_bytes());
+                dst.put_u64(sig.destination_port);
+                dst.put_slice(&sig.signature.to_bytes());
             }
-            Frame::SnekBootstrap(packet) => {
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_u16(packet.source.coordinates.len() as u16);
-                for port in packet.source.coordinates {
-                    dst.put_u64(port);
-                }
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u64(packet.path_id);
+        }
+        Frame::SnekBootstrap(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_u16(packet.source.coordinates.len() as u16);
+            for port in packet.source.coordinates {
+                dst.put_u64(port);
             }
-            Frame::SnekBootstrapACK(packet) => {
-                dst.put_u16(packet.destination_coordinates.coordinates.len() as u16);
-                for coord in packet.destination_coordinates.coordinates {
-                    dst.put_u64(coord);
-                }
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_u16(packet.source_coordinates.coordinates.len() as u16);
-                for coord in packet.source_coordinates.coordinates {
-                    dst.put_u64(coord);
-                }
-                dst.put_slice(packet.source_key.as_slice());
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u64(packet.path_id);
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u64(packet.path_id);
+        }
+        Frame::SnekBootstrapACK(packet) => {
+            dst.put_u16(packet.destination_coordinates.coordinates.len() as u16);
+            for coord in packet.destination_coordinates.coordinates {
+                dst.put_u64(coord);
             }
-            Frame::SnekSetup(packet) => {
-                dst.put_u16(packet.destination.coordinates.len() as u16);
-                for coord in packet.destination.coordinates {
-                    dst.put_u64(coord);
-                }
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_slice(packet.source_key.as_slice());
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u64(packet.path_id);
-            }
-            Frame::SnekSetupACK(packet) => {
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u64(packet.path_id);
-            }
-            Frame::SnekTeardown(packet) => {
-                dst.put_slice(packet.destination_key.as_slice());
-                dst.put_slice(packet.root.public_key.as_slice());
-                dst.put_u64(packet.root.sequence_number);
-                dst.put_u64(packet.path_id);
-            }
-        }
-        Ok(())
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_u16(packet.source_coordinates.coordinates.len() as u16);
+            for coord in packet.source_coordinates.coordinates {
+                dst.put_u64(coord);
+            }
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u64(packet.path_id);
+        }
+        Frame::SnekSetup(packet) => {
+            dst.put_u16(packet.destination.coordinates.len() as u16);
+            for coord in packet.destination.coordinates {
+                dst.put_u64(coord);
+            }
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u64(packet.path_id);
+        }
+        Frame::SnekSetupACK(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u64(packet.path_id);
+        }
+        Frame::SnekTeardown(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u64(packet.path_id);
+        }
+        Frame::CoordinatesRequest(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u64(packet.id);
+        }
+        Frame::CoordinatesResponse(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u64(packet.id);
+            dst.put_slice(&packet.signature.to_bytes());
+            dst.put_slice(packet.root.public_key.as_bytes());
+            dst.put_u64(packet.root.sequence_number);
+            dst.put_u16(packet.coordinates.coordinates.len() as u16);
+            for coord in packet.coordinates.coordinates {
+                dst.put_u64(coord);
+            }
+        }
+        Frame::HybridRouted(packet) => {
+            dst.put_u16(packet.destination_coordinates.coordinates.len() as u16);
+            for coord in packet.destination_coordinates.coordinates {
+                dst.put_u64(coord);
+            }
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_slice(packet.payload.as_slice());
+        }
+        Frame::Probe(probe) => {
+            dst.put_slice(probe.destination_key.as_bytes());
+            dst.put_slice(probe.source_key.as_bytes());
+            dst.put_u64(probe.id);
+            dst.put_u8(probe.trace as u8);
+        }
+        Frame::ProbeReply(reply) => {
+            dst.put_slice(reply.destination_key.as_bytes());
+            dst.put_slice(reply.source_key.as_bytes());
+            dst.put_u64(reply.id);
+            dst.put_u8(reply.distance);
+            dst.put_u64(reply.port);
+        }
+        Frame::Broadcast(packet) => {
+            let topic_len = u8::try_from(packet.topic.len())
+                .map_err(|_| RouterError::EncodingError("Topic is too long"))?;
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u64(packet.id);
+            dst.put_slice(&packet.signature.to_bytes());
+            dst.put_u8(topic_len);
+            dst.put_slice(packet.topic.as_bytes());
+            dst.put_slice(packet.payload.as_slice());
+        }
+        Frame::DhtPut(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u8(packet.replicas);
+            encode_record(*packet.record, dst)?;
+        }
+        Frame::DhtGet(packet) => {
+            let key_len = u8::try_from(packet.key.len())
+                .map_err(|_| RouterError::EncodingError("Key is too long"))?;
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u64(packet.id);
+            dst.put_slice(&packet.signature.to_bytes());
+            dst.put_u8(key_len);
+            dst.put_slice(packet.key.as_slice());
+        }
+        Frame::DhtGetResponse(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_u64(packet.id);
+            for record in packet.records {
+                encode_record(record, dst)?;
+            }
+        }
+        Frame::Mail(packet) => {
+            dst.put_slice(packet.destination_key.as_bytes());
+            dst.put_slice(packet.source_key.as_bytes());
+            dst.put_slice(packet.reply_key.as_bytes());
+            dst.put_u64(packet.id);
+            dst.put_slice(&packet.signature.to_bytes());
+            dst.put_slice(packet.payload.as_slice());
+        }
+        Frame::MailReply(reply) => {
+            dst.put_slice(reply.destination_key.as_bytes());
+            dst.put_slice(reply.source_key.as_bytes());
+            dst.put_u64(reply.id);
+            dst.put_u8(reply.status.to_byte());
+        }
     }
+    Ok(())
+}
+fn encode_record(record: DhtRecord, dst: &mut BytesMut) -> Result<(), RouterError> {
+    let key_len = u8::try_from(record.key.len())
+        .map_err(|_| RouterError::EncodingError("Key is too long"))?;
+    let value_len = u16::try_from(record.value.len())
+        .map_err(|_| RouterError::EncodingError("Value is too large"))?;
+    dst.put_u8(key_len);
+    dst.put_slice(record.key.as_slice());
+    dst.put_u16(value_len);
+    dst.put_slice(record.value.as_slice());
+    dst.put_slice(record.writer.as_bytes());
+    dst.put_u64(record.sequence);
+    dst.put_u64(record.expires);
+    dst.put_slice(&record.signature.to_bytes());
+    Ok(())
 }
-fn decode_key(src: &mut BytesMut) -> PublicKey {
-    let mut key: PublicKey = [0; 32];
-    for i in 0..32 {
-        key[i] = src.get_u8();
+fn ensure_remaining(src: &BytesMut, len: usize) -> Result<(), RouterError> {
+    if src.remaining() < len {
+        return Err(RouterError::DecodingError(
+            "Frame is shorter than its fields",
+        ));
     }
-    key
+    Ok(())
+}
+fn decode_u8(src: &mut BytesMut) -> Result<u8, RouterError> {
+    ensure_remaining(src, 1)?;
+    Ok(src.get_u8())
+}
+fn decode_u16(src: &mut BytesMut) -> Result<u16, RouterError> {
+    ensure_remaining(src, 2)?;
+    Ok(src.get_u16())
 }
-fn decode_signature(src: &mut BytesMut) -> Signature {
+fn decode_u64(src: &mut BytesMut) -> Result<u64, RouterError> {
+    ensure_remaining(src, 8)?;
+    Ok(src.get_u64())
+}
+fn decode_key(src: &mut BytesMut) -> Result<PublicKey, RouterError> {
+    ensure_remaining(src, 32)?;
+    let mut key = [0; 32];
+    src.copy_to_slice(&mut key);
+    Ok(PublicKey::from(key))
+}
+fn decode_signature(src: &mut BytesMut) -> Result<Signature, RouterError> {
+    ensure_remaining(src, 64)?;
     let mut sig = [0; 64];
-    for i in 0..64 {
-        sig[i] = src.get_u8();
+    src.copy_to_slice(&mut sig);
+    Ok(sig.into())
+}
+fn decode_bytes(src: &mut BytesMut, len: usize) -> Result<Vec<u8>, RouterError> {
+    ensure_remaining(src, len)?;
+    Ok(src.split_to(len).to_vec())
+}
+fn decode_record(src: &mut BytesMut) -> Result<DhtRecord, RouterError> {
+    let key_len = decode_u8(src)? as usize;
+    let key = decode_bytes(src, key_len)?;
+    let value_len = decode_u16(src)? as usize;
+    let value = decode_bytes(src, value_len)?;
+    Ok(DhtRecord {
+        key,
+        value,
+        writer: decode_key(src)?,
+        sequence: decode_u64(src)?,
+        expires: decode_u64(src)?,
+        signature: decode_signature(src)?,
+    })
+}
+fn decode_coordinates(src: &mut BytesMut) -> Result<Coordinates, RouterError> {
+    let len = decode_u16(src)?;
+    let mut coordinates = vec![];
+    for _i in 0..len {
+        coordinates.push(decode_u64(src)?);
     }
-    sig.into()
+    Ok(Coordinates::new(coordinates))
+}
+fn decode_root(src: &mut BytesMut) -> Result<Root, RouterError> {
+    Ok(Root {
+        public_key: decode_key(src)?,
+        sequence_number: decode_u64(src)?,
+    })
 }
 impl Decoder for PineconeCodec {
     type Item = Frame;
     type Error = RouterError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
-        }
         if src.len() < 4 {
             return Ok(None);
         }
         if !src.starts_with(FRAME_MAGIC_BYTES.as_slice()) {
             return Err(Self::Error::DecodingError("Magic Bytes not found"));
         }
-        if src.len() < 10 {
+        if src.len() < FRAME_HEADER_LENGTH as usize {
             return Ok(None);
         }
-        src.get_u32(); // Discard Magic Bytes
-        if src.get_u8() != 0 {
-            return Err(Self::Error::DecodingError("Not frame version 0"));
+        let len = u16::from_be_bytes([src[8], src[9]]) as usize;
+        if len < FRAME_HEADER_LENGTH as usize {
+            return Err(Self::Error::DecodingError(
+                "Frame is shorter than its header",
+            ));
         }
-        let frame_type = src.get_u8();
-        let _extra1 = src.get_u8();
-        let _extra2 = src.get_u8();
-        let len = src.get_u16();
-        if src.len() < (len - 10) as usize {
-            return Err(Self::Error::DecodingError("Did not receive enough bytes"));
+        if src.len() < len {
+            // Wait until the whole frame has arrived.
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+        let mut header = src.split_to(FRAME_HEADER_LENGTH as usize);
+        let mut src = src.split_to(len - FRAME_HEADER_LENGTH as usize);
+        header.get_u32(); // Discard Magic Bytes
+        if header.get_u8() != 0 {
+            return Err(Self::Error::DecodingError("Not frame version 0"));
         }
+        let frame_type = header.get_u8();
+        let traffic_class = TrafficClass::from_byte(header.get_u8());
+        let hop_limit = match header.get_u8() {
+            // Frames from routers that don't set a hop limit.
+            0 => DEFAULT_HOP_LIMIT,
+            hop_limit => hop_limit,
+        };
+        let src = &mut src;
         match frame_type {
             1  /*TreeAnnouncement*/ => {
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let sig_len = src.get_u16();
+                let root = decode_root(src)?;
+                let sig_len = decode_u16(src)?;
                 let mut sigs = vec![];
                 for _i in 0..sig_len {
-                    let sig_key = decode_key(src);
-                    let sig_port = src.get_u64();
-                    let signature = decode_signature(src);
                     sigs.push(RootAnnouncementSignature {
-                        signing_public_key: sig_key,
-                        destination_port: sig_port,
-                        signature
+                        signing_public_key: decode_key(src)?,
+                        destination_port: decode_u64(src)?,
+                        signature: decode_signature(src)?,
                     })
                 }
                 Ok(Some(Frame::TreeAnnouncement(TreeAnnouncement {
-                    root: Root {
-                        public_key: root_key,
-                        sequence_number: root_sequence
-                    },
+                    root,
                     signatures: sigs,
-                    receive_time: SystemTime::now(),
+                    receive_time: Instant::now(),
                     receive_order: 0
                 })))
             }
             2 /*TreePacket*/ => {
-                let dest_len = src.get_u16();
-                let mut dest = vec![];
-                for _i in 0..dest_len {
-                    dest.push(src.get_u64());
-                }
-                let source_len = src.get_u16();
-                let mut source = vec![];
-                for _i in 0..dest_len {
-                    source.push(src.get_u64());
-                }
-                let payload_len = len - 10 -4-dest_len*8-source_len*8;
-                let mut payload = vec![];
-                for _i in 0..payload_len {
-                    payload.push(src.get_u8());
-                }
+                let dest = decode_coordinates(src)?;
+                let source = decode_coordinates(src)?;
                 Ok(Some(Frame::TreeRouted(TreePacket {
-                    source_coordinates: Coordinates::new(source),
-                    destination_coordinates: Coordinates::new(dest),
-                    payload
+                    source_coordinates: source,
+                    destination_coordinates: dest,
+                    traffic_class,
+                    hop_limit,
+                    payload: src.to_vec()
                 })))
             }
             3 /*SnekBootstrap*/ => {
-                let dest_key = decode_key(src);
-                let source_len = src.get_u16();
-                let mut source = vec![];
-                for _i in 0..source_len {
-                    source.push(src.get_u64());
-                }
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let path_id = src.get_u64();
+                let dest_key = decode_key(src)?;
+                let source = decode_coordinates(src)?;
+                let root = decode_root(src)?;
+                let path_id = decode_u64(src)?;
                 Ok(Some(Frame::SnekBootstrap(SnekBootstrap {
-                    root: Root { public_key: root_key, sequence_number: root_sequence },
+                    root,
                     destination_key: dest_key,
-                    source: Coordinates::new(source),
-                    path_id
+                    source,
+                    path_id,
+                    hop_limit
                 })))
             }
             4 /*SnekBootstrapAck*/ => {
-                let dest_len = src.get_u16();
-                let mut dest = vec![];
-                for _i in 0..dest_len {
-                    dest.push(src.get_u64());
-                }
-                let dest_key = decode_key(src);
-                let source_len = src.get_u16();
-                let mut source = vec![];
-                for _i in 0..source_len {
-                    source.push(src.get_u64());
-                }
-                let source_key = decode_key(src);
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let path_id = src.get_u64();
+                let dest = decode_coordinates(src)?;
+                let dest_key = decode_key(src)?;
+                let source = decode_coordinates(src)?;
+                let source_key = decode_key(src)?;
+                let root = decode_root(src)?;
+                let path_id = decode_u64(src)?;
                 Ok(Some(Frame::SnekBootstrapACK(SnekBootstrapAck {
-                    destination_coordinates: Coordinates::new(dest),
+                    destination_coordinates: dest,
                     destination_key: dest_key,
-                    source_coordinates: Coordinates::new(source),
+                    source_coordinates: source,
                     source_key,
-                    root: Root { public_key: root_key, sequence_number: root_sequence },
+                    root,
                     path_id
                 })))
             }
             5 /*SnekSetup*/ => {
-                let dest_len = src.get_u16();
-                let mut dest = vec![];
-                for _i in 0..dest_len {
-                    dest.push(src.get_u64());
-                }
-                let dest_key = decode_key(src);
-                let source_key = decode_key(src);
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let path_id = src.get_u64();
+                let dest = decode_coordinates(src)?;
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let root = decode_root(src)?;
+                let path_id = decode_u64(src)?;
                 Ok(Some(Frame::SnekSetup(SnekSetup {
-                    root: Root { public_key: root_key, sequence_number: root_sequence },
-                    destination: Coordinates::new(dest),
+                    root,
+                    destination: dest,
                     destination_key: dest_key,
                     source_key,
                     path_id
                 })))
             }
             6 /*SnekSetupAck*/ => {
-                let dest_key = decode_key(src);
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let path_id = src.get_u64();
+                let dest_key = decode_key(src)?;
+                let root = decode_root(src)?;
+                let path_id = decode_u64(src)?;
                 Ok(Some(Frame::SnekSetupACK(SnekSetupAck {
-                    root: Root { public_key: root_key, sequence_number: root_sequence },
+                    root,
                     destination_key: dest_key,
                     path_id
                 })))
             }
             7 /*SnekTeardown*/ => {
-                let dest_key = decode_key(src);
-                let root_key = decode_key(src);
-                let root_sequence = src.get_u64();
-                let path_id = src.get_u64();
+                let dest_key = decode_key(src)?;
+                let root = decode_root(src)?;
+                let path_id = decode_u64(src)?;
                 Ok(Some(Frame::SnekTeardown(SnekTeardown {
-                    root: Root { public_key: root_key, sequence_number: root_sequence },
+                    root,
                     destination_key: dest_key,
                     path_id
                 })))
             }
             8 /*SnekPacket*/ => {
-                let dest_key = decode_key(src);
-                let source_key = decode_key(src);
-                let payload_len = len -10-64;
-                let mut payload = vec![];
-                for _i in 0..payload_len {
-                    payload.push(src.get_u8());
-                }
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
                 Ok(Some(Frame::SnekRouted(SnekPacket {
                     destination_key: dest_key,
                     source_key,
-                    payload
+                    traffic_class,
+                    hop_limit,
+                    payload: src.to_vec()
+                })))
+            }
+            9 /*CoordinatesRequest*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                Ok(Some(Frame::CoordinatesRequest(CoordinatesRequest {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    hop_limit
+                })))
+            }
+            10 /*CoordinatesResponse*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let signature = decode_signature(src)?;
+                let root = decode_root(src)?;
+                let coordinates = decode_coordinates(src)?;
+                Ok(Some(Frame::CoordinatesResponse(CoordinatesResponse {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    signature,
+                    root,
+                    coordinates,
+                    hop_limit
+                })))
+            }
+            11 /*HybridPacket*/ => {
+                let dest = decode_coordinates(src)?;
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                Ok(Some(Frame::HybridRouted(HybridPacket {
+                    destination_coordinates: dest,
+                    destination_key: dest_key,
+                    source_key,
+                    traffic_class,
+                    hop_limit,
+                    payload: src.to_vec()
+                })))
+            }
+            12 /*Probe*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let trace = decode_u8(src)? != 0;
+                Ok(Some(Frame::Probe(Probe {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    trace,
+                    hop_limit
+                })))
+            }
+            13 /*ProbeReply*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let distance = decode_u8(src)?;
+                let port = decode_u64(src)?;
+                Ok(Some(Frame::ProbeReply(ProbeReply {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    distance,
+                    port,
+                    hop_limit
+                })))
+            }
+            14 /*Broadcast*/ => {
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let signature = decode_signature(src)?;
+                let topic_len = decode_u8(src)? as usize;
+                ensure_remaining(src, topic_len)?;
+                let topic = String::from_utf8(src.split_to(topic_len).to_vec())
+                    .map_err(|_| Self::Error::DecodingError("Topic is not UTF-8"))?;
+                Ok(Some(Frame::Broadcast(Broadcast {
+                    source_key,
+                    id,
+                    signature,
+                    topic,
+                    traffic_class,
+                    hop_limit,
+                    payload: src.to_vec()
+                })))
+            }
+            15 /*DhtPut*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let replicas = decode_u8(src)?;
+                let record = decode_record(src)?;
+                Ok(Some(Frame::DhtPut(DhtPut {
+                    destination_key: dest_key,
+                    source_key,
+                    replicas,
+                    record: Box::new(record),
+                    hop_limit
+                })))
+            }
+            16 /*DhtGet*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let signature = decode_signature(src)?;
+                let key_len = decode_u8(src)? as usize;
+                let key = decode_bytes(src, key_len)?;
+                Ok(Some(Frame::DhtGet(DhtGet {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    signature,
+                    key,
+                    hop_limit
+                })))
+            }
+            17 /*DhtGetResponse*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let mut records = vec![];
+                while src.has_remaining() {
+                    records.push(decode_record(src)?);
+                }
+                Ok(Some(Frame::DhtGetResponse(DhtGetResponse {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    records,
+                    hop_limit
+                })))
+            }
+            18 /*MailPacket*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let reply_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let signature = decode_signature(src)?;
+                Ok(Some(Frame::Mail(MailPacket {
+                    destination_key: dest_key,
+                    source_key,
+                    reply_key,
+                    id,
+                    payload: src.to_vec(),
+                    signature,
+                    hop_limit
+                })))
+            }
+            19 /*MailReply*/ => {
+                let dest_key = decode_key(src)?;
+                let source_key = decode_key(src)?;
+                let id = decode_u64(src)?;
+                let status = MailStatus::from_byte(decode_u8(src)?)
+                    .ok_or(Self::Error::DecodingError("Unknown mail status"))?;
+                Ok(Some(Frame::MailReply(MailReply {
+                    destination_key: dest_key,
+                    source_key,
+                    id,
+                    status,
+                    hop_limit
                 })))
             }
             _ => {
@@ -354,3 +724,237 @@ impl Decoder for PineconeCodec {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ed25519_consensus::SigningKey;
+
+    #[test]
+    fn decode_announcement() {
+        let key = SigningKey::from([1; 32]);
+        let mut announcement = TreeAnnouncement {
+            root: Root {
+                public_key: PublicKey::from(key.verification_key()),
+                sequence_number: 1,
+            },
+            signatures: vec![],
+            receive_time: Instant::now(),
+            receive_order: 0,
+        };
+        announcement.append_signature(key, 1);
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::TreeAnnouncement(announcement.clone()), &mut buffer)
+            .unwrap();
+        let mut partial = buffer.split_to(buffer.len() - 1);
+        assert!(PineconeCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buffer);
+        match PineconeCodec.decode(&mut partial) {
+            Ok(Some(Frame::TreeAnnouncement(decoded))) => {
+                assert_eq!(decoded.root, announcement.root);
+                assert_eq!(decoded.signatures, announcement.signatures);
+            }
+            result => panic!("Should have decoded announcement but got {:?}", result),
+        }
+        assert!(partial.is_empty());
+    }
+    #[test]
+    fn reject_truncated_frame() {
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(FRAME_MAGIC_BYTES.as_slice());
+        buffer.put_u8(0);
+        buffer.put_u8(7); // SnekTeardown
+        buffer.put_u16(0);
+        buffer.put_u16(10 + 32);
+        buffer.put_slice(&[0; 32]);
+        assert!(matches!(
+            PineconeCodec.decode(&mut buffer),
+            Err(RouterError::DecodingError(_))
+        ));
+    }
+    #[test]
+    fn traffic_class_in_header() {
+        let packet = SnekPacket {
+            destination_key: PublicKey::from([1; 32]),
+            source_key: PublicKey::from([2; 32]),
+            traffic_class: TrafficClass::Interactive,
+            hop_limit: 5,
+            payload: vec![3; 10],
+        };
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::SnekRouted(packet.clone()), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer[6], 2);
+        assert_eq!(buffer[7], 5);
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::SnekRouted(decoded))) => assert_eq!(decoded, packet),
+            result => panic!("Should have decoded packet but got {:?}", result),
+        }
+    }
+    #[test]
+    fn decode_coordinates_response() {
+        let response = CoordinatesResponse::new(
+            PublicKey::from([1; 32]),
+            7,
+            Root {
+                public_key: PublicKey::from([3; 32]),
+                sequence_number: 4,
+            },
+            Coordinates::new(vec![5, 6]),
+            &SigningKey::from([2; 32]),
+        );
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::CoordinatesResponse(response.clone()), &mut buffer)
+            .unwrap();
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::CoordinatesResponse(decoded))) => {
+                assert!(decoded.verify());
+                assert_eq!(decoded, response);
+            }
+            result => panic!("Should have decoded response but got {:?}", result),
+        }
+        assert!(buffer.is_empty());
+    }
+    #[test]
+    fn decode_broadcast() {
+        let mut broadcast = Broadcast {
+            source_key: PublicKey::from([1; 32]),
+            id: 2,
+            signature: Signature::from([0; 64]),
+            topic: String::from("services"),
+            traffic_class: TrafficClass::Bulk,
+            hop_limit: 3,
+            payload: vec![4; 10],
+        };
+        broadcast.sign(&SigningKey::from([1; 32]));
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::Broadcast(broadcast.clone()), &mut buffer)
+            .unwrap();
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::Broadcast(decoded))) => {
+                assert!(decoded.verify());
+                assert_eq!(decoded, broadcast);
+            }
+            result => panic!("Should have decoded broadcast but got {:?}", result),
+        }
+        assert!(buffer.is_empty());
+    }
+    #[test]
+    fn failed_encode_leaves_no_bytes() {
+        let broadcast = Broadcast {
+            source_key: PublicKey::from([1; 32]),
+            id: 2,
+            signature: Signature::from([0; 64]),
+            topic: String::from("services"),
+            traffic_class: TrafficClass::Bulk,
+            hop_limit: 3,
+            payload: vec![4; 10],
+        };
+        let too_long = Broadcast {
+            topic: "a".repeat(256),
+            ..broadcast.clone()
+        };
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::Broadcast(broadcast.clone()), &mut buffer)
+            .unwrap();
+        assert!(PineconeCodec
+            .encode(Frame::Broadcast(too_long), &mut buffer)
+            .is_err());
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::Broadcast(decoded))) => assert_eq!(decoded, broadcast),
+            result => panic!("Should have decoded broadcast but got {:?}", result),
+        }
+        assert!(buffer.is_empty());
+    }
+    #[test]
+    fn decode_dht_frames() {
+        let key = SigningKey::from([1; 32]);
+        let record = DhtRecord::new(
+            b"key".to_vec(),
+            vec![2; 10],
+            std::time::Duration::from_secs(60),
+            &key,
+        );
+        let frames = vec![
+            Frame::DhtPut(DhtPut {
+                destination_key: PublicKey::from([3; 32]),
+                source_key: PublicKey::from([4; 32]),
+                replicas: 2,
+                record: Box::new(record.clone()),
+                hop_limit: DEFAULT_HOP_LIMIT,
+            }),
+            Frame::DhtGet(DhtGet::new(
+                PublicKey::from([3; 32]),
+                5,
+                b"key".to_vec(),
+                &SigningKey::from([4; 32]),
+            )),
+            Frame::DhtGetResponse(DhtGetResponse {
+                destination_key: PublicKey::from([4; 32]),
+                source_key: PublicKey::from([3; 32]),
+                id: 5,
+                records: vec![record.clone(), record],
+                hop_limit: DEFAULT_HOP_LIMIT,
+            }),
+        ];
+        for frame in frames {
+            let mut buffer = BytesMut::new();
+            PineconeCodec.encode(frame.clone(), &mut buffer).unwrap();
+            match (PineconeCodec.decode(&mut buffer), frame) {
+                (Ok(Some(Frame::DhtPut(decoded))), Frame::DhtPut(put)) => assert_eq!(decoded, put),
+                (Ok(Some(Frame::DhtGet(decoded))), Frame::DhtGet(get)) => assert_eq!(decoded, get),
+                (Ok(Some(Frame::DhtGetResponse(decoded))), Frame::DhtGetResponse(response)) => {
+                    assert_eq!(decoded, response)
+                }
+                (result, frame) => panic!("Should have decoded {:?} but got {:?}", frame, result),
+            }
+            assert!(buffer.is_empty());
+        }
+    }
+    #[test]
+    fn decode_signed_mail() {
+        let key = SigningKey::from([1; 32]);
+        let mail = MailPacket::new(PublicKey::from([2; 32]), 3, b"sealed".to_vec(), &key);
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::Mail(mail.clone()), &mut buffer)
+            .unwrap();
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::Mail(decoded))) => {
+                assert_eq!(decoded, mail);
+                assert!(decoded.verify());
+            }
+            result => panic!("Should have decoded mail but got {:?}", result),
+        }
+        assert!(buffer.is_empty());
+        let forged = MailPacket {
+            source_key: PublicKey::from([4; 32]),
+            ..mail
+        };
+        assert!(!forged.verify());
+    }
+    #[test]
+    fn decode_mail_reply() {
+        let reply = MailReply {
+            destination_key: PublicKey::from([1; 32]),
+            source_key: PublicKey::from([2; 32]),
+            id: 3,
+            status: MailStatus::Stored,
+            hop_limit: 7,
+        };
+        let mut buffer = BytesMut::new();
+        PineconeCodec
+            .encode(Frame::MailReply(reply.clone()), &mut buffer)
+            .unwrap();
+        match PineconeCodec.decode(&mut buffer) {
+            Ok(Some(Frame::MailReply(decoded))) => assert_eq!(decoded, reply),
+            result => panic!("Should have decoded reply but got {:?}", result),
+        }
+        assert!(buffer.is_empty());
+    }
+}